@@ -0,0 +1,669 @@
+//! The admin API under `/_conduit/admin/v1`, which offers the operations of the admin room
+//! commands to scripts. Requests are authenticated with the bearer tokens in `admin_api_tokens`,
+//! each of which only gives access to the endpoints of its scopes.
+
+use std::collections::BTreeMap;
+
+use axum::{body::Bytes, extract::Path, Json};
+use http::{header::AUTHORIZATION, HeaderMap};
+use ruma::{
+    api::{appservice::Registration, client::error::ErrorKind},
+    events::room::message::RoomMessageEventContent,
+    MxcUri, OwnedMxcUri, OwnedServerName, OwnedUserId, RoomId, ServerName, UserId,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use tracing::info;
+
+use crate::{
+    api::client_server::{leave_all_rooms, AUTO_GEN_PASSWORD_LENGTH},
+    config::{AdminApiScope, AdminApiToken},
+    service::{
+        media::{FileInfo, MediaQuery, MediaQueryFileInfo, MediaQueryThumbInfo},
+        server_notices,
+    },
+    services, utils, Error, Result,
+};
+
+#[derive(Deserialize)]
+pub struct CreateUserBody {
+    username: String,
+    password: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct DeactivateUserBody {
+    #[serde(default)]
+    leave_rooms: bool,
+    #[serde(default)]
+    purge_media: bool,
+    #[serde(default)]
+    force_filehash: bool,
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordBody {
+    password: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct PurgeMediaBody {
+    media: Vec<OwnedMxcUri>,
+    #[serde(default)]
+    force_filehash: bool,
+}
+
+#[derive(Deserialize)]
+pub struct BlockMediaBody {
+    media: Vec<OwnedMxcUri>,
+    reason: Option<String>,
+    #[serde(default)]
+    purge: bool,
+}
+
+#[derive(Deserialize)]
+pub struct DisableRoomBody {
+    disabled: bool,
+}
+
+#[derive(Deserialize)]
+pub struct ServerNoticeBody {
+    user_id: Option<String>,
+    body: String,
+    limit_type: Option<String>,
+    admin_contact: Option<String>,
+}
+
+/// # `POST /_conduit/admin/v1/users`
+///
+/// Creates a local user, with a random password if none is given.
+pub async fn create_user_route(headers: HeaderMap, body: Bytes) -> Result<Json<Value>> {
+    authorize(&headers, AdminApiScope::Users)?;
+    let body: CreateUserBody = parse_body(&body)?;
+
+    let user_id = local_user_id(&body.username)?;
+    if user_id.is_historical() {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidUsername,
+            "User id is not allowed due to historical characters.",
+        ));
+    }
+    if services().users.exists(&user_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::UserInUse,
+            "User id is already taken.",
+        ));
+    }
+
+    services()
+        .users
+        .provision(&user_id, None, "the admin API")
+        .await?;
+
+    let password = body
+        .password
+        .unwrap_or_else(|| utils::random_string(AUTO_GEN_PASSWORD_LENGTH));
+    services().users.set_password(&user_id, Some(&password))?;
+
+    Ok(Json(json!({
+        "user_id": user_id,
+        "password": password,
+    })))
+}
+
+/// # `POST /_conduit/admin/v1/users/{userId}/deactivate`
+///
+/// Deactivates a local user.
+///
+/// - Makes the user leave all rooms if `leave_rooms` is set
+/// - Purges the media the user uploaded if `purge_media` is set
+pub async fn deactivate_user_route(
+    headers: HeaderMap,
+    Path(user_id): Path<String>,
+    body: Bytes,
+) -> Result<Json<Value>> {
+    authorize(&headers, AdminApiScope::Users)?;
+    let body: DeactivateUserBody = parse_body(&body)?;
+
+    let user_id = existing_local_user_id(&user_id)?;
+
+    services().users.deactivate_account(&user_id)?;
+    info!("{user_id} was deactivated through the admin API");
+
+    if body.leave_rooms {
+        leave_all_rooms(&user_id).await?;
+    }
+
+    let failed_media_purges = if body.purge_media {
+        services()
+            .media
+            .purge_from_user(&user_id, body.force_filehash, None)
+            .await
+    } else {
+        Vec::new()
+    };
+
+    Ok(Json(json!({
+        "user_id": user_id,
+        "failed_media_purges": errors_to_json(failed_media_purges),
+    })))
+}
+
+/// # `POST /_conduit/admin/v1/users/{userId}/reset_password`
+///
+/// Sets the password of a local user, to a random one if none is given.
+pub async fn reset_password_route(
+    headers: HeaderMap,
+    Path(user_id): Path<String>,
+    body: Bytes,
+) -> Result<Json<Value>> {
+    authorize(&headers, AdminApiScope::Users)?;
+    let body: ResetPasswordBody = parse_body(&body)?;
+
+    let user_id = existing_local_user_id(&user_id)?;
+
+    let password = body
+        .password
+        .unwrap_or_else(|| utils::random_string(AUTO_GEN_PASSWORD_LENGTH));
+    services().users.set_password(&user_id, Some(&password))?;
+
+    Ok(Json(json!({
+        "user_id": user_id,
+        "password": password,
+    })))
+}
+
+/// # `POST /_conduit/admin/v1/server_notices`
+///
+/// Sends a server notice to a local user, or to all local users if `user_id` is not set.
+///
+/// - Sends a resource-limit notice if `limit_type` is set
+/// - 404 signals that `user_id` is not a local user
+pub async fn send_server_notice_route(headers: HeaderMap, body: Bytes) -> Result<Json<Value>> {
+    authorize(&headers, AdminApiScope::Users)?;
+    let body: ServerNoticeBody = parse_body(&body)?;
+
+    let content = match body.limit_type {
+        Some(limit_type) => {
+            server_notices::usage_limit_reached_notice(body.body, &limit_type, body.admin_contact)
+        }
+        None => RoomMessageEventContent::text_plain(body.body),
+    };
+
+    let (sent, failed) = match body.user_id {
+        Some(user_id) => {
+            // Remote users can't receive server notices, so they are reported like unknown users
+            let user_id = match local_user_id(&user_id) {
+                Ok(user_id)
+                    if services().users.exists(&user_id)?
+                        && user_id != services().globals.server_user() =>
+                {
+                    user_id
+                }
+                _ => {
+                    return Err(Error::BadRequest(
+                        ErrorKind::NotFound,
+                        "User does not exist.",
+                    ))
+                }
+            };
+            services().server_notices.send(&user_id, &content).await?;
+            (1, 0)
+        }
+        None => services().server_notices.send_to_all(&content).await,
+    };
+
+    Ok(Json(json!({
+        "sent": sent,
+        "failed": failed,
+    })))
+}
+
+/// # `GET /_conduit/admin/v1/media/{serverName}/{mediaId}`
+///
+/// Returns information about the media and its thumbnails.
+pub async fn query_media_route(
+    headers: HeaderMap,
+    Path((server_name, media_id)): Path<(String, String)>,
+) -> Result<Json<Value>> {
+    authorize(&headers, AdminApiScope::Media)?;
+
+    let server_name = <&ServerName>::try_from(server_name.as_str())
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid server name."))?;
+
+    let MediaQuery {
+        is_blocked,
+        source_file,
+        thumbnails,
+    } = services().media.query(server_name, &media_id)?;
+
+    let source_file = source_file.map(
+        |MediaQueryFileInfo {
+             uploader_localpart,
+             sha256_hex,
+             filename,
+             content_type,
+             unauthenticated_access_permitted,
+             is_blocked_via_filehash,
+             file_info,
+         }| {
+            json!({
+                "uploader": uploader_localpart.map(|localpart| format!("@{localpart}:{server_name}")),
+                "sha256": sha256_hex,
+                "filename": filename,
+                "content_type": content_type,
+                "unauthenticated_access_permitted": unauthenticated_access_permitted,
+                "is_blocked_via_filehash": is_blocked_via_filehash,
+                "file": file_info.map(file_info_to_json),
+            })
+        },
+    );
+
+    let thumbnails = thumbnails
+        .into_iter()
+        .map(
+            |MediaQueryThumbInfo {
+                 width,
+                 height,
+                 sha256_hex,
+                 filename,
+                 content_type,
+                 unauthenticated_access_permitted,
+                 is_blocked_via_filehash,
+                 file_info,
+             }| {
+                json!({
+                    "width": width,
+                    "height": height,
+                    "sha256": sha256_hex,
+                    "filename": filename,
+                    "content_type": content_type,
+                    "unauthenticated_access_permitted": unauthenticated_access_permitted,
+                    "is_blocked_via_filehash": is_blocked_via_filehash,
+                    "file": file_info.map(file_info_to_json),
+                })
+            },
+        )
+        .collect::<Vec<_>>();
+
+    Ok(Json(json!({
+        "is_blocked": is_blocked,
+        "source_file": source_file,
+        "thumbnails": thumbnails,
+    })))
+}
+
+/// # `POST /_conduit/admin/v1/media/purge`
+///
+/// Deletes the listed media.
+///
+/// - Also deletes media with the same sha256 hash if `force_filehash` is set
+pub async fn purge_media_route(headers: HeaderMap, body: Bytes) -> Result<Json<Value>> {
+    authorize(&headers, AdminApiScope::Media)?;
+    let body: PurgeMediaBody = parse_body(&body)?;
+
+    let failed = services()
+        .media
+        .purge(&media_ids(&body.media)?, body.force_filehash)
+        .await;
+
+    Ok(Json(json!({
+        "failed": errors_to_json(failed),
+    })))
+}
+
+/// # `POST /_conduit/admin/v1/media/block`
+///
+/// Prevents the listed media, and media with the same sha256 hash, from being accessed.
+///
+/// - Also deletes the media if `purge` is set
+pub async fn block_media_route(headers: HeaderMap, body: Bytes) -> Result<Json<Value>> {
+    authorize(&headers, AdminApiScope::Media)?;
+    let body: BlockMediaBody = parse_body(&body)?;
+
+    let media = media_ids(&body.media)?;
+
+    let failed_blocks = services().media.block(&media, body.reason);
+    let failed_purges = if body.purge {
+        services().media.purge(&media, true).await
+    } else {
+        Vec::new()
+    };
+
+    Ok(Json(json!({
+        "failed_blocks": errors_to_json(failed_blocks),
+        "failed_purges": errors_to_json(failed_purges),
+    })))
+}
+
+/// # `GET /_conduit/admin/v1/rooms`
+///
+/// Lists all rooms the server knows about.
+pub async fn list_rooms_route(headers: HeaderMap) -> Result<Json<Value>> {
+    authorize(&headers, AdminApiScope::Rooms)?;
+
+    let rooms = services()
+        .rooms
+        .metadata
+        .iter_ids()
+        .filter_map(|r| r.ok())
+        .map(|room_id| {
+            Ok(json!({
+                "joined_members": services()
+                    .rooms
+                    .state_cache
+                    .room_joined_count(&room_id)?
+                    .unwrap_or(0),
+                "disabled": services().rooms.metadata.is_disabled(&room_id)?,
+                "room_id": room_id,
+            }))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(Json(json!({ "rooms": rooms })))
+}
+
+/// # `PUT /_conduit/admin/v1/rooms/{roomId}/disabled`
+///
+/// Disables or enables the handling of incoming federation for a room.
+pub async fn disable_room_route(
+    headers: HeaderMap,
+    Path(room_id): Path<String>,
+    body: Bytes,
+) -> Result<Json<Value>> {
+    authorize(&headers, AdminApiScope::Rooms)?;
+    let body: DisableRoomBody = parse_body(&body)?;
+
+    let room_id = <&RoomId>::try_from(room_id.as_str())
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid room id."))?;
+
+    services()
+        .rooms
+        .metadata
+        .disable_room(room_id, body.disabled)?;
+
+    Ok(Json(json!({})))
+}
+
+/// # `GET /_conduit/admin/v1/appservices`
+///
+/// Lists the ids of the registered appservices.
+pub async fn list_appservices_route(headers: HeaderMap) -> Result<Json<Value>> {
+    authorize(&headers, AdminApiScope::Appservices)?;
+
+    Ok(Json(json!({
+        "appservices": services().appservice.iter_ids().await,
+    })))
+}
+
+/// # `POST /_conduit/admin/v1/appservices`
+///
+/// Registers an appservice. The body is its registration in YAML or JSON.
+///
+/// - Replaces the appservice with the same id, if there is one
+pub async fn register_appservice_route(headers: HeaderMap, body: Bytes) -> Result<Json<Value>> {
+    authorize(&headers, AdminApiScope::Appservices)?;
+
+    // JSON is valid YAML
+    let registration = serde_yaml::from_slice::<Registration>(&body)
+        .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid appservice registration."))?;
+
+    let id = services()
+        .appservice
+        .register_appservice(registration)
+        .await?;
+
+    Ok(Json(json!({ "id": id })))
+}
+
+/// # `DELETE /_conduit/admin/v1/appservices/{id}`
+///
+/// Unregisters an appservice.
+pub async fn unregister_appservice_route(
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<Value>> {
+    authorize(&headers, AdminApiScope::Appservices)?;
+
+    services().appservice.unregister_appservice(&id).await?;
+
+    Ok(Json(json!({})))
+}
+
+/// # `GET /_conduit/admin/v1/config`
+///
+/// Returns the config values shown by the `show-config` admin command.
+pub async fn get_config_route(headers: HeaderMap) -> Result<Json<Value>> {
+    authorize(&headers, AdminApiScope::Config)?;
+
+    let config = services()
+        .globals
+        .config
+        .shown_values()
+        .into_iter()
+        .collect::<BTreeMap<_, _>>();
+
+    Ok(Json(json!({ "config": config })))
+}
+
+/// Fails unless the request has a bearer token from `admin_api_tokens` with the scope.
+fn authorize(headers: &HeaderMap, scope: AdminApiScope) -> Result<()> {
+    check_token(&services().globals.config.admin_api_tokens, headers, scope)
+}
+
+fn check_token(tokens: &[AdminApiToken], headers: &HeaderMap, scope: AdminApiScope) -> Result<()> {
+    let token = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(Error::BadRequest(
+            ErrorKind::MissingToken,
+            "Missing admin API token.",
+        ))?;
+
+    let token = tokens
+        .iter()
+        .find(|admin_api_token| {
+            utils::constant_time_eq(admin_api_token.token.as_bytes(), token.as_bytes())
+        })
+        .ok_or(Error::BadRequest(
+            ErrorKind::UnknownToken { soft_logout: false },
+            "Unknown admin API token.",
+        ))?;
+
+    if !token.scopes.contains(&scope) {
+        return Err(Error::BadRequest(
+            ErrorKind::forbidden(),
+            "The admin API token is not allowed to use this endpoint.",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Parses a JSON body. An empty body is treated like an empty object.
+fn parse_body<T: DeserializeOwned>(body: &[u8]) -> Result<T> {
+    let body = if body.is_empty() { b"{}" } else { body };
+
+    serde_json::from_slice(body)
+        .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid request body."))
+}
+
+/// Parses a user id or the localpart of a user id, which must belong to this server.
+fn local_user_id(username: &str) -> Result<OwnedUserId> {
+    let user_id =
+        UserId::parse_with_server_name(username.to_lowercase(), services().globals.server_name())
+            .map_err(|_| Error::BadRequest(ErrorKind::InvalidUsername, "Invalid user id."))?;
+
+    if user_id.server_name() != services().globals.server_name() {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "User is not from this server.",
+        ));
+    }
+
+    Ok(user_id)
+}
+
+/// Like `local_user_id`, but the user must exist and must not be the server user.
+fn existing_local_user_id(username: &str) -> Result<OwnedUserId> {
+    let user_id = local_user_id(username)?;
+
+    if !services().users.exists(&user_id)? || user_id == services().globals.server_user() {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "User does not exist.",
+        ));
+    }
+
+    Ok(user_id)
+}
+
+fn media_ids(mxc_uris: &[OwnedMxcUri]) -> Result<Vec<(OwnedServerName, String)>> {
+    mxc_uris
+        .iter()
+        .map(|mxc| {
+            <&MxcUri>::from(mxc.as_str())
+                .parts()
+                .map(|(server_name, media_id)| (server_name.to_owned(), media_id.to_owned()))
+                .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid MXC URI."))
+        })
+        .collect()
+}
+
+fn file_info_to_json(
+    FileInfo {
+        creation,
+        last_access,
+        size,
+    }: FileInfo,
+) -> Value {
+    json!({
+        "created_at": creation,
+        "last_accessed_at": last_access,
+        "size": size,
+    })
+}
+
+fn errors_to_json(errors: Vec<Error>) -> Vec<String> {
+    errors.iter().map(ToString::to_string).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{header::AUTHORIZATION, HeaderMap, HeaderValue};
+    use ruma::api::client::error::ErrorKind;
+
+    use super::check_token;
+    use crate::{
+        config::{AdminApiScope, AdminApiToken},
+        Error,
+    };
+
+    fn tokens() -> Vec<AdminApiToken> {
+        vec![
+            AdminApiToken {
+                token: "users-token".to_owned(),
+                scopes: vec![AdminApiScope::Users],
+            },
+            AdminApiToken {
+                token: "media-token".to_owned(),
+                scopes: vec![AdminApiScope::Media, AdminApiScope::Rooms],
+            },
+        ]
+    }
+
+    fn headers(authorization: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_str(authorization).unwrap());
+        headers
+    }
+
+    #[test]
+    fn token_with_scope_is_allowed() {
+        let tokens = tokens();
+
+        assert!(check_token(
+            &tokens,
+            &headers("Bearer users-token"),
+            AdminApiScope::Users
+        )
+        .is_ok());
+        assert!(check_token(
+            &tokens,
+            &headers("Bearer media-token"),
+            AdminApiScope::Media
+        )
+        .is_ok());
+        assert!(check_token(
+            &tokens,
+            &headers("Bearer media-token"),
+            AdminApiScope::Rooms
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn token_without_scope_is_forbidden() {
+        let tokens = tokens();
+
+        for (token, scope) in [
+            ("Bearer users-token", AdminApiScope::Media),
+            ("Bearer users-token", AdminApiScope::Config),
+            ("Bearer media-token", AdminApiScope::Users),
+            ("Bearer media-token", AdminApiScope::Appservices),
+        ] {
+            assert!(
+                matches!(
+                    check_token(&tokens, &headers(token), scope),
+                    Err(Error::BadRequest(ErrorKind::Forbidden { .. }, _))
+                ),
+                "{token} {scope:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_tokens_are_rejected() {
+        let tokens = tokens();
+
+        for token in [
+            "Bearer users",
+            "Bearer users-token2",
+            "Bearer USERS-TOKEN",
+            "Bearer ",
+        ] {
+            assert!(
+                matches!(
+                    check_token(&tokens, &headers(token), AdminApiScope::Users),
+                    Err(Error::BadRequest(ErrorKind::UnknownToken { .. }, _))
+                ),
+                "{token}"
+            );
+        }
+    }
+
+    #[test]
+    fn missing_tokens_are_rejected() {
+        let tokens = tokens();
+
+        for headers in [
+            HeaderMap::new(),
+            headers("users-token"),
+            headers("Basic users-token"),
+        ] {
+            assert!(matches!(
+                check_token(&tokens, &headers, AdminApiScope::Users),
+                Err(Error::BadRequest(ErrorKind::MissingToken, _))
+            ));
+        }
+    }
+
+    #[test]
+    fn no_tokens_configured() {
+        assert!(matches!(
+            check_token(&[], &headers("Bearer users-token"), AdminApiScope::Users),
+            Err(Error::BadRequest(ErrorKind::UnknownToken { .. }, _))
+        ));
+    }
+}
@@ -1,20 +1,30 @@
 use super::{DEVICE_ID_LENGTH, SESSION_ID_LENGTH, TOKEN_LENGTH};
-use crate::{api::client_server, services, utils, Error, Result, Ruma};
+use crate::{api::client_server, service::threepid::Purpose, services, utils, Error, Result, Ruma};
+use axum::{
+    extract::Query,
+    response::{IntoResponse, Redirect, Response},
+};
+use http::header::CONTENT_TYPE;
 use ruma::{
     api::client::{
         account::{
-            change_password, deactivate, get_3pids, get_username_availability,
+            add_3pid, bind_3pid, change_password, deactivate, delete_3pid, get_3pids,
+            get_username_availability,
             register::{self, LoginType},
             request_3pid_management_token_via_email, request_3pid_management_token_via_msisdn,
-            whoami, ThirdPartyIdRemovalStatus,
+            request_password_change_token_via_email, whoami, ThirdPartyIdRemovalStatus,
         },
         error::ErrorKind,
-        uiaa::{AuthFlow, AuthType, UiaaInfo},
+        uiaa::{AuthData, AuthFlow, AuthType, EmailIdentity, UiaaInfo},
     },
     events::{room::message::RoomMessageEventContent, GlobalAccountDataEventType},
-    push, UserId,
+    push,
+    thirdparty::Medium,
+    OwnedClientSecret, OwnedSessionId, UserId,
 };
+use serde::Deserialize;
 use tracing::{info, warn};
+use url::Url;
 
 use register::RegistrationKind;
 
@@ -141,6 +151,8 @@ pub async fn register_route(body: Ruma<register::v3::Request>) -> Result<registe
         ));
     }
 
+    services().policy.check_registration(&user_id).await?;
+
     // UIAA
     let mut uiaainfo;
     let skip_auth = if services().globals.config.registration_token.is_some() {
@@ -171,13 +183,16 @@ pub async fn register_route(body: Ruma<register::v3::Request>) -> Result<registe
 
     if !skip_auth {
         if let Some(auth) = &body.auth {
-            let (worked, uiaainfo) = services().uiaa.try_auth(
-                &UserId::parse_with_server_name("", services().globals.server_name())
-                    .expect("we know this is valid"),
-                "".into(),
-                auth,
-                &uiaainfo,
-            )?;
+            let (worked, uiaainfo) = services()
+                .uiaa
+                .try_auth(
+                    &UserId::parse_with_server_name("", services().globals.server_name())
+                        .expect("we know this is valid"),
+                    "".into(),
+                    auth,
+                    &uiaainfo,
+                )
+                .await?;
             if !worked {
                 return Err(Error::Uiaa(uiaainfo));
             }
@@ -205,6 +220,11 @@ pub async fn register_route(body: Ruma<register::v3::Request>) -> Result<registe
 
     // Create user
     services().users.create(&user_id, password)?;
+    if let Some(ref info) = body.appservice_info {
+        services()
+            .appservice
+            .add_user(&info.registration.id, &user_id)?;
+    }
 
     // Default to pretty displayname
     let mut displayname = user_id.localpart().to_owned();
@@ -261,6 +281,16 @@ pub async fn register_route(body: Ruma<register::v3::Request>) -> Result<registe
         body.initial_device_display_name.clone(),
     )?;
 
+    // Only clients that support refresh tokens get an access token that expires
+    let (refresh_token, expires_in) = if body.refresh_token {
+        services()
+            .users
+            .create_refresh_token(&user_id, &device_id)?
+            .unzip()
+    } else {
+        (None, None)
+    };
+
     info!("New user {} registered on this server.", user_id);
     if body.appservice_info.is_none() && !is_guest {
         services()
@@ -294,8 +324,8 @@ pub async fn register_route(body: Ruma<register::v3::Request>) -> Result<registe
         access_token: Some(token),
         user_id,
         device_id: Some(device_id),
-        refresh_token: None,
-        expires_in: None,
+        refresh_token,
+        expires_in,
     })
 }
 
@@ -303,7 +333,9 @@ pub async fn register_route(body: Ruma<register::v3::Request>) -> Result<registe
 ///
 /// Changes the password of this account.
 ///
-/// - Requires UIAA to verify user password
+/// - Requires UIAA to verify user password or email address
+/// - Users who are not logged in can reset their password by verifying an email address that is
+///   linked to their account
 /// - Changes the password of the sender user
 /// - The password hash is calculated using argon2 with 32 character salt, the plain password is
 ///   not saved
@@ -316,55 +348,88 @@ pub async fn register_route(body: Ruma<register::v3::Request>) -> Result<registe
 pub async fn change_password_route(
     body: Ruma<change_password::v3::Request>,
 ) -> Result<change_password::v3::Response> {
-    let sender_user = body
-        .sender_user
-        .as_ref()
-        // In the future password changes could be performed with UIA with 3PIDs, but we don't support that currently
-        .ok_or_else(|| Error::BadRequest(ErrorKind::MissingToken, "Missing access token."))?;
-    let sender_device = body.sender_device.as_ref().expect("user is authenticated");
-
-    let mut uiaainfo = UiaaInfo {
-        flows: vec![AuthFlow {
-            stages: vec![AuthType::Password],
-        }],
-        completed: Vec::new(),
-        params: Default::default(),
-        session: None,
-        auth_error: None,
-    };
+    let (sender_user, sender_device) = match (&body.sender_user, &body.sender_device) {
+        (Some(sender_user), Some(sender_device)) => {
+            let mut uiaainfo = UiaaInfo {
+                flows: identity_flows(sender_user),
+                completed: Vec::new(),
+                params: Default::default(),
+                session: None,
+                auth_error: None,
+            };
+
+            if let Some(auth) = &body.auth {
+                let (worked, uiaainfo) = services()
+                    .uiaa
+                    .try_auth(sender_user, sender_device, auth, &uiaainfo)
+                    .await?;
+                if !worked {
+                    return Err(Error::Uiaa(uiaainfo));
+                }
+            // Success!
+            } else if let Some(json) = &body.json_body {
+                uiaainfo.session = Some(utils::random_string(SESSION_ID_LENGTH));
+                services()
+                    .uiaa
+                    .create(sender_user, sender_device, &uiaainfo, json)?;
+                return Err(Error::Uiaa(uiaainfo));
+            } else {
+                return Err(Error::BadRequest(ErrorKind::NotJson, "Not json."));
+            }
 
-    if let Some(auth) = &body.auth {
-        let (worked, uiaainfo) =
+            (sender_user.clone(), Some(sender_device))
+        }
+        _ => {
+            // The user forgot their password, so the email address is the only stage
+            let Some(AuthData::EmailIdentity(EmailIdentity {
+                thirdparty_id_creds,
+                ..
+            })) = &body.auth
+            else {
+                return Err(Error::Uiaa(UiaaInfo {
+                    flows: vec![AuthFlow {
+                        stages: vec![AuthType::EmailIdentity],
+                    }],
+                    completed: Vec::new(),
+                    params: Default::default(),
+                    session: Some(utils::random_string(SESSION_ID_LENGTH)),
+                    auth_error: None,
+                }));
+            };
+
+            let validation = services().threepid.validated(
+                &thirdparty_id_creds.sid,
+                &thirdparty_id_creds.client_secret,
+                Purpose::PasswordReset,
+            )?;
+            let sender_user = services()
+                .threepid
+                .owner(&validation.medium, &validation.address)?
+                .ok_or(Error::BadRequest(
+                    ErrorKind::ThreepidNotFound,
+                    "This email address is not linked to any account.",
+                ))?;
             services()
-                .uiaa
-                .try_auth(sender_user, sender_device, auth, &uiaainfo)?;
-        if !worked {
-            return Err(Error::Uiaa(uiaainfo));
+                .threepid
+                .end_validation(&thirdparty_id_creds.sid)?;
+
+            (sender_user, None)
         }
-    // Success!
-    } else if let Some(json) = body.json_body {
-        uiaainfo.session = Some(utils::random_string(SESSION_ID_LENGTH));
-        services()
-            .uiaa
-            .create(sender_user, sender_device, &uiaainfo, &json)?;
-        return Err(Error::Uiaa(uiaainfo));
-    } else {
-        return Err(Error::BadRequest(ErrorKind::NotJson, "Not json."));
-    }
+    };
 
     services()
         .users
-        .set_password(sender_user, Some(&body.new_password))?;
+        .set_password(&sender_user, Some(&body.new_password))?;
 
     if body.logout_devices {
         // Logout all devices except the current one
         for id in services()
             .users
-            .all_device_ids(sender_user)
+            .all_device_ids(&sender_user)
             .filter_map(|id| id.ok())
-            .filter(|id| id != sender_device)
+            .filter(|id| Some(id) != sender_device)
         {
-            services().users.remove_device(sender_user, &id)?;
+            services().users.remove_device(&sender_user, &id)?;
         }
     }
 
@@ -378,6 +443,31 @@ pub async fn change_password_route(
     Ok(change_password::v3::Response {})
 }
 
+/// # `POST /_matrix/client/v3/account/password/email/requestToken`
+///
+/// Sends an email with a link that allows the user to reset their password.
+///
+/// - 400 signals that the email address is not linked to any account
+/// - 403 signals that the homeserver can't send emails
+pub async fn request_password_change_token_via_email_route(
+    body: Ruma<request_password_change_token_via_email::v3::Request>,
+) -> Result<request_password_change_token_via_email::v3::Response> {
+    let sid = services()
+        .threepid
+        .request_email_validation(
+            &body.client_secret,
+            &body.email,
+            body.send_attempt,
+            body.next_link.clone(),
+            Purpose::PasswordReset,
+        )
+        .await?;
+
+    Ok(request_password_change_token_via_email::v3::Response::new(
+        sid,
+    ))
+}
+
 /// # `GET _matrix/client/r0/account/whoami`
 ///
 /// Get user_id of the sender user.
@@ -415,9 +505,7 @@ pub async fn deactivate_route(
     let sender_device = body.sender_device.as_ref().expect("user is authenticated");
 
     let mut uiaainfo = UiaaInfo {
-        flows: vec![AuthFlow {
-            stages: vec![AuthType::Password],
-        }],
+        flows: identity_flows(sender_user),
         completed: Vec::new(),
         params: Default::default(),
         session: None,
@@ -425,10 +513,10 @@ pub async fn deactivate_route(
     };
 
     if let Some(auth) = &body.auth {
-        let (worked, uiaainfo) =
-            services()
-                .uiaa
-                .try_auth(sender_user, sender_device, auth, &uiaainfo)?;
+        let (worked, uiaainfo) = services()
+            .uiaa
+            .try_auth(sender_user, sender_device, auth, &uiaainfo)
+            .await?;
         if !worked {
             return Err(Error::Uiaa(uiaainfo));
         }
@@ -448,6 +536,7 @@ pub async fn deactivate_route(
 
     // Remove devices and mark account as deactivated
     services().users.deactivate_account(sender_user)?;
+    services().threepid.remove_all(sender_user)?;
 
     info!("User {} deactivated their account.", sender_user);
     services()
@@ -464,27 +553,43 @@ pub async fn deactivate_route(
 /// # `GET _matrix/client/v3/account/3pid`
 ///
 /// Get a list of third party identifiers associated with this account.
-///
-/// - Currently always returns empty list
 pub async fn third_party_route(
     body: Ruma<get_3pids::v3::Request>,
 ) -> Result<get_3pids::v3::Response> {
-    let _sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    Ok(get_3pids::v3::Response::new(Vec::new()))
+    let threepids = services()
+        .threepid
+        .threepids(sender_user)
+        .filter_map(|threepid| threepid.ok())
+        .collect();
+
+    Ok(get_3pids::v3::Response::new(threepids))
 }
 
 /// # `POST /_matrix/client/v3/account/3pid/email/requestToken`
 ///
 /// "This API should be used to request validation tokens when adding an email address to an account"
 ///
-/// - 403 signals that The homeserver does not allow the third party identifier as a contact option.
+/// - Sends an email with a link that validates the session
+/// - 400 signals that the email address is already in use
+/// - 403 signals that the homeserver can't send emails
 pub async fn request_3pid_management_token_via_email_route(
-    _body: Ruma<request_3pid_management_token_via_email::v3::Request>,
+    body: Ruma<request_3pid_management_token_via_email::v3::Request>,
 ) -> Result<request_3pid_management_token_via_email::v3::Response> {
-    Err(Error::BadRequest(
-        ErrorKind::ThreepidDenied,
-        "Third party identifiers are currently unsupported by this server implementation",
+    let sid = services()
+        .threepid
+        .request_email_validation(
+            &body.client_secret,
+            &body.email,
+            body.send_attempt,
+            body.next_link.clone(),
+            Purpose::AddThreepid,
+        )
+        .await?;
+
+    Ok(request_3pid_management_token_via_email::v3::Response::new(
+        sid,
     ))
 }
 
@@ -493,11 +598,187 @@ pub async fn request_3pid_management_token_via_email_route(
 /// "This API should be used to request validation tokens when adding an phone number to an account"
 ///
 /// - 403 signals that The homeserver does not allow the third party identifier as a contact option.
+///   Conduit can't send text messages, so phone numbers can't be validated.
 pub async fn request_3pid_management_token_via_msisdn_route(
     _body: Ruma<request_3pid_management_token_via_msisdn::v3::Request>,
 ) -> Result<request_3pid_management_token_via_msisdn::v3::Response> {
     Err(Error::BadRequest(
         ErrorKind::ThreepidDenied,
-        "Third party identifiers are currently unsupported by this server implementation",
+        "Phone numbers are currently unsupported by this server implementation",
     ))
 }
+
+#[derive(Deserialize)]
+pub struct SubmitTokenParams {
+    sid: OwnedSessionId,
+    client_secret: OwnedClientSecret,
+    token: String,
+}
+
+/// # `GET /_conduit/client/3pid/email/submit_token`
+///
+/// Validates the session of an email address. This is the link that is sent in validation emails.
+///
+/// - Redirects to the `next_link` of the session, if the client set one
+pub async fn submit_email_token_route(Query(params): Query<SubmitTokenParams>) -> Result<Response> {
+    let next_link =
+        services()
+            .threepid
+            .submit_token(&params.sid, &params.client_secret, &params.token)?;
+
+    Ok(match next_link {
+        Some(next_link) => Redirect::to(next_link.as_str()).into_response(),
+        None => "Your email address has been validated. You can now go back to your client."
+            .into_response(),
+    })
+}
+
+/// # `POST /_matrix/client/v3/account/3pid/add`
+///
+/// Links a third party identifier that was validated before to the account.
+///
+/// - Requires UIAA to verify user password
+pub async fn add_3pid_route(body: Ruma<add_3pid::v3::Request>) -> Result<add_3pid::v3::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_device = body.sender_device.as_ref().expect("user is authenticated");
+
+    let mut uiaainfo = UiaaInfo {
+        flows: vec![AuthFlow {
+            stages: vec![AuthType::Password],
+        }],
+        completed: Vec::new(),
+        params: Default::default(),
+        session: None,
+        auth_error: None,
+    };
+
+    if let Some(auth) = &body.auth {
+        let (worked, uiaainfo) = services()
+            .uiaa
+            .try_auth(sender_user, sender_device, auth, &uiaainfo)
+            .await?;
+        if !worked {
+            return Err(Error::Uiaa(uiaainfo));
+        }
+    // Success!
+    } else if let Some(json) = &body.json_body {
+        uiaainfo.session = Some(utils::random_string(SESSION_ID_LENGTH));
+        services()
+            .uiaa
+            .create(sender_user, sender_device, &uiaainfo, json)?;
+        return Err(Error::Uiaa(uiaainfo));
+    } else {
+        return Err(Error::BadRequest(ErrorKind::NotJson, "Not json."));
+    }
+
+    services()
+        .threepid
+        .add(sender_user, &body.sid, &body.client_secret)?;
+
+    Ok(add_3pid::v3::Response::new())
+}
+
+/// # `POST /_matrix/client/v3/account/3pid/delete`
+///
+/// Removes a third party identifier from the account.
+///
+/// - Bindings on identity servers are not removed
+pub async fn delete_3pid_route(
+    body: Ruma<delete_3pid::v3::Request>,
+) -> Result<delete_3pid::v3::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    services()
+        .threepid
+        .remove(sender_user, &body.medium, &body.address)?;
+
+    Ok(delete_3pid::v3::Response::new(
+        ThirdPartyIdRemovalStatus::NoSupport,
+    ))
+}
+
+/// # `POST /_matrix/client/v3/account/3pid/bind`
+///
+/// Asks the identity server to publish that a third party identifier belongs to this user.
+///
+/// - The session must have been validated by the identity server
+/// - Identity servers on denied IP addresses (see `url_preview_ip_range_denylist`) can't be used
+pub async fn bind_3pid_route(
+    body: Ruma<bind_3pid::v3::Request>,
+) -> Result<bind_3pid::v3::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    // The identity server is chosen by the user, so it must not be used to reach internal services
+    let url = Url::parse(&format!(
+        "https://{}/_matrix/identity/v2/3pid/bind",
+        body.identity_server_info.id_server
+    ))
+    .ok()
+    .filter(|url| {
+        url.username().is_empty()
+            && url.password().is_none()
+            && url.path() == "/_matrix/identity/v2/3pid/bind"
+            && url.query().is_none()
+            && url.fragment().is_none()
+            && !services().globals.is_url_preview_denied(url)
+    })
+    .ok_or(Error::BadRequest(
+        ErrorKind::InvalidParam,
+        "Invalid identity server.",
+    ))?;
+
+    let response = services()
+        .globals
+        .url_preview_client()
+        .post(url)
+        .bearer_auth(&body.identity_server_info.id_access_token)
+        .header(CONTENT_TYPE, "application/json")
+        .body(
+            serde_json::to_vec(&serde_json::json!({
+                "sid": body.sid,
+                "client_secret": body.client_secret,
+                "mxid": sender_user,
+            }))
+            .expect("json can be serialized"),
+        )
+        .send()
+        .await
+        .map_err(|e| {
+            warn!("Failed to reach identity server: {e}");
+            Error::BadServerResponse("Failed to reach the identity server.")
+        })?;
+
+    if !response.status().is_success() {
+        warn!(
+            "Identity server refused to bind 3pid of {sender_user}: {}",
+            response.status()
+        );
+        return Err(Error::BadRequest(
+            ErrorKind::ThreepidAuthFailed,
+            "The identity server refused to bind the third party identifier.",
+        ));
+    }
+
+    Ok(bind_3pid::v3::Response::new())
+}
+
+/// UIAA flows that confirm the identity of the user. Users who have an email address linked to
+/// their account can use it instead of their password.
+fn identity_flows(user_id: &UserId) -> Vec<AuthFlow> {
+    let mut flows = vec![AuthFlow {
+        stages: vec![AuthType::Password],
+    }];
+
+    if services()
+        .threepid
+        .threepids(user_id)
+        .filter_map(|threepid| threepid.ok())
+        .any(|threepid| threepid.medium == Medium::Email)
+    {
+        flows.push(AuthFlow {
+            stages: vec![AuthType::EmailIdentity],
+        });
+    }
+
+    flows
+}
@@ -0,0 +1,150 @@
+use crate::{services, Error, Result, Ruma};
+use ruma::api::client::{
+    dehydrated_device::{
+        delete_dehydrated_device, get_dehydrated_device, get_events, put_dehydrated_device,
+    },
+    error::ErrorKind,
+};
+
+/// # `PUT /_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device`
+///
+/// Uploads a dehydrated device, which receives to-device messages while the user has no other
+/// device online.
+///
+/// - Replaces the previous dehydrated device of the user
+/// - Adds the device keys, one-time keys and fallback keys of the device
+pub async fn put_dehydrated_device_route(
+    body: Ruma<put_dehydrated_device::unstable::Request>,
+) -> Result<put_dehydrated_device::unstable::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    if services()
+        .users
+        .get_device_metadata(sender_user, &body.device_id)?
+        .is_some()
+        && services()
+            .users
+            .get_dehydrated_device(sender_user)?
+            .is_none_or(|(device_id, _)| device_id != body.device_id)
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "A device with this id already exists.",
+        ));
+    }
+
+    body.device_keys
+        .deserialize()
+        .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Body contained invalid device keys"))?;
+    for key_value in body.one_time_keys.values() {
+        key_value.deserialize().map_err(|_| {
+            Error::BadRequest(ErrorKind::BadJson, "Body contained invalid one-time key")
+        })?;
+    }
+    for key_value in body.fallback_keys.values() {
+        key_value.deserialize().map_err(|_| {
+            Error::BadRequest(ErrorKind::BadJson, "Body contained invalid fallback key")
+        })?;
+    }
+
+    services().users.set_dehydrated_device(
+        sender_user,
+        &body.device_id,
+        body.initial_device_display_name.clone(),
+        &body.device_data,
+        &body.device_keys,
+        &body.one_time_keys,
+        &body.fallback_keys,
+    )?;
+
+    Ok(put_dehydrated_device::unstable::Response {
+        device_id: body.device_id.clone(),
+    })
+}
+
+/// # `GET /_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device`
+///
+/// Returns the dehydrated device of the sender user.
+pub async fn get_dehydrated_device_route(
+    body: Ruma<get_dehydrated_device::unstable::Request>,
+) -> Result<get_dehydrated_device::unstable::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    let (device_id, device_data) =
+        services()
+            .users
+            .get_dehydrated_device(sender_user)?
+            .ok_or(Error::BadRequest(
+                ErrorKind::NotFound,
+                "No dehydrated device is stored.",
+            ))?;
+
+    Ok(get_dehydrated_device::unstable::Response {
+        device_id,
+        device_data,
+    })
+}
+
+/// # `DELETE /_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device`
+///
+/// Deletes the dehydrated device of the sender user.
+pub async fn delete_dehydrated_device_route(
+    body: Ruma<delete_dehydrated_device::unstable::Request>,
+) -> Result<delete_dehydrated_device::unstable::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    let (device_id, _) =
+        services()
+            .users
+            .get_dehydrated_device(sender_user)?
+            .ok_or(Error::BadRequest(
+                ErrorKind::NotFound,
+                "No dehydrated device is stored.",
+            ))?;
+
+    services().users.remove_device(sender_user, &device_id)?;
+
+    Ok(delete_dehydrated_device::unstable::Response { device_id })
+}
+
+/// # `POST /_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device/{deviceId}/events`
+///
+/// Returns the to-device messages the dehydrated device received.
+///
+/// - Messages up to the `next_batch` of the previous call are deleted
+pub async fn get_dehydrated_events_route(
+    body: Ruma<get_events::unstable::Request>,
+) -> Result<get_events::unstable::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    if services()
+        .users
+        .get_dehydrated_device(sender_user)?
+        .is_none_or(|(device_id, _)| device_id != body.device_id)
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::forbidden(),
+            "Device is not the dehydrated device of the user.",
+        ));
+    }
+
+    if let Some(since) = &body.next_batch {
+        let since = since
+            .parse()
+            .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid next_batch."))?;
+
+        services()
+            .users
+            .remove_to_device_events(sender_user, &body.device_id, since)?;
+    }
+
+    let next_batch = services().globals.current_count()?;
+    let events = services()
+        .users
+        .get_to_device_events(sender_user, &body.device_id)?;
+
+    Ok(get_events::unstable::Response {
+        next_batch: Some(next_batch.to_string()),
+        events,
+    })
+}
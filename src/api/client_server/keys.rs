@@ -28,6 +28,7 @@ use tracing::{debug, error};
 /// Publish end-to-end encryption keys for the sender device.
 ///
 /// - Adds one time keys
+/// - Replaces the fallback keys of the uploaded algorithms
 /// - If there are no device keys yet: Adds device keys (TODO: merge with existing keys?)
 pub async fn upload_keys_route(
     body: Ruma<upload_keys::v3::Request>,
@@ -45,6 +46,16 @@ pub async fn upload_keys_route(
             .add_one_time_key(sender_user, sender_device, key_key, key_value)?;
     }
 
+    for (key_key, key_value) in &body.fallback_keys {
+        key_value.deserialize().map_err(|_| {
+            Error::BadRequest(ErrorKind::BadJson, "Body contained invalid fallback key")
+        })?;
+
+        services()
+            .users
+            .add_fallback_key(sender_user, sender_device, key_key, key_value)?;
+    }
+
     if let Some(device_keys) = &body.device_keys {
         // TODO: merge this and the existing event?
         // This check is needed to assure that signatures are kept
@@ -89,6 +100,8 @@ pub async fn get_keys_route(body: Ruma<get_keys::v3::Request>) -> Result<get_key
 /// # `POST /_matrix/client/r0/keys/claim`
 ///
 /// Claims one-time keys
+///
+/// - Hands out the fallback key of a device once it has no one-time keys left
 pub async fn claim_keys_route(
     body: Ruma<claim_keys::v3::Request>,
 ) -> Result<claim_keys::v3::Response> {
@@ -120,10 +133,10 @@ pub async fn upload_signing_keys_route(
     };
 
     if let Some(auth) = &body.auth {
-        let (worked, uiaainfo) =
-            services()
-                .uiaa
-                .try_auth(sender_user, sender_device, auth, &uiaainfo)?;
+        let (worked, uiaainfo) = services()
+            .uiaa
+            .try_auth(sender_user, sender_device, auth, &uiaainfo)
+            .await?;
         if !worked {
             return Err(Error::Uiaa(uiaainfo));
         }
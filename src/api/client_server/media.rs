@@ -10,6 +10,7 @@ use ruma::{
         client::{
             authenticated_media::{
                 get_content, get_content_as_filename, get_content_thumbnail, get_media_config,
+                get_media_preview,
             },
             error::ErrorKind,
             media::{self, create_content},
@@ -20,6 +21,7 @@ use ruma::{
     media::Method,
     ServerName, UInt,
 };
+use serde_json::value::{to_raw_value, RawValue as RawJsonValue};
 
 const MXC_LENGTH: usize = 32;
 
@@ -45,6 +47,47 @@ pub async fn get_media_config_auth_route(
     })
 }
 
+/// # `GET /_matrix/media/r0/preview_url`
+///
+/// Returns OpenGraph data of the given url.
+///
+/// - Results are cached for a day
+/// - Preview images are stored as local media
+pub async fn get_media_preview_route(
+    body: Ruma<media::get_media_preview::v3::Request>,
+) -> Result<media::get_media_preview::v3::Response> {
+    Ok(media::get_media_preview::v3::Response {
+        data: Some(get_media_preview(&body.url).await?),
+    })
+}
+
+/// # `GET /_matrix/client/v1/media/preview_url`
+///
+/// Returns OpenGraph data of the given url.
+///
+/// - Results are cached for a day
+/// - Preview images are stored as local media
+pub async fn get_media_preview_auth_route(
+    body: Ruma<get_media_preview::v1::Request>,
+) -> Result<get_media_preview::v1::Response> {
+    Ok(get_media_preview::v1::Response {
+        data: Some(get_media_preview(&body.url).await?),
+    })
+}
+
+async fn get_media_preview(url: &str) -> Result<Box<RawJsonValue>> {
+    if !services().globals.allow_url_preview() {
+        return Err(Error::BadRequest(
+            ErrorKind::forbidden(),
+            "URL previews are disabled on this server.",
+        ));
+    }
+
+    let preview = services().url_preview.get(url).await?;
+
+    Ok(to_raw_value(&preview).expect("UrlPreviewData can be serialized"))
+}
+
 /// # `POST /_matrix/media/r0/upload`
 ///
 /// Permanently save media in the server.
@@ -129,6 +129,13 @@ pub async fn knock_room_route(
         .get_room_id_and_via_servers(sender_user, body.room_id_or_alias, body.via)
         .await?;
 
+    if services().rooms.metadata.is_banned(&room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::forbidden(),
+            "This room has been shut down on this server.",
+        ));
+    }
+
     let mutex_state = Arc::clone(
         services()
             .globals
@@ -593,6 +600,7 @@ pub async fn joined_rooms_route(
 /// Lists all joined users in a room (TODO: at a specific point in time, with a specific membership).
 ///
 /// - Only works if the user is currently joined
+/// - Waits until the room has its full state after a partial state join
 pub async fn get_member_events_route(
     body: Ruma<get_member_events::v3::Request>,
 ) -> Result<get_member_events::v3::Response> {
@@ -609,6 +617,13 @@ pub async fn get_member_events_route(
         ));
     }
 
+    // Partial state is missing most members
+    services()
+        .rooms
+        .partial_state
+        .wait_for_full_state(&body.room_id)
+        .await?;
+
     Ok(get_member_events::v3::Response {
         chunk: services()
             .rooms
@@ -628,6 +643,7 @@ pub async fn get_member_events_route(
 ///
 /// - The sender user must be in the room
 /// - TODO: An appservice just needs a puppet joined
+/// - Waits until the room has its full state after a partial state join
 pub async fn joined_members_route(
     body: Ruma<joined_members::v3::Request>,
 ) -> Result<joined_members::v3::Response> {
@@ -644,6 +660,13 @@ pub async fn joined_members_route(
         ));
     }
 
+    // Partial state is missing most members
+    services()
+        .rooms
+        .partial_state
+        .wait_for_full_state(&body.room_id)
+        .await?;
+
     let mut joined = BTreeMap::new();
     for user_id in services()
         .rooms
@@ -673,6 +696,22 @@ pub(crate) async fn invite_helper(
     reason: Option<String>,
     is_direct: bool,
 ) -> Result<()> {
+    if services().rooms.metadata.is_banned(room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::forbidden(),
+            "This room has been shut down on this server.",
+        ));
+    }
+
+    services()
+        .policy
+        .check_membership(sender_user, room_id, "inviting users to")
+        .await?;
+    services()
+        .policy
+        .check_membership(user_id, room_id, "being invited to")
+        .await?;
+
     if user_id.server_name() != services().globals.server_name() {
         let (pdu, pdu_json, invite_room_state) = {
             let mutex_state = Arc::clone(
@@ -6,6 +6,7 @@ use ruma::{
     api::client::{
         error::ErrorKind,
         message::{get_message_events, send_message_event},
+        room::get_event_by_timestamp,
     },
     events::{StateEventType, TimelineEventType},
 };
@@ -271,3 +272,58 @@ pub async fn get_message_events_route(
 
     Ok(resp)
 }
+
+/// # `GET /_matrix/client/v1/rooms/{roomId}/timestamp_to_event`
+///
+/// Finds the event closest to the given timestamp in the given direction.
+///
+/// - Asks other servers in the room if our timeline has a gap where the event could be
+pub async fn get_event_by_timestamp_route(
+    body: Ruma<get_event_by_timestamp::v1::Request>,
+) -> Result<get_event_by_timestamp::v1::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    if !services()
+        .rooms
+        .state_accessor
+        .user_can_see_state_events(sender_user, &body.room_id)?
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::forbidden(),
+            "You don't have permission to view this room.",
+        ));
+    }
+
+    let (event_id, origin_server_ts) = services()
+        .rooms
+        .timeline
+        .closest_event_by_timestamp(&body.room_id, body.ts, body.dir)
+        .await?
+        .ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "No event found in the given direction.",
+        ))?;
+
+    // Events without state can't be checked for visibility
+    if services()
+        .rooms
+        .state_accessor
+        .pdu_shortstatehash(&event_id)?
+        .is_none()
+        || !services().rooms.state_accessor.user_can_see_event(
+            sender_user,
+            &body.room_id,
+            &event_id,
+        )?
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "No event found in the given direction.",
+        ));
+    }
+
+    Ok(get_event_by_timestamp::v1::Response::new(
+        event_id,
+        origin_server_ts,
+    ))
+}
@@ -5,6 +5,7 @@ mod backup;
 mod capabilities;
 mod config;
 mod context;
+mod dehydrated_device;
 mod device;
 mod directory;
 mod filter;
@@ -43,6 +44,7 @@ pub use backup::*;
 pub use capabilities::*;
 pub use config::*;
 pub use context::*;
+pub use dehydrated_device::*;
 pub use device::*;
 pub use directory::*;
 pub use filter::*;
@@ -1,4 +1,4 @@
-use crate::{service::pdu::PduBuilder, services, utils, Error, Result, Ruma};
+use crate::{service::pdu::PduBuilder, services, Error, Result, Ruma};
 use ruma::{
     api::{
         client::{
@@ -26,92 +26,8 @@ pub async fn set_displayname_route(
 
     services()
         .users
-        .set_displayname(sender_user, body.displayname.clone())?;
-
-    // Send a new membership event and presence update into all joined rooms
-    let all_rooms_joined: Vec<_> = services()
-        .rooms
-        .state_cache
-        .rooms_joined(sender_user)
-        .filter_map(|r| r.ok())
-        .map(|room_id| {
-            Ok::<_, Error>((
-                PduBuilder {
-                    event_type: TimelineEventType::RoomMember,
-                    content: to_raw_value(&RoomMemberEventContent {
-                        displayname: body.displayname.clone(),
-                        join_authorized_via_users_server: None,
-                        ..serde_json::from_str(
-                            services()
-                                .rooms
-                                .state_accessor
-                                .room_state_get(
-                                    &room_id,
-                                    &StateEventType::RoomMember,
-                                    sender_user.as_str(),
-                                )?
-                                .ok_or_else(|| {
-                                    Error::bad_database(
-                                        "Tried to send displayname update for user not in the \
-                                     room.",
-                                    )
-                                })?
-                                .content
-                                .get(),
-                        )
-                        .map_err(|_| Error::bad_database("Database contains invalid PDU."))?
-                    })
-                    .expect("event is valid, we just created it"),
-                    unsigned: None,
-                    state_key: Some(sender_user.to_string()),
-                    redacts: None,
-                    timestamp: None,
-                },
-                room_id,
-            ))
-        })
-        .filter_map(|r| r.ok())
-        .collect();
-
-    for (pdu_builder, room_id) in all_rooms_joined {
-        let mutex_state = Arc::clone(
-            services()
-                .globals
-                .roomid_mutex_state
-                .write()
-                .await
-                .entry(room_id.clone())
-                .or_default(),
-        );
-        let state_lock = mutex_state.lock().await;
-
-        let _ = services()
-            .rooms
-            .timeline
-            .build_and_append_pdu(pdu_builder, sender_user, &room_id, &state_lock)
-            .await;
-
-        // Presence update
-        services().rooms.edus.presence.update_presence(
-            sender_user,
-            &room_id,
-            ruma::events::presence::PresenceEvent {
-                content: ruma::events::presence::PresenceEventContent {
-                    avatar_url: services().users.avatar_url(sender_user)?,
-                    currently_active: None,
-                    displayname: services().users.displayname(sender_user)?,
-                    last_active_ago: Some(
-                        utils::millis_since_unix_epoch()
-                            .try_into()
-                            .expect("time is valid"),
-                    ),
-                    presence: ruma::presence::PresenceState::Online,
-                    status_msg: None,
-                },
-                sender: sender_user.clone(),
-            },
-        )?;
-    }
+        .update_displayname(sender_user, body.displayname.clone())
+        .await?;
 
     Ok(set_display_name::v3::Response {})
 }
@@ -120,7 +36,7 @@ pub async fn set_displayname_route(
 ///
 /// Returns the displayname of the user.
 ///
-/// - If user is on another server: Fetches displayname over federation
+/// - If user is on another server: Fetches displayname over federation and remembers it
 pub async fn get_displayname_route(
     body: Ruma<get_display_name::v3::Request>,
 ) -> Result<get_display_name::v3::Response> {
@@ -136,6 +52,10 @@ pub async fn get_displayname_route(
             )
             .await?;
 
+        services()
+            .users
+            .set_displayname(&body.user_id, response.displayname.clone())?;
+
         return Ok(get_display_name::v3::Response {
             displayname: response.displayname,
         });
@@ -226,29 +146,16 @@ pub async fn set_avatar_url_route(
             .timeline
             .build_and_append_pdu(pdu_builder, sender_user, &room_id, &state_lock)
             .await;
-
-        // Presence update
-        services().rooms.edus.presence.update_presence(
-            sender_user,
-            &room_id,
-            ruma::events::presence::PresenceEvent {
-                content: ruma::events::presence::PresenceEventContent {
-                    avatar_url: services().users.avatar_url(sender_user)?,
-                    currently_active: None,
-                    displayname: services().users.displayname(sender_user)?,
-                    last_active_ago: Some(
-                        utils::millis_since_unix_epoch()
-                            .try_into()
-                            .expect("time is valid"),
-                    ),
-                    presence: ruma::presence::PresenceState::Online,
-                    status_msg: None,
-                },
-                sender: sender_user.clone(),
-            },
-        )?;
     }
 
+    // Presence update
+    services()
+        .rooms
+        .edus
+        .presence
+        .resend_presence(sender_user)
+        .await?;
+
     Ok(set_avatar_url::v3::Response {})
 }
 
@@ -256,7 +163,8 @@ pub async fn set_avatar_url_route(
 ///
 /// Returns the avatar_url and blurhash of the user.
 ///
-/// - If user is on another server: Fetches avatar_url and blurhash over federation
+/// - If user is on another server: Fetches avatar_url and blurhash over federation and remembers
+///   them
 pub async fn get_avatar_url_route(
     body: Ruma<get_avatar_url::v3::Request>,
 ) -> Result<get_avatar_url::v3::Response> {
@@ -272,6 +180,13 @@ pub async fn get_avatar_url_route(
             )
             .await?;
 
+        services()
+            .users
+            .set_avatar_url(&body.user_id, response.avatar_url.clone())?;
+        services()
+            .users
+            .set_blurhash(&body.user_id, response.blurhash.clone())?;
+
         return Ok(get_avatar_url::v3::Response {
             avatar_url: response.avatar_url,
             blurhash: response.blurhash,
@@ -288,7 +203,7 @@ pub async fn get_avatar_url_route(
 ///
 /// Returns the displayname, avatar_url and blurhash of the user.
 ///
-/// - If user is on another server: Fetches profile over federation
+/// - If user is on another server: Fetches profile over federation and remembers it
 pub async fn get_profile_route(
     body: Ruma<get_profile::v3::Request>,
 ) -> Result<get_profile::v3::Response> {
@@ -304,6 +219,18 @@ pub async fn get_profile_route(
             )
             .await?;
 
+        // Room member events can contain per-room profiles, so this is the only place where we
+        // learn the global profile of remote users
+        services()
+            .users
+            .set_displayname(&body.user_id, response.displayname.clone())?;
+        services()
+            .users
+            .set_avatar_url(&body.user_id, response.avatar_url.clone())?;
+        services()
+            .users
+            .set_blurhash(&body.user_id, response.blurhash.clone())?;
+
         return Ok(get_profile::v3::Response {
             displayname: response.displayname,
             avatar_url: response.avatar_url,
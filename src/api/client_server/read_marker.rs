@@ -35,10 +35,11 @@ pub async fn set_read_marker_route(
     }
 
     if body.private_read_receipt.is_some() || body.read_receipt.is_some() {
-        services()
-            .rooms
-            .user
-            .reset_notification_counts(sender_user, &body.room_id)?;
+        services().rooms.user.reset_notification_counts(
+            sender_user,
+            &body.room_id,
+            &ReceiptThread::Unthreaded,
+        )?;
     }
 
     if let Some(event) = &body.private_read_receipt {
@@ -82,14 +83,19 @@ pub async fn set_read_marker_route(
         let mut receipt_content = BTreeMap::new();
         receipt_content.insert(event.to_owned(), receipts);
 
-        services().rooms.edus.read_receipt.readreceipt_update(
-            sender_user,
-            &body.room_id,
-            ruma::events::receipt::ReceiptEvent {
-                content: ruma::events::receipt::ReceiptEventContent(receipt_content),
-                room_id: body.room_id.clone(),
-            },
-        )?;
+        services()
+            .rooms
+            .edus
+            .read_receipt
+            .readreceipt_update(
+                sender_user,
+                &body.room_id,
+                ruma::events::receipt::ReceiptEvent {
+                    content: ruma::events::receipt::ReceiptEventContent(receipt_content),
+                    room_id: body.room_id.clone(),
+                },
+            )
+            .await?;
     }
 
     Ok(set_read_marker::v3::Response {})
@@ -103,14 +109,24 @@ pub async fn create_receipt_route(
 ) -> Result<create_receipt::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
+    if body.receipt_type == create_receipt::v3::ReceiptType::FullyRead
+        && body.thread != ReceiptThread::Unthreaded
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Fully read markers can't be threaded.",
+        ));
+    }
+
     if matches!(
         &body.receipt_type,
         create_receipt::v3::ReceiptType::Read | create_receipt::v3::ReceiptType::ReadPrivate
     ) {
-        services()
-            .rooms
-            .user
-            .reset_notification_counts(sender_user, &body.room_id)?;
+        services().rooms.user.reset_notification_counts(
+            sender_user,
+            &body.room_id,
+            &body.thread,
+        )?;
     }
 
     match body.receipt_type {
@@ -133,7 +149,7 @@ pub async fn create_receipt_route(
                 sender_user.clone(),
                 ruma::events::receipt::Receipt {
                     ts: Some(MilliSecondsSinceUnixEpoch::now()),
-                    thread: ReceiptThread::Unthreaded,
+                    thread: body.thread.clone(),
                 },
             );
             let mut receipts = BTreeMap::new();
@@ -142,14 +158,19 @@ pub async fn create_receipt_route(
             let mut receipt_content = BTreeMap::new();
             receipt_content.insert(body.event_id.to_owned(), receipts);
 
-            services().rooms.edus.read_receipt.readreceipt_update(
-                sender_user,
-                &body.room_id,
-                ruma::events::receipt::ReceiptEvent {
-                    content: ruma::events::receipt::ReceiptEventContent(receipt_content),
-                    room_id: body.room_id.clone(),
-                },
-            )?;
+            services()
+                .rooms
+                .edus
+                .read_receipt
+                .readreceipt_update(
+                    sender_user,
+                    &body.room_id,
+                    ruma::events::receipt::ReceiptEvent {
+                        content: ruma::events::receipt::ReceiptEventContent(receipt_content),
+                        room_id: body.room_id.clone(),
+                    },
+                )
+                .await?;
         }
         create_receipt::v3::ReceiptType::ReadPrivate => {
             let count = services()
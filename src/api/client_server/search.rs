@@ -1,19 +1,29 @@
-use crate::{services, Error, Result, Ruma};
-use ruma::api::client::{
-    error::ErrorKind,
-    search::search_events::{
-        self,
-        v3::{EventContextResult, ResultCategories, ResultRoomEvents, SearchResult},
+use crate::{service::rooms::search::SearchQuery, services, Error, PduEvent, Result, Ruma};
+use ruma::{
+    api::client::{
+        error::ErrorKind,
+        search::search_events::{
+            self,
+            v3::{
+                EventContext, EventContextResult, GroupingKey, OrderBy, ResultCategories,
+                ResultGroup, ResultRoomEvents, RoomIdOrUserId, SearchResult, UserProfile,
+            },
+        },
     },
+    events::{room::member::RoomMemberEventContent, StateEventType},
+    OwnedRoomId, UserId,
 };
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashSet};
 
 /// # `POST /_matrix/client/r0/search`
 ///
 /// Searches rooms for messages.
 ///
 /// - Only works if the user is currently joined to the room (TODO: Respect history visibility)
+/// - Every word of the search term has to be the start of a word in the message, text in double
+///   quotes has to occur as it is
+/// - Results are ordered by relevance if `order_by` is `rank`, otherwise newest first
 pub async fn search_events_route(
     body: Ruma<search_events::v3::Request>,
 ) -> Result<search_events::v3::Response> {
@@ -22,7 +32,7 @@ pub async fn search_events_route(
     let search_criteria = body.search_categories.room_events.as_ref().unwrap();
     let filter = &search_criteria.filter;
 
-    let room_ids = filter.rooms.clone().unwrap_or_else(|| {
+    let mut room_ids = filter.rooms.clone().unwrap_or_else(|| {
         services()
             .rooms
             .state_cache
@@ -30,17 +40,20 @@ pub async fn search_events_route(
             .filter_map(|r| r.ok())
             .collect()
     });
+    room_ids.retain(|room_id| !filter.not_rooms.contains(room_id));
 
     // Use limit or else 10, with maximum 100
     let limit = filter.limit.map_or(10, u64::from).min(100) as usize;
 
-    let mut searches = Vec::new();
+    let query = SearchQuery::parse(&search_criteria.search_term);
+
+    let mut matches = Vec::new();
 
-    for room_id in room_ids {
+    for room_id in &room_ids {
         if !services()
             .rooms
             .state_cache
-            .is_joined(sender_user, &room_id)?
+            .is_joined(sender_user, room_id)?
         {
             return Err(Error::BadRequest(
                 ErrorKind::forbidden(),
@@ -48,12 +61,31 @@ pub async fn search_events_route(
             ));
         }
 
-        if let Some(search) = services()
-            .rooms
-            .search
-            .search_pdus(&room_id, &search_criteria.search_term)?
-        {
-            searches.push(search.0.peekable());
+        matches.extend(services().rooms.search.search_pdus(room_id, &query)?);
+    }
+
+    match search_criteria.order_by {
+        Some(OrderBy::Rank) => {
+            matches.sort_unstable_by(|(_, a), (_, b)| b.total_cmp(a));
+        }
+        _ => {
+            // Newest first
+            let mut matches_with_count = matches
+                .into_iter()
+                .map(|(pdu_id, rank)| {
+                    Ok((
+                        services().rooms.timeline.pdu_id_count(&pdu_id)?,
+                        pdu_id,
+                        rank,
+                    ))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            matches_with_count.sort_unstable_by(|(a, _, _), (b, _, _)| b.cmp(a));
+
+            matches = matches_with_count
+                .into_iter()
+                .map(|(_, pdu_id, rank)| (pdu_id, rank))
+                .collect();
         }
     }
 
@@ -68,72 +100,192 @@ pub async fn search_events_route(
         None => 0, // Default to the start
     };
 
-    let mut results = Vec::new();
-    for _ in 0..skip + limit {
-        if let Some(s) = searches
-            .iter_mut()
-            .map(|s| (s.peek().cloned(), s))
-            .max_by_key(|(peek, _)| peek.clone())
-            .and_then(|(_, i)| i.next())
-        {
-            results.push(s);
-        }
-    }
-
-    let results: Vec<_> = results
-        .iter()
-        .filter_map(|result| {
+    let visible_matches: Vec<_> = matches
+        .into_iter()
+        .filter_map(|(pdu_id, rank)| {
             services()
                 .rooms
                 .timeline
-                .get_pdu_from_id(result)
+                .get_pdu_from_id(&pdu_id)
                 .ok()?
                 .filter(|pdu| {
                     !pdu.is_redacted()
+                        && filter
+                            .senders
+                            .as_ref()
+                            .is_none_or(|senders| senders.contains(&pdu.sender))
+                        && !filter.not_senders.contains(&pdu.sender)
                         && services()
                             .rooms
                             .state_accessor
                             .user_can_see_event(sender_user, &pdu.room_id(), &pdu.event_id)
                             .unwrap_or(false)
                 })
-                .map(|pdu| pdu.to_room_event())
-        })
-        .map(|result| {
-            Ok::<_, Error>(SearchResult {
-                context: EventContextResult {
-                    end: None,
-                    events_after: Vec::new(),
-                    events_before: Vec::new(),
-                    profile_info: BTreeMap::new(),
-                    start: None,
-                },
-                rank: None,
-                result: Some(result),
-            })
+                .map(|pdu| (pdu, rank))
         })
-        .filter_map(|r| r.ok())
-        .skip(skip)
-        .take(limit)
         .collect();
 
-    let next_batch = if results.len() < limit {
-        None
-    } else {
-        Some((skip + limit).to_string())
-    };
+    // Only count the matches the user can see, so hidden events don't reveal themselves
+    let count = visible_matches.len();
+
+    let next_batch = (skip + limit < count).then(|| (skip + limit).to_string());
+
+    let page: Vec<_> = visible_matches.into_iter().skip(skip).take(limit).collect();
+
+    let mut groups = BTreeMap::new();
+    for key in search_criteria
+        .groupings
+        .group_by
+        .iter()
+        .filter_map(|grouping| grouping.key.as_ref())
+    {
+        let mut group = BTreeMap::new();
+
+        for (pdu, _) in &page {
+            let id = match key {
+                GroupingKey::RoomId => RoomIdOrUserId::RoomId(pdu.room_id().into_owned()),
+                GroupingKey::Sender => RoomIdOrUserId::UserId(pdu.sender.clone()),
+                _ => continue,
+            };
+
+            let order = group.len();
+            group
+                .entry(id)
+                .or_insert_with(|| ResultGroup {
+                    next_batch: None,
+                    order: Some((order as u32).into()),
+                    results: Vec::new(),
+                })
+                .results
+                .push((*pdu.event_id).to_owned());
+        }
+
+        groups.insert(key.clone(), group);
+    }
+
+    let mut state = BTreeMap::new();
+    if search_criteria.include_state == Some(true) {
+        let room_ids: HashSet<OwnedRoomId> = page
+            .iter()
+            .map(|(pdu, _)| pdu.room_id().into_owned())
+            .collect();
+
+        for room_id in room_ids {
+            let room_state = services()
+                .rooms
+                .state_accessor
+                .room_state_full(&room_id)
+                .await?
+                .values()
+                .map(|pdu| pdu.to_state_event())
+                .collect();
+
+            state.insert(room_id, room_state);
+        }
+    }
+
+    let results = page
+        .into_iter()
+        .map(|(pdu, rank)| {
+            Ok(SearchResult {
+                context: event_context(sender_user, &pdu, &search_criteria.event_context)?,
+                rank: Some(rank),
+                result: Some(pdu.to_room_event()),
+            })
+        })
+        .collect::<Result<_>>()?;
 
     Ok(search_events::v3::Response::new(ResultCategories {
         room_events: ResultRoomEvents {
-            count: Some((results.len() as u32).into()), // TODO: set this to none. Element shouldn't depend on it
-            groups: BTreeMap::new(),                    // TODO
+            count: Some((count as u32).into()),
+            groups,
             next_batch,
             results,
-            state: BTreeMap::new(), // TODO
-            highlights: search_criteria
-                .search_term
-                .split_terminator(|c: char| !c.is_alphanumeric())
-                .map(str::to_lowercase)
-                .collect(),
+            state,
+            highlights: query.highlights(),
         },
     }))
 }
+
+/// Returns the events around a search result, and the profiles of their senders if requested.
+fn event_context(
+    sender_user: &UserId,
+    pdu: &PduEvent,
+    event_context: &EventContext,
+) -> Result<EventContextResult> {
+    let room_id = pdu.room_id();
+    let base_count = services()
+        .rooms
+        .timeline
+        .get_pdu_count(&pdu.event_id)?
+        .ok_or_else(|| Error::bad_database("Search result is not in the timeline."))?;
+
+    let visible = |(_, pdu): &(_, PduEvent)| {
+        services()
+            .rooms
+            .state_accessor
+            .user_can_see_event(sender_user, &room_id, &pdu.event_id)
+            .unwrap_or(false)
+    };
+
+    let events_before: Vec<_> = services()
+        .rooms
+        .timeline
+        .pdus_until(sender_user, &room_id, base_count)?
+        .take(u64::from(event_context.before_limit) as usize)
+        .filter_map(|r| r.ok())
+        .filter(visible)
+        .collect();
+
+    let events_after: Vec<_> = services()
+        .rooms
+        .timeline
+        .pdus_after(sender_user, &room_id, base_count)?
+        .take(u64::from(event_context.after_limit) as usize)
+        .filter_map(|r| r.ok())
+        .filter(visible)
+        .collect();
+
+    let mut profile_info = BTreeMap::new();
+    if event_context.include_profile {
+        for sender in events_before
+            .iter()
+            .chain(&events_after)
+            .map(|(_, pdu)| &pdu.sender)
+            .chain([&pdu.sender])
+        {
+            if profile_info.contains_key(sender) {
+                continue;
+            }
+
+            let profile = services()
+                .rooms
+                .state_accessor
+                .room_state_get(&room_id, &StateEventType::RoomMember, sender.as_str())?
+                .and_then(|member| {
+                    serde_json::from_str::<RoomMemberEventContent>(member.content.get()).ok()
+                })
+                .map(|member| UserProfile {
+                    avatar_url: member.avatar_url,
+                    displayname: member.displayname,
+                })
+                .unwrap_or_default();
+
+            profile_info.insert(sender.clone(), profile);
+        }
+    }
+
+    Ok(EventContextResult {
+        start: events_before.last().map(|(count, _)| count.stringify()),
+        end: events_after.last().map(|(count, _)| count.stringify()),
+        events_before: events_before
+            .into_iter()
+            .map(|(_, pdu)| pdu.to_room_event())
+            .collect(),
+        events_after: events_after
+            .into_iter()
+            .map(|(_, pdu)| pdu.to_room_event())
+            .collect(),
+        profile_info,
+    })
+}
@@ -1,9 +1,16 @@
 use super::{DEVICE_ID_LENGTH, TOKEN_LENGTH};
-use crate::{services, utils, Error, Result, Ruma};
+use crate::{service::sso, services, utils, Error, Result, Ruma};
+use axum::{
+    extract::Query,
+    response::{Html, IntoResponse, Response},
+};
 use ruma::{
     api::client::{
         error::ErrorKind,
-        session::{get_login_types, login, logout, logout_all},
+        session::{
+            get_login_types, login, logout, logout_all, refresh_token, sso_login,
+            sso_login_with_provider,
+        },
         uiaa::UserIdentifier,
     },
     UserId,
@@ -24,17 +31,32 @@ struct Claims {
 pub async fn get_login_types_route(
     _body: Ruma<get_login_types::v3::Request>,
 ) -> Result<get_login_types::v3::Response> {
-    Ok(get_login_types::v3::Response::new(vec![
+    let mut login_types = vec![
         get_login_types::v3::LoginType::Password(Default::default()),
         get_login_types::v3::LoginType::ApplicationService(Default::default()),
-    ]))
+    ];
+
+    if services().sso.is_enabled() {
+        let mut sso = get_login_types::v3::SsoLoginType::new();
+        sso.identity_providers = services().sso.identity_providers();
+        login_types.push(get_login_types::v3::LoginType::Sso(sso));
+    }
+
+    if services().sso.is_enabled() || services().globals.jwt_decoding_key().is_some() {
+        login_types.push(get_login_types::v3::LoginType::Token(Default::default()));
+    }
+
+    Ok(get_login_types::v3::Response::new(login_types))
 }
 
 /// # `POST /_matrix/client/r0/login`
 ///
 /// Authenticates the user and returns an access token it can use in subsequent requests.
 ///
-/// - The user needs to authenticate using their password (or if enabled using a json web token)
+/// - The user needs to authenticate using their password (or if enabled using a json web token or
+///   a login token from single sign-on)
+/// - If LDAP is configured, passwords are checked against the directory and users who only exist
+///   there get an account on their first login
 /// - If `device_id` is known: invalidates old access token of that device
 /// - If `device_id` is unknown: creates a new device
 /// - Returns access token that is associated with the user and device
@@ -74,24 +96,14 @@ pub async fn login_route(body: Ruma<login::v3::Request>) -> Result<login::v3::Re
                 ));
             }
 
-            let hash = services()
-                .users
-                .password_hash(&user_id)?
-                .ok_or(Error::BadRequest(
-                    ErrorKind::forbidden(),
-                    "Wrong username or password.",
-                ))?;
-
-            if hash.is_empty() {
+            if services().users.exists(&user_id)? && services().users.is_deactivated(&user_id)? {
                 return Err(Error::BadRequest(
                     ErrorKind::UserDeactivated,
                     "The user has been deactivated",
                 ));
             }
 
-            let hash_matches = argon2::verify_encoded(&hash, password.as_bytes()).unwrap_or(false);
-
-            if !hash_matches {
+            if !services().users.check_password(&user_id, password).await? {
                 return Err(Error::BadRequest(
                     ErrorKind::forbidden(),
                     "Wrong username or password.",
@@ -101,7 +113,9 @@ pub async fn login_route(body: Ruma<login::v3::Request>) -> Result<login::v3::Re
             user_id
         }
         login::v3::LoginInfo::Token(login::v3::Token { token }) => {
-            if let Some(jwt_decoding_key) = services().globals.jwt_decoding_key() {
+            if let Some(user_id) = services().sso.consume_login_token(token) {
+                user_id
+            } else if let Some(jwt_decoding_key) = services().globals.jwt_decoding_key() {
                 let token = jsonwebtoken::decode::<Claims>(
                     token,
                     jwt_decoding_key,
@@ -123,6 +137,11 @@ pub async fn login_route(body: Ruma<login::v3::Request>) -> Result<login::v3::Re
                 }
 
                 user_id
+            } else if services().sso.is_enabled() {
+                return Err(Error::BadRequest(
+                    ErrorKind::forbidden(),
+                    "Invalid or expired login token.",
+                ));
             } else {
                 return Err(Error::BadRequest(
                     ErrorKind::Unknown,
@@ -154,6 +173,10 @@ pub async fn login_route(body: Ruma<login::v3::Request>) -> Result<login::v3::Re
                         "User is not in namespace.",
                     ));
                 }
+
+                services()
+                    .appservice
+                    .add_user(&info.registration.id, &user_id)?;
             } else {
                 return Err(Error::BadRequest(
                     ErrorKind::MissingToken,
@@ -200,6 +223,16 @@ pub async fn login_route(body: Ruma<login::v3::Request>) -> Result<login::v3::Re
         )?;
     }
 
+    // Only clients that support refresh tokens get an access token that expires
+    let (refresh_token, expires_in) = if body.refresh_token {
+        services()
+            .users
+            .create_refresh_token(&user_id, &device_id)?
+            .unzip()
+    } else {
+        (None, None)
+    };
+
     info!("{} logged in", user_id);
 
     // Homeservers are still required to send the `home_server` field
@@ -210,8 +243,101 @@ pub async fn login_route(body: Ruma<login::v3::Request>) -> Result<login::v3::Re
         home_server: Some(services().globals.server_name().to_owned()),
         device_id,
         well_known: None,
-        refresh_token: None,
-        expires_in: None,
+        refresh_token,
+        expires_in,
+    })
+}
+
+/// # `GET /_matrix/client/v3/login/sso/redirect`
+///
+/// Redirects the user to the first configured identity provider to log in.
+pub async fn sso_login_route(
+    body: Ruma<sso_login::v3::Request>,
+) -> Result<sso_login::v3::Response> {
+    let location = services().sso.start_login(None, &body.redirect_url).await?;
+
+    Ok(sso_login::v3::Response::new(location))
+}
+
+/// # `GET /_matrix/client/v3/login/sso/redirect/{idpId}`
+///
+/// Redirects the user to the identity provider to log in.
+pub async fn sso_login_with_provider_route(
+    body: Ruma<sso_login_with_provider::v3::Request>,
+) -> Result<sso_login_with_provider::v3::Response> {
+    let location = services()
+        .sso
+        .start_login(Some(&body.idp_id), &body.redirect_url)
+        .await?;
+
+    Ok(sso_login_with_provider::v3::Response::new(location))
+}
+
+#[derive(Deserialize)]
+pub struct OidcCallbackParams {
+    state: String,
+    code: Option<String>,
+    error: Option<String>,
+}
+
+/// # `GET /_conduit/client/oidc/callback`
+///
+/// The identity provider redirects the user here after they logged in.
+///
+/// - Asks the user to confirm the client, then sends them back to it with a `loginToken` for
+///   `m.login.token`
+pub async fn oidc_callback_route(Query(params): Query<OidcCallbackParams>) -> Result<Response> {
+    let Some(code) = params.code else {
+        warn!(
+            "Identity provider refused login: {}",
+            params.error.as_deref().unwrap_or("no code")
+        );
+        return Err(Error::BadRequest(
+            ErrorKind::forbidden(),
+            "The identity provider refused the login.",
+        ));
+    };
+
+    let location = services().sso.complete_login(&params.state, &code).await?;
+
+    Ok(Html(sso::confirmation_page(
+        services().globals.server_name(),
+        &location,
+    ))
+    .into_response())
+}
+
+/// # `POST /_matrix/client/v3/refresh`
+///
+/// Exchanges a refresh token for a new access token.
+///
+/// - The old access token and refresh token are invalidated
+/// - The new access token expires again after the configured `access_token_ttl`
+pub async fn refresh_token_route(
+    body: Ruma<refresh_token::v3::Request>,
+) -> Result<refresh_token::v3::Response> {
+    let (user_id, device_id) = services()
+        .users
+        .find_from_refresh_token(&body.refresh_token)?
+        .ok_or(Error::BadRequest(
+            ErrorKind::UnknownToken { soft_logout: false },
+            "Unknown refresh token.",
+        ))?;
+
+    let token = utils::random_string(TOKEN_LENGTH);
+    services().users.set_token(&user_id, &device_id, &token)?;
+
+    // Refresh tokens might have been disabled in the meantime, in which case the new access token
+    // simply doesn't expire
+    let (refresh_token, expires_in_ms) = services()
+        .users
+        .create_refresh_token(&user_id, &device_id)?
+        .unzip();
+
+    Ok(refresh_token::v3::Response {
+        access_token: token,
+        refresh_token,
+        expires_in_ms,
     })
 }
 
@@ -271,8 +397,16 @@ pub async fn logout_all_route(
         ));
     }
 
+    // The dehydrated device is meant to outlive all other devices
+    let dehydrated_device_id = services()
+        .users
+        .get_dehydrated_device(sender_user)?
+        .map(|(device_id, _)| device_id);
+
     for device_id in services().users.all_device_ids(sender_user).flatten() {
-        services().users.remove_device(sender_user, &device_id)?;
+        if Some(&device_id) != dehydrated_device_id.as_ref() {
+            services().users.remove_device(sender_user, &device_id)?;
+        }
     }
 
     Ok(logout_all::v3::Response::new())
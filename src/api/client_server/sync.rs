@@ -1,5 +1,8 @@
 use crate::{
-    service::{pdu::EventHash, rooms::timeline::PduCount},
+    service::{
+        pdu::EventHash,
+        rooms::{partial_state::SyncBehavior, timeline::PduCount},
+    },
     services, utils, Error, PduEvent, Result, Ruma, RumaResponse,
 };
 
@@ -21,6 +24,7 @@ use ruma::{
         room::member::{MembershipState, RoomMemberEventContent},
         StateEventType, TimelineEventType,
     },
+    presence::PresenceState,
     serde::Raw,
     uint, DeviceId, EventId, JsOption, OwnedDeviceId, OwnedUserId, RoomId, UInt, UserId,
 };
@@ -56,6 +60,8 @@ use tracing::{error, info};
 /// - Device list updates that happened after `since`
 /// - If there are events in the timeline we send or the user send updated his read mark: Notification counts
 /// - EDUs that are active now (read receipts, typing updates, presence)
+/// - Rooms that are still missing members after a partial state join are left out unless lazy
+///   loading is activated, and sent like newly joined rooms once their state is complete
 /// - TODO: Allow multiple sync streams to support Pantalaimon
 ///
 /// For invited rooms:
@@ -185,8 +191,12 @@ async fn sync_helper(
     body: sync_events::v3::Request,
     // bool = caching allowed
 ) -> Result<(sync_events::v3::Response, bool), Error> {
-    // TODO: match body.set_presence {
-    services().rooms.edus.presence.ping_presence(&sender_user)?;
+    services()
+        .rooms
+        .edus
+        .presence
+        .ping_presence(&sender_user, &body.set_presence)
+        .await?;
 
     // Setup watchers, so if there's no response, we can wait for them
     let watcher = services().globals.watch(&sender_user, &sender_device);
@@ -212,6 +222,8 @@ async fn sync_helper(
         _ => (false, false),
     };
 
+    let unread_thread_notifications = filter.room.timeline.unread_thread_notifications;
+
     let full_state = body.full_state;
 
     let mut joined_rooms = BTreeMap::new();
@@ -242,16 +254,29 @@ async fn sync_helper(
         .collect::<Vec<_>>();
     for room_id in all_joined_rooms {
         let room_id = room_id?;
+
+        let (room_since, room_sincecount) = match services().rooms.partial_state.sync_behavior(
+            &room_id,
+            since,
+            lazy_load_enabled,
+        )? {
+            SyncBehavior::Normal => (since, sincecount),
+            SyncBehavior::Hidden => continue,
+            // Like an initial sync of this room
+            SyncBehavior::Rejoined => (0, PduCount::Normal(0)),
+        };
+
         if let Ok(joined_room) = load_joined_room(
             &sender_user,
             &sender_device,
             &room_id,
-            since,
-            sincecount,
+            room_since,
+            room_sincecount,
             next_batch,
             next_batchcount,
             lazy_load_enabled,
             lazy_load_send_redundant,
+            unread_thread_notifications,
             full_state,
             &mut device_list_updates,
             &mut left_encrypted_users,
@@ -625,8 +650,11 @@ async fn sync_helper(
                 .users
                 .get_to_device_events(&sender_user, &sender_device)?,
         },
-        // Fallback keys are not yet supported
-        device_unused_fallback_key_types: None,
+        device_unused_fallback_key_types: Some(
+            services()
+                .users
+                .unused_fallback_key_types(&sender_user, &sender_device)?,
+        ),
     };
 
     // TODO: Retry the endpoint instead of returning (waiting for #118)
@@ -661,6 +689,7 @@ async fn load_joined_room(
     next_batchcount: PduCount,
     lazy_load_enabled: bool,
     lazy_load_send_redundant: bool,
+    unread_thread_notifications: bool,
     full_state: bool,
     device_list_updates: &mut HashSet<OwnedUserId>,
     left_encrypted_users: &mut HashSet<OwnedUserId>,
@@ -1096,12 +1125,29 @@ async fn load_joined_room(
             .filter_map(|r| r.ok()),
     );
 
+    let thread_notification_counts = if send_notification_counts && unread_thread_notifications {
+        services()
+            .rooms
+            .user
+            .thread_notification_counts(sender_user, room_id)?
+    } else {
+        BTreeMap::new()
+    };
+
+    // When threads are counted separately, the room counts only cover the main timeline
+    let (thread_notifications, thread_highlights) = thread_notification_counts
+        .values()
+        .fold((0, 0), |(notifications, highlights), (n, h)| {
+            (notifications + n, highlights + h)
+        });
+
     let notification_count = if send_notification_counts {
         Some(
             services()
                 .rooms
                 .user
                 .notification_count(sender_user, room_id)?
+                .saturating_sub(thread_notifications)
                 .try_into()
                 .expect("notification count can't go that high"),
         )
@@ -1115,6 +1161,7 @@ async fn load_joined_room(
                 .rooms
                 .user
                 .highlight_count(sender_user, room_id)?
+                .saturating_sub(thread_highlights)
                 .try_into()
                 .expect("highlight count can't go that high"),
         )
@@ -1122,6 +1169,27 @@ async fn load_joined_room(
         None
     };
 
+    let unread_thread_notifications = thread_notification_counts
+        .into_iter()
+        .map(|(thread_root, (notification_count, highlight_count))| {
+            (
+                thread_root,
+                UnreadNotificationsCount {
+                    highlight_count: Some(
+                        highlight_count
+                            .try_into()
+                            .expect("highlight count can't go that high"),
+                    ),
+                    notification_count: Some(
+                        notification_count
+                            .try_into()
+                            .expect("notification count can't go that high"),
+                    ),
+                },
+            )
+        })
+        .collect();
+
     let prev_batch = timeline_pdus
         .first()
         .map_or(Ok::<_, Error>(None), |(pdu_count, _)| {
@@ -1206,7 +1274,7 @@ async fn load_joined_room(
                 .collect(),
         }),
         ephemeral: Ephemeral { events: edus },
-        unread_thread_notifications: BTreeMap::new(),
+        unread_thread_notifications,
     })
 }
 
@@ -1296,6 +1364,13 @@ pub async fn sync_events_v5_route(
             .await;
     });
 
+    services()
+        .rooms
+        .edus
+        .presence
+        .ping_presence(&sender_user, &PresenceState::Online)
+        .await?;
+
     let mut body = body.body;
     // Setup watchers, so if there's no response, we can wait for them
     let watcher = services().globals.watch(&sender_user, &sender_device);
@@ -1821,8 +1896,11 @@ pub async fn sync_events_v5_route(
                 device_one_time_keys_count: services()
                     .users
                     .count_one_time_keys(&sender_user, &sender_device)?,
-                // Fallback keys are not yet supported
-                device_unused_fallback_key_types: None,
+                device_unused_fallback_key_types: Some(
+                    services()
+                        .users
+                        .unused_fallback_key_types(&sender_user, &sender_device)?,
+                ),
             },
             account_data: sync_events::v5::response::AccountData {
                 global: if body.extensions.account_data.enabled.unwrap_or(false) {
@@ -1,3 +1,4 @@
+pub mod admin_server;
 pub mod appservice_server;
 pub mod client_server;
 pub mod ruma_wrapper;
@@ -29,6 +29,7 @@ use crate::{service::appservice::RegistrationInfo, services, Error, Result};
 enum Token {
     Appservice(Box<RegistrationInfo>),
     User((OwnedUserId, OwnedDeviceId)),
+    Expired,
     Invalid,
     None,
 }
@@ -45,6 +46,8 @@ where
         struct QueryParams {
             access_token: Option<String>,
             user_id: Option<String>,
+            #[serde(alias = "org.matrix.msc3202.device_id")]
+            device_id: Option<OwnedDeviceId>,
         }
 
         let (mut parts, mut body) = {
@@ -103,7 +106,11 @@ where
             if let Some(reg_info) = services().appservice.find_from_token(token).await {
                 Token::Appservice(Box::new(reg_info.clone()))
             } else if let Some((user_id, device_id)) = services().users.find_from_token(token)? {
-                Token::User((user_id, device_id))
+                if services().users.is_token_expired(&user_id, &device_id)? {
+                    Token::Expired
+                } else {
+                    Token::User((user_id, device_id))
+                }
             } else {
                 Token::Invalid
             }
@@ -126,6 +133,14 @@ where
                         ));
                     }
                 }
+                // Endpoints like /refresh don't need authentication, so clients can still use them
+                (AuthScheme::None, Token::Expired) => (None, None, None, None),
+                (_, Token::Expired) => {
+                    return Err(Error::BadRequest(
+                        ErrorKind::UnknownToken { soft_logout: true },
+                        "Access token has expired.",
+                    ));
+                }
                 (AuthScheme::AccessToken, Token::Appservice(info)) => {
                     let user_id = query_params
                         .user_id
@@ -156,7 +171,21 @@ where
                         ));
                     }
 
-                    (Some(user_id), None, None, Some(*info))
+                    // Appservices can masquerade as one of the devices of their users
+                    if let Some(device_id) = &query_params.device_id {
+                        if services()
+                            .users
+                            .get_device_metadata(&user_id, device_id)?
+                            .is_none()
+                        {
+                            return Err(Error::BadRequest(
+                                ErrorKind::forbidden(),
+                                "Device does not exist for this user.",
+                            ));
+                        }
+                    }
+
+                    (Some(user_id), query_params.device_id, None, Some(*info))
                 }
                 (
                     AuthScheme::None
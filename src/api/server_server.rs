@@ -5,7 +5,7 @@ use crate::{
     service::{
         globals::SigningKeys,
         media::FileMeta,
-        pdu::{gen_event_id_canonical_json, PduBuilder},
+        pdu::{gen_event_id, gen_event_id_canonical_json, referenced_event_ids, PduBuilder},
     },
     services, utils, Error, PduEvent, Result, Ruma, SUPPORTED_VERSIONS,
 };
@@ -26,10 +26,13 @@ use ruma::{
             device::get_devices::{self, v1::UserDevice},
             directory::{get_public_rooms, get_public_rooms_filtered},
             discovery::{
-                discover_homeserver, get_server_keys, get_server_version, ServerSigningKeys,
-                VerifyKey,
+                discover_homeserver, get_remote_server_keys, get_remote_server_keys_batch,
+                get_server_keys, get_server_version, ServerSigningKeys, VerifyKey,
+            },
+            event::{
+                get_event, get_event_by_timestamp, get_missing_events, get_room_state,
+                get_room_state_ids,
             },
-            event::{get_event, get_missing_events, get_room_state, get_room_state_ids},
             keys::{claim_keys, get_keys},
             membership::{
                 create_invite, create_join_event, create_knock_event, create_leave_event,
@@ -702,28 +705,9 @@ pub async fn get_server_version_route(
 ///   forever.
 // Response type for this endpoint is Json because we need to calculate a signature for the response
 pub async fn get_server_keys_route() -> Result<impl IntoResponse> {
-    let mut verify_keys: BTreeMap<OwnedServerSigningKeyId, VerifyKey> = BTreeMap::new();
-    verify_keys.insert(
-        format!("ed25519:{}", services().globals.keypair().version())
-            .try_into()
-            .expect("found invalid server signing keys in DB"),
-        VerifyKey {
-            key: Base64::new(services().globals.keypair().public_key().to_vec()),
-        },
-    );
     let mut response = serde_json::from_slice(
         get_server_keys::v2::Response {
-            server_key: Raw::new(&ServerSigningKeys {
-                server_name: services().globals.server_name().to_owned(),
-                verify_keys,
-                old_verify_keys: BTreeMap::new(),
-                signatures: Signatures::new(),
-                valid_until_ts: MilliSecondsSinceUnixEpoch::from_system_time(
-                    SystemTime::now() + Duration::from_secs(86400 * 7),
-                )
-                .expect("time is valid"),
-            })
-            .expect("static conversion, no errors"),
+            server_key: Raw::new(&own_server_keys()).expect("static conversion, no errors"),
         }
         .try_into_http_response::<Vec<u8>>()
         .unwrap()
@@ -741,6 +725,30 @@ pub async fn get_server_keys_route() -> Result<impl IntoResponse> {
     Ok(Json(response))
 }
 
+/// The unsigned public signing keys of this server.
+fn own_server_keys() -> ServerSigningKeys {
+    let mut verify_keys: BTreeMap<OwnedServerSigningKeyId, VerifyKey> = BTreeMap::new();
+    verify_keys.insert(
+        format!("ed25519:{}", services().globals.keypair().version())
+            .try_into()
+            .expect("found invalid server signing keys in DB"),
+        VerifyKey {
+            key: Base64::new(services().globals.keypair().public_key().to_vec()),
+        },
+    );
+
+    ServerSigningKeys {
+        server_name: services().globals.server_name().to_owned(),
+        verify_keys,
+        old_verify_keys: BTreeMap::new(),
+        signatures: Signatures::new(),
+        valid_until_ts: MilliSecondsSinceUnixEpoch::from_system_time(
+            SystemTime::now() + Duration::from_secs(86400 * 7),
+        )
+        .expect("time is valid"),
+    }
+}
+
 /// # `GET /_matrix/key/v2/server/{keyId}`
 ///
 /// Gets the public signing keys of this server.
@@ -751,6 +759,136 @@ pub async fn get_server_keys_deprecated_route() -> impl IntoResponse {
     get_server_keys_route().await
 }
 
+/// # `GET /_matrix/key/v2/query/{serverName}`
+///
+/// Gets the public signing keys of another server, acting as a notary for it.
+///
+/// - Fetches the keys from the server if they are not cached or not valid until
+///   `minimum_valid_until_ts`
+pub async fn get_remote_server_keys_route(
+    body: Ruma<get_remote_server_keys::v2::Request>,
+) -> Result<get_remote_server_keys::v2::Response> {
+    let server_keys =
+        notary_server_keys(&body.server_name, Vec::new(), body.minimum_valid_until_ts)
+            .await?
+            .into_iter()
+            .collect();
+
+    Ok(get_remote_server_keys::v2::Response::new(server_keys))
+}
+
+/// # `POST /_matrix/key/v2/query`
+///
+/// Gets the public signing keys of other servers, acting as a notary for them.
+///
+/// - Fetches the keys from the servers if they are not cached or not valid until the
+///   `minimum_valid_until_ts` of the query criteria
+pub async fn get_remote_server_keys_batch_route(
+    body: Ruma<get_remote_server_keys_batch::v2::Request>,
+) -> Result<get_remote_server_keys_batch::v2::Response> {
+    let mut server_keys = Vec::new();
+
+    for (server_name, criteria) in &body.server_keys {
+        let minimum_valid_until_ts = criteria
+            .values()
+            .filter_map(|criteria| criteria.minimum_valid_until_ts)
+            .max()
+            .unwrap_or_else(MilliSecondsSinceUnixEpoch::now);
+        let key_ids = criteria.keys().map(ToString::to_string).collect();
+
+        server_keys.extend(notary_server_keys(server_name, key_ids, minimum_valid_until_ts).await?);
+    }
+
+    Ok(get_remote_server_keys_batch::v2::Response::new(server_keys))
+}
+
+/// Returns the keys of the server as the server signed them, with our signature added. Keys that
+/// are missing, not valid until `minimum_valid_until_ts` or don't contain any of the `key_ids` are
+/// fetched from the server first. If that fails, the cached keys are returned anyway, as the
+/// requesting server can decide whether to use them.
+///
+/// The keys can't be filtered down to the `key_ids`, as that would invalidate the signature of
+/// the server, so they are only left out if they contain none of them.
+async fn notary_server_keys(
+    server_name: &ServerName,
+    key_ids: Vec<String>,
+    minimum_valid_until_ts: MilliSecondsSinceUnixEpoch,
+) -> Result<Option<Raw<ServerSigningKeys>>> {
+    let keys = if server_name == services().globals.server_name() {
+        Some(Raw::new(&own_server_keys()).expect("static conversion, no errors"))
+    } else {
+        let mut keys = services().globals.origin_server_keys(server_name)?;
+
+        if keys
+            .as_ref()
+            .is_none_or(|keys| !keys_satisfy(keys, &key_ids, minimum_valid_until_ts))
+        {
+            // Servers asking a notary expect it to ask the origin, not other notaries
+            if let Err(e) = services()
+                .rooms
+                .event_handler
+                .fetch_signing_keys(server_name, key_ids.clone(), false)
+                .await
+            {
+                debug!("Failed to fetch the signing keys of {server_name} as a notary: {e}");
+            }
+
+            keys = services().globals.origin_server_keys(server_name)?;
+        }
+
+        keys
+    };
+
+    let Some(keys) = keys else {
+        return Ok(None);
+    };
+
+    if !contains_any_key(&keys, &key_ids) {
+        return Ok(None);
+    }
+
+    let mut keys: CanonicalJsonObject = serde_json::from_str(keys.json().get())
+        .map_err(|_| Error::bad_database("Invalid origin server keys in db."))?;
+    ruma::signatures::sign_json(
+        services().globals.server_name().as_str(),
+        services().globals.keypair(),
+        &mut keys,
+    )
+    .expect("ServerSigningKeys is an object");
+
+    Ok(Some(Raw::from_json(
+        to_raw_value(&keys).expect("CanonicalJsonObject can be serialized"),
+    )))
+}
+
+fn keys_satisfy(
+    keys: &Raw<ServerSigningKeys>,
+    key_ids: &[String],
+    minimum_valid_until_ts: MilliSecondsSinceUnixEpoch,
+) -> bool {
+    keys.get_field::<MilliSecondsSinceUnixEpoch>("valid_until_ts")
+        .ok()
+        .flatten()
+        .is_some_and(|valid_until_ts| valid_until_ts >= minimum_valid_until_ts)
+        && contains_any_key(keys, key_ids)
+}
+
+/// Whether the keys contain one of the key ids, current or old. No key ids means any key.
+fn contains_any_key(keys: &Raw<ServerSigningKeys>, key_ids: &[String]) -> bool {
+    if key_ids.is_empty() {
+        return true;
+    }
+
+    let Ok(keys) = keys.deserialize() else {
+        return false;
+    };
+
+    key_ids.iter().any(|key_id| {
+        keys.verify_keys.keys().any(|id| id.as_str() == key_id)
+            || keys.old_verify_keys.keys().any(|id| id.as_str() == key_id)
+    })
+}
+
 /// # `POST /_matrix/federation/v1/publicRooms`
 ///
 /// Lists the public rooms on this server.
@@ -871,19 +1009,22 @@ async fn handle_pdu_in_transaction(
     let mutex_lock = mutex.lock().await;
     let start_time = Instant::now();
 
-    if let Err(e) = services()
+    let result = services()
         .rooms
         .event_handler
         .handle_incoming_pdu(origin, &event_id, &room_id, value, true, pub_key_map)
-        .await
-    {
-        warn!("Error appending PDU to timeline: {}: {:?}", e, pdu);
-        return (Some(event_id), Err(e));
-    }
+        .await;
 
     drop(mutex_lock);
 
     let elapsed = start_time.elapsed();
+    services().metrics.record_event_handling(elapsed);
+
+    if let Err(e) = result {
+        warn!("Error appending PDU to timeline: {}: {:?}", e, pdu);
+        return (Some(event_id), Err(e));
+    }
+
     debug!(
         "Handling transaction of event {} took {}m{}s",
         event_id,
@@ -932,7 +1073,25 @@ pub async fn send_transaction_message_route(
         .filter_map(|edu| serde_json::from_str::<Edu>(edu.json().get()).ok())
     {
         match edu {
-            Edu::Presence(_) => {}
+            Edu::Presence(presence) => {
+                for update in presence.push {
+                    if update.user_id.server_name() == sender_servername
+                        && services()
+                            .rooms
+                            .state_cache
+                            .rooms_joined(&update.user_id)
+                            .next()
+                            .is_some()
+                    {
+                        services()
+                            .rooms
+                            .edus
+                            .presence
+                            .update_remote_presence(update)
+                            .await?;
+                    }
+                }
+            }
             Edu::Receipt(receipt) => {
                 for (room_id, room_updates) in receipt.receipts {
                     for (user_id, user_updates) in room_updates.read {
@@ -968,7 +1127,8 @@ pub async fn send_transaction_message_route(
                                     .rooms
                                     .edus
                                     .read_receipt
-                                    .readreceipt_update(&user_id, &room_id, event)?;
+                                    .readreceipt_update(&user_id, &room_id, event)
+                                    .await?;
                             } else {
                                 // TODO fetch missing events
                                 debug!("No known event ids in read receipt: {:?}", user_updates);
@@ -1027,37 +1187,43 @@ pub async fn send_transaction_message_route(
                         for (target_device_id_maybe, event) in map {
                             match target_device_id_maybe {
                                 DeviceIdOrAllDevices::DeviceId(target_device_id) => {
-                                    services().users.add_to_device_event(
-                                        &sender,
-                                        target_user_id,
-                                        target_device_id,
-                                        &ev_type.to_string(),
-                                        event.deserialize_as().map_err(|e| {
-                                            warn!("To-Device event is invalid: {event:?} {e}");
-                                            Error::BadRequest(
-                                                ErrorKind::InvalidParam,
-                                                "Event is invalid",
-                                            )
-                                        })?,
-                                    )?
-                                }
-
-                                DeviceIdOrAllDevices::AllDevices => {
-                                    for target_device_id in
-                                        services().users.all_device_ids(target_user_id)
-                                    {
-                                        services().users.add_to_device_event(
+                                    services()
+                                        .users
+                                        .add_to_device_event(
                                             &sender,
                                             target_user_id,
-                                            &target_device_id?,
+                                            target_device_id,
                                             &ev_type.to_string(),
-                                            event.deserialize_as().map_err(|_| {
+                                            event.deserialize_as().map_err(|e| {
+                                                warn!("To-Device event is invalid: {event:?} {e}");
                                                 Error::BadRequest(
                                                     ErrorKind::InvalidParam,
                                                     "Event is invalid",
                                                 )
                                             })?,
-                                        )?;
+                                        )
+                                        .await?
+                                }
+
+                                DeviceIdOrAllDevices::AllDevices => {
+                                    for target_device_id in
+                                        services().users.all_device_ids(target_user_id)
+                                    {
+                                        services()
+                                            .users
+                                            .add_to_device_event(
+                                                &sender,
+                                                target_user_id,
+                                                &target_device_id?,
+                                                &ev_type.to_string(),
+                                                event.deserialize_as().map_err(|_| {
+                                                    Error::BadRequest(
+                                                        ErrorKind::InvalidParam,
+                                                        "Event is invalid",
+                                                    )
+                                                })?,
+                                            )
+                                            .await?;
                                     }
                                 }
                             }
@@ -1292,14 +1458,16 @@ pub async fn get_missing_events_route(
                 continue;
             }
 
-            queued_events.extend_from_slice(
-                &serde_json::from_value::<Vec<OwnedEventId>>(
-                    serde_json::to_value(pdu.get("prev_events").cloned().ok_or_else(|| {
-                        Error::bad_database("Event in db has no prev_events field.")
-                    })?)
-                    .expect("canonical json is valid json value"),
-                )
-                .map_err(|_| Error::bad_database("Invalid prev_events content in pdu in db."))?,
+            queued_events.extend(
+                pdu.get("prev_events")
+                    .ok_or_else(|| Error::bad_database("Event in db has no prev_events field."))
+                    .and_then(|prev_events| {
+                        referenced_event_ids(prev_events).ok_or_else(|| {
+                            Error::bad_database("Invalid prev_events content in pdu in db.")
+                        })
+                    })?
+                    .into_iter()
+                    .map(|event_id| (*event_id).to_owned()),
             );
             events.push(PduEvent::convert_to_outgoing_federation_event(pdu));
         }
@@ -1309,6 +1477,61 @@ pub async fn get_missing_events_route(
     Ok(get_missing_events::v1::Response { events })
 }
 
+/// # `GET /_matrix/federation/v1/timestamp_to_event/{roomId}`
+///
+/// Finds the event closest to the given timestamp in the given direction.
+///
+/// - Only looks at our own timeline
+pub async fn get_event_by_timestamp_route(
+    body: Ruma<get_event_by_timestamp::v1::Request>,
+) -> Result<get_event_by_timestamp::v1::Response> {
+    let sender_servername = body
+        .sender_servername
+        .as_ref()
+        .expect("server is authenticated");
+
+    if !services()
+        .rooms
+        .state_cache
+        .server_in_room(sender_servername, &body.room_id)?
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::forbidden(),
+            "Server is not in room.",
+        ));
+    }
+
+    services()
+        .rooms
+        .event_handler
+        .acl_check(sender_servername, &body.room_id)?;
+
+    let pdu = services()
+        .rooms
+        .timeline
+        .closest_local_pdu_by_timestamp(&body.room_id, body.ts, body.dir)?
+        .0
+        .filter(|pdu| {
+            matches!(
+                services().rooms.state_accessor.server_can_see_event(
+                    sender_servername,
+                    &body.room_id,
+                    &pdu.event_id,
+                ),
+                Ok(true),
+            )
+        })
+        .ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "No event found in the given direction.",
+        ))?;
+
+    Ok(get_event_by_timestamp::v1::Response::new(
+        pdu.event_id.as_ref().to_owned(),
+        MilliSecondsSinceUnixEpoch(pdu.origin_server_ts),
+    ))
+}
+
 /// # `GET /_matrix/federation/v1/event_auth/{roomId}/{eventId}`
 ///
 /// Retrieves the auth chain for a given event.
@@ -1396,6 +1619,8 @@ pub async fn get_room_state_route(
         .event_handler
         .acl_check(sender_servername, &body.room_id)?;
 
+    full_state_check(&body.room_id)?;
+
     let shortstatehash = services()
         .rooms
         .state_accessor
@@ -1472,6 +1697,8 @@ pub async fn get_room_state_ids_route(
         .event_handler
         .acl_check(sender_servername, &body.room_id)?;
 
+    full_state_check(&body.room_id)?;
+
     let shortstatehash = services()
         .rooms
         .state_accessor
@@ -1554,6 +1781,7 @@ pub async fn create_join_event_template_route(
 ) -> Result<prepare_join_event::v1::Response> {
     let (mutex_state, room_version_id) =
         member_shake_preamble(&body.sender_servername, &body.room_id).await?;
+    full_state_check(&body.room_id)?;
     let state_lock = mutex_state.lock().await;
 
     let join_authorized_via_users_server = if
@@ -1669,6 +1897,13 @@ fn room_and_acl_check(room_id: &RoomId, sender_servername: &OwnedServerName) ->
         ));
     }
 
+    if services().rooms.metadata.is_banned(room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::forbidden(),
+            "This room has been shut down on this server.",
+        ));
+    }
+
     services()
         .rooms
         .event_handler
@@ -1676,6 +1911,18 @@ fn room_and_acl_check(room_id: &RoomId, sender_servername: &OwnedServerName) ->
     Ok(())
 }
 
+/// Fails if we joined the room without receiving all of its state and are still fetching it.
+fn full_state_check(room_id: &RoomId) -> Result<(), Error> {
+    if services().rooms.partial_state.is_partial_state(room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Unknown,
+            "This server does not have the full state of the room yet.",
+        ));
+    }
+
+    Ok(())
+}
+
 /// Takes care of common boilerpalte for room membership handshake endpoints.
 /// The returned mutex must be locked by the caller.
 async fn member_shake_preamble(
@@ -1707,6 +1954,7 @@ async fn create_join_event(
 ) -> Result<create_join_event::v1::RoomState> {
     let sender_servername = sender_servername.as_ref().expect("server is authenticated");
     room_and_acl_check(room_id, sender_servername)?;
+    full_state_check(room_id)?;
 
     // We need to return the state prior to joining, let's keep a reference to that here
     let shortstatehash = services()
@@ -2090,6 +2338,13 @@ pub async fn create_invite_route(
 
     let sender_servername = sender_servername.expect("server is authenticated");
 
+    if services().rooms.metadata.is_banned(&room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::forbidden(),
+            "This room has been shut down on this server.",
+        ));
+    }
+
     services()
         .rooms
         .event_handler
@@ -2124,12 +2379,7 @@ pub async fn create_invite_route(
     .expect("Invites should always be signed");
 
     // Generate event id
-    let event_id = EventId::parse(format!(
-        "${}",
-        ruma::signatures::reference_hash(&signed_event, &rules)
-            .expect("Event format validated when event was hashed")
-    ))
-    .expect("ruma's reference hashes are valid event ids");
+    let event_id = gen_event_id(&signed_event, &rules)?;
 
     // Add event_id back
     signed_event.insert(
@@ -2149,6 +2399,11 @@ pub async fn create_invite_route(
     )
     .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "sender is not a user id."))?;
 
+    services()
+        .policy
+        .check_membership(&sender, &room_id, "inviting users to")
+        .await?;
+
     let invited_user: Box<_> = serde_json::from_value(
         signed_event
             .get("state_key")
@@ -2545,7 +2800,9 @@ pub async fn well_known_server(
 
 #[cfg(test)]
 mod tests {
-    use super::{add_port_to_hostname, get_ip_with_port, FedDest};
+    use ruma::{serde::Raw, MilliSecondsSinceUnixEpoch, UInt};
+
+    use super::{add_port_to_hostname, contains_any_key, get_ip_with_port, keys_satisfy, FedDest};
 
     #[test]
     fn ips_get_default_ports() {
@@ -2586,4 +2843,31 @@ mod tests {
             FedDest::Named(String::from("example.com"), String::from(":1337"))
         )
     }
+
+    #[test]
+    fn notary_keys_match_key_ids() {
+        let keys = Raw::from_json(
+            serde_json::value::to_raw_value(&serde_json::json!({
+                "server_name": "example.com",
+                "verify_keys": { "ed25519:new": { "key": "bmV3" } },
+                "old_verify_keys": { "ed25519:old": { "key": "b2xk", "expired_ts": 1000 } },
+                "signatures": {},
+                "valid_until_ts": 5000,
+            }))
+            .unwrap(),
+        );
+        let ts = |millis: u32| MilliSecondsSinceUnixEpoch(UInt::from(millis));
+
+        assert!(contains_any_key(&keys, &[]));
+        assert!(contains_any_key(&keys, &["ed25519:old".to_owned()]));
+        assert!(contains_any_key(
+            &keys,
+            &["ed25519:other".to_owned(), "ed25519:new".to_owned()]
+        ));
+        assert!(!contains_any_key(&keys, &["ed25519:other".to_owned()]));
+
+        assert!(keys_satisfy(&keys, &[], ts(5000)));
+        assert!(!keys_satisfy(&keys, &[], ts(5001)));
+        assert!(!keys_satisfy(&keys, &["ed25519:other".to_owned()], ts(0)));
+    }
 }
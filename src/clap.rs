@@ -1,6 +1,8 @@
 //! Integration with `clap`
 
-use clap::Parser;
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
 
 /// Returns the current version of the crate with extra info if supplied
 ///
@@ -19,7 +21,32 @@ fn version() -> String {
 /// Command line arguments
 #[derive(Parser)]
 #[clap(about, version = version())]
-pub struct Args {}
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Maintenance tasks that are run instead of the server
+#[derive(Subcommand)]
+pub enum Command {
+    /// Write a consistent snapshot of the database to a new directory
+    ///
+    /// RocksDB databases can only be backed up like this while Conduit is stopped, use the
+    /// `backup-database` admin command while it is running. Media files are not included.
+    Backup {
+        /// The directory to write the backup to, which must not exist yet
+        path: PathBuf,
+    },
+
+    /// Replace the database with a backup
+    ///
+    /// Conduit must be stopped. The files of the current database are moved into a new
+    /// directory in `database_path`.
+    Restore {
+        /// The directory containing the backup
+        path: PathBuf,
+    },
+}
 
 /// Parse command line arguments into structured data
 pub fn parse() -> Args {
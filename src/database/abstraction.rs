@@ -1,7 +1,7 @@
 use super::Config;
-use crate::Result;
+use crate::{Error, Result};
 
-use std::{future::Future, pin::Pin, sync::Arc};
+use std::{future::Future, path::Path, pin::Pin, sync::Arc};
 
 #[cfg(feature = "sqlite")]
 pub mod sqlite;
@@ -9,7 +9,10 @@ pub mod sqlite;
 #[cfg(feature = "rocksdb")]
 pub mod rocksdb;
 
-#[cfg(any(feature = "sqlite", feature = "rocksdb"))]
+#[cfg(feature = "postgres")]
+pub mod postgres;
+
+#[cfg(any(feature = "sqlite", feature = "rocksdb", feature = "postgres"))]
 pub mod watchers;
 
 pub trait KeyValueDatabaseEngine: Send + Sync {
@@ -24,6 +27,17 @@ pub trait KeyValueDatabaseEngine: Send + Sync {
     fn memory_usage(&self) -> Result<String> {
         Ok("Current database engine does not support memory usage reporting.".to_owned())
     }
+    /// The same numbers as `memory_usage`, in bytes
+    fn memory_usage_bytes(&self) -> Result<Vec<(&'static str, u64)>> {
+        Ok(Vec::new())
+    }
+    /// Writes a consistent snapshot of the database to `path`, which must not exist yet. The
+    /// snapshot can be used as `database_path` with the same backend.
+    fn backup(&self, _path: &Path) -> Result<()> {
+        Err(Error::BadConfig(
+            "Current database engine does not support backups.",
+        ))
+    }
 }
 
 pub trait KvTree: Send + Sync {
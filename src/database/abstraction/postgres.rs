@@ -0,0 +1,684 @@
+use super::{watchers::Watchers, KeyValueDatabaseEngine, KvTree};
+use crate::{database::Config, Error, Result};
+use parking_lot::{Condvar, Mutex, RwLock};
+use postgres::{fallible_iterator::FallibleIterator, types::ToSql, Client};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    future::Future,
+    path::Path,
+    pin::Pin,
+    sync::{Arc, Once},
+    thread,
+    time::Duration,
+};
+use tokio::runtime::{Handle, RuntimeFlavor};
+use tokio_postgres_rustls::MakeRustlsConnect;
+use tracing::error;
+
+/// Channel on which changes to watched tables are announced
+const NOTIFY_CHANNEL: &str = "conduit_kv";
+
+/// Most iterators are only used for a few rows, so the first page is small
+const FIRST_PAGE_SIZE: i64 = 32;
+const MAX_PAGE_SIZE: i64 = 1024;
+
+/// How many rows are written with a single statement
+const BATCH_SIZE: usize = 1000;
+
+/// How many read connections are open at most. Reads wait for a free connection beyond that.
+const MAX_READERS: usize = 16;
+
+/// Announces the table and hex encoded key of every changed row. Notifications are limited to
+/// 8000 bytes, so very long keys are cut off.
+const NOTIFY_FUNCTION: &str = "
+CREATE OR REPLACE FUNCTION conduit_notify() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify('conduit_kv', TG_TABLE_NAME || ' ' || encode(substring(NEW.key from 1 for 3000), 'hex'));
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+";
+
+type TupleOfBytes = (Vec<u8>, Vec<u8>);
+
+/// The synchronous client drives its own runtime, which is not allowed on threads of the async
+/// runtime unless they are marked as blocking.
+fn blocking<T>(f: impl FnOnce() -> T) -> T {
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(f)
+        }
+        _ => f(),
+    }
+}
+
+/// The pooled read connections.
+#[derive(Default)]
+struct Readers {
+    idle: Vec<Client>,
+    /// The number of idle and used connections
+    open: usize,
+}
+
+pub struct Engine {
+    config: postgres::Config,
+    tls: MakeRustlsConnect,
+    writer: Mutex<Client>,
+    readers: Mutex<Readers>,
+    reader_returned: Condvar,
+    watchers: Arc<RwLock<HashMap<&'static str, Arc<Watchers>>>>,
+}
+
+impl Engine {
+    fn connect(url: &str) -> Result<Arc<Self>> {
+        let config: postgres::Config = url
+            .parse()
+            .map_err(|_| Error::bad_config("database_url is not a valid PostgreSQL URL."))?;
+        let tls = tls_connector()?;
+
+        let writer = blocking(|| -> Result<_> {
+            let mut writer = config.connect(tls.clone())?;
+            writer.batch_execute(NOTIFY_FUNCTION)?;
+            Ok(writer)
+        })?;
+
+        let engine = Arc::new(Engine {
+            config,
+            tls,
+            writer: Mutex::new(writer),
+            readers: Mutex::default(),
+            reader_returned: Condvar::new(),
+            watchers: Arc::default(),
+        });
+        engine.start_listener();
+
+        Ok(engine)
+    }
+
+    /// Runs `f` with one of the pooled read connections.
+    fn read<T>(&self, f: impl FnOnce(&mut Client) -> Result<T, postgres::Error>) -> Result<T> {
+        blocking(|| {
+            let mut client = self.take_reader()?;
+            let result = f(&mut client);
+            self.return_reader(client);
+
+            Ok(result?)
+        })
+    }
+
+    /// Takes an idle read connection, opens a new one or waits for one to be returned if
+    /// `MAX_READERS` are open.
+    fn take_reader(&self) -> Result<Client> {
+        let mut readers = self.readers.lock();
+
+        loop {
+            if let Some(client) = readers.idle.pop() {
+                if !client.is_closed() {
+                    return Ok(client);
+                }
+                readers.open -= 1;
+            } else if readers.open < MAX_READERS {
+                readers.open += 1;
+                drop(readers);
+
+                return self.config.connect(self.tls.clone()).map_err(|e| {
+                    self.readers.lock().open -= 1;
+                    self.reader_returned.notify_one();
+                    e.into()
+                });
+            } else {
+                self.reader_returned.wait(&mut readers);
+            }
+        }
+    }
+
+    fn return_reader(&self, client: Client) {
+        let mut readers = self.readers.lock();
+        if client.is_closed() {
+            readers.open -= 1;
+        } else {
+            readers.idle.push(client);
+        }
+        drop(readers);
+
+        self.reader_returned.notify_one();
+    }
+
+    /// Runs `f` with the write connection. Writes are serialized, like with the other backends.
+    fn write<T>(&self, f: impl FnOnce(&mut Client) -> Result<T, postgres::Error>) -> Result<T> {
+        blocking(|| {
+            let mut writer = self.writer.lock();
+            if writer.is_closed() {
+                *writer = self.config.connect(self.tls.clone())?;
+            }
+
+            Ok(f(&mut writer)?)
+        })
+    }
+
+    /// Wakes up watchers when other connections, possibly of other Conduit instances, change
+    /// watched tables.
+    fn start_listener(&self) {
+        let config = self.config.clone();
+        let tls = self.tls.clone();
+        let watchers = Arc::clone(&self.watchers);
+
+        thread::spawn(move || loop {
+            if let Err(e) = listen(&config, tls.clone(), &watchers) {
+                error!("Lost connection for database notifications: {e}");
+            }
+
+            // Changes might have been missed while the connection was down
+            for watchers in watchers.read().values() {
+                watchers.wake_all();
+            }
+
+            thread::sleep(Duration::from_secs(1));
+        });
+    }
+}
+
+/// Verifies the certificates of the database server with the root certificates of the system.
+/// Whether TLS is used is set by the `sslmode` parameter of `database_url`.
+fn tls_connector() -> Result<MakeRustlsConnect> {
+    let certificates = rustls_native_certs::load_native_certs().map_err(|e| {
+        error!("Failed to load the root certificates of the system: {e}");
+        Error::bad_config("Failed to load root certificates for connections to PostgreSQL.")
+    })?;
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add_parsable_certificates(certificates);
+
+    Ok(MakeRustlsConnect::new(
+        rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+    ))
+}
+
+fn listen(
+    config: &postgres::Config,
+    tls: MakeRustlsConnect,
+    watchers: &RwLock<HashMap<&'static str, Arc<Watchers>>>,
+) -> Result<(), postgres::Error> {
+    let mut client = config.connect(tls)?;
+    client.batch_execute(&format!("LISTEN {NOTIFY_CHANNEL}"))?;
+
+    let mut notifications = client.notifications();
+    let mut notifications = notifications.blocking_iter();
+
+    while let Some(notification) = notifications.next()? {
+        let Some((table, key)) = notification.payload().split_once(' ') else {
+            continue;
+        };
+
+        let Ok(key) = hex::decode(key) else {
+            continue;
+        };
+
+        if let Some(watchers) = watchers.read().get(table) {
+            watchers.wake(&key);
+        }
+    }
+
+    Ok(())
+}
+
+impl KeyValueDatabaseEngine for Arc<Engine> {
+    fn open(config: &Config) -> Result<Self> {
+        let url = config.database_url.as_deref().ok_or_else(|| {
+            Error::bad_config("database_url has to be set to use the postgresql backend.")
+        })?;
+
+        Engine::connect(url)
+    }
+
+    fn open_tree(&self, name: &'static str) -> Result<Arc<dyn KvTree>> {
+        self.write(|client| {
+            client.batch_execute(&format!(
+                "CREATE TABLE IF NOT EXISTS {name} (key BYTEA PRIMARY KEY, value BYTEA NOT NULL)"
+            ))
+        })?;
+
+        let watchers = Arc::clone(self.watchers.write().entry(name).or_default());
+
+        Ok(Arc::new(PostgresTable {
+            engine: Arc::clone(self),
+            name,
+            watchers,
+            notify_trigger: Once::new(),
+        }))
+    }
+
+    fn flush(&self) -> Result<()> {
+        // Every statement is committed when it returns
+        Ok(())
+    }
+
+    fn backup(&self, _path: &Path) -> Result<()> {
+        Err(Error::BadConfig(
+            "PostgreSQL databases have to be backed up using pg_dump.",
+        ))
+    }
+}
+
+pub struct PostgresTable {
+    engine: Arc<Engine>,
+    name: &'static str,
+    watchers: Arc<Watchers>,
+    notify_trigger: Once,
+}
+
+impl PostgresTable {
+    /// Makes the table announce its changes. Only watched tables do this, as notifications are
+    /// not free.
+    fn create_notify_trigger(&self) {
+        self.notify_trigger.call_once(|| {
+            let name = self.name;
+
+            if let Err(e) = self.engine.write(|client| {
+                client.batch_execute(&format!(
+                    "DROP TRIGGER IF EXISTS {name}_notify ON {name};
+                     CREATE TRIGGER {name}_notify AFTER INSERT OR UPDATE ON {name}
+                     FOR EACH ROW EXECUTE FUNCTION conduit_notify();"
+                ))
+            }) {
+                error!("Failed to create notification trigger for {name}: {e}");
+            }
+        });
+    }
+
+    fn upsert_batch(
+        &self,
+        client: &mut impl postgres::GenericClient,
+        rows: &[(&[u8], &[u8])],
+    ) -> Result<(), postgres::Error> {
+        let statement = format!(
+            "INSERT INTO {} (key, value) SELECT * FROM UNNEST($1::BYTEA[], $2::BYTEA[])
+             ON CONFLICT (key) DO UPDATE SET value = EXCLUDED.value",
+            self.name
+        );
+
+        for chunk in rows.chunks(BATCH_SIZE) {
+            let (keys, values): (Vec<_>, Vec<_>) = chunk.iter().copied().unzip();
+            client.execute(&statement, &[&keys, &values])?;
+        }
+
+        Ok(())
+    }
+
+    /// Increments every key the given number of times and returns the new values, in the order of
+    /// the keys.
+    fn increment_all(&self, increments: &BTreeMap<Vec<u8>, usize>) -> Result<Vec<Vec<u8>>> {
+        let keys = increments.keys().map(Vec::as_slice).collect::<Vec<_>>();
+
+        let new_values = self.engine.write(|client| {
+            let mut transaction = client.transaction()?;
+
+            let mut values = HashMap::new();
+            for chunk in keys.chunks(BATCH_SIZE) {
+                for row in transaction.query(
+                    &format!(
+                        "SELECT key, value FROM {} WHERE key = ANY($1) FOR UPDATE",
+                        self.name
+                    ),
+                    &[&chunk],
+                )? {
+                    values.insert(row.get::<_, Vec<u8>>(0), row.get::<_, Vec<u8>>(1));
+                }
+            }
+
+            let new_values = increments
+                .iter()
+                .map(|(key, count)| {
+                    let mut value = values.remove(key);
+                    for _ in 0..*count {
+                        value = crate::utils::increment(value.as_deref());
+                    }
+                    value.expect("utils::increment always returns Some")
+                })
+                .collect::<Vec<_>>();
+
+            let rows = keys
+                .iter()
+                .copied()
+                .zip(new_values.iter().map(Vec::as_slice))
+                .collect::<Vec<_>>();
+
+            self.upsert_batch(&mut transaction, &rows)?;
+            transaction.commit()?;
+
+            Ok(new_values)
+        })?;
+
+        for key in increments.keys() {
+            self.watchers.wake(key);
+        }
+
+        Ok(new_values)
+    }
+
+    fn iter_range(
+        &self,
+        from: Option<Vec<u8>>,
+        until: Option<Vec<u8>>,
+        backwards: bool,
+    ) -> Box<dyn Iterator<Item = TupleOfBytes> + '_> {
+        Box::new(PageIterator {
+            table: self,
+            from: from.map(|from| (from, true)),
+            until,
+            backwards,
+            page: VecDeque::new(),
+            page_size: FIRST_PAGE_SIZE,
+            done: false,
+        })
+    }
+}
+
+impl KvTree for PostgresTable {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.engine.read(|client| {
+            Ok(client
+                .query_opt(
+                    &format!("SELECT value FROM {} WHERE key = $1", self.name),
+                    &[&key],
+                )?
+                .map(|row| row.get(0)))
+        })
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.engine
+            .write(|client| self.upsert_batch(client, &[(key, value)]))?;
+        self.watchers.wake(key);
+        Ok(())
+    }
+
+    fn insert_batch(&self, iter: &mut dyn Iterator<Item = (Vec<u8>, Vec<u8>)>) -> Result<()> {
+        // A key can only be changed once per statement, the last value wins
+        let rows = iter.collect::<BTreeMap<_, _>>();
+        let rows = rows
+            .iter()
+            .map(|(key, value)| (key.as_slice(), value.as_slice()))
+            .collect::<Vec<_>>();
+
+        self.engine.write(|client| {
+            let mut transaction = client.transaction()?;
+            self.upsert_batch(&mut transaction, &rows)?;
+            transaction.commit()
+        })?;
+
+        for (key, _) in &rows {
+            self.watchers.wake(key);
+        }
+
+        Ok(())
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<()> {
+        self.engine.write(|client| {
+            client.execute(
+                &format!("DELETE FROM {} WHERE key = $1", self.name),
+                &[&key],
+            )
+        })?;
+
+        Ok(())
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = TupleOfBytes> + 'a> {
+        self.iter_range(None, None, false)
+    }
+
+    fn iter_from<'a>(
+        &'a self,
+        from: &[u8],
+        backwards: bool,
+    ) -> Box<dyn Iterator<Item = TupleOfBytes> + 'a> {
+        self.iter_range(Some(from.to_vec()), None, backwards)
+    }
+
+    fn increment(&self, key: &[u8]) -> Result<Vec<u8>> {
+        let mut new = self.increment_all(&BTreeMap::from([(key.to_vec(), 1)]))?;
+        Ok(new.pop().expect("one key was incremented"))
+    }
+
+    fn increment_batch(&self, iter: &mut dyn Iterator<Item = Vec<u8>>) -> Result<()> {
+        let mut increments = BTreeMap::<Vec<u8>, usize>::new();
+        for key in iter {
+            *increments.entry(key).or_default() += 1;
+        }
+
+        self.increment_all(&increments)?;
+        Ok(())
+    }
+
+    fn scan_prefix<'a>(&'a self, prefix: Vec<u8>) -> Box<dyn Iterator<Item = TupleOfBytes> + 'a> {
+        let until = prefix_end(&prefix);
+        self.iter_range(Some(prefix), until, false)
+    }
+
+    fn watch_prefix<'a>(&'a self, prefix: &[u8]) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+        self.create_notify_trigger();
+        self.watchers.watch(prefix)
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.engine
+            .write(|client| client.batch_execute(&format!("DELETE FROM {}", self.name)))
+    }
+}
+
+/// Returns the smallest key that is larger than all keys starting with `prefix`, if there is one.
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+
+    None
+}
+
+/// Iterates over a range of the table, fetching a page of rows at a time.
+struct PageIterator<'a> {
+    table: &'a PostgresTable,
+    /// Where the next page starts, and whether that key is included
+    from: Option<(Vec<u8>, bool)>,
+    /// Exclusive end of forward iteration
+    until: Option<Vec<u8>>,
+    backwards: bool,
+    page: VecDeque<TupleOfBytes>,
+    page_size: i64,
+    done: bool,
+}
+
+impl PageIterator<'_> {
+    fn fetch_page(&mut self) -> Result<Vec<TupleOfBytes>> {
+        let mut conditions = Vec::new();
+        let mut params: Vec<&(dyn ToSql + Sync)> = Vec::new();
+
+        let (less, greater) = match &self.from {
+            Some((_, true)) => ("<=", ">="),
+            _ => ("<", ">"),
+        };
+
+        if let Some((from, _)) = &self.from {
+            params.push(from);
+            conditions.push(format!(
+                "key {} ${}",
+                if self.backwards { less } else { greater },
+                params.len()
+            ));
+        }
+
+        if let Some(until) = &self.until {
+            params.push(until);
+            conditions.push(format!("key < ${}", params.len()));
+        }
+
+        params.push(&self.page_size);
+        let statement = format!(
+            "SELECT key, value FROM {} {} ORDER BY key {} LIMIT ${}",
+            self.table.name,
+            if conditions.is_empty() {
+                String::new()
+            } else {
+                format!("WHERE {}", conditions.join(" AND "))
+            },
+            if self.backwards { "DESC" } else { "ASC" },
+            params.len()
+        );
+
+        self.table.engine.read(|client| {
+            Ok(client
+                .query(&statement, &params)?
+                .into_iter()
+                .map(|row| (row.get(0), row.get(1)))
+                .collect())
+        })
+    }
+}
+
+impl Iterator for PageIterator<'_> {
+    type Item = TupleOfBytes;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.page.is_empty() && !self.done {
+            match self.fetch_page() {
+                Ok(rows) => {
+                    self.done = rows.len() < self.page_size as usize;
+                    self.from = rows.last().map(|(key, _)| (key.clone(), false));
+                    self.page_size = (self.page_size * 4).min(MAX_PAGE_SIZE);
+                    self.page.extend(rows);
+                }
+                Err(e) => {
+                    error!("Failed to iterate over {}: {e}", self.table.name);
+                    self.done = true;
+                }
+            }
+        }
+
+        self.page.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    //! These tests need a PostgreSQL database, e.g.
+    //! `CONDUIT_TEST_DATABASE_URL=postgresql://conduit@localhost/conduit_test cargo test --features postgres`.
+    //! They are skipped if `CONDUIT_TEST_DATABASE_URL` is not set.
+
+    use super::*;
+    use postgres::NoTls;
+
+    fn open_tree(name: &'static str) -> Option<Arc<dyn KvTree>> {
+        let url = std::env::var("CONDUIT_TEST_DATABASE_URL").ok()?;
+        let tree = Engine::connect(&url)
+            .and_then(|engine| engine.open_tree(name))
+            .expect("test database is available");
+        tree.clear().unwrap();
+
+        Some(tree)
+    }
+
+    #[test]
+    fn insert_get_remove() {
+        let Some(tree) = open_tree("test_insert_get_remove") else {
+            return;
+        };
+
+        tree.insert(b"key", b"value").unwrap();
+        assert_eq!(tree.get(b"key").unwrap(), Some(b"value".to_vec()));
+
+        tree.insert(b"key", b"other value").unwrap();
+        assert_eq!(tree.get(b"key").unwrap(), Some(b"other value".to_vec()));
+
+        tree.remove(b"key").unwrap();
+        assert_eq!(tree.get(b"key").unwrap(), None);
+    }
+
+    #[test]
+    fn iteration() {
+        let Some(tree) = open_tree("test_iteration") else {
+            return;
+        };
+
+        // More rows than fit on the first page
+        let keys = (0..100_u8)
+            .map(|i| vec![i / 10, 0xff, i % 10])
+            .collect::<Vec<_>>();
+        tree.insert_batch(&mut keys.iter().map(|key| (key.clone(), Vec::new())))
+            .unwrap();
+
+        let all = tree.iter().map(|(key, _)| key).collect::<Vec<_>>();
+        assert_eq!(all, keys);
+
+        let backwards = tree
+            .iter_from(&[5, 0xff, 5], true)
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            backwards,
+            keys[..=55].iter().rev().cloned().collect::<Vec<_>>()
+        );
+
+        let prefix = tree
+            .scan_prefix(vec![3, 0xff])
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>();
+        assert_eq!(prefix, keys[30..40]);
+    }
+
+    #[test]
+    fn increment() {
+        let Some(tree) = open_tree("test_increment") else {
+            return;
+        };
+
+        assert_eq!(tree.increment(b"counter").unwrap(), 1_u64.to_be_bytes());
+        assert_eq!(tree.increment(b"counter").unwrap(), 2_u64.to_be_bytes());
+
+        tree.increment_batch(
+            &mut [b"counter".to_vec(), b"counter".to_vec(), b"new".to_vec()].into_iter(),
+        )
+        .unwrap();
+        assert_eq!(
+            tree.get(b"counter").unwrap(),
+            Some(4_u64.to_be_bytes().to_vec())
+        );
+        assert_eq!(
+            tree.get(b"new").unwrap(),
+            Some(1_u64.to_be_bytes().to_vec())
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn watch_prefix_is_notified() {
+        let Some(tree) = open_tree("test_watch_prefix") else {
+            return;
+        };
+
+        let watch = tree.watch_prefix(b"prefix");
+
+        // Changes by other connections are only seen through notifications
+        let url = std::env::var("CONDUIT_TEST_DATABASE_URL").unwrap();
+        blocking(|| {
+            postgres::Client::connect(&url, NoTls)
+                .unwrap()
+                .execute(
+                    "INSERT INTO test_watch_prefix (key, value) VALUES ($1, $2)",
+                    &[&b"prefix and more".as_slice(), &b"".as_slice()],
+                )
+                .unwrap();
+        });
+
+        tokio::time::timeout(Duration::from_secs(5), watch)
+            .await
+            .expect("watcher was woken up");
+    }
+}
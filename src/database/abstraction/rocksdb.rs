@@ -2,6 +2,7 @@ use super::{super::Config, watchers::Watchers, KeyValueDatabaseEngine, KvTree};
 use crate::{utils, Result};
 use std::{
     future::Future,
+    path::Path,
     pin::Pin,
     sync::{Arc, RwLock},
 };
@@ -109,6 +110,13 @@ impl KeyValueDatabaseEngine for Arc<Engine> {
         Ok(())
     }
 
+    fn backup(&self, path: &Path) -> Result<()> {
+        // Checkpoints hard link the immutable files, so they are cheap as long as the backup is
+        // on the same filesystem
+        rocksdb::checkpoint::Checkpoint::new(&self.rocks)?.create_checkpoint(path)?;
+        Ok(())
+    }
+
     fn memory_usage(&self) -> Result<String> {
         let stats =
             rocksdb::perf::get_memory_usage_stats(Some(&[&self.rocks]), Some(&[&self.cache]))?;
@@ -126,6 +134,18 @@ impl KeyValueDatabaseEngine for Arc<Engine> {
             self.cache.get_pinned_usage() as f64 / 1024.0 / 1024.0,
         ))
     }
+
+    fn memory_usage_bytes(&self) -> Result<Vec<(&'static str, u64)>> {
+        let stats =
+            rocksdb::perf::get_memory_usage_stats(Some(&[&self.rocks]), Some(&[&self.cache]))?;
+        Ok(vec![
+            ("mem_tables", stats.mem_table_total),
+            ("unflushed_mem_tables", stats.mem_table_unflushed),
+            ("table_readers", stats.mem_table_readers_total),
+            ("cache", stats.cache_total),
+            ("cache_pinned", self.cache.get_pinned_usage() as u64),
+        ])
+    }
 }
 
 impl RocksDbEngineTree<'_> {
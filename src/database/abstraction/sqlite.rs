@@ -1,9 +1,10 @@
 use super::{watchers::Watchers, KeyValueDatabaseEngine, KvTree};
-use crate::{database::Config, Result};
+use crate::{database::Config, Error, Result};
 use parking_lot::{Mutex, MutexGuard};
 use rusqlite::{Connection, DatabaseName::Main, OptionalExtension};
 use std::{
     cell::RefCell,
+    fs,
     future::Future,
     path::{Path, PathBuf},
     pin::Pin,
@@ -123,6 +124,20 @@ impl KeyValueDatabaseEngine for Arc<Engine> {
     fn cleanup(&self) -> Result<()> {
         self.flush_wal()
     }
+
+    fn backup(&self, path: &Path) -> Result<()> {
+        fs::create_dir(path)?;
+
+        let target = path.join("conduit.db");
+        let target = target
+            .to_str()
+            .ok_or(Error::BadConfig("Backup path is not valid UTF-8."))?;
+
+        // Runs in a read transaction, so writers are not blocked
+        self.read_lock().execute("VACUUM INTO ?", [target])?;
+
+        Ok(())
+    }
 }
 
 pub struct SqliteTable {
@@ -52,4 +52,11 @@ impl Watchers {
             }
         };
     }
+
+    /// Wakes up every watcher, e.g. when changes might have been missed.
+    pub(super) fn wake_all(&self) {
+        for (_, (tx, _)) in self.watchers.write().unwrap().drain() {
+            let _ = tx.send(());
+        }
+    }
 }
@@ -1,4 +1,4 @@
-use ruma::api::appservice::Registration;
+use ruma::{api::appservice::Registration, OwnedUserId, UserId};
 
 use crate::{database::KeyValueDatabase, service, utils, Error, Result};
 
@@ -22,6 +22,13 @@ impl service::appservice::Data for KeyValueDatabase {
     fn unregister_appservice(&self, service_name: &str) -> Result<()> {
         self.id_appserviceregistrations
             .remove(service_name.as_bytes())?;
+
+        let mut prefix = service_name.as_bytes().to_vec();
+        prefix.push(0xff);
+        for (key, _) in self.appserviceid_userid.scan_prefix(prefix) {
+            self.appserviceid_userid.remove(&key)?;
+        }
+
         Ok(())
     }
 
@@ -58,4 +65,34 @@ impl service::appservice::Data for KeyValueDatabase {
             })
             .collect()
     }
+
+    fn add_user(&self, appservice_id: &str, user_id: &UserId) -> Result<()> {
+        let mut key = appservice_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(user_id.as_bytes());
+
+        self.appserviceid_userid.insert(&key, &[])
+    }
+
+    fn iter_users<'a>(
+        &'a self,
+        appservice_id: &str,
+    ) -> Box<dyn Iterator<Item = Result<OwnedUserId>> + 'a> {
+        let mut prefix = appservice_id.as_bytes().to_vec();
+        prefix.push(0xff);
+        let prefix_len = prefix.len();
+
+        Box::new(
+            self.appserviceid_userid
+                .scan_prefix(prefix)
+                .map(move |(key, _)| {
+                    utils::string_from_bytes(&key[prefix_len..])
+                        .map_err(|_| {
+                            Error::bad_database("Invalid user id bytes in appserviceid_userid.")
+                        })?
+                        .try_into()
+                        .map_err(|_| Error::bad_database("Invalid user id in appserviceid_userid."))
+                }),
+        )
+    }
 }
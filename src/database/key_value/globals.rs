@@ -1,10 +1,11 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, path::Path};
 
 use async_trait::async_trait;
 use futures_util::{stream::FuturesUnordered, StreamExt};
 use lru_cache::LruCache;
 use ruma::{
     api::federation::discovery::{OldVerifyKey, ServerSigningKeys},
+    serde::Raw,
     signatures::Ed25519KeyPair,
     DeviceId, ServerName, UserId,
 };
@@ -103,10 +104,14 @@ impl service::globals::Data for KeyValueDatabase {
             })));
 
             futures.push(self.readreceiptid_readreceipt.watch_prefix(&roomid_prefix));
+            futures.push(self.presenceid_presence.watch_prefix(&roomid_prefix));
 
             // Key changes
             futures.push(self.keychangeid_userid.watch_prefix(&roomid_prefix));
 
+            // Rooms that received their full state after a partial state join
+            futures.push(self.roomid_fullstatecount.watch_prefix(&roomid_bytes));
+
             // Room account data
             let mut roomuser_prefix = roomid_prefix.clone();
             roomuser_prefix.extend_from_slice(&userid_prefix);
@@ -144,26 +149,11 @@ impl service::globals::Data for KeyValueDatabase {
     }
 
     fn memory_usage(&self) -> String {
-        let pdu_cache = self.pdu_cache.lock().unwrap().len();
-        let shorteventid_cache = self.shorteventid_cache.lock().unwrap().len();
-        let auth_chain_cache = self.auth_chain_cache.lock().unwrap().len();
-        let eventidshort_cache = self.eventidshort_cache.lock().unwrap().len();
-        let statekeyshort_cache = self.statekeyshort_cache.lock().unwrap().len();
-        let our_real_users_cache = self.our_real_users_cache.read().unwrap().len();
-        let appservice_in_room_cache = self.appservice_in_room_cache.read().unwrap().len();
-        let lasttimelinecount_cache = self.lasttimelinecount_cache.lock().unwrap().len();
-
-        let mut response = format!(
-            "\
-pdu_cache: {pdu_cache}
-shorteventid_cache: {shorteventid_cache}
-auth_chain_cache: {auth_chain_cache}
-eventidshort_cache: {eventidshort_cache}
-statekeyshort_cache: {statekeyshort_cache}
-our_real_users_cache: {our_real_users_cache}
-appservice_in_room_cache: {appservice_in_room_cache}
-lasttimelinecount_cache: {lasttimelinecount_cache}\n"
-        );
+        let mut response = self
+            .cache_sizes()
+            .into_iter()
+            .map(|(cache, entries)| format!("{cache}: {entries}\n"))
+            .collect::<String>();
         if let Ok(db_stats) = self._db.memory_usage() {
             response += &db_stats;
         }
@@ -171,6 +161,48 @@ lasttimelinecount_cache: {lasttimelinecount_cache}\n"
         response
     }
 
+    fn cache_sizes(&self) -> Vec<(&'static str, usize)> {
+        vec![
+            ("pdu_cache", self.pdu_cache.lock().unwrap().len()),
+            (
+                "shorteventid_cache",
+                self.shorteventid_cache.lock().unwrap().len(),
+            ),
+            (
+                "auth_chain_cache",
+                self.auth_chain_cache.lock().unwrap().len(),
+            ),
+            (
+                "eventidshort_cache",
+                self.eventidshort_cache.lock().unwrap().len(),
+            ),
+            (
+                "statekeyshort_cache",
+                self.statekeyshort_cache.lock().unwrap().len(),
+            ),
+            (
+                "our_real_users_cache",
+                self.our_real_users_cache.read().unwrap().len(),
+            ),
+            (
+                "appservice_in_room_cache",
+                self.appservice_in_room_cache.read().unwrap().len(),
+            ),
+            (
+                "lasttimelinecount_cache",
+                self.lasttimelinecount_cache.lock().unwrap().len(),
+            ),
+        ]
+    }
+
+    fn database_memory_usage(&self) -> Vec<(&'static str, u64)> {
+        self._db.memory_usage_bytes().unwrap_or_default()
+    }
+
+    fn backup(&self, path: &Path) -> Result<()> {
+        self._db.backup(path)
+    }
+
     fn clear_caches(&self, amount: u32) {
         if amount > 0 {
             let c = &mut *self.pdu_cache.lock().unwrap();
@@ -340,6 +372,25 @@ lasttimelinecount_cache: {lasttimelinecount_cache}\n"
         Ok(signingkeys)
     }
 
+    fn set_origin_server_keys(
+        &self,
+        origin: &ServerName,
+        keys: &Raw<ServerSigningKeys>,
+    ) -> Result<()> {
+        self.servername_originserverkeys
+            .insert(origin.as_bytes(), keys.json().get().as_bytes())
+    }
+
+    fn origin_server_keys(&self, origin: &ServerName) -> Result<Option<Raw<ServerSigningKeys>>> {
+        self.servername_originserverkeys
+            .get(origin.as_bytes())?
+            .map(|bytes| {
+                serde_json::from_slice(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid origin server keys in db."))
+            })
+            .transpose()
+    }
+
     fn database_version(&self) -> Result<u64> {
         self.global.get(b"version")?.map_or(Ok(0), |version| {
             utils::u64_from_bytes(&version)
@@ -8,6 +8,10 @@ pub(super) mod media;
 mod pusher;
 mod rooms;
 mod sending;
+mod sso;
+mod threepid;
 mod transaction_ids;
 mod uiaa;
+mod url_preview;
+mod user_directory;
 mod users;
@@ -1,9 +1,9 @@
 use ruma::{
     api::client::push::{set_pusher, Pusher},
-    UserId,
+    EventId, OwnedEventId, OwnedRoomId, OwnedUserId, RoomId, UserId,
 };
 
-use crate::{database::KeyValueDatabase, service, utils, Error, Result};
+use crate::{database::KeyValueDatabase, service, services, utils, Error, Result};
 
 impl service::pusher::Data for KeyValueDatabase {
     fn set_pusher(&self, sender: &UserId, pusher: set_pusher::v3::PusherAction) -> Result<()> {
@@ -73,4 +73,85 @@ impl service::pusher::Data for KeyValueDatabase {
             Ok(push_key_string)
         }))
     }
+
+    fn queue_email_notification(
+        &self,
+        user: &UserId,
+        address: &str,
+        room_id: &RoomId,
+        event_id: &EventId,
+        highlight: bool,
+    ) -> Result<()> {
+        let mut key = email_prefix(user, address, room_id);
+        key.extend_from_slice(&services().globals.next_count()?.to_be_bytes());
+
+        let mut value = vec![u8::from(highlight)];
+        value.extend_from_slice(event_id.as_bytes());
+
+        self.emailid_notification.insert(&key, &value)
+    }
+
+    fn take_email_notifications(
+        &self,
+        user: &UserId,
+        address: &str,
+        room_id: &RoomId,
+    ) -> Result<Vec<(OwnedEventId, bool)>> {
+        let mut notifications = Vec::new();
+
+        for (key, value) in self
+            .emailid_notification
+            .scan_prefix(email_prefix(user, address, room_id))
+        {
+            self.emailid_notification.remove(&key)?;
+
+            let (&highlight, event_id) = value.split_first().ok_or_else(|| {
+                Error::bad_database("Invalid notification in emailid_notification.")
+            })?;
+            let event_id = EventId::parse(utils::string_from_bytes(event_id).map_err(|_| {
+                Error::bad_database("Event ID in emailid_notification is invalid unicode.")
+            })?)
+            .map_err(|_| Error::bad_database("Event ID in emailid_notification is invalid."))?;
+
+            notifications.push((event_id, highlight != 0));
+        }
+
+        Ok(notifications)
+    }
+
+    fn queued_emails<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<(OwnedUserId, String, OwnedRoomId)>> + 'a> {
+        Box::new(self.emailid_notification.iter().map(|(key, _)| {
+            let mut parts = key.split(|&b| b == 0xff);
+            let mut next = || {
+                parts
+                    .next()
+                    .ok_or_else(|| Error::bad_database("Invalid emailid_notification in db."))
+                    .and_then(|bytes| {
+                        utils::string_from_bytes(bytes).map_err(|_| {
+                            Error::bad_database("Invalid unicode in emailid_notification.")
+                        })
+                    })
+            };
+
+            let user = UserId::parse(next()?)
+                .map_err(|_| Error::bad_database("User ID in emailid_notification is invalid."))?;
+            let address = next()?;
+            let room_id = RoomId::parse(next()?)
+                .map_err(|_| Error::bad_database("Room ID in emailid_notification is invalid."))?;
+
+            Ok((user, address, room_id))
+        }))
+    }
+}
+
+fn email_prefix(user: &UserId, address: &str, room_id: &RoomId) -> Vec<u8> {
+    let mut prefix = user.as_bytes().to_vec();
+    prefix.push(0xff);
+    prefix.extend_from_slice(address.as_bytes());
+    prefix.push(0xff);
+    prefix.extend_from_slice(room_id.as_bytes());
+    prefix.push(0xff);
+    prefix
 }
@@ -1,46 +1,63 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, mem::size_of};
 
-use ruma::{
-    events::presence::PresenceEvent, presence::PresenceState, OwnedUserId, RoomId, UInt, UserId,
-};
+use ruma::{events::presence::PresenceEvent, OwnedRoomId, OwnedUserId, RoomId, UserId};
 
-use crate::{database::KeyValueDatabase, service, services, utils, Error, Result};
+use crate::{database::KeyValueDatabase, service, utils, Error, Result};
 
 impl service::rooms::edus::presence::Data for KeyValueDatabase {
     fn update_presence(
         &self,
         user_id: &UserId,
-        room_id: &RoomId,
+        room_ids: &[OwnedRoomId],
+        count: u64,
         presence: PresenceEvent,
     ) -> Result<()> {
-        // TODO: Remove old entry? Or maybe just wipe completely from time to time?
+        let old_count = self
+            .userid_presence
+            .get(user_id.as_bytes())?
+            .map(|bytes| parse_presence(&bytes).map(|(count, _)| count))
+            .transpose()?;
+
+        // Store the event first, so that watchers woken up by the room entries below already
+        // see the new presence
+        let mut value = count.to_be_bytes().to_vec();
+        value.extend_from_slice(
+            &serde_json::to_vec(&presence).expect("PresenceEvent can be serialized"),
+        );
+        self.userid_presence.insert(user_id.as_bytes(), &value)?;
 
-        let count = services().globals.next_count()?.to_be_bytes();
+        for room_id in room_ids {
+            let mut prefix = room_id.as_bytes().to_vec();
+            prefix.push(0xff);
 
-        let mut presence_id = room_id.as_bytes().to_vec();
-        presence_id.push(0xff);
-        presence_id.extend_from_slice(&count);
-        presence_id.push(0xff);
-        presence_id.extend_from_slice(presence.sender.as_bytes());
+            // Remove old entry
+            if let Some(old_count) = old_count {
+                let mut old_presence_id = prefix.clone();
+                old_presence_id.extend_from_slice(&old_count.to_be_bytes());
+                old_presence_id.push(0xff);
+                old_presence_id.extend_from_slice(user_id.as_bytes());
 
-        self.presenceid_presence.insert(
-            &presence_id,
-            &serde_json::to_vec(&presence).expect("PresenceEvent can be serialized"),
-        )?;
+                self.presenceid_presence.remove(&old_presence_id)?;
+            }
+
+            let mut presence_id = prefix;
+            presence_id.extend_from_slice(&count.to_be_bytes());
+            presence_id.push(0xff);
+            presence_id.extend_from_slice(user_id.as_bytes());
 
-        self.userid_lastpresenceupdate.insert(
-            user_id.as_bytes(),
-            &utils::millis_since_unix_epoch().to_be_bytes(),
-        )?;
+            self.presenceid_presence.insert(&presence_id, &[])?;
+        }
 
         Ok(())
     }
 
     fn ping_presence(&self, user_id: &UserId) -> Result<()> {
-        self.userid_lastpresenceupdate.insert(
-            user_id.as_bytes(),
-            &utils::millis_since_unix_epoch().to_be_bytes(),
-        )?;
+        self.set_last_presence_update(user_id, utils::millis_since_unix_epoch())
+    }
+
+    fn set_last_presence_update(&self, user_id: &UserId, timestamp: u64) -> Result<()> {
+        self.userid_lastpresenceupdate
+            .insert(user_id.as_bytes(), &timestamp.to_be_bytes())?;
 
         Ok(())
     }
@@ -56,21 +73,36 @@ impl service::rooms::edus::presence::Data for KeyValueDatabase {
             .transpose()
     }
 
-    fn get_presence_event(
-        &self,
-        room_id: &RoomId,
-        user_id: &UserId,
-        count: u64,
-    ) -> Result<Option<PresenceEvent>> {
-        let mut presence_id = room_id.as_bytes().to_vec();
-        presence_id.push(0xff);
-        presence_id.extend_from_slice(&count.to_be_bytes());
-        presence_id.push(0xff);
-        presence_id.extend_from_slice(user_id.as_bytes());
-
-        self.presenceid_presence
-            .get(&presence_id)?
-            .map(|value| parse_presence_event(&value))
+    fn last_presence_updates<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<(OwnedUserId, u64)>> + 'a> {
+        Box::new(
+            self.userid_lastpresenceupdate
+                .iter()
+                .map(|(user_id_bytes, timestamp)| {
+                    let user_id =
+                        UserId::parse(utils::string_from_bytes(&user_id_bytes).map_err(|_| {
+                            Error::bad_database(
+                                "Invalid UserId bytes in userid_lastpresenceupdate.",
+                            )
+                        })?)
+                        .map_err(|_| {
+                            Error::bad_database("Invalid UserId in userid_lastpresenceupdate.")
+                        })?;
+
+                    let timestamp = utils::u64_from_bytes(&timestamp).map_err(|_| {
+                        Error::bad_database("Invalid timestamp in userid_lastpresenceupdate.")
+                    })?;
+
+                    Ok((user_id, timestamp))
+                }),
+        )
+    }
+
+    fn get_presence_event(&self, user_id: &UserId) -> Result<Option<PresenceEvent>> {
+        self.userid_presence
+            .get(user_id.as_bytes())?
+            .map(|bytes| parse_presence(&bytes).map(|(_, presence)| presence))
             .transpose()
     }
 
@@ -78,7 +110,7 @@ impl service::rooms::edus::presence::Data for KeyValueDatabase {
         &self,
         room_id: &RoomId,
         since: u64,
-    ) -> Result<HashMap<OwnedUserId, PresenceEvent>> {
+    ) -> Result<HashMap<OwnedUserId, (u64, PresenceEvent)>> {
         let mut prefix = room_id.as_bytes().to_vec();
         prefix.push(0xff);
 
@@ -86,11 +118,17 @@ impl service::rooms::edus::presence::Data for KeyValueDatabase {
         first_possible_edu.extend_from_slice(&(since + 1).to_be_bytes()); // +1 so we don't send the event at since
         let mut hashmap = HashMap::new();
 
-        for (key, value) in self
+        for (key, _) in self
             .presenceid_presence
             .iter_from(&first_possible_edu, false)
             .take_while(|(key, _)| key.starts_with(&prefix))
         {
+            let count = utils::u64_from_bytes(
+                key.get(prefix.len()..prefix.len() + size_of::<u64>())
+                    .ok_or_else(|| Error::bad_database("Invalid presenceid in db."))?,
+            )
+            .map_err(|_| Error::bad_database("Invalid presenceid count in db."))?;
+
             let user_id = UserId::parse(
                 utils::string_from_bytes(
                     key.rsplit(|&b| b == 0xff)
@@ -101,52 +139,116 @@ impl service::rooms::edus::presence::Data for KeyValueDatabase {
             )
             .map_err(|_| Error::bad_database("Invalid UserId in presenceid_presence."))?;
 
-            let presence = parse_presence_event(&value)?;
+            let Some((current_count, presence)) = self
+                .userid_presence
+                .get(user_id.as_bytes())?
+                .map(|bytes| parse_presence(&bytes))
+                .transpose()?
+            else {
+                continue;
+            };
+
+            // Entries of rooms the user left before their presence changed are never removed,
+            // so we need to make sure this is still the latest update.
+            if current_count != count {
+                continue;
+            }
 
-            hashmap.insert(user_id, presence);
+            hashmap.insert(user_id, (count, presence));
         }
 
         Ok(hashmap)
     }
-
-    /*
-    fn presence_maintain(&self, db: Arc<TokioRwLock<Database>>) {
-        // TODO @M0dEx: move this to a timed tasks module
-        tokio::spawn(async move {
-            loop {
-                select! {
-                    Some(user_id) = self.presence_timers.next() {
-                        // TODO @M0dEx: would it be better to acquire the lock outside the loop?
-                        let guard = db.read().await;
-
-                        // TODO @M0dEx: add self.presence_timers
-                        // TODO @M0dEx: maintain presence
-                    }
-                }
-            }
-        });
-    }
-    */
 }
 
-fn parse_presence_event(bytes: &[u8]) -> Result<PresenceEvent> {
-    let mut presence: PresenceEvent = serde_json::from_slice(bytes)
+/// Parses the value of `userid_presence`, which is the count of the update followed by the event.
+fn parse_presence(bytes: &[u8]) -> Result<(u64, PresenceEvent)> {
+    let count = utils::u64_from_bytes(
+        bytes
+            .get(..size_of::<u64>())
+            .ok_or_else(|| Error::bad_database("Invalid presence in userid_presence."))?,
+    )
+    .map_err(|_| Error::bad_database("Invalid presence count in userid_presence."))?;
+
+    let presence = serde_json::from_slice(&bytes[size_of::<u64>()..])
         .map_err(|_| Error::bad_database("Invalid presence event in db."))?;
 
-    let current_timestamp: UInt = utils::millis_since_unix_epoch()
-        .try_into()
-        .expect("time is valid");
-
-    if presence.content.presence == PresenceState::Online {
-        // Don't set last_active_ago when the user is online
-        presence.content.last_active_ago = None;
-    } else {
-        // Convert from timestamp to duration
-        presence.content.last_active_ago = presence
-            .content
-            .last_active_ago
-            .map(|timestamp| current_timestamp - timestamp);
+    Ok((count, presence))
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use std::fs;
+
+    use ruma::{
+        events::presence::{PresenceEvent, PresenceEventContent},
+        owned_room_id,
+        presence::PresenceState,
+        room_id, user_id, UserId,
+    };
+
+    use crate::{
+        database::tests::open_db, service::rooms::edus::presence::Data as _, utils, Result,
+    };
+
+    #[test]
+    fn ping_resets_last_presence_update() {
+        let (db, path) = open_db();
+        let user_id = user_id!("@alice:example.com");
+        assert_eq!(db.last_presence_update(user_id).unwrap(), None);
+
+        db.set_last_presence_update(user_id, 1_000).unwrap();
+        let before = utils::millis_since_unix_epoch();
+        db.ping_presence(user_id).unwrap();
+
+        let last_active = db.last_presence_update(user_id).unwrap().unwrap();
+        assert!(last_active >= before);
+        assert_eq!(
+            db.last_presence_updates()
+                .collect::<Result<Vec<_>>>()
+                .unwrap(),
+            [(user_id.to_owned(), last_active)]
+        );
+
+        drop(db);
+        fs::remove_dir_all(path).unwrap();
     }
 
-    Ok(presence)
+    fn presence(user_id: &UserId) -> PresenceEvent {
+        PresenceEvent {
+            content: PresenceEventContent::new(PresenceState::Online),
+            sender: user_id.to_owned(),
+        }
+    }
+
+    #[test]
+    fn presence_is_only_announced_in_joined_rooms() {
+        let (db, path) = open_db();
+        let alice = user_id!("@alice:example.com");
+        let bob = user_id!("@bob:example.com");
+        let shared = owned_room_id!("!shared:example.com");
+        let other = owned_room_id!("!other:example.com");
+
+        db.update_presence(alice, &[shared.clone()], 1, presence(alice))
+            .unwrap();
+        db.update_presence(bob, &[other.clone()], 2, presence(bob))
+            .unwrap();
+
+        let updates = db.presence_since(&shared, 0).unwrap();
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[alice].0, 1);
+
+        // Newer updates replace the entries of the user in all their rooms
+        db.update_presence(alice, &[shared.clone(), other.clone()], 3, presence(alice))
+            .unwrap();
+        assert_eq!(db.presence_since(&shared, 1).unwrap()[alice].0, 3);
+        assert_eq!(db.presence_since(&other, 0).unwrap().len(), 2);
+        assert!(db
+            .presence_since(room_id!("!unknown:example.com"), 0)
+            .unwrap()
+            .is_empty());
+
+        drop(db);
+        fs::remove_dir_all(path).unwrap();
+    }
 }
@@ -1,5 +1,7 @@
 use ruma::{
-    events::receipt::ReceiptEvent, serde::Raw, CanonicalJsonObject, OwnedUserId, RoomId, UserId,
+    events::receipt::{ReceiptEvent, ReceiptThread},
+    serde::Raw,
+    CanonicalJsonObject, OwnedUserId, RoomId, UserId,
 };
 
 use crate::{database::KeyValueDatabase, service, services, utils, Error, Result};
@@ -14,22 +16,13 @@ impl service::rooms::edus::read_receipt::Data for KeyValueDatabase {
         let mut prefix = room_id.as_bytes().to_vec();
         prefix.push(0xff);
 
-        let mut last_possible_key = prefix.clone();
-        last_possible_key.extend_from_slice(&u64::MAX.to_be_bytes());
-
-        // Remove old entry
-        if let Some((old, _)) = self
-            .readreceiptid_readreceipt
-            .iter_from(&last_possible_key, true)
-            .take_while(|(key, _)| key.starts_with(&prefix))
-            .find(|(key, _)| {
-                key.rsplit(|&b| b == 0xff)
-                    .next()
-                    .expect("rsplit always returns an element")
-                    == user_id.as_bytes()
-            })
+        // Remove old entry, users have one receipt per thread
+        let roomuserthread_id =
+            get_roomuserthread_id_bytes(room_id, user_id, &receipt_thread(&event, user_id));
+        if let Some(old) = self
+            .roomuserthreadid_readreceiptid
+            .get(&roomuserthread_id)?
         {
-            // This is the old room_latest
             self.readreceiptid_readreceipt.remove(&old)?;
         }
 
@@ -42,6 +35,8 @@ impl service::rooms::edus::read_receipt::Data for KeyValueDatabase {
             &room_latest_id,
             &serde_json::to_vec(&event).expect("EduEvent::to_string always works"),
         )?;
+        self.roomuserthreadid_readreceiptid
+            .insert(&roomuserthread_id, &room_latest_id)?;
 
         Ok(())
     }
@@ -147,3 +142,65 @@ impl service::rooms::edus::read_receipt::Data for KeyValueDatabase {
             .unwrap_or(0))
     }
 }
+
+impl KeyValueDatabase {
+    /// Indexes the stored read receipts by user and thread. Receipts that were replaced by a
+    /// newer one in the same thread are removed.
+    pub(crate) fn index_read_receipts(&self) -> Result<()> {
+        for (readreceipt_id, value) in self.readreceiptid_readreceipt.iter() {
+            let mut parts = readreceipt_id.splitn(2, |&b| b == 0xff);
+            let room_id = parts
+                .next()
+                .and_then(|bytes| utils::string_from_bytes(bytes).ok())
+                .and_then(|room_id| RoomId::parse(room_id).ok())
+                .ok_or_else(|| Error::bad_database("Invalid room id in readreceiptid."))?;
+            let user_id = parts
+                .next()
+                .and_then(|rest| rest.get(size_of::<u64>() + 1..))
+                .and_then(|bytes| utils::string_from_bytes(bytes).ok())
+                .and_then(|user_id| UserId::parse(user_id).ok())
+                .ok_or_else(|| Error::bad_database("Invalid user id in readreceiptid."))?;
+            let event = serde_json::from_slice::<ReceiptEvent>(&value)
+                .map_err(|_| Error::bad_database("Invalid read receipt in db."))?;
+
+            // Receipts are iterated oldest first in each room
+            let roomuserthread_id =
+                get_roomuserthread_id_bytes(&room_id, &user_id, &receipt_thread(&event, &user_id));
+            if let Some(old) = self
+                .roomuserthreadid_readreceiptid
+                .get(&roomuserthread_id)?
+            {
+                self.readreceiptid_readreceipt.remove(&old)?;
+            }
+            self.roomuserthreadid_readreceiptid
+                .insert(&roomuserthread_id, &readreceipt_id)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn get_roomuserthread_id_bytes(
+    room_id: &RoomId,
+    user_id: &UserId,
+    thread: &ReceiptThread,
+) -> Vec<u8> {
+    let mut key = room_id.as_bytes().to_vec();
+    key.push(0xff);
+    key.extend_from_slice(user_id.as_bytes());
+    key.push(0xff);
+    // Unthreaded receipts have an empty thread
+    key.extend_from_slice(thread.as_str().unwrap_or_default().as_bytes());
+    key
+}
+
+/// Returns the thread the receipt of the user is for.
+fn receipt_thread(event: &ReceiptEvent, user_id: &UserId) -> ReceiptThread {
+    event
+        .content
+        .0
+        .values()
+        .flat_map(|receipts| receipts.values())
+        .find_map(|receipts| receipts.get(user_id))
+        .map_or(ReceiptThread::Unthreaded, |receipt| receipt.thread.clone())
+}
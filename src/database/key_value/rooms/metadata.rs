@@ -1,6 +1,12 @@
-use ruma::{OwnedRoomId, RoomId};
+use std::{collections::HashSet, mem::size_of};
 
-use crate::{database::KeyValueDatabase, service, services, utils, Error, Result};
+use ruma::{OwnedRoomAliasId, OwnedRoomId, RoomId};
+
+use crate::{
+    database::KeyValueDatabase,
+    service::{self, globals::Data as _, rooms::state_compressor::Data as _},
+    services, utils, Error, PduEvent, Result,
+};
 
 impl service::rooms::metadata::Data for KeyValueDatabase {
     fn exists(&self, room_id: &RoomId) -> Result<bool> {
@@ -42,4 +48,372 @@ impl service::rooms::metadata::Data for KeyValueDatabase {
 
         Ok(())
     }
+
+    fn is_banned(&self, room_id: &RoomId) -> Result<bool> {
+        Ok(self.bannedroomids.get(room_id.as_bytes())?.is_some())
+    }
+
+    fn ban_room(&self, room_id: &RoomId, banned: bool) -> Result<()> {
+        if banned {
+            self.bannedroomids.insert(room_id.as_bytes(), &[])?;
+        } else {
+            self.bannedroomids.remove(room_id.as_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    fn purge_room(&self, room_id: &RoomId) -> Result<()> {
+        let mut room_prefix = room_id.as_bytes().to_vec();
+        room_prefix.push(0xff);
+
+        // Memberships
+        let mut user_ids = HashSet::new();
+        for tree in [
+            &self.roomuserid_joined,
+            &self.roomuserid_invitecount,
+            &self.roomuserid_knockcount,
+            &self.roomuserid_leftcount,
+        ] {
+            for (key, _) in tree.scan_prefix(room_prefix.clone()) {
+                user_ids.insert(key[room_prefix.len()..].to_vec());
+                tree.remove(&key)?;
+            }
+        }
+
+        for user_id in user_ids {
+            let mut userroom_id = user_id.clone();
+            userroom_id.push(0xff);
+            userroom_id.extend_from_slice(room_id.as_bytes());
+
+            for tree in [
+                &self.userroomid_joined,
+                &self.userroomid_invitestate,
+                &self.userroomid_knockstate,
+                &self.userroomid_leftstate,
+                &self.roomuseroncejoinedids,
+                &self.userroomid_notificationcount,
+                &self.userroomid_highlightcount,
+            ] {
+                tree.remove(&userroom_id)?;
+            }
+
+            userroom_id.push(0xff);
+            for tree in [
+                &self.userroomthreadid_notificationcount,
+                &self.userroomthreadid_highlightcount,
+            ] {
+                for (key, _) in tree.scan_prefix(userroom_id.clone()) {
+                    tree.remove(&key)?;
+                }
+            }
+
+            let mut prefix = user_id;
+            prefix.push(0xff);
+            for (key, _) in self.lazyloadedids.scan_prefix(prefix) {
+                // LazyLoadedIds = UserId + DeviceId + RoomId + LazyLoadedUserId
+                if key.split(|&b| b == 0xff).nth(2) == Some(room_id.as_bytes()) {
+                    self.lazyloadedids.remove(&key)?;
+                }
+            }
+        }
+
+        for (key, _) in self.roomserverids.scan_prefix(room_prefix.clone()) {
+            let mut serverroom_id = key[room_prefix.len()..].to_vec();
+            serverroom_id.push(0xff);
+            serverroom_id.extend_from_slice(room_id.as_bytes());
+
+            self.serverroomids.remove(&serverroom_id)?;
+            self.roomserverids.remove(&key)?;
+        }
+
+        for (key, alias) in self.aliasid_alias.scan_prefix(room_prefix.clone()) {
+            let alias = utils::string_from_bytes(&alias)
+                .ok()
+                .and_then(|alias| OwnedRoomAliasId::try_from(alias).ok())
+                .ok_or_else(|| Error::bad_database("Invalid alias in aliasid_alias."))?;
+
+            if self.alias_roomid.get(alias.alias().as_bytes())?.as_deref()
+                == Some(room_id.as_bytes())
+            {
+                self.alias_roomid.remove(alias.alias().as_bytes())?;
+                self.alias_userid.remove(alias.alias().as_bytes())?;
+            }
+            self.aliasid_alias.remove(&key)?;
+        }
+
+        for tree in [
+            &self.readreceiptid_readreceipt,
+            &self.roomuserthreadid_readreceiptid,
+            &self.roomuserid_privateread,
+            &self.roomuserid_lastprivatereadupdate,
+            &self.roomuserid_lastnotificationread,
+            &self.presenceid_presence,
+            &self.roomid_pduleaves,
+            &self.roomuserdataid_accountdata,
+            &self.roomusertype_roomuserdataid,
+        ] {
+            for (key, _) in tree.scan_prefix(room_prefix.clone()) {
+                tree.remove(&key)?;
+            }
+        }
+
+        // Event ids always start with a $
+        let mut prefix = room_id.as_bytes().to_vec();
+        prefix.push(b'$');
+        for (key, _) in self.referencedevents.scan_prefix(prefix) {
+            self.referencedevents.remove(&key)?;
+        }
+
+        // The user gets a new server notices room with the next notice
+        for (user_id, notices_room_id) in self.userid_servernoticesroomid.iter() {
+            if notices_room_id == room_id.as_bytes() {
+                self.userid_servernoticesroomid.remove(&user_id)?;
+            }
+        }
+
+        for tree in [
+            &self.roomid_joinedcount,
+            &self.roomid_invitedcount,
+            &self.publicroomids,
+            &self.roomid_partialstate,
+        ] {
+            tree.remove(room_id.as_bytes())?;
+        }
+
+        let mut shortstatehashes = HashSet::new();
+        if let Some(shortstatehash) = self.roomid_shortstatehash.get(room_id.as_bytes())? {
+            shortstatehashes.insert(shortstatehash);
+            self.roomid_shortstatehash.remove(room_id.as_bytes())?;
+        }
+
+        let mut shorteventids = HashSet::new();
+        if let Some(shortroomid) = self.roomid_shortroomid.get(room_id.as_bytes())? {
+            for (pdu_id, pdu) in self.pduid_pdu.scan_prefix(shortroomid.clone()) {
+                let pdu = serde_json::from_slice::<PduEvent>(&pdu)
+                    .map_err(|_| Error::bad_database("Invalid PDU in db."))?;
+
+                if let Some(shorteventid) =
+                    self.eventid_shorteventid.get(pdu.event_id.as_bytes())?
+                {
+                    shorteventids.insert(shorteventid);
+                }
+                self.eventid_pduid.remove(pdu.event_id.as_bytes())?;
+
+                // Relations are only stored between events with a normal count
+                if pdu_id.len() == 2 * size_of::<u64>() {
+                    for (key, _) in self
+                        .tofrom_relation
+                        .scan_prefix(pdu_id[size_of::<u64>()..].to_vec())
+                    {
+                        self.tofrom_relation.remove(&key)?;
+                    }
+                }
+
+                self.pduid_pdu.remove(&pdu_id)?;
+            }
+
+            for tree in [&self.tokenids, &self.threadid_userids] {
+                for (key, _) in tree.scan_prefix(shortroomid.clone()) {
+                    tree.remove(&key)?;
+                }
+            }
+
+            for (key, shortstatehash) in self
+                .roomsynctoken_shortstatehash
+                .scan_prefix(shortroomid.clone())
+            {
+                shortstatehashes.insert(shortstatehash);
+                self.roomsynctoken_shortstatehash.remove(&key)?;
+            }
+
+            self.roomid_shortroomid.remove(room_id.as_bytes())?;
+        }
+
+        for shorteventid in &shorteventids {
+            if let Some(shortstatehash) = self.shorteventid_shortstatehash.get(shorteventid)? {
+                shortstatehashes.insert(shortstatehash);
+            }
+        }
+
+        // Every event that was ever part of the room state is in one of the state diffs
+        let mut todo = shortstatehashes
+            .iter()
+            .map(|bytes| {
+                utils::u64_from_bytes(bytes)
+                    .map_err(|_| Error::bad_database("Invalid shortstatehash in db."))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut visited = HashSet::new();
+        while let Some(shortstatehash) = todo.pop() {
+            if !visited.insert(shortstatehash)
+                || self
+                    .shortstatehash_statediff
+                    .get(&shortstatehash.to_be_bytes())?
+                    .is_none()
+            {
+                continue;
+            }
+
+            let diff = self.get_statediff(shortstatehash)?;
+            for compressed in diff.added.iter().chain(diff.removed.iter()) {
+                shorteventids.insert(compressed[size_of::<u64>()..].to_vec());
+            }
+            todo.extend(diff.parent);
+
+            self.shortstatehash_statediff
+                .remove(&shortstatehash.to_be_bytes())?;
+        }
+
+        for (statehash, shortstatehash) in self.statehash_shortstatehash.iter() {
+            if utils::u64_from_bytes(&shortstatehash).is_ok_and(|s| visited.contains(&s)) {
+                self.statehash_shortstatehash.remove(&statehash)?;
+            }
+        }
+
+        // Auth events which are neither in the timeline nor in the state
+        for shorteventid in shorteventids.clone() {
+            if let Some(auth_chain) = self.shorteventid_authchain.get(&shorteventid)? {
+                shorteventids.extend(
+                    auth_chain
+                        .chunks_exact(size_of::<u64>())
+                        .map(<[u8]>::to_vec),
+                );
+            }
+        }
+
+        for shorteventid in &shorteventids {
+            if let Some(event_id) = self.shorteventid_eventid.get(shorteventid)? {
+                self.eventid_shorteventid.remove(&event_id)?;
+                self.eventid_pduid.remove(&event_id)?;
+                self.eventid_outlierpdu.remove(&event_id)?;
+                self.softfailedeventids.remove(&event_id)?;
+            }
+            self.shorteventid_eventid.remove(shorteventid)?;
+            self.shorteventid_shortstatehash.remove(shorteventid)?;
+            self.shorteventid_authchain.remove(shorteventid)?;
+        }
+
+        // The caches can still contain events and short ids of the room
+        self.clear_caches(u32::MAX);
+
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use std::fs;
+
+    use ruma::{room_id, RoomId};
+    use serde_json::json;
+
+    use crate::{
+        database::{abstraction::KvTree, tests::open_db, KeyValueDatabase},
+        service::{rooms::metadata::Data as _, sending::Data as _},
+    };
+
+    const PURGED: u64 = 1;
+    const KEPT: u64 = 2;
+
+    fn add_room(db: &KeyValueDatabase, room_id: &RoomId, shortroomid: u64) {
+        let user_id = "@alice:example.com";
+        let event_id = format!("${shortroomid}");
+
+        let mut room_prefix = room_id.as_bytes().to_vec();
+        room_prefix.push(0xff);
+        let mut roomuser_id = room_prefix.clone();
+        roomuser_id.extend_from_slice(user_id.as_bytes());
+        let mut userroom_id = user_id.as_bytes().to_vec();
+        userroom_id.push(0xff);
+        userroom_id.extend_from_slice(room_id.as_bytes());
+
+        let mut pdu_id = shortroomid.to_be_bytes().to_vec();
+        pdu_id.extend_from_slice(&1_u64.to_be_bytes());
+        let pdu = json!({
+            "event_id": event_id,
+            "room_id": room_id,
+            "sender": user_id,
+            "origin_server_ts": 0,
+            "type": "m.room.message",
+            "content": { "body": "hello" },
+            "prev_events": [],
+            "depth": 1,
+            "auth_events": [],
+            "hashes": { "sha256": "" },
+        });
+
+        let mut queued = b"remote.example.com".to_vec();
+        queued.push(0xff);
+        queued.extend_from_slice(&pdu_id);
+
+        db.roomid_shortroomid
+            .insert(room_id.as_bytes(), &shortroomid.to_be_bytes())
+            .unwrap();
+        db.pduid_pdu
+            .insert(&pdu_id, &serde_json::to_vec(&pdu).unwrap())
+            .unwrap();
+        db.eventid_pduid
+            .insert(event_id.as_bytes(), &pdu_id)
+            .unwrap();
+        db.roomuserid_joined.insert(&roomuser_id, &[]).unwrap();
+        db.userroomid_joined.insert(&userroom_id, &[]).unwrap();
+        db.roomid_joinedcount
+            .insert(room_id.as_bytes(), &1_u64.to_be_bytes())
+            .unwrap();
+        db.servernameevent_data.insert(&queued, &[]).unwrap();
+        db.userid_servernoticesroomid
+            .insert(
+                format!("@{shortroomid}:example.com").as_bytes(),
+                room_id.as_bytes(),
+            )
+            .unwrap();
+    }
+
+    /// Returns the trees of the test rooms, and how many entries each has
+    fn tree_sizes(db: &KeyValueDatabase) -> Vec<(&'static str, usize)> {
+        let trees: [(&'static str, &dyn KvTree); 8] = [
+            ("roomid_shortroomid", &*db.roomid_shortroomid),
+            ("pduid_pdu", &*db.pduid_pdu),
+            ("eventid_pduid", &*db.eventid_pduid),
+            ("roomuserid_joined", &*db.roomuserid_joined),
+            ("userroomid_joined", &*db.userroomid_joined),
+            ("roomid_joinedcount", &*db.roomid_joinedcount),
+            ("servernameevent_data", &*db.servernameevent_data),
+            (
+                "userid_servernoticesroomid",
+                &*db.userid_servernoticesroomid,
+            ),
+        ];
+
+        trees
+            .into_iter()
+            .map(|(name, tree)| (name, tree.iter().count()))
+            .collect()
+    }
+
+    #[test]
+    fn purge_removes_all_entries_of_the_room() {
+        let (db, path) = open_db();
+        let purged = room_id!("!purged:example.com");
+        add_room(&db, purged, PURGED);
+        add_room(&db, room_id!("!kept:example.com"), KEPT);
+
+        db.delete_queued_pdus(PURGED).unwrap();
+        db.purge_room(purged).unwrap();
+
+        // Only the entries of the other room are left
+        for (name, size) in tree_sizes(&db) {
+            assert_eq!(size, 1, "{name}");
+        }
+        assert_eq!(db.roomid_shortroomid.get(purged.as_bytes()).unwrap(), None);
+        assert!(db
+            .pduid_pdu
+            .scan_prefix(PURGED.to_be_bytes().to_vec())
+            .next()
+            .is_none());
+
+        drop(db);
+        fs::remove_dir_all(path).unwrap();
+    }
 }
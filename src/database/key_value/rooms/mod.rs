@@ -5,6 +5,7 @@ mod edus;
 mod lazy_load;
 mod metadata;
 mod outlier;
+mod partial_state;
 mod pdu_metadata;
 mod search;
 mod short;
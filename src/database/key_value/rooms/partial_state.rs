@@ -0,0 +1,77 @@
+use ruma::{EventId, OwnedEventId, OwnedRoomId, OwnedServerName, RoomId, ServerName};
+
+use crate::{database::KeyValueDatabase, service, utils, Error, Result};
+
+impl service::rooms::partial_state::Data for KeyValueDatabase {
+    fn mark_partial_state(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+        servers: &[&ServerName],
+    ) -> Result<()> {
+        let mut value = event_id.as_bytes().to_vec();
+        for server in servers {
+            value.push(0xff);
+            value.extend_from_slice(server.as_bytes());
+        }
+
+        self.roomid_partialstate.insert(room_id.as_bytes(), &value)
+    }
+
+    fn unmark_partial_state(&self, room_id: &RoomId, count: u64) -> Result<()> {
+        self.roomid_fullstatecount
+            .insert(room_id.as_bytes(), &count.to_be_bytes())?;
+        self.roomid_partialstate.remove(room_id.as_bytes())
+    }
+
+    fn full_state_count(&self, room_id: &RoomId) -> Result<Option<u64>> {
+        self.roomid_fullstatecount
+            .get(room_id.as_bytes())?
+            .map(|bytes| {
+                utils::u64_from_bytes(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid count in roomid_fullstatecount."))
+            })
+            .transpose()
+    }
+
+    fn partial_state(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Option<(OwnedEventId, Vec<OwnedServerName>)>> {
+        let Some(value) = self.roomid_partialstate.get(room_id.as_bytes())? else {
+            return Ok(None);
+        };
+
+        let mut parts = value.split(|&b| b == 0xff);
+
+        let event_id =
+            utils::string_from_bytes(parts.next().expect("split always returns one element"))
+                .map_err(|_| {
+                    Error::bad_database("Event ID in roomid_partialstate is invalid unicode.")
+                })?;
+        let event_id = EventId::parse(event_id)
+            .map_err(|_| Error::bad_database("Event ID in roomid_partialstate is invalid."))?;
+
+        let servers = parts
+            .map(|bytes| {
+                let server = utils::string_from_bytes(bytes).map_err(|_| {
+                    Error::bad_database("Server name in roomid_partialstate is invalid unicode.")
+                })?;
+                ServerName::parse(server).map_err(|_| {
+                    Error::bad_database("Server name in roomid_partialstate is invalid.")
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Some((event_id, servers)))
+    }
+
+    fn partial_state_rooms<'a>(&'a self) -> Box<dyn Iterator<Item = Result<OwnedRoomId>> + 'a> {
+        Box::new(self.roomid_partialstate.iter().map(|(bytes, _)| {
+            RoomId::parse(utils::string_from_bytes(&bytes).map_err(|_| {
+                Error::bad_database("Room ID in roomid_partialstate is invalid unicode.")
+            })?)
+            .map_err(|_| Error::bad_database("Room ID in roomid_partialstate is invalid."))
+        }))
+    }
+}
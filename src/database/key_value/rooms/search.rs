@@ -1,33 +1,38 @@
-use ruma::RoomId;
-
-use crate::{database::KeyValueDatabase, service, services, utils, Result};
-
-/// Splits a string into tokens used as keys in the search inverted index
-///
-/// This may be used to tokenize both message bodies (for indexing) or search
-/// queries (for querying).
-fn tokenize(body: &str) -> impl Iterator<Item = String> + '_ {
-    body.split_terminator(|c: char| !c.is_alphanumeric())
-        .filter(|s| !s.is_empty())
-        .filter(|word| word.len() <= 50)
-        .map(str::to_lowercase)
-}
+use std::{
+    collections::{HashMap, HashSet},
+    mem::size_of,
+};
+
+use crate::{
+    database::KeyValueDatabase,
+    service::{self, rooms::search::Posting},
+    Error, Result,
+};
 
 impl service::rooms::search::Data for KeyValueDatabase {
-    fn index_pdu<'a>(&self, shortroomid: u64, pdu_id: &[u8], message_body: &str) -> Result<()> {
-        let mut batch = tokenize(message_body).map(|word| {
+    fn index_pdu(
+        &self,
+        shortroomid: u64,
+        pdu_id: &[u8],
+        tokens: &HashMap<String, u32>,
+        length: u32,
+    ) -> Result<()> {
+        let mut batch = tokens.iter().map(|(word, frequency)| {
             let mut key = shortroomid.to_be_bytes().to_vec();
             key.extend_from_slice(word.as_bytes());
             key.push(0xff);
             key.extend_from_slice(pdu_id); // TODO: currently we save the room id a second time here
-            (key, Vec::new())
+
+            let mut value = frequency.to_be_bytes().to_vec();
+            value.extend_from_slice(&length.to_be_bytes());
+            (key, value)
         });
 
         self.tokenids.insert_batch(&mut batch)
     }
 
-    fn deindex_pdu(&self, shortroomid: u64, pdu_id: &[u8], message_body: &str) -> Result<()> {
-        let batch = tokenize(message_body).map(|word| {
+    fn deindex_pdu(&self, shortroomid: u64, pdu_id: &[u8], tokens: &HashSet<String>) -> Result<()> {
+        let batch = tokens.iter().map(|word| {
             let mut key = shortroomid.to_be_bytes().to_vec();
             key.extend_from_slice(word.as_bytes());
             key.push(0xFF);
@@ -42,44 +47,61 @@ impl service::rooms::search::Data for KeyValueDatabase {
         Ok(())
     }
 
-    fn search_pdus<'a>(
+    fn search_prefix<'a>(
         &'a self,
-        room_id: &RoomId,
-        search_string: &str,
-    ) -> Result<Option<(Box<dyn Iterator<Item = Vec<u8>> + 'a>, Vec<String>)>> {
-        let prefix = services()
-            .rooms
-            .short
-            .get_shortroomid(room_id)?
-            .expect("room exists")
-            .to_be_bytes()
-            .to_vec();
-
-        let words: Vec<_> = tokenize(search_string).collect();
-
-        let iterators = words.clone().into_iter().map(move |word| {
-            let mut prefix2 = prefix.clone();
-            prefix2.extend_from_slice(word.as_bytes());
-            prefix2.push(0xff);
-            let prefix3 = prefix2.clone();
-
-            let mut last_possible_id = prefix2.clone();
-            last_possible_id.extend_from_slice(&u64::MAX.to_be_bytes());
-
-            self.tokenids
-                .iter_from(&last_possible_id, true) // Newest pdus first
-                .take_while(move |(k, _)| k.starts_with(&prefix2))
-                .map(move |(key, _)| key[prefix3.len()..].to_vec())
-        });
+        shortroomid: u64,
+        prefix: &str,
+    ) -> Box<dyn Iterator<Item = Result<Posting>> + 'a> {
+        let mut key_prefix = shortroomid.to_be_bytes().to_vec();
+        key_prefix.extend_from_slice(prefix.as_bytes());
+        let prefix_len = key_prefix.len();
+
+        let parse = move |(key, value): (Vec<u8>, Vec<u8>)| -> Result<Posting> {
+            // Words are valid UTF-8, so they never contain 0xff
+            let word_end = key[prefix_len..]
+                .iter()
+                .position(|&b| b == 0xff)
+                .ok_or_else(|| Error::bad_database("Invalid tokenid in db."))?
+                + prefix_len;
 
-        let common_elements = match utils::common_elements(iterators, |a, b| {
-            // We compare b with a because we reversed the iterator earlier
-            b.cmp(a)
-        }) {
-            Some(it) => it,
-            None => return Ok(None),
+            let frequency = value
+                .get(..size_of::<u32>())
+                .and_then(|bytes| bytes.try_into().ok())
+                .map(u32::from_be_bytes)
+                .ok_or_else(|| Error::bad_database("Invalid token frequency in tokenids."))?;
+            let length = value
+                .get(size_of::<u32>()..)
+                .and_then(|bytes| bytes.try_into().ok())
+                .map(u32::from_be_bytes)
+                .ok_or_else(|| Error::bad_database("Invalid message length in tokenids."))?;
+
+            Ok(Posting {
+                pdu_id: key[word_end + 1..].to_vec(),
+                exact: word_end == prefix_len,
+                frequency,
+                length,
+            })
         };
 
-        Ok(Some((Box::new(common_elements), words)))
+        let mut exact_prefix = key_prefix.clone();
+        exact_prefix.push(0xff);
+
+        // Pdu ids are a shortroomid and a count, so no pdu id is larger than this
+        let mut last_possible_key = exact_prefix.clone();
+        last_possible_key.extend_from_slice(&[0xff; 2 * size_of::<u64>()]);
+
+        let exact = self
+            .tokenids
+            .iter_from(&last_possible_key, true)
+            .take_while(move |(key, _)| key.starts_with(&exact_prefix))
+            .map(parse);
+
+        let longer = self
+            .tokenids
+            .scan_prefix(key_prefix)
+            .map(parse)
+            .filter(|posting| !matches!(posting, Ok(posting) if posting.exact));
+
+        Box::new(exact.chain(longer))
     }
 }
@@ -47,6 +47,11 @@ impl service::rooms::timeline::Data for KeyValueDatabase {
             .transpose()
     }
 
+    /// Returns the `count` contained in a pdu id.
+    fn pdu_id_count(&self, pdu_id: &[u8]) -> Result<PduCount> {
+        pdu_count(pdu_id)
+    }
+
     /// Returns the json of a pdu.
     fn get_pdu_json(&self, event_id: &EventId) -> Result<Option<CanonicalJsonObject>> {
         self.get_non_outlier_pdu_json(event_id)?.map_or_else(
@@ -282,6 +287,7 @@ impl service::rooms::timeline::Data for KeyValueDatabase {
     fn increment_notification_counts(
         &self,
         room_id: &RoomId,
+        thread_root: Option<&EventId>,
         notifies: Vec<OwnedUserId>,
         highlights: Vec<OwnedUserId>,
     ) -> Result<()> {
@@ -296,6 +302,19 @@ impl service::rooms::timeline::Data for KeyValueDatabase {
             highlights_batch.push(userroom_id);
         }
 
+        if let Some(thread_root) = thread_root {
+            let userroomthread_id = |mut userroom_id: Vec<u8>| {
+                userroom_id.push(0xff);
+                userroom_id.extend_from_slice(thread_root.as_bytes());
+                userroom_id
+            };
+
+            self.userroomthreadid_notificationcount
+                .increment_batch(&mut notifies_batch.iter().cloned().map(userroomthread_id))?;
+            self.userroomthreadid_highlightcount
+                .increment_batch(&mut highlights_batch.iter().cloned().map(userroomthread_id))?;
+        }
+
         self.userroomid_notificationcount
             .increment_batch(&mut notifies_batch.into_iter())?;
         self.userroomid_highlightcount
@@ -1,4 +1,6 @@
-use ruma::{OwnedRoomId, OwnedUserId, RoomId, UserId};
+use std::collections::BTreeMap;
+
+use ruma::{EventId, OwnedEventId, OwnedRoomId, OwnedUserId, RoomId, UserId};
 
 use crate::{database::KeyValueDatabase, service, services, utils, Error, Result};
 
@@ -13,6 +15,59 @@ impl service::rooms::user::Data for KeyValueDatabase {
         self.userroomid_highlightcount
             .insert(&userroom_id, &0_u64.to_be_bytes())?;
 
+        let mut prefix = userroom_id;
+        prefix.push(0xff);
+        for tree in [
+            &self.userroomthreadid_notificationcount,
+            &self.userroomthreadid_highlightcount,
+        ] {
+            for (key, _) in tree.scan_prefix(prefix.clone()).collect::<Vec<_>>() {
+                tree.remove(&key)?;
+            }
+        }
+
+        self.roomuserid_lastnotificationread.insert(
+            &roomuser_id,
+            &services().globals.next_count()?.to_be_bytes(),
+        )?;
+
+        Ok(())
+    }
+
+    fn reset_thread_notification_counts(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        thread_root: Option<&EventId>,
+    ) -> Result<()> {
+        let (roomuser_id, userroom_id) = get_room_and_user_byte_ids(room_id, user_id);
+
+        let thread_counts = self.thread_notification_counts(user_id, room_id)?;
+        let (notification_count, highlight_count) = unread_after_reading(
+            (
+                self.notification_count(user_id, room_id)?,
+                self.highlight_count(user_id, room_id)?,
+            ),
+            &thread_counts,
+            thread_root,
+        );
+
+        if let Some(thread_root) = thread_root {
+            let mut userroomthread_id = userroom_id.clone();
+            userroomthread_id.push(0xff);
+            userroomthread_id.extend_from_slice(thread_root.as_bytes());
+
+            self.userroomthreadid_notificationcount
+                .remove(&userroomthread_id)?;
+            self.userroomthreadid_highlightcount
+                .remove(&userroomthread_id)?;
+        }
+
+        self.userroomid_notificationcount
+            .insert(&userroom_id, &notification_count.to_be_bytes())?;
+        self.userroomid_highlightcount
+            .insert(&userroom_id, &highlight_count.to_be_bytes())?;
+
         self.roomuserid_lastnotificationread.insert(
             &roomuser_id,
             &services().globals.next_count()?.to_be_bytes(),
@@ -45,6 +100,41 @@ impl service::rooms::user::Data for KeyValueDatabase {
             .unwrap_or(Ok(0))
     }
 
+    fn thread_notification_counts(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+    ) -> Result<BTreeMap<OwnedEventId, (u64, u64)>> {
+        let mut prefix = get_userroom_id_bytes(user_id, room_id);
+        prefix.push(0xff);
+
+        let mut counts = BTreeMap::new();
+
+        for (highlight, tree) in [
+            (false, &self.userroomthreadid_notificationcount),
+            (true, &self.userroomthreadid_highlightcount),
+        ] {
+            for (key, value) in tree.scan_prefix(prefix.clone()) {
+                let thread_root =
+                    EventId::parse(utils::string_from_bytes(&key[prefix.len()..]).map_err(
+                        |_| Error::bad_database("Invalid thread root bytes in userroomthreadid."),
+                    )?)
+                    .map_err(|_| Error::bad_database("Invalid thread root in userroomthreadid."))?;
+                let count = utils::u64_from_bytes(&value)
+                    .map_err(|_| Error::bad_database("Invalid thread notification count in db."))?;
+
+                let entry: &mut (u64, u64) = counts.entry(thread_root).or_default();
+                if highlight {
+                    entry.1 = count;
+                } else {
+                    entry.0 = count;
+                }
+            }
+        }
+
+        Ok(counts)
+    }
+
     fn last_notification_read(&self, user_id: &UserId, room_id: &RoomId) -> Result<u64> {
         let mut key = room_id.as_bytes().to_vec();
         key.push(0xff);
@@ -140,3 +230,134 @@ impl service::rooms::user::Data for KeyValueDatabase {
         ))
     }
 }
+
+/// Returns the notification and highlight counts of the room after the user read the thread, or
+/// the main timeline if `thread_root` is `None`. The room counts include the events of all
+/// threads.
+fn unread_after_reading(
+    (notification_count, highlight_count): (u64, u64),
+    thread_counts: &BTreeMap<OwnedEventId, (u64, u64)>,
+    thread_root: Option<&EventId>,
+) -> (u64, u64) {
+    match thread_root {
+        // Only the events in threads stay unread
+        None => thread_counts
+            .values()
+            .fold((0, 0), |(notifications, highlights), (n, h)| {
+                (notifications + n, highlights + h)
+            }),
+        Some(thread_root) => {
+            let (thread_notifications, thread_highlights) =
+                thread_counts.get(thread_root).copied().unwrap_or_default();
+
+            (
+                notification_count.saturating_sub(thread_notifications),
+                highlight_count.saturating_sub(thread_highlights),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use ruma::{event_id, owned_event_id};
+
+    use super::unread_after_reading;
+
+    #[test]
+    fn read_main_timeline() {
+        let thread_counts = BTreeMap::from([
+            (owned_event_id!("$a"), (2, 1)),
+            (owned_event_id!("$b"), (3, 0)),
+        ]);
+
+        // The events in threads stay unread
+        assert_eq!(unread_after_reading((10, 4), &thread_counts, None), (5, 1));
+        assert_eq!(
+            unread_after_reading((10, 4), &BTreeMap::new(), None),
+            (0, 0)
+        );
+    }
+
+    #[test]
+    fn read_thread() {
+        let thread_counts = BTreeMap::from([
+            (owned_event_id!("$a"), (2, 1)),
+            (owned_event_id!("$b"), (3, 0)),
+        ]);
+
+        assert_eq!(
+            unread_after_reading((10, 4), &thread_counts, Some(event_id!("$a"))),
+            (8, 3)
+        );
+        assert_eq!(
+            unread_after_reading((10, 4), &thread_counts, Some(event_id!("$b"))),
+            (7, 4)
+        );
+        // Threads without unread events don't change anything
+        assert_eq!(
+            unread_after_reading((10, 4), &thread_counts, Some(event_id!("$c"))),
+            (10, 4)
+        );
+        assert_eq!(
+            unread_after_reading((1, 0), &thread_counts, Some(event_id!("$b"))),
+            (0, 0)
+        );
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn count_thread_events() {
+        use std::fs;
+
+        use ruma::{room_id, user_id};
+
+        use crate::{
+            database::tests::open_db,
+            service::rooms::{timeline::Data as _, user::Data as _},
+        };
+
+        let (db, path) = open_db();
+        let room_id = room_id!("!room:example.com");
+        let alice = user_id!("@alice:example.com");
+        let bob = user_id!("@bob:example.com");
+
+        // One event in the main timeline and three in two threads
+        for (thread_root, highlights) in [
+            (None, vec![]),
+            (Some(event_id!("$a")), vec![alice.to_owned()]),
+            (Some(event_id!("$a")), vec![]),
+            (Some(event_id!("$b")), vec![alice.to_owned()]),
+        ] {
+            db.increment_notification_counts(
+                room_id,
+                thread_root,
+                vec![alice.to_owned(), bob.to_owned()],
+                highlights,
+            )
+            .unwrap();
+        }
+
+        assert_eq!(db.notification_count(alice, room_id).unwrap(), 4);
+        assert_eq!(db.highlight_count(alice, room_id).unwrap(), 2);
+        assert_eq!(
+            db.thread_notification_counts(alice, room_id).unwrap(),
+            BTreeMap::from([
+                (owned_event_id!("$a"), (2, 1)),
+                (owned_event_id!("$b"), (1, 1)),
+            ])
+        );
+        assert_eq!(
+            db.thread_notification_counts(bob, room_id).unwrap(),
+            BTreeMap::from([
+                (owned_event_id!("$a"), (2, 0)),
+                (owned_event_id!("$b"), (1, 0)),
+            ])
+        );
+
+        drop(db);
+        fs::remove_dir_all(path).unwrap();
+    }
+}
@@ -96,6 +96,14 @@ impl service::sending::Data for KeyValueDatabase {
         )
     }
 
+    fn all_queued_requests<'a>(&'a self) -> Box<dyn Iterator<Item = Result<OutgoingKind>> + 'a> {
+        Box::new(
+            self.servernameevent_data.iter().map(|(k, v)| {
+                parse_servercurrentevent(&k, v).map(|(outgoing_kind, _)| outgoing_kind)
+            }),
+        )
+    }
+
     fn mark_as_active(&self, events: &[(SendingEventType, Vec<u8>)]) -> Result<()> {
         for (e, key) in events {
             let value = if let SendingEventType::Edu(value) = &e {
@@ -110,6 +118,25 @@ impl service::sending::Data for KeyValueDatabase {
         Ok(())
     }
 
+    fn delete_queued_pdus(&self, shortroomid: u64) -> Result<Vec<OutgoingKind>> {
+        let mut deleted = Vec::new();
+        for (key, value) in self.servernameevent_data.iter() {
+            let (outgoing_kind, event) = parse_servercurrentevent(&key, value)?;
+
+            // Pdu ids start with the shortroomid
+            let in_room = matches!(
+                event,
+                SendingEventType::Pdu(pdu_id) if pdu_id.starts_with(&shortroomid.to_be_bytes())
+            );
+            if in_room {
+                self.servernameevent_data.remove(&key)?;
+                deleted.push(outgoing_kind);
+            }
+        }
+
+        Ok(deleted)
+    }
+
     fn set_latest_educount(&self, server_name: &ServerName, last_count: u64) -> Result<()> {
         self.servername_educount
             .insert(server_name.as_bytes(), &last_count.to_be_bytes())
@@ -123,6 +150,24 @@ impl service::sending::Data for KeyValueDatabase {
                     .map_err(|_| Error::bad_database("Invalid u64 in servername_educount."))
             })
     }
+
+    fn set_latest_appservice_educount(&self, appservice_id: &str, last_count: u64) -> Result<()> {
+        let mut key = b"+".to_vec();
+        key.extend_from_slice(appservice_id.as_bytes());
+
+        self.servername_educount
+            .insert(&key, &last_count.to_be_bytes())
+    }
+
+    fn get_latest_appservice_educount(&self, appservice_id: &str) -> Result<u64> {
+        let mut key = b"+".to_vec();
+        key.extend_from_slice(appservice_id.as_bytes());
+
+        self.servername_educount.get(&key)?.map_or(Ok(0), |bytes| {
+            utils::u64_from_bytes(&bytes)
+                .map_err(|_| Error::bad_database("Invalid u64 in servername_educount."))
+        })
+    }
 }
 
 #[tracing::instrument(skip(key))]
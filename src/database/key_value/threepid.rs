@@ -0,0 +1,144 @@
+use ruma::{
+    thirdparty::{Medium, ThirdPartyIdentifier},
+    ClientSecret, OwnedSessionId, OwnedUserId, SessionId, UserId,
+};
+
+use crate::{
+    database::KeyValueDatabase,
+    service::{self, threepid::Validation},
+    utils, Error, Result,
+};
+
+fn threepid_key(medium: &Medium, address: &str) -> Vec<u8> {
+    let mut key = medium.as_str().as_bytes().to_vec();
+    key.push(0xff);
+    key.extend_from_slice(address.as_bytes());
+    key
+}
+
+fn validation_key(client_secret: &ClientSecret, medium: &Medium, address: &str) -> Vec<u8> {
+    let mut key = client_secret.as_bytes().to_vec();
+    key.push(0xff);
+    key.extend_from_slice(&threepid_key(medium, address));
+    key
+}
+
+impl service::threepid::Data for KeyValueDatabase {
+    fn save_validation(&self, session_id: &SessionId, validation: &Validation) -> Result<()> {
+        self.sessionid_threepidvalidation.insert(
+            session_id.as_bytes(),
+            &serde_json::to_vec(validation).expect("Validation::to_vec always works"),
+        )?;
+        self.clientsecretthreepid_sessionid.insert(
+            &validation_key(
+                &validation.client_secret,
+                &validation.medium,
+                &validation.address,
+            ),
+            session_id.as_bytes(),
+        )
+    }
+
+    fn validation(&self, session_id: &SessionId) -> Result<Option<Validation>> {
+        self.sessionid_threepidvalidation
+            .get(session_id.as_bytes())?
+            .map(|bytes| {
+                serde_json::from_slice(&bytes).map_err(|_| {
+                    Error::bad_database("Validation in sessionid_threepidvalidation is invalid.")
+                })
+            })
+            .transpose()
+    }
+
+    fn validation_session(
+        &self,
+        client_secret: &ClientSecret,
+        medium: &Medium,
+        address: &str,
+    ) -> Result<Option<OwnedSessionId>> {
+        self.clientsecretthreepid_sessionid
+            .get(&validation_key(client_secret, medium, address))?
+            .map(|bytes| {
+                SessionId::parse(utils::string_from_bytes(&bytes).map_err(|_| {
+                    Error::bad_database(
+                        "Session ID in clientsecretthreepid_sessionid is invalid unicode.",
+                    )
+                })?)
+                .map_err(|_| {
+                    Error::bad_database("Session ID in clientsecretthreepid_sessionid is invalid.")
+                })
+            })
+            .transpose()
+    }
+
+    fn remove_validation(&self, session_id: &SessionId) -> Result<()> {
+        if let Some(validation) = self.validation(session_id)? {
+            self.clientsecretthreepid_sessionid.remove(&validation_key(
+                &validation.client_secret,
+                &validation.medium,
+                &validation.address,
+            ))?;
+        }
+
+        self.sessionid_threepidvalidation
+            .remove(session_id.as_bytes())
+    }
+
+    fn add_threepid(&self, user_id: &UserId, threepid: &ThirdPartyIdentifier) -> Result<()> {
+        let threepid_key = threepid_key(&threepid.medium, &threepid.address);
+
+        let mut userthreepid = user_id.as_bytes().to_vec();
+        userthreepid.push(0xff);
+        userthreepid.extend_from_slice(&threepid_key);
+
+        self.userthreepid_threepid.insert(
+            &userthreepid,
+            &serde_json::to_vec(threepid).expect("ThirdPartyIdentifier::to_vec always works"),
+        )?;
+        self.threepid_userid
+            .insert(&threepid_key, user_id.as_bytes())
+    }
+
+    fn remove_threepid(&self, user_id: &UserId, medium: &Medium, address: &str) -> Result<()> {
+        let threepid_key = threepid_key(medium, address);
+
+        let mut userthreepid = user_id.as_bytes().to_vec();
+        userthreepid.push(0xff);
+        userthreepid.extend_from_slice(&threepid_key);
+
+        self.userthreepid_threepid.remove(&userthreepid)?;
+        self.threepid_userid.remove(&threepid_key)
+    }
+
+    fn threepid_owner(&self, medium: &Medium, address: &str) -> Result<Option<OwnedUserId>> {
+        self.threepid_userid
+            .get(&threepid_key(medium, address))?
+            .map(|bytes| {
+                UserId::parse(utils::string_from_bytes(&bytes).map_err(|_| {
+                    Error::bad_database("User ID in threepid_userid is invalid unicode.")
+                })?)
+                .map_err(|_| Error::bad_database("User ID in threepid_userid is invalid."))
+            })
+            .transpose()
+    }
+
+    fn threepids<'a>(
+        &'a self,
+        user_id: &UserId,
+    ) -> Box<dyn Iterator<Item = Result<ThirdPartyIdentifier>> + 'a> {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        Box::new(
+            self.userthreepid_threepid
+                .scan_prefix(prefix)
+                .map(|(_, bytes)| {
+                    serde_json::from_slice(&bytes).map_err(|_| {
+                        Error::bad_database(
+                            "ThirdPartyIdentifier in userthreepid_threepid is invalid.",
+                        )
+                    })
+                }),
+        )
+    }
+}
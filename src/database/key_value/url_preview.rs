@@ -0,0 +1,53 @@
+use std::mem::size_of;
+
+use crate::{
+    database::KeyValueDatabase,
+    service::{self, url_preview::UrlPreviewData},
+    utils, Error, Result,
+};
+
+impl service::url_preview::Data for KeyValueDatabase {
+    fn set_url_preview(&self, url: &str, timestamp: u64, preview: &UrlPreviewData) -> Result<()> {
+        let mut value = timestamp.to_be_bytes().to_vec();
+        value.extend_from_slice(
+            &serde_json::to_vec(preview).expect("UrlPreviewData can be serialized"),
+        );
+
+        self.url_previews.insert(url.as_bytes(), &value)
+    }
+
+    fn url_preview(&self, url: &str) -> Result<Option<(u64, UrlPreviewData)>> {
+        self.url_previews
+            .get(url.as_bytes())?
+            .map(|bytes| {
+                let timestamp = utils::u64_from_bytes(
+                    bytes
+                        .get(..size_of::<u64>())
+                        .ok_or_else(|| Error::bad_database("Invalid url preview in db."))?,
+                )
+                .map_err(|_| Error::bad_database("Invalid url preview timestamp in db."))?;
+
+                let preview = serde_json::from_slice(&bytes[size_of::<u64>()..])
+                    .map_err(|_| Error::bad_database("Invalid url preview in db."))?;
+
+                Ok((timestamp, preview))
+            })
+            .transpose()
+    }
+
+    fn set_preview_image(&self, sha256_digest: &[u8], media_id: &str) -> Result<()> {
+        self.filehash_previewmediaid
+            .insert(sha256_digest, media_id.as_bytes())
+    }
+
+    fn preview_image(&self, sha256_digest: &[u8]) -> Result<Option<String>> {
+        self.filehash_previewmediaid
+            .get(sha256_digest)?
+            .map(|bytes| {
+                utils::string_from_bytes(&bytes).map_err(|_| {
+                    Error::bad_database("Invalid media id in filehash_previewmediaid.")
+                })
+            })
+            .transpose()
+    }
+}
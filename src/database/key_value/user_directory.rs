@@ -0,0 +1,80 @@
+use std::collections::BTreeSet;
+
+use ruma::{OwnedUserId, UserId};
+
+use crate::{database::KeyValueDatabase, service, utils, Error, Result};
+
+impl service::user_directory::Data for KeyValueDatabase {
+    fn set_user_tokens(&self, user_id: &UserId, tokens: &BTreeSet<String>) -> Result<()> {
+        self.remove_user(user_id)?;
+
+        for token in tokens {
+            let mut key = token.as_bytes().to_vec();
+            key.push(0xff);
+            key.extend_from_slice(user_id.as_bytes());
+
+            self.directorytoken_userid.insert(&key, &[])?;
+        }
+
+        self.userid_directorytokens.insert(
+            user_id.as_bytes(),
+            &tokens
+                .iter()
+                .map(|token| token.as_bytes())
+                .collect::<Vec<_>>()
+                .join(&0xff),
+        )
+    }
+
+    fn remove_user(&self, user_id: &UserId) -> Result<()> {
+        for token in self.user_tokens(user_id)? {
+            let mut key = token.into_bytes();
+            key.push(0xff);
+            key.extend_from_slice(user_id.as_bytes());
+
+            self.directorytoken_userid.remove(&key)?;
+        }
+
+        self.userid_directorytokens.remove(user_id.as_bytes())
+    }
+
+    fn user_tokens(&self, user_id: &UserId) -> Result<Vec<String>> {
+        let Some(bytes) = self.userid_directorytokens.get(user_id.as_bytes())? else {
+            return Ok(Vec::new());
+        };
+
+        if bytes.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        bytes
+            .split(|&b| b == 0xff)
+            .map(|token| {
+                utils::string_from_bytes(token).map_err(|_| {
+                    Error::bad_database("Invalid token bytes in userid_directorytokens.")
+                })
+            })
+            .collect()
+    }
+
+    fn search_prefix<'a>(
+        &'a self,
+        prefix: &str,
+    ) -> Box<dyn Iterator<Item = Result<OwnedUserId>> + 'a> {
+        Box::new(
+            self.directorytoken_userid
+                .scan_prefix(prefix.as_bytes().to_vec())
+                .map(|(key, _)| {
+                    let user_id = key
+                        .splitn(2, |&b| b == 0xff)
+                        .nth(1)
+                        .ok_or_else(|| Error::bad_database("Invalid directorytoken in db."))?;
+
+                    UserId::parse(utils::string_from_bytes(user_id).map_err(|_| {
+                        Error::bad_database("Invalid UserId bytes in directorytoken_userid.")
+                    })?)
+                    .map_err(|_| Error::bad_database("Invalid UserId in directorytoken_userid."))
+                }),
+        )
+    }
+}
@@ -1,12 +1,15 @@
 use std::{collections::BTreeMap, mem::size_of};
 
 use ruma::{
-    api::client::{device::Device, error::ErrorKind, filter::FilterDefinition},
+    api::client::{
+        dehydrated_device::DehydratedDeviceData, device::Device, error::ErrorKind,
+        filter::FilterDefinition,
+    },
     encryption::{CrossSigningKey, DeviceKeys, OneTimeKey},
     events::{AnyToDeviceEvent, StateEventType},
     serde::Raw,
     DeviceId, MilliSecondsSinceUnixEpoch, OneTimeKeyAlgorithm, OwnedDeviceId, OwnedMxcUri,
-    OwnedOneTimeKeyId, OwnedUserId, UInt, UserId,
+    OwnedOneTimeKeyId, OwnedRoomId, OwnedUserId, RoomId, UInt, UserId,
 };
 use tracing::warn;
 
@@ -238,17 +241,34 @@ impl service::users::Data for KeyValueDatabase {
             self.userdeviceid_token.remove(&userdeviceid)?;
             self.token_userdeviceid.remove(&old_token)?;
         }
+        self.userdeviceid_tokenexpiresat.remove(&userdeviceid)?;
+        if let Some(old_refresh_token) = self.userdeviceid_refreshtoken.get(&userdeviceid)? {
+            self.userdeviceid_refreshtoken.remove(&userdeviceid)?;
+            self.refreshtoken_userdeviceid.remove(&old_refresh_token)?;
+        }
 
         // Remove todevice events
         let mut prefix = userdeviceid.clone();
         prefix.push(0xff);
 
-        for (key, _) in self.todeviceid_events.scan_prefix(prefix) {
+        for (key, _) in self.todeviceid_events.scan_prefix(prefix.clone()) {
             self.todeviceid_events.remove(&key)?;
         }
 
         // TODO: Remove onetimekeys
 
+        // Remove fallback keys
+        for (key, _) in self.fallbackkeyid_fallbackkey.scan_prefix(prefix) {
+            self.fallbackkeyid_fallbackkey.remove(&key)?;
+        }
+
+        if self
+            .get_dehydrated_device(user_id)?
+            .is_some_and(|(dehydrated_device_id, _)| dehydrated_device_id == device_id)
+        {
+            self.remove_dehydrated_device(user_id)?;
+        }
+
         self.userid_devicelistversion
             .increment(user_id.as_bytes())?;
 
@@ -297,6 +317,13 @@ impl service::users::Data for KeyValueDatabase {
             // It will be removed from userdeviceid_token by the insert later
         }
 
+        // The new token does not expire until a refresh token is created for it
+        self.userdeviceid_tokenexpiresat.remove(&userdeviceid)?;
+        if let Some(old_refresh_token) = self.userdeviceid_refreshtoken.get(&userdeviceid)? {
+            self.userdeviceid_refreshtoken.remove(&userdeviceid)?;
+            self.refreshtoken_userdeviceid.remove(&old_refresh_token)?;
+        }
+
         // Assign token to user device combination
         self.userdeviceid_token
             .insert(&userdeviceid, token.as_bytes())?;
@@ -306,6 +333,92 @@ impl service::users::Data for KeyValueDatabase {
         Ok(())
     }
 
+    fn create_refresh_token(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        expires_in: u64,
+    ) -> Result<String> {
+        let mut userdeviceid = user_id.as_bytes().to_vec();
+        userdeviceid.push(0xff);
+        userdeviceid.extend_from_slice(device_id.as_bytes());
+
+        // All devices have metadata
+        assert!(self.userdeviceid_metadata.get(&userdeviceid)?.is_some());
+
+        let refresh_token = utils::random_string(TOKEN_LENGTH);
+        let expires_at = expires_in
+            .checked_mul(1000)
+            .and_then(|expires_in| utils::millis_since_unix_epoch().checked_add(expires_in))
+            .ok_or_else(|| Error::bad_config("access_token_ttl is too large."))?;
+
+        // Remove old refresh token
+        if let Some(old_refresh_token) = self.userdeviceid_refreshtoken.get(&userdeviceid)? {
+            self.refreshtoken_userdeviceid.remove(&old_refresh_token)?;
+            // It will be removed from userdeviceid_refreshtoken by the insert later
+        }
+
+        self.userdeviceid_refreshtoken
+            .insert(&userdeviceid, refresh_token.as_bytes())?;
+        self.refreshtoken_userdeviceid
+            .insert(refresh_token.as_bytes(), &userdeviceid)?;
+
+        self.userdeviceid_tokenexpiresat
+            .insert(&userdeviceid, &expires_at.to_be_bytes())?;
+
+        Ok(refresh_token)
+    }
+
+    fn find_from_refresh_token(
+        &self,
+        refresh_token: &str,
+    ) -> Result<Option<(OwnedUserId, OwnedDeviceId)>> {
+        self.refreshtoken_userdeviceid
+            .get(refresh_token.as_bytes())?
+            .map_or(Ok(None), |bytes| {
+                let mut parts = bytes.split(|&b| b == 0xff);
+                let user_bytes = parts.next().ok_or_else(|| {
+                    Error::bad_database("User ID in refreshtoken_userdeviceid is invalid.")
+                })?;
+                let device_bytes = parts.next().ok_or_else(|| {
+                    Error::bad_database("Device ID in refreshtoken_userdeviceid is invalid.")
+                })?;
+
+                Ok(Some((
+                    UserId::parse(utils::string_from_bytes(user_bytes).map_err(|_| {
+                        Error::bad_database(
+                            "User ID in refreshtoken_userdeviceid is invalid unicode.",
+                        )
+                    })?)
+                    .map_err(|_| {
+                        Error::bad_database("User ID in refreshtoken_userdeviceid is invalid.")
+                    })?,
+                    utils::string_from_bytes(device_bytes)
+                        .map_err(|_| {
+                            Error::bad_database(
+                                "Device ID in refreshtoken_userdeviceid is invalid.",
+                            )
+                        })?
+                        .into(),
+                )))
+            })
+    }
+
+    fn token_expires_at(&self, user_id: &UserId, device_id: &DeviceId) -> Result<Option<u64>> {
+        let mut userdeviceid = user_id.as_bytes().to_vec();
+        userdeviceid.push(0xff);
+        userdeviceid.extend_from_slice(device_id.as_bytes());
+
+        self.userdeviceid_tokenexpiresat
+            .get(&userdeviceid)?
+            .map(|bytes| {
+                utils::u64_from_bytes(&bytes).map_err(|_| {
+                    Error::bad_database("Invalid timestamp in userdeviceid_tokenexpiresat.")
+                })
+            })
+            .transpose()
+    }
+
     fn add_one_time_key(
         &self,
         user_id: &UserId,
@@ -423,6 +536,117 @@ impl service::users::Data for KeyValueDatabase {
         Ok(counts)
     }
 
+    fn add_fallback_key(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        fallback_key_key: &OwnedOneTimeKeyId,
+        fallback_key_value: &Raw<OneTimeKey>,
+    ) -> Result<()> {
+        let mut key = user_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(device_id.as_bytes());
+
+        // Only existing devices should be able to call this.
+        assert!(self.userdeviceid_metadata.get(&key)?.is_some());
+
+        key.push(0xff);
+        key.extend_from_slice(fallback_key_key.algorithm().as_ref().as_bytes());
+
+        // The first byte marks whether the key was handed out already
+        let mut value = vec![0];
+        value.extend_from_slice(
+            serde_json::to_string(fallback_key_key)
+                .expect("DeviceKeyId::to_string always works")
+                .as_bytes(),
+        );
+        value.push(0xff);
+        value.extend_from_slice(
+            &serde_json::to_vec(&fallback_key_value).expect("OneTimeKey::to_vec always works"),
+        );
+
+        self.fallbackkeyid_fallbackkey.insert(&key, &value)?;
+
+        self.userid_lastonetimekeyupdate.insert(
+            user_id.as_bytes(),
+            &services().globals.next_count()?.to_be_bytes(),
+        )?;
+
+        Ok(())
+    }
+
+    fn take_fallback_key(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        key_algorithm: &OneTimeKeyAlgorithm,
+    ) -> Result<Option<(OwnedOneTimeKeyId, Raw<OneTimeKey>)>> {
+        let mut key = user_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(device_id.as_bytes());
+        key.push(0xff);
+        key.extend_from_slice(key_algorithm.as_ref().as_bytes());
+
+        let Some(mut value) = self.fallbackkeyid_fallbackkey.get(&key)? else {
+            return Ok(None);
+        };
+
+        let mut parts = value
+            .get(1..)
+            .ok_or_else(|| Error::bad_database("Fallback key in db is invalid."))?
+            .splitn(2, |&b| b == 0xff);
+        let fallback_key_key = serde_json::from_slice(
+            parts
+                .next()
+                .ok_or_else(|| Error::bad_database("Fallback key id in db is invalid."))?,
+        )
+        .map_err(|_| Error::bad_database("Fallback key id in db is invalid."))?;
+        let fallback_key_value = serde_json::from_slice(
+            parts
+                .next()
+                .ok_or_else(|| Error::bad_database("Fallback key in db is invalid."))?,
+        )
+        .map_err(|_| Error::bad_database("Fallback key in db is invalid."))?;
+
+        if value[0] == 0 {
+            value[0] = 1;
+            self.fallbackkeyid_fallbackkey.insert(&key, &value)?;
+
+            self.userid_lastonetimekeyupdate.insert(
+                user_id.as_bytes(),
+                &services().globals.next_count()?.to_be_bytes(),
+            )?;
+        }
+
+        Ok(Some((fallback_key_key, fallback_key_value)))
+    }
+
+    fn unused_fallback_key_types(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Vec<OneTimeKeyAlgorithm>> {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+        prefix.extend_from_slice(device_id.as_bytes());
+        prefix.push(0xff);
+
+        self.fallbackkeyid_fallbackkey
+            .scan_prefix(prefix)
+            .filter(|(_, value)| value.first() == Some(&0))
+            .map(|(key, _)| {
+                let algorithm = key
+                    .rsplit(|&b| b == 0xff)
+                    .next()
+                    .expect("rsplit always returns an element");
+
+                utils::string_from_bytes(algorithm)
+                    .map(OneTimeKeyAlgorithm::from)
+                    .map_err(|_| Error::bad_database("Fallback key algorithm in db is invalid."))
+            })
+            .collect()
+    }
+
     fn add_device_keys(
         &self,
         user_id: &UserId,
@@ -946,6 +1170,70 @@ impl service::users::Data for KeyValueDatabase {
         }))
     }
 
+    fn set_dehydrated_device(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        device_data: &Raw<DehydratedDeviceData>,
+    ) -> Result<()> {
+        let mut value = device_id.as_bytes().to_vec();
+        value.push(0xff);
+        value.extend_from_slice(device_data.json().get().as_bytes());
+
+        self.userid_dehydrateddevice
+            .insert(user_id.as_bytes(), &value)
+    }
+
+    fn get_dehydrated_device(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Option<(OwnedDeviceId, Raw<DehydratedDeviceData>)>> {
+        self.userid_dehydrateddevice
+            .get(user_id.as_bytes())?
+            .map(|value| {
+                let mut parts = value.splitn(2, |&b| b == 0xff);
+                let device_id = utils::string_from_bytes(
+                    parts.next().expect("splitn always returns one element"),
+                )
+                .map_err(|_| Error::bad_database("Invalid device id in userid_dehydrateddevice."))?
+                .into();
+                let device_data = serde_json::from_slice(parts.next().ok_or_else(|| {
+                    Error::bad_database("Missing device data in userid_dehydrateddevice.")
+                })?)
+                .map_err(|_| {
+                    Error::bad_database("Invalid device data in userid_dehydrateddevice.")
+                })?;
+
+                Ok((device_id, device_data))
+            })
+            .transpose()
+    }
+
+    fn remove_dehydrated_device(&self, user_id: &UserId) -> Result<()> {
+        self.userid_dehydrateddevice.remove(user_id.as_bytes())
+    }
+
+    fn set_server_notices_room(&self, user_id: &UserId, room_id: &RoomId) -> Result<()> {
+        self.userid_servernoticesroomid
+            .insert(user_id.as_bytes(), room_id.as_bytes())
+    }
+
+    fn server_notices_room(&self, user_id: &UserId) -> Result<Option<OwnedRoomId>> {
+        self.userid_servernoticesroomid
+            .get(user_id.as_bytes())?
+            .map(|bytes| {
+                utils::string_from_bytes(&bytes)
+                    .map_err(|_| {
+                        Error::bad_database("Invalid room id bytes in userid_servernoticesroomid.")
+                    })?
+                    .try_into()
+                    .map_err(|_| {
+                        Error::bad_database("Invalid room id in userid_servernoticesroomid.")
+                    })
+            })
+            .transpose()
+    }
+
     /// Creates a new sync filter. Returns the filter id.
     fn create_filter(&self, user_id: &UserId, filter: &FilterDefinition) -> Result<String> {
         let filter_id = utils::random_string(4);
@@ -982,9 +1270,10 @@ impl service::users::Data for KeyValueDatabase {
         let token = utils::random_string(TOKEN_LENGTH);
 
         let expires_in = services().globals.config.openid_token_ttl;
-        let expires_at = utils::millis_since_unix_epoch()
-            .checked_add(expires_in * 1000)
-            .expect("time is valid");
+        let expires_at = expires_in
+            .checked_mul(1000)
+            .and_then(|expires_in| utils::millis_since_unix_epoch().checked_add(expires_in))
+            .ok_or_else(|| Error::bad_config("openid_token_ttl is too large."))?;
 
         let mut value = expires_at.to_be_bytes().to_vec();
         value.extend_from_slice(user_id.as_bytes());
@@ -15,7 +15,7 @@ use ruma::{
     events::{
         push_rules::{PushRulesEvent, PushRulesEventContent},
         room::message::RoomMessageEventContent,
-        GlobalAccountDataEvent, GlobalAccountDataEventType, StateEventType,
+        GlobalAccountDataEvent, GlobalAccountDataEventType, StateEventType, TimelineEventType,
     },
     push::Ruleset,
     CanonicalJsonValue, EventId, OwnedDeviceId, OwnedEventId, OwnedMxcUri, OwnedRoomId,
@@ -36,6 +36,9 @@ use tokio::{io::AsyncReadExt, time::interval};
 
 use tracing::{debug, error, info, warn};
 
+/// The version of the database layout, which is increased by every migration
+const LATEST_DATABASE_VERSION: u64 = 22;
+
 /// This trait should only be used for migrations, and hence should never be made "pub"
 trait GlobalsMigrationsExt {
     /// As the name states, old version of `get_media_file`, only for usage in migrations
@@ -68,6 +71,7 @@ pub struct KeyValueDatabase {
     //pub globals: globals::Globals,
     pub(super) global: Arc<dyn KvTree>,
     pub(super) server_signingkeys: Arc<dyn KvTree>,
+    pub(super) servername_originserverkeys: Arc<dyn KvTree>, // OriginServerKeys = the response of the server, as it was signed
 
     //pub users: users::Users,
     pub(super) userid_password: Arc<dyn KvTree>,
@@ -78,10 +82,16 @@ pub struct KeyValueDatabase {
     pub(super) userdeviceid_metadata: Arc<dyn KvTree>, // This is also used to check if a device exists
     pub(super) userid_devicelistversion: Arc<dyn KvTree>, // DevicelistVersion = u64
     pub(super) token_userdeviceid: Arc<dyn KvTree>,
+    pub(super) userdeviceid_tokenexpiresat: Arc<dyn KvTree>, // TokenExpiresAt = Timestamp
+    pub(super) userdeviceid_refreshtoken: Arc<dyn KvTree>,
+    pub(super) refreshtoken_userdeviceid: Arc<dyn KvTree>,
+    pub(super) userid_directorytokens: Arc<dyn KvTree>, // DirectoryTokens = Tokens separated by 0xff
+    pub(super) directorytoken_userid: Arc<dyn KvTree>,  // DirectoryToken = Token + UserId
 
     pub(super) onetimekeyid_onetimekeys: Arc<dyn KvTree>, // OneTimeKeyId = UserId + DeviceKeyId
     pub(super) userid_lastonetimekeyupdate: Arc<dyn KvTree>, // LastOneTimeKeyUpdate = Count
-    pub(super) keychangeid_userid: Arc<dyn KvTree>,       // KeyChangeId = UserId/RoomId + Count
+    pub(super) fallbackkeyid_fallbackkey: Arc<dyn KvTree>, // FallbackKeyId = UserId + DeviceId + Algorithm
+    pub(super) keychangeid_userid: Arc<dyn KvTree>,        // KeyChangeId = UserId/RoomId + Count
     pub(super) keyid_key: Arc<dyn KvTree>, // KeyId = UserId + KeyId (depends on key type)
     pub(super) userid_masterkeyid: Arc<dyn KvTree>,
     pub(super) userid_selfsigningkeyid: Arc<dyn KvTree>,
@@ -91,18 +101,31 @@ pub struct KeyValueDatabase {
     pub(super) userfilterid_filter: Arc<dyn KvTree>, // UserFilterId = UserId + FilterId
 
     pub(super) todeviceid_events: Arc<dyn KvTree>, // ToDeviceId = UserId + DeviceId + Count
+    pub(super) userid_dehydrateddevice: Arc<dyn KvTree>, // DehydratedDevice = DeviceId + DeviceData
+    pub(super) userid_servernoticesroomid: Arc<dyn KvTree>,
 
     //pub uiaa: uiaa::Uiaa,
     pub(super) userdevicesessionid_uiaainfo: Arc<dyn KvTree>, // User-interactive authentication
     pub(super) userdevicesessionid_uiaarequest:
         RwLock<BTreeMap<(OwnedUserId, OwnedDeviceId, String), CanonicalJsonValue>>,
 
+    //pub threepid: threepid::ThreePid,
+    pub(super) sessionid_threepidvalidation: Arc<dyn KvTree>,
+    pub(super) clientsecretthreepid_sessionid: Arc<dyn KvTree>, // ClientSecretThreePid = ClientSecret + Medium + Address
+    pub(super) threepid_userid: Arc<dyn KvTree>,                // ThreePid = Medium + Address
+    pub(super) userthreepid_threepid: Arc<dyn KvTree>, // UserThreePid = UserId + Medium + Address
+
+    //pub sso: sso::Sso,
+    pub(super) idpsubject_userid: Arc<dyn KvTree>, // IdpSubject = IdpId + Subject
+
     //pub edus: RoomEdus,
     pub(super) readreceiptid_readreceipt: Arc<dyn KvTree>, // ReadReceiptId = RoomId + Count + UserId
+    pub(super) roomuserthreadid_readreceiptid: Arc<dyn KvTree>, // RoomUserThreadId = RoomId + UserId + Thread (empty if unthreaded)
     pub(super) roomuserid_privateread: Arc<dyn KvTree>, // RoomUserId = Room + User, PrivateRead = Count
     pub(super) roomuserid_lastprivatereadupdate: Arc<dyn KvTree>, // LastPrivateReadUpdate = Count
     pub(super) presenceid_presence: Arc<dyn KvTree>,    // PresenceId = RoomId + Count + UserId
-    pub(super) userid_lastpresenceupdate: Arc<dyn KvTree>, // LastPresenceUpdate = Count
+    pub(super) userid_presence: Arc<dyn KvTree>,        // Presence = Count + PresenceEvent
+    pub(super) userid_lastpresenceupdate: Arc<dyn KvTree>, // LastPresenceUpdate = Timestamp
 
     //pub rooms: rooms::Rooms,
     pub(super) pduid_pdu: Arc<dyn KvTree>, // PduId = ShortRoomId + Count
@@ -135,12 +158,17 @@ pub struct KeyValueDatabase {
     pub(super) alias_userid: Arc<dyn KvTree>, // User who created the alias
 
     pub(super) disabledroomids: Arc<dyn KvTree>, // Rooms where incoming federation handling is disabled
+    pub(super) bannedroomids: Arc<dyn KvTree>, // Rooms that were shut down and can't be joined anymore
+    pub(super) roomid_partialstate: Arc<dyn KvTree>, // PartialState = JoinEventId + (0xff + ServerName)*
+    pub(super) roomid_fullstatecount: Arc<dyn KvTree>, // Count when the partial state was completed
 
     pub(super) lazyloadedids: Arc<dyn KvTree>, // LazyLoadedIds = UserId + DeviceId + RoomId + LazyLoadedUserId
 
     pub(super) userroomid_notificationcount: Arc<dyn KvTree>, // NotifyCount = u64
     pub(super) userroomid_highlightcount: Arc<dyn KvTree>,    // HighlightCount = u64
-    pub(super) roomuserid_lastnotificationread: Arc<dyn KvTree>, // LastNotificationRead = u64
+    pub(super) userroomthreadid_notificationcount: Arc<dyn KvTree>, // UserRoomThreadId = UserId + RoomId + ThreadRootEventId, NotifyCount = u64
+    pub(super) userroomthreadid_highlightcount: Arc<dyn KvTree>,    // HighlightCount = u64
+    pub(super) roomuserid_lastnotificationread: Arc<dyn KvTree>,    // LastNotificationRead = u64
 
     /// Remember the current state hash of a room.
     pub(super) roomid_shortstatehash: Arc<dyn KvTree>,
@@ -184,6 +212,8 @@ pub struct KeyValueDatabase {
     pub(super) servernamemediaid_userlocalpart: Arc<dyn KvTree>, // Servername + MediaID -> User Localpart, used to remove keys from above when files are deleted by unrelated means
     pub(super) thumbnailid_metadata: Arc<dyn KvTree>, // ThumbnailId = Servername + MediaID + width + height -> Filename + ContentType + extra 0xff byte if media is allowed on unauthenticated endpoints
     pub(super) filehash_thumbnailid: Arc<dyn KvTree>, // sha256 of content + "ThumbnailId", as defined above. Used to dangling references to filehashes from thumbnailIds
+    pub(super) url_previews: Arc<dyn KvTree>,         // Url -> fetch time + UrlPreviewData
+    pub(super) filehash_previewmediaid: Arc<dyn KvTree>, // sha256 of preview image -> MediaID
     //pub key_backups: key_backups::KeyBackups,
     pub(super) backupid_algorithm: Arc<dyn KvTree>, // BackupId = UserId + Version(Count)
     pub(super) backupid_etag: Arc<dyn KvTree>,      // BackupId = UserId + Version(Count)
@@ -192,15 +222,17 @@ pub struct KeyValueDatabase {
     //pub transaction_ids: transaction_ids::TransactionIds,
     pub(super) userdevicetxnid_response: Arc<dyn KvTree>, // Response can be empty (/sendToDevice) or the event id (/send)
     //pub sending: sending::Sending,
-    pub(super) servername_educount: Arc<dyn KvTree>, // EduCount: Count of last EDU sync
+    pub(super) servername_educount: Arc<dyn KvTree>, // EduCount: Count of last EDU sync, appservice ids are prefixed with +
     pub(super) servernameevent_data: Arc<dyn KvTree>, // ServernameEvent = (+ / $)SenderKey / ServerName / UserId + PduId / Id (for edus), Data = EDU content
     pub(super) servercurrentevent_data: Arc<dyn KvTree>, // ServerCurrentEvents = (+ / $)ServerName / UserId + PduId / Id (for edus), Data = EDU content
 
     //pub appservice: appservice::Appservice,
     pub(super) id_appserviceregistrations: Arc<dyn KvTree>,
+    pub(super) appserviceid_userid: Arc<dyn KvTree>, // AppserviceId = Id + UserId
 
     //pub pusher: pusher::PushData,
     pub(super) senderkey_pusher: Arc<dyn KvTree>,
+    pub(super) emailid_notification: Arc<dyn KvTree>, // EmailId = UserId + Address + RoomId + Count, Notification = Highlight + EventId
 
     pub(super) pdu_cache: Mutex<LruCache<OwnedEventId, Arc<PduEvent>>>,
     pub(super) shorteventid_cache: Mutex<LruCache<u64, Arc<EventId>>>,
@@ -273,34 +305,122 @@ impl KeyValueDatabase {
         Ok(())
     }
 
-    /// Load an existing database or create a new one.
-    pub async fn load_or_create(config: Config) -> Result<()> {
-        Self::check_db_setup(&config)?;
-
-        if !Path::new(&config.database_path).exists() {
-            fs::create_dir_all(&config.database_path)
-                .map_err(|_| Error::BadConfig("Database folder doesn't exists and couldn't be created (e.g. due to missing permissions). Please create the database folder yourself."))?;
-        }
-
-        let builder: Arc<dyn KeyValueDatabaseEngine> = match &*config.database_backend {
+    fn open_engine(config: &Config) -> Result<Arc<dyn KeyValueDatabaseEngine>> {
+        Ok(match &*config.database_backend {
             #[cfg(feature = "sqlite")]
-            "sqlite" => Arc::new(Arc::<abstraction::sqlite::Engine>::open(&config)?),
+            "sqlite" => Arc::new(Arc::<abstraction::sqlite::Engine>::open(config)?),
             #[cfg(feature = "rocksdb")]
-            "rocksdb" => Arc::new(Arc::<abstraction::rocksdb::Engine>::open(&config)?),
+            "rocksdb" => Arc::new(Arc::<abstraction::rocksdb::Engine>::open(config)?),
+            #[cfg(feature = "postgres")]
+            "postgresql" => Arc::new(Arc::<abstraction::postgres::Engine>::open(config)?),
             _ => {
                 return Err(Error::BadConfig("Database backend not found."));
             }
+        })
+    }
+
+    /// Writes a backup of the database to `path` without starting the server. SQLite databases
+    /// can be backed up like this while Conduit is running, RocksDB databases are locked by it.
+    pub fn backup(config: &Config, path: &Path) -> Result<()> {
+        Self::check_db_setup(config)?;
+        Self::open_engine(config)?.backup(path)
+    }
+
+    /// Replaces the database with a backup and returns the database version of the backup. Conduit
+    /// must not be running.
+    ///
+    /// The files of the old database are moved into a new directory in `database_path`, the media
+    /// files stay where they are.
+    pub fn restore(config: &Config, backup: &Path) -> Result<u64> {
+        let is_database_file: fn(&str) -> bool = match &*config.database_backend {
+            "sqlite" => |name| name.starts_with("conduit.db"),
+            "rocksdb" => is_rocksdb_file,
+            _ => {
+                return Err(Error::bad_config(
+                    "Only sqlite and rocksdb databases can be restored by Conduit.",
+                ))
+            }
         };
 
-        if config.registration_token == Some(String::new()) {
-            return Err(Error::bad_config("Registration token is empty"));
+        let marker = if config.database_backend == "sqlite" {
+            "conduit.db"
+        } else {
+            "IDENTITY"
+        };
+        if !backup.join(marker).is_file() {
+            return Err(Error::bad_config(
+                "The backup does not contain a database of the configured backend.",
+            ));
         }
 
-        if config.max_request_size < 1024 {
-            error!(?config.max_request_size, "Max request size is less than 1KB. Please increase it.");
+        let database_path = Path::new(&config.database_path);
+        fs::create_dir_all(database_path)?;
+
+        let started = utils::millis_since_unix_epoch();
+
+        // Opening a database writes to it, so a copy of the backup is opened instead
+        let copy = database_path.join(format!("restoring-{started}"));
+        fs::create_dir(&copy)?;
+
+        let version = transfer_database_files(backup, &copy, is_database_file, fs::copy)
+            .and_then(|()| Self::backup_version(config, &copy));
+        let version = match version {
+            Ok(version) => version,
+            Err(e) => {
+                let _ = remove_dir_all(&copy);
+                return Err(e);
+            }
+        };
+
+        let old_database = database_path.join(format!("before-restore-{started}"));
+        fs::create_dir(&old_database)?;
+
+        transfer_database_files(database_path, &old_database, is_database_file, fs::rename)?;
+        transfer_database_files(&copy, database_path, is_database_file, fs::rename)?;
+        remove_dir_all(&copy)?;
+
+        info!(
+            "Restored database with version {version}, the old database was moved to {}",
+            old_database.display()
+        );
+
+        Ok(version)
+    }
+
+    /// Returns the database version of the backup at `path`, if it can be restored.
+    fn backup_version(config: &Config, path: &Path) -> Result<u64> {
+        let mut backup_config = config.clone();
+        backup_config.database_path = path
+            .to_str()
+            .ok_or(Error::BadConfig("Database path is not valid UTF-8."))?
+            .to_owned();
+
+        let version = Self::open_engine(&backup_config)?
+            .open_tree("global")?
+            .get(b"version")?
+            .map(|version| {
+                utils::u64_from_bytes(&version)
+                    .map_err(|_| Error::bad_database("Database version id is invalid."))
+            })
+            .transpose()?
+            .unwrap_or(0);
+
+        if version == 0 {
+            return Err(Error::bad_config("The backup is not a Conduit database."));
         }
 
-        let db_raw = Box::new(Self {
+        if version > LATEST_DATABASE_VERSION {
+            return Err(Error::bad_config(
+                "The backup was made by a newer version of Conduit, which uses a database layout this version does not understand.",
+            ));
+        }
+
+        Ok(version)
+    }
+
+    /// Opens all trees of the database.
+    fn open_trees(builder: Arc<dyn KeyValueDatabaseEngine>, config: &Config) -> Result<Self> {
+        Ok(Self {
             _db: builder.clone(),
             userid_password: builder.open_tree("userid_password")?,
             userid_displayname: builder.open_tree("userid_displayname")?,
@@ -310,8 +430,14 @@ impl KeyValueDatabase {
             userdeviceid_metadata: builder.open_tree("userdeviceid_metadata")?,
             userid_devicelistversion: builder.open_tree("userid_devicelistversion")?,
             token_userdeviceid: builder.open_tree("token_userdeviceid")?,
+            userdeviceid_tokenexpiresat: builder.open_tree("userdeviceid_tokenexpiresat")?,
+            userdeviceid_refreshtoken: builder.open_tree("userdeviceid_refreshtoken")?,
+            refreshtoken_userdeviceid: builder.open_tree("refreshtoken_userdeviceid")?,
+            userid_directorytokens: builder.open_tree("userid_directorytokens")?,
+            directorytoken_userid: builder.open_tree("directorytoken_userid")?,
             onetimekeyid_onetimekeys: builder.open_tree("onetimekeyid_onetimekeys")?,
             userid_lastonetimekeyupdate: builder.open_tree("userid_lastonetimekeyupdate")?,
+            fallbackkeyid_fallbackkey: builder.open_tree("fallbackkeyid_fallbackkey")?,
             keychangeid_userid: builder.open_tree("keychangeid_userid")?,
             keyid_key: builder.open_tree("keyid_key")?,
             userid_masterkeyid: builder.open_tree("userid_masterkeyid")?,
@@ -320,14 +446,23 @@ impl KeyValueDatabase {
             openidtoken_expiresatuserid: builder.open_tree("openidtoken_expiresatuserid")?,
             userfilterid_filter: builder.open_tree("userfilterid_filter")?,
             todeviceid_events: builder.open_tree("todeviceid_events")?,
+            userid_dehydrateddevice: builder.open_tree("userid_dehydrateddevice")?,
+            userid_servernoticesroomid: builder.open_tree("userid_servernoticesroomid")?,
 
             userdevicesessionid_uiaainfo: builder.open_tree("userdevicesessionid_uiaainfo")?,
             userdevicesessionid_uiaarequest: RwLock::new(BTreeMap::new()),
+            sessionid_threepidvalidation: builder.open_tree("sessionid_threepidvalidation")?,
+            clientsecretthreepid_sessionid: builder.open_tree("clientsecretthreepid_sessionid")?,
+            threepid_userid: builder.open_tree("threepid_userid")?,
+            userthreepid_threepid: builder.open_tree("userthreepid_threepid")?,
+            idpsubject_userid: builder.open_tree("idpsubject_userid")?,
             readreceiptid_readreceipt: builder.open_tree("readreceiptid_readreceipt")?,
+            roomuserthreadid_readreceiptid: builder.open_tree("roomuserthreadid_readreceiptid")?,
             roomuserid_privateread: builder.open_tree("roomuserid_privateread")?, // "Private" read receipt
             roomuserid_lastprivatereadupdate: builder
                 .open_tree("roomuserid_lastprivatereadupdate")?,
             presenceid_presence: builder.open_tree("presenceid_presence")?,
+            userid_presence: builder.open_tree("userid_presence")?,
             userid_lastpresenceupdate: builder.open_tree("userid_lastpresenceupdate")?,
             pduid_pdu: builder.open_tree("pduid_pdu")?,
             eventid_pduid: builder.open_tree("eventid_pduid")?,
@@ -358,11 +493,18 @@ impl KeyValueDatabase {
             alias_userid: builder.open_tree("alias_userid")?,
 
             disabledroomids: builder.open_tree("disabledroomids")?,
+            bannedroomids: builder.open_tree("bannedroomids")?,
+            roomid_partialstate: builder.open_tree("roomid_partialstate")?,
+            roomid_fullstatecount: builder.open_tree("roomid_fullstatecount")?,
 
             lazyloadedids: builder.open_tree("lazyloadedids")?,
 
             userroomid_notificationcount: builder.open_tree("userroomid_notificationcount")?,
             userroomid_highlightcount: builder.open_tree("userroomid_highlightcount")?,
+            userroomthreadid_notificationcount: builder
+                .open_tree("userroomthreadid_notificationcount")?,
+            userroomthreadid_highlightcount: builder
+                .open_tree("userroomthreadid_highlightcount")?,
             roomuserid_lastnotificationread: builder.open_tree("userroomid_highlightcount")?,
 
             statekey_shortstatekey: builder.open_tree("statekey_shortstatekey")?,
@@ -397,6 +539,8 @@ impl KeyValueDatabase {
                 .open_tree("servernamemediaid_userlocalpart")?,
             thumbnailid_metadata: builder.open_tree("thumbnailid_metadata")?,
             filehash_thumbnailid: builder.open_tree("filehash_thumbnailid")?,
+            url_previews: builder.open_tree("url_previews")?,
+            filehash_previewmediaid: builder.open_tree("filehash_previewmediaid")?,
             backupid_algorithm: builder.open_tree("backupid_algorithm")?,
             backupid_etag: builder.open_tree("backupid_etag")?,
             backupkeyid_backup: builder.open_tree("backupkeyid_backup")?,
@@ -405,9 +549,12 @@ impl KeyValueDatabase {
             servernameevent_data: builder.open_tree("servernameevent_data")?,
             servercurrentevent_data: builder.open_tree("servercurrentevent_data")?,
             id_appserviceregistrations: builder.open_tree("id_appserviceregistrations")?,
+            appserviceid_userid: builder.open_tree("appserviceid_userid")?,
             senderkey_pusher: builder.open_tree("senderkey_pusher")?,
+            emailid_notification: builder.open_tree("emailid_notification")?,
             global: builder.open_tree("global")?,
             server_signingkeys: builder.open_tree("server_signingkeys")?,
+            servername_originserverkeys: builder.open_tree("servername_originserverkeys")?,
 
             pdu_cache: Mutex::new(LruCache::new(
                 config
@@ -433,7 +580,29 @@ impl KeyValueDatabase {
             our_real_users_cache: RwLock::new(HashMap::new()),
             appservice_in_room_cache: RwLock::new(HashMap::new()),
             lasttimelinecount_cache: Mutex::new(HashMap::new()),
-        });
+        })
+    }
+
+    /// Load an existing database or create a new one.
+    pub async fn load_or_create(config: Config) -> Result<()> {
+        Self::check_db_setup(&config)?;
+
+        if !Path::new(&config.database_path).exists() {
+            fs::create_dir_all(&config.database_path)
+                .map_err(|_| Error::BadConfig("Database folder doesn't exists and couldn't be created (e.g. due to missing permissions). Please create the database folder yourself."))?;
+        }
+
+        let builder = Self::open_engine(&config)?;
+
+        if config.registration_token == Some(String::new()) {
+            return Err(Error::bad_config("Registration token is empty"));
+        }
+
+        if config.max_request_size < 1024 {
+            error!(?config.max_request_size, "Max request size is less than 1KB. Please increase it.");
+        }
+
+        let db_raw = Box::new(Self::open_trees(builder, &config)?);
 
         let db = Box::leak(db_raw);
 
@@ -459,7 +628,7 @@ impl KeyValueDatabase {
         }
 
         // If the database has any data, perform data migrations before starting
-        let latest_database_version = 18;
+        let latest_database_version = LATEST_DATABASE_VERSION;
 
         if services().users.count()? > 0 {
             // MIGRATIONS
@@ -1090,6 +1259,103 @@ impl KeyValueDatabase {
                 warn!("Migration: 17 -> 18 finished");
             }
 
+            if services().globals.database_version()? < 19 {
+                warn!("Building the user directory. If there are a lot of users, this may take a while.");
+
+                for user_id in services().users.iter() {
+                    let user_id = user_id?;
+
+                    services().user_directory.update_user(&user_id)?;
+                }
+
+                services().globals.bump_database_version(19)?;
+
+                warn!("Migration: 18 -> 19 finished");
+            }
+
+            if services().globals.database_version()? < 20 {
+                warn!("Rebuilding the search index. If there are a lot of messages, this may take a while.");
+
+                // The index now stores how often words occur, and tokenizes CJK text differently
+                db.tokenids.clear()?;
+
+                #[derive(Deserialize)]
+                struct ExtractBody {
+                    body: Option<String>,
+                }
+
+                for room_id in services().rooms.metadata.iter_ids() {
+                    let room_id = room_id?;
+                    let Some(shortroomid) = services().rooms.short.get_shortroomid(&room_id)?
+                    else {
+                        continue;
+                    };
+
+                    for pdu in services()
+                        .rooms
+                        .timeline
+                        .all_pdus(services().globals.server_user(), &room_id)?
+                    {
+                        let (_, pdu) = pdu?;
+
+                        if pdu.kind != TimelineEventType::RoomMessage {
+                            continue;
+                        }
+
+                        let Some(body) = serde_json::from_str::<ExtractBody>(pdu.content.get())
+                            .ok()
+                            .and_then(|content| content.body)
+                        else {
+                            continue;
+                        };
+
+                        if let Some(pdu_id) = services().rooms.timeline.get_pdu_id(&pdu.event_id)? {
+                            services()
+                                .rooms
+                                .search
+                                .index_pdu(shortroomid, &pdu_id, &body)?;
+                        }
+                    }
+                }
+
+                services().globals.bump_database_version(20)?;
+
+                warn!("Migration: 19 -> 20 finished");
+            }
+
+            if services().globals.database_version()? < 21 {
+                // Appservices get the device changes of the users they logged in as, which are
+                // now indexed. Before, all users in their namespaces were checked.
+                let appservices = services().appservice.read().await.clone();
+
+                for user_id in services().users.iter() {
+                    let user_id = user_id?;
+                    if services().users.all_device_ids(&user_id).next().is_none() {
+                        continue;
+                    }
+
+                    for (id, info) in &appservices {
+                        if info.is_user_match(&user_id) {
+                            services().appservice.add_user(id, &user_id)?;
+                        }
+                    }
+                }
+
+                services().globals.bump_database_version(21)?;
+
+                warn!("Migration: 20 -> 21 finished");
+            }
+
+            if services().globals.database_version()? < 22 {
+                // Read receipts are looked up by user and thread instead of reading all receipts
+                // of the room
+                db.index_read_receipts()?;
+
+                services().globals.bump_database_version(22)?;
+
+                warn!("Migration: 21 -> 22 finished");
+            }
+
             assert_eq!(
                 services().globals.database_version().unwrap(),
                 latest_database_version
@@ -1115,9 +1381,6 @@ impl KeyValueDatabase {
             );
         }
 
-        // This data is probably outdated
-        db.presenceid_presence.clear()?;
-
         services().admin.start_handler();
 
         // Set emergency access for the conduit user
@@ -1137,9 +1400,15 @@ impl KeyValueDatabase {
         };
 
         services().sending.start_handler();
+        services().rooms.partial_state.start_resync_tasks();
+        services().pusher.start_email_tasks().await;
+        services().policy.start_subscriptions();
 
         services().media.start_time_retention_checker();
         services().users.start_device_last_seen_update_task();
+        if services().globals.allow_presence() {
+            services().rooms.edus.presence.start_maintain_task();
+        }
 
         Self::start_cleanup_task().await;
         if services().globals.allow_check_for_updates() {
@@ -1453,6 +1722,41 @@ async fn migrate_to_sha256_media(
     Ok(())
 }
 
+/// Whether the file is part of a RocksDB database, so that other files in the same directory are
+/// left alone.
+fn is_rocksdb_file(name: &str) -> bool {
+    let is_numbered = |extension: &str| {
+        name.strip_suffix(extension)
+            .is_some_and(|number| !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()))
+    };
+
+    matches!(name, "CURRENT" | "IDENTITY" | "LOCK" | "LOG")
+        || name.starts_with("LOG.old.")
+        || name.starts_with("MANIFEST-")
+        || name.starts_with("OPTIONS-")
+        || is_numbered(".sst")
+        || is_numbered(".log")
+        || is_numbered(".blob")
+}
+
+/// Copies or moves the files of a database from one directory to another.
+fn transfer_database_files<T>(
+    from: &Path,
+    to: &Path,
+    is_database_file: fn(&str) -> bool,
+    transfer: impl Fn(PathBuf, PathBuf) -> std::io::Result<T>,
+) -> Result<()> {
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() && entry.file_name().to_str().is_some_and(is_database_file)
+        {
+            transfer(entry.path(), to.join(entry.file_name()))?;
+        }
+    }
+
+    Ok(())
+}
+
 /// Sets the emergency password and push rules for the @conduit account in case emergency password is set
 fn set_emergency_access() -> Result<bool> {
     let conduit_user = services().globals.server_user();
@@ -1479,3 +1783,59 @@ fn set_emergency_access() -> Result<bool> {
 
     res
 }
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "sqlite")]
+    use std::{env, fs, path::PathBuf};
+
+    #[cfg(feature = "sqlite")]
+    use serde_json::json;
+
+    use super::is_rocksdb_file;
+    #[cfg(feature = "sqlite")]
+    use super::KeyValueDatabase;
+    #[cfg(feature = "sqlite")]
+    use crate::{utils, Config};
+
+    /// Opens an empty SQLite database in a new temporary directory, which the test has to remove.
+    #[cfg(feature = "sqlite")]
+    pub(super) fn open_db() -> (KeyValueDatabase, PathBuf) {
+        let path = env::temp_dir().join(format!("conduit-test-{}", utils::random_string(8)));
+        fs::create_dir_all(&path).unwrap();
+
+        let config: Config = serde_json::from_value(json!({
+            "server_name": "example.com",
+            "database_backend": "sqlite",
+            "database_path": path,
+        }))
+        .unwrap();
+
+        let db =
+            KeyValueDatabase::open_trees(KeyValueDatabase::open_engine(&config).unwrap(), &config)
+                .unwrap();
+
+        (db, path)
+    }
+
+    #[test]
+    fn rocksdb_files() {
+        for name in [
+            "CURRENT",
+            "IDENTITY",
+            "LOCK",
+            "LOG",
+            "LOG.old.1700000000000000",
+            "MANIFEST-000005",
+            "OPTIONS-000007",
+            "000004.log",
+            "000012.sst",
+        ] {
+            assert!(is_rocksdb_file(name), "{name}");
+        }
+
+        for name in ["conduit.toml", "media", "backup.sst", ".sst", "notes.log"] {
+            assert!(!is_rocksdb_file(name), "{name}");
+        }
+    }
+}
@@ -1,15 +1,21 @@
-use std::{future::Future, io, net::SocketAddr, sync::atomic, time::Duration};
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    sync::atomic,
+    time::{Duration, Instant},
+};
 
 use axum::{
     body::Body,
     extract::{FromRequestParts, MatchedPath},
     middleware::map_response,
     response::{IntoResponse, Response},
-    routing::{any, get, on, MethodFilter},
+    routing::{any, delete, get, on, post, put, MethodFilter},
     Router,
 };
 use axum_server::{bind, bind_rustls, tls_rustls::RustlsConfig, Handle as ServerHandle};
-use conduit::api::{client_server, server_server};
+use conduit::api::{admin_server, client_server, server_server};
 use figment::{
     providers::{Env, Format, Toml},
     value::Uncased,
@@ -46,15 +52,15 @@ use tikv_jemallocator::Jemalloc;
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
-static SUB_TABLES: [&str; 3] = ["well_known", "tls", "media"]; // Not doing `proxy` cause setting that with env vars would be a pain
+static SUB_TABLES: [&str; 5] = ["well_known", "tls", "media", "email", "ldap"]; // Not doing `proxy` cause setting that with env vars would be a pain
 
 // Yeah, I know it's terrible, but since it seems the container users dont want syntax like A[B][C]="...",
 // this is what we have to deal with. Also see: https://github.com/SergioBenitez/Figment/issues/12#issuecomment-801449465
-static SUB_SUB_TABLES: [&str; 2] = ["directory_structure", "retention"];
+static SUB_SUB_TABLES: [&str; 3] = ["directory_structure", "retention", "templates"];
 
 #[tokio::main]
 async fn main() {
-    clap::parse();
+    let args = clap::parse();
 
     // Initialize config
     let raw_config = Figment::new()
@@ -178,6 +184,27 @@ async fn main() {
     #[cfg(unix)]
     maximize_fd_limit().expect("should be able to increase the soft limit to the hard limit");
 
+    match args.command {
+        Some(clap::Command::Backup { path }) => {
+            if let Err(error) = KeyValueDatabase::backup(&config, &path) {
+                error!(?error, "The database couldn't be backed up");
+                std::process::exit(1);
+            }
+
+            info!("Wrote a backup of the database to {}", path.display());
+            return;
+        }
+        Some(clap::Command::Restore { path }) => {
+            if let Err(error) = KeyValueDatabase::restore(&config, &path) {
+                error!(?error, "The database couldn't be restored");
+                std::process::exit(1);
+            }
+
+            return;
+        }
+        None => {}
+    }
+
     info!("Loading database");
     if let Err(error) = KeyValueDatabase::load_or_create(config).await {
         error!(?error, "The database couldn't be loaded or created");
@@ -245,6 +272,19 @@ async fn run_server() -> io::Result<()> {
     let app = routes(config).layer(middlewares).into_make_service();
     let handle = ServerHandle::new();
 
+    if let Some(metrics_port) = config.metrics_port {
+        let metrics_addr = SocketAddr::from((config.metrics_address, metrics_port));
+        let metrics_app = Router::new()
+            .route("/_conduit/metrics", get(metrics))
+            .into_make_service();
+
+        tokio::spawn(async move {
+            if let Err(e) = bind(metrics_addr).serve(metrics_app).await {
+                error!("Metrics listener failed: {e}");
+            }
+        });
+    }
+
     tokio::spawn(shutdown_signal(handle.clone()));
 
     match &config.tls {
@@ -310,7 +350,14 @@ fn routes(config: &Config) -> Router {
         .ruma_route(client_server::get_register_available_route)
         .ruma_route(client_server::register_route)
         .ruma_route(client_server::get_login_types_route)
+        .ruma_route(client_server::sso_login_route)
+        .ruma_route(client_server::sso_login_with_provider_route)
+        .route(
+            "/_conduit/client/oidc/callback",
+            get(client_server::oidc_callback_route),
+        )
         .ruma_route(client_server::login_route)
+        .ruma_route(client_server::refresh_token_route)
         .ruma_route(client_server::whoami_route)
         .ruma_route(client_server::logout_route)
         .ruma_route(client_server::logout_all_route)
@@ -319,6 +366,14 @@ fn routes(config: &Config) -> Router {
         .ruma_route(client_server::third_party_route)
         .ruma_route(client_server::request_3pid_management_token_via_email_route)
         .ruma_route(client_server::request_3pid_management_token_via_msisdn_route)
+        .ruma_route(client_server::request_password_change_token_via_email_route)
+        .route(
+            "/_conduit/client/3pid/email/submit_token",
+            get(client_server::submit_email_token_route),
+        )
+        .ruma_route(client_server::add_3pid_route)
+        .ruma_route(client_server::delete_3pid_route)
+        .ruma_route(client_server::bind_3pid_route)
         .ruma_route(client_server::get_capabilities_route)
         .ruma_route(client_server::get_pushrules_all_route)
         .ruma_route(client_server::set_pushrule_route)
@@ -419,11 +474,14 @@ fn routes(config: &Config) -> Router {
         .ruma_route(client_server::sync_events_v5_route)
         .ruma_route(client_server::get_context_route)
         .ruma_route(client_server::get_message_events_route)
+        .ruma_route(client_server::get_event_by_timestamp_route)
         .ruma_route(client_server::search_events_route)
         .ruma_route(client_server::turn_server_route)
         .ruma_route(client_server::send_event_to_device_route)
         .ruma_route(client_server::get_media_config_route)
         .ruma_route(client_server::get_media_config_auth_route)
+        .ruma_route(client_server::get_media_preview_route)
+        .ruma_route(client_server::get_media_preview_auth_route)
         .ruma_route(client_server::create_content_route)
         .ruma_route(client_server::get_content_route)
         .ruma_route(client_server::get_content_auth_route)
@@ -436,6 +494,10 @@ fn routes(config: &Config) -> Router {
         .ruma_route(client_server::update_device_route)
         .ruma_route(client_server::delete_device_route)
         .ruma_route(client_server::delete_devices_route)
+        .ruma_route(client_server::put_dehydrated_device_route)
+        .ruma_route(client_server::get_dehydrated_device_route)
+        .ruma_route(client_server::delete_dehydrated_device_route)
+        .ruma_route(client_server::get_dehydrated_events_route)
         .ruma_route(client_server::get_tags_route)
         .ruma_route(client_server::update_tag_route)
         .ruma_route(client_server::delete_tag_route)
@@ -460,6 +522,54 @@ fn routes(config: &Config) -> Router {
             "/_matrix/client/v3/rooms/{room_id}/initialSync",
             get(initial_sync),
         )
+        .route(
+            "/_conduit/admin/v1/users",
+            post(admin_server::create_user_route),
+        )
+        .route(
+            "/_conduit/admin/v1/users/{user_id}/deactivate",
+            post(admin_server::deactivate_user_route),
+        )
+        .route(
+            "/_conduit/admin/v1/users/{user_id}/reset_password",
+            post(admin_server::reset_password_route),
+        )
+        .route(
+            "/_conduit/admin/v1/server_notices",
+            post(admin_server::send_server_notice_route),
+        )
+        .route(
+            "/_conduit/admin/v1/media/{server_name}/{media_id}",
+            get(admin_server::query_media_route),
+        )
+        .route(
+            "/_conduit/admin/v1/media/purge",
+            post(admin_server::purge_media_route),
+        )
+        .route(
+            "/_conduit/admin/v1/media/block",
+            post(admin_server::block_media_route),
+        )
+        .route(
+            "/_conduit/admin/v1/rooms",
+            get(admin_server::list_rooms_route),
+        )
+        .route(
+            "/_conduit/admin/v1/rooms/{room_id}/disabled",
+            put(admin_server::disable_room_route),
+        )
+        .route(
+            "/_conduit/admin/v1/appservices",
+            get(admin_server::list_appservices_route).post(admin_server::register_appservice_route),
+        )
+        .route(
+            "/_conduit/admin/v1/appservices/{id}",
+            delete(admin_server::unregister_appservice_route),
+        )
+        .route(
+            "/_conduit/admin/v1/config",
+            get(admin_server::get_config_route),
+        )
         .route("/", get(it_works))
         .fallback(not_found);
 
@@ -474,12 +584,15 @@ fn routes(config: &Config) -> Router {
                 "/_matrix/key/v2/server/{key_id}",
                 get(server_server::get_server_keys_deprecated_route),
             )
+            .ruma_route(server_server::get_remote_server_keys_route)
+            .ruma_route(server_server::get_remote_server_keys_batch_route)
             .ruma_route(server_server::get_public_rooms_route)
             .ruma_route(server_server::get_public_rooms_filtered_route)
             .ruma_route(server_server::send_transaction_message_route)
             .ruma_route(server_server::get_event_route)
             .ruma_route(server_server::get_backfill_route)
             .ruma_route(server_server::get_missing_events_route)
+            .ruma_route(server_server::get_event_by_timestamp_route)
             .ruma_route(server_server::get_event_authorization_route)
             .ruma_route(server_server::get_room_state_route)
             .ruma_route(server_server::get_room_state_ids_route)
@@ -559,6 +672,15 @@ async fn initial_sync(_uri: Uri) -> impl IntoResponse {
     )
 }
 
+async fn metrics() -> impl IntoResponse {
+    services().metrics.render().await.map(|metrics| {
+        (
+            [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+            metrics,
+        )
+    })
+}
+
 async fn it_works() -> &'static str {
     "Hello from Conduit!"
 }
@@ -605,9 +727,15 @@ macro_rules! impl_ruma_handler {
 
                 for path in meta.history.all_paths() {
                     let handler = self.clone();
+                    let method = meta.method.clone();
+
+                    router = router.route(path, on(method_filter, move |$( $ty: $ty, )* req| async move {
+                        let start = Instant::now();
+                        let response = handler($($ty,)* req).await.map(RumaResponse).into_response();
+
+                        services().metrics.record_request(&method, path, response.status(), start.elapsed());
 
-                    router = router.route(path, on(method_filter, |$( $ty: $ty, )* req| async move {
-                        handler($($ty,)* req).await.map(RumaResponse)
+                        response
                     }))
                 }
 
@@ -1,7 +1,8 @@
 use std::{
     borrow::Cow,
-    collections::BTreeMap,
+    collections::{BTreeMap, HashSet},
     convert::TryFrom,
+    path::PathBuf,
     sync::Arc,
     time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
@@ -34,13 +35,14 @@ use ruma::{
     },
     room_version_rules::RoomVersionRules,
     EventId, MilliSecondsSinceUnixEpoch, MxcUri, OwnedMxcUri, OwnedRoomAliasId, OwnedRoomId,
-    OwnedServerName, RoomAliasId, RoomId, RoomVersionId, ServerName, UserId,
+    OwnedServerName, OwnedUserId, RoomAliasId, RoomId, RoomVersionId, ServerName, UserId,
 };
 use serde_json::value::to_raw_value;
 use tokio::sync::{mpsc, Mutex, RwLock};
+use tracing::warn;
 
 use crate::{
-    api::client_server::{self, leave_all_rooms, AUTO_GEN_PASSWORD_LENGTH},
+    api::client_server::{self, leave_all_rooms, leave_room, AUTO_GEN_PASSWORD_LENGTH},
     services,
     utils::{self, HtmlEscape},
     Error, PduEvent, Result,
@@ -52,6 +54,7 @@ use super::{
         MediaQueryThumbInfo, ServerNameOrUserId,
     },
     pdu::PduBuilder,
+    server_notices,
 };
 
 #[cfg_attr(test, derive(Debug))]
@@ -329,6 +332,15 @@ enum AdminCommand {
     /// Clears all of Conduit's service caches with index smaller than the amount
     ClearServiceCaches { amount: u32 },
 
+    /// Write a consistent snapshot of the database to a directory on the server
+    ///
+    /// Conduit keeps running while the backup is made. The backup can be
+    /// restored using `conduit restore`. Media files are not included.
+    BackupDatabase {
+        /// The directory to write the backup to, which must not exist yet
+        path: PathBuf,
+    },
+
     /// Show configuration values
     ShowConfig,
 
@@ -354,6 +366,50 @@ enum AdminCommand {
     /// Enables incoming federation handling for a room again.
     EnableRoom { room_id: Box<RoomId> },
 
+    /// Makes all local users leave a room and prevents anyone from joining it through this
+    /// server again.
+    ///
+    /// The room is also removed from the room directory and its local aliases are deleted.
+    ShutdownRoom {
+        room_id: Box<RoomId>,
+        #[arg(short, long)]
+        /// Create a new room with this notice and move the local users there
+        notice: Option<String>,
+    },
+
+    /// Deletes the timeline, state, search index, aliases and all other data of a room.
+    ///
+    /// Local users can't be in the room anymore, so use shutdown-room first.
+    PurgeRoom {
+        room_id: Box<RoomId>,
+        #[arg(short = 'm', long)]
+        /// Also purge all media that was sent in the room
+        ///
+        /// Note: This will also delete identical media sent elsewhere
+        purge_media: bool,
+    },
+
+    /// Sends a server notice to a local user, or to all local users if no user is given
+    ///
+    /// Every user receives the notices in their own room, which is tagged with `m.server_notice`
+    /// and created when needed. The notice must be provided in a Markdown code-block below the
+    /// command.
+    ///
+    /// [commandbody]()
+    /// # ```
+    /// # Notice text here
+    /// # ```
+    SendServerNotice {
+        user_id: Option<Box<UserId>>,
+        #[arg(long)]
+        /// Send a resource-limit notice (`m.server_notice.usage_limit_reached`) for this kind of
+        /// limit, e.g. `monthly_active_user`
+        limit_type: Option<String>,
+        #[arg(long, requires = "limit_type")]
+        /// Where users can contact the admins about the limit, e.g. `mailto:admin@example.com`
+        admin_contact: Option<String>,
+    },
+
     /// Sign a json object using Conduit's signing keys, putting the json in a codeblock
     SignJson,
 
@@ -780,6 +836,26 @@ impl Service {
 
                 RoomMessageEventContent::text_plain("Done.").into()
             }
+            AdminCommand::BackupDatabase { path } => {
+                let start = Instant::now();
+                let backup_path = path.clone();
+                let result =
+                    tokio::task::spawn_blocking(move || services().globals.db.backup(&backup_path))
+                        .await
+                        .expect("backup task does not panic");
+
+                match result {
+                    Ok(()) => RoomMessageEventContent::text_plain(format!(
+                        "Wrote a backup of the database to {} in {:?}.",
+                        path.display(),
+                        start.elapsed()
+                    )),
+                    Err(e) => RoomMessageEventContent::text_plain(format!(
+                        "Failed to back up the database: {e}"
+                    )),
+                }
+                .into()
+            }
             AdminCommand::ShowConfig => {
                 // Construct and send the response
                 RoomMessageEventContent::text_plain(format!("{}", services().globals.config)).into()
@@ -936,6 +1012,181 @@ impl Service {
                 services().rooms.metadata.disable_room(&room_id, false)?;
                 RoomMessageEventContent::text_plain("Room enabled.").into()
             }
+            AdminCommand::ShutdownRoom { room_id, notice } => {
+                if services().admin.get_admin_room()?.as_deref() == Some(&*room_id) {
+                    RoomMessageEventContent::text_plain("The admin room can't be shut down.")
+                } else {
+                    services().rooms.metadata.ban_room(&room_id, true)?;
+                    services().rooms.directory.set_not_public(&room_id)?;
+
+                    let aliases = services()
+                        .rooms
+                        .alias
+                        .local_aliases_for_room(&room_id)
+                        .filter_map(Result::ok)
+                        .collect::<Vec<_>>();
+                    for alias in aliases {
+                        services()
+                            .rooms
+                            .alias
+                            .remove_alias(&alias, services().globals.server_user())?;
+                    }
+
+                    let local_users = services()
+                        .rooms
+                        .state_cache
+                        .room_members(&room_id)
+                        .chain(
+                            services()
+                                .rooms
+                                .state_cache
+                                .room_members_invited(&room_id),
+                        )
+                        .filter_map(Result::ok)
+                        .filter(|user_id| {
+                            user_id.server_name() == services().globals.server_name()
+                        })
+                        .collect::<Vec<_>>();
+
+                    let reason = "This room has been shut down by the server administrators.";
+                    let mut failed_count = 0;
+                    for user_id in &local_users {
+                        if let Err(e) =
+                            leave_room(user_id, &room_id, Some(reason.to_owned())).await
+                        {
+                            warn!("Failed to make {user_id} leave {room_id}: {e}");
+                            failed_count += 1;
+                        }
+                    }
+
+                    let mut message = format!(
+                        "Room shut down, {} local users were removed.",
+                        local_users.len() - failed_count
+                    );
+                    if failed_count != 0 {
+                        message.push_str(&format!(
+                            "\nFailed to remove {failed_count} users, check the logs for more details"
+                        ));
+                    }
+                    if let Some(notice) = notice {
+                        let new_room_id = self.create_notice_room(&notice, &local_users).await?;
+                        message.push_str(&format!("\nThe users were moved to {new_room_id}."));
+                    }
+
+                    RoomMessageEventContent::text_plain(message)
+                }
+                .into()
+            }
+            AdminCommand::PurgeRoom {
+                room_id,
+                purge_media,
+            } => {
+                if services().admin.get_admin_room()?.as_deref() == Some(&*room_id) {
+                    RoomMessageEventContent::text_plain("The admin room can't be purged.")
+                } else if services()
+                    .rooms
+                    .state_cache
+                    .server_in_room(services().globals.server_name(), &room_id)?
+                {
+                    RoomMessageEventContent::text_plain(
+                        "Local users are still in the room. Use shutdown-room first.",
+                    )
+                } else {
+                    let mutex_state = Arc::clone(
+                        services()
+                            .globals
+                            .roomid_mutex_state
+                            .write()
+                            .await
+                            .entry(room_id.clone().into())
+                            .or_default(),
+                    );
+                    let state_lock = mutex_state.lock().await;
+
+                    let media = if purge_media {
+                        let mut media = HashSet::new();
+                        for pdu in services()
+                            .rooms
+                            .timeline
+                            .all_pdus(services().globals.server_user(), &room_id)?
+                            .filter_map(Result::ok)
+                            .map(|(_, pdu)| pdu)
+                        {
+                            if let Ok(content) = serde_json::from_str(pdu.content.get()) {
+                                media_in_content(&content, &mut media);
+                            }
+                        }
+                        media.into_iter().collect::<Vec<_>>()
+                    } else {
+                        Vec::new()
+                    };
+
+                    services().rooms.metadata.purge_room(&room_id)?;
+                    drop(state_lock);
+                    services().clear_caches(u32::MAX).await;
+
+                    let failed_count = if media.is_empty() {
+                        0
+                    } else {
+                        services().media.purge(&media, false).await.len()
+                    };
+
+                    if failed_count == 0 {
+                        RoomMessageEventContent::text_plain("Room purged.")
+                    } else {
+                        RoomMessageEventContent::text_plain(format!(
+                            "Room purged, but {failed_count} media failed to be purged, check the logs for more details"
+                        ))
+                    }
+                }
+                .into()
+            }
+            AdminCommand::SendServerNotice {
+                user_id,
+                limit_type,
+                admin_contact,
+            } => {
+                if body.len() > 2 && body[0].trim() == "```" && body.last().unwrap().trim() == "```"
+                {
+                    let notice = body[1..body.len() - 1].join("\n");
+                    let content = match limit_type {
+                        Some(limit_type) => server_notices::usage_limit_reached_notice(
+                            notice,
+                            &limit_type,
+                            admin_contact,
+                        ),
+                        None => RoomMessageEventContent::text_plain(notice),
+                    };
+
+                    match user_id {
+                        Some(user_id) => {
+                            services().server_notices.send(&user_id, &content).await?;
+
+                            RoomMessageEventContent::text_plain(format!(
+                                "Sent the notice to {user_id}."
+                            ))
+                        }
+                        None => {
+                            let (sent_count, failed_count) =
+                                services().server_notices.send_to_all(&content).await;
+
+                            let mut message = format!("Sent the notice to {sent_count} users.");
+                            if failed_count != 0 {
+                                message.push_str(&format!(
+                                    "\nFailed to send it to {failed_count} users, check the logs for more details"
+                                ));
+                            }
+
+                            RoomMessageEventContent::text_plain(message)
+                        }
+                    }
+                } else {
+                    RoomMessageEventContent::text_plain(
+                        "Expected code block in command body. Add --help for details.",
+                    )
+                }
+                .into()
+            }
             AdminCommand::DeactivateUser {
                 leave_rooms,
                 user_id,
@@ -2101,6 +2352,67 @@ impl Service {
 
         services().rooms.state_cache.is_joined(user_id, &admin_room)
     }
+
+    /// Creates a room where only the server user can send messages, posts the notice and makes
+    /// the local users join it. Used as the replacement of rooms that were shut down.
+    async fn create_notice_room(
+        &self,
+        notice: &str,
+        user_ids: &[OwnedUserId],
+    ) -> Result<OwnedRoomId> {
+        let conduit_user = services().globals.server_user();
+
+        let (room_id, mutex_state) = services()
+            .server_notices
+            .create_room(
+                true,
+                vec![(
+                    TimelineEventType::RoomMessage,
+                    to_raw_value(&RoomMessageEventContent::notice_plain(notice))
+                        .expect("event is valid, we just created it"),
+                    None,
+                )],
+            )
+            .await?;
+        let state_lock = mutex_state.lock().await;
+
+        let member = |membership| {
+            to_raw_value(&RoomMemberEventContent::new(membership))
+                .expect("event is valid, we just created it")
+        };
+
+        // Invite and join the users
+        for user_id in user_ids {
+            for (membership, sender) in [
+                (MembershipState::Invite, conduit_user),
+                (MembershipState::Join, &**user_id),
+            ] {
+                if let Err(e) = services()
+                    .rooms
+                    .timeline
+                    .build_and_append_pdu(
+                        PduBuilder {
+                            event_type: TimelineEventType::RoomMember,
+                            content: member(membership),
+                            unsigned: None,
+                            state_key: Some(user_id.to_string()),
+                            redacts: None,
+                            timestamp: None,
+                        },
+                        sender,
+                        &room_id,
+                        &state_lock,
+                    )
+                    .await
+                {
+                    warn!("Failed to move {user_id} to {room_id}: {e}");
+                    break;
+                }
+            }
+        }
+
+        Ok(room_id)
+    }
 }
 
 fn userids_from_body<'a>(
@@ -2193,6 +2505,28 @@ fn media_from_body(body: Vec<&str>) -> Result<Vec<(OwnedServerName, String)>, Me
     }
 }
 
+/// Collects the MXC URIs of all media referenced in the content of an event
+fn media_in_content(value: &serde_json::Value, media: &mut HashSet<(OwnedServerName, String)>) {
+    match value {
+        serde_json::Value::String(s) if s.starts_with("mxc://") => {
+            if let Ok((server_name, media_id)) = <&MxcUri>::from(s.as_str()).parts() {
+                media.insert((server_name.to_owned(), media_id.to_owned()));
+            }
+        }
+        serde_json::Value::Array(values) => {
+            for value in values {
+                media_in_content(value, media);
+            }
+        }
+        serde_json::Value::Object(map) => {
+            for value in map.values() {
+                media_in_content(value, media);
+            }
+        }
+        _ => {}
+    }
+}
+
 fn unix_secs_from_duration(duration: Duration) -> Result<u64> {
     SystemTime::now()
         .checked_sub(duration).ok_or_else(||Error::AdminCommand("Given timeframe cannot be represented as system time, please try again with a shorter time-frame"))
@@ -2231,4 +2565,29 @@ mod test {
         assert!(error.contains("Commands:"));
         assert!(error.contains("Options:"));
     }
+
+    #[test]
+    fn media_in_event_content() {
+        let content = serde_json::json!({
+            "msgtype": "m.image",
+            "body": "mxc://not.media/in-body text",
+            "url": "mxc://conduit.rs/image",
+            "info": {
+                "thumbnail_url": "mxc://conduit.rs/thumbnail",
+            },
+            "avatars": ["mxc://example.com/avatar", "https://example.com/avatar"],
+        });
+
+        let mut media = HashSet::new();
+        media_in_content(&content, &mut media);
+
+        let mut media = media.into_iter().collect::<Vec<_>>();
+        media.sort();
+        let expected: Vec<(OwnedServerName, String)> = vec![
+            ("conduit.rs".try_into().unwrap(), "image".to_owned()),
+            ("conduit.rs".try_into().unwrap(), "thumbnail".to_owned()),
+            ("example.com".try_into().unwrap(), "avatar".to_owned()),
+        ];
+        assert_eq!(media, expected);
+    }
 }
@@ -1,4 +1,4 @@
-use ruma::api::appservice::Registration;
+use ruma::{api::appservice::Registration, OwnedUserId, UserId};
 
 use crate::Result;
 
@@ -18,4 +18,13 @@ pub trait Data: Send + Sync {
     fn iter_ids<'a>(&'a self) -> Result<Box<dyn Iterator<Item = Result<String>> + 'a>>;
 
     fn all(&self) -> Result<Vec<(String, Registration)>>;
+
+    /// Remembers that the appservice logged in or registered as the user.
+    fn add_user(&self, appservice_id: &str, user_id: &UserId) -> Result<()>;
+
+    /// Returns the users the appservice logged in or registered as.
+    fn iter_users<'a>(
+        &'a self,
+        appservice_id: &str,
+    ) -> Box<dyn Iterator<Item = Result<OwnedUserId>> + 'a>;
 }
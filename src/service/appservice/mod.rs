@@ -8,7 +8,7 @@ use futures_util::Future;
 use regex::RegexSet;
 use ruma::{
     api::appservice::{Namespace, Registration},
-    RoomAliasId, RoomId, UserId,
+    OwnedUserId, RoomAliasId, RoomId, UserId,
 };
 use tokio::sync::RwLock;
 
@@ -167,6 +167,20 @@ impl Service {
         self.db.unregister_appservice(service_name)
     }
 
+    /// Remembers that the appservice logged in or registered as the user, so that it gets the
+    /// device changes of the user.
+    pub fn add_user(&self, appservice_id: &str, user_id: &UserId) -> Result<()> {
+        self.db.add_user(appservice_id, user_id)
+    }
+
+    /// Returns the users the appservice logged in or registered as.
+    pub fn iter_users<'a>(
+        &'a self,
+        appservice_id: &str,
+    ) -> impl Iterator<Item = Result<OwnedUserId>> + 'a {
+        self.db.iter_users(appservice_id)
+    }
+
     pub async fn get_registration(&self, id: &str) -> Option<Registration> {
         self.registration_info
             .read()
@@ -0,0 +1,237 @@
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use ruma::api::client::error::ErrorKind;
+use tracing::warn;
+
+use crate::{config::EmailConfig, Error, Result};
+
+/// Sends emails through the configured SMTP server.
+pub struct Service {
+    mailer: Option<(AsyncSmtpTransport<Tokio1Executor>, Mailbox)>,
+}
+
+impl Service {
+    pub fn build(config: Option<&EmailConfig>) -> Result<Self> {
+        let mailer = config
+            .map(|config| {
+                let transport = AsyncSmtpTransport::<Tokio1Executor>::from_url(&config.smtp_url)
+                    .map_err(|_| Error::bad_config("email.smtp_url is not a valid SMTP URL."))?
+                    .build();
+                let from = config
+                    .from
+                    .parse()
+                    .map_err(|_| Error::bad_config("email.from is not a valid mailbox."))?;
+
+                Ok::<_, Error>((transport, from))
+            })
+            .transpose()?;
+
+        Ok(Self { mailer })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.mailer.is_some()
+    }
+
+    /// Sends a plain text email.
+    pub async fn send(&self, to: &str, subject: &str, body: String) -> Result<()> {
+        let Some((transport, from)) = &self.mailer else {
+            return Err(Error::BadRequest(
+                ErrorKind::Unrecognized,
+                "Sending emails is not configured on this server.",
+            ));
+        };
+
+        let to: Mailbox = to
+            .parse()
+            .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid email address."))?;
+
+        let message = Message::builder()
+            .from(from.clone())
+            .to(to)
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(body)
+            .map_err(|e| {
+                warn!("Failed to build email: {e}");
+                Error::BadServerResponse("Failed to build email.")
+            })?;
+
+        transport.send(message).await.map_err(|e| {
+            warn!("Failed to send email: {e}");
+            Error::BadServerResponse("Failed to send email.")
+        })?;
+
+        Ok(())
+    }
+}
+
+/// Replaces the `{name}` placeholders of a template. Unknown placeholders are kept, and
+/// placeholders in the values are not replaced, as they can come from other users.
+pub fn render(template: &str, values: &[(&str, &str)]) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let value = rest.find('}').and_then(|end| {
+            values
+                .iter()
+                .find(|(name, _)| *name == &rest[1..end])
+                .map(|(_, value)| (end, value))
+        });
+
+        match value {
+            Some((end, value)) => {
+                rendered.push_str(value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                rendered.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    rendered.push_str(rest);
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::{SocketAddr, TcpListener},
+        sync::mpsc,
+        thread,
+        time::Duration,
+    };
+
+    use super::{render, Service};
+    use crate::config::{EmailConfig, EmailTemplates};
+
+    /// Accepts one SMTP session and passes on the data of the mails it receives.
+    fn smtp_sink() -> (SocketAddr, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let mut read_line = || {
+                let mut line = String::new();
+                (reader.read_line(&mut line).unwrap() > 0).then_some(line)
+            };
+
+            writer.write_all(b"220 localhost ESMTP\r\n").unwrap();
+
+            while let Some(command) = read_line() {
+                let command = command.to_ascii_uppercase();
+
+                if command.starts_with("DATA") {
+                    writer.write_all(b"354 Go ahead\r\n").unwrap();
+
+                    let mut data = String::new();
+                    while let Some(line) = read_line() {
+                        if line == ".\r\n" {
+                            break;
+                        }
+                        data.push_str(&line);
+                    }
+
+                    sender.send(data).unwrap();
+                    writer.write_all(b"250 OK\r\n").unwrap();
+                } else if command.starts_with("QUIT") {
+                    writer.write_all(b"221 Bye\r\n").unwrap();
+                    break;
+                } else {
+                    writer.write_all(b"250 OK\r\n").unwrap();
+                }
+            }
+        });
+
+        (address, receiver)
+    }
+
+    #[tokio::test]
+    async fn send_to_smtp_server() {
+        let (address, received) = smtp_sink();
+
+        let service = Service::build(Some(&EmailConfig {
+            smtp_url: format!("smtp://{address}"),
+            from: "Conduit <conduit@example.com>".to_owned(),
+            notification_delay: Duration::from_secs(0),
+            templates: EmailTemplates::default(),
+        }))
+        .unwrap();
+
+        service
+            .send(
+                "alice@example.com",
+                "3 new messages in Lobby",
+                "Hello Alice".to_owned(),
+            )
+            .await
+            .unwrap();
+
+        let mail = received.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(mail.contains("From: Conduit <conduit@example.com>\r\n"));
+        assert!(mail.contains("To: alice@example.com\r\n"));
+        assert!(mail.contains("Subject: 3 new messages in Lobby\r\n"));
+        assert!(mail.contains("\r\n\r\nHello Alice"));
+    }
+
+    #[tokio::test]
+    async fn send_rejects_invalid_addresses() {
+        let (address, _received) = smtp_sink();
+
+        let service = Service::build(Some(&EmailConfig {
+            smtp_url: format!("smtp://{address}"),
+            from: "conduit@example.com".to_owned(),
+            notification_delay: Duration::from_secs(0),
+            templates: EmailTemplates::default(),
+        }))
+        .unwrap();
+
+        assert!(service
+            .send("not an address", "Subject", "Body".to_owned())
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn render_placeholders() {
+        assert_eq!(
+            render(
+                "{count} new messages in {room_name}",
+                &[("count", "3"), ("room_name", "Lobby")]
+            ),
+            "3 new messages in Lobby"
+        );
+    }
+
+    #[test]
+    fn render_keeps_unknown_placeholders() {
+        assert_eq!(
+            render("{unknown} and {room_name} {", &[("room_name", "Lobby")]),
+            "{unknown} and Lobby {"
+        );
+    }
+
+    #[test]
+    fn render_does_not_replace_in_values() {
+        assert_eq!(
+            render(
+                "{messages} by {sender}",
+                &[("messages", "{sender}"), ("sender", "Alice")]
+            ),
+            "{sender} by Alice"
+        );
+    }
+}
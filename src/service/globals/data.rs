@@ -1,5 +1,6 @@
 use std::{
     collections::BTreeMap,
+    path::Path,
     time::{Duration, SystemTime},
 };
 
@@ -7,7 +8,7 @@ use crate::{services, Result};
 use async_trait::async_trait;
 use ruma::{
     api::federation::discovery::{OldVerifyKey, ServerSigningKeys, VerifyKey},
-    serde::Base64,
+    serde::{Base64, Raw},
     signatures::Ed25519KeyPair,
     DeviceId, MilliSecondsSinceUnixEpoch, ServerName, UserId,
 };
@@ -76,7 +77,13 @@ pub trait Data: Send + Sync {
     async fn watch(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()>;
     fn cleanup(&self) -> Result<()>;
     fn memory_usage(&self) -> String;
+    /// Returns the number of entries in each of the in-memory caches of the database.
+    fn cache_sizes(&self) -> Vec<(&'static str, usize)>;
+    /// Returns the approximate memory usage of the database engine in bytes, if it is known.
+    fn database_memory_usage(&self) -> Vec<(&'static str, u64)>;
     fn clear_caches(&self, amount: u32);
+    /// Writes a consistent snapshot of the database to `path`, while it is in use.
+    fn backup(&self, path: &Path) -> Result<()>;
     fn load_keypair(&self) -> Result<Ed25519KeyPair>;
     fn remove_keypair(&self) -> Result<()>;
     /// Only extends the cached keys, not moving any verify_keys to old_verify_keys, as if we suddenly
@@ -96,6 +103,15 @@ pub trait Data: Send + Sync {
 
     /// This returns an empty `Ok(BTreeMap<..>)` when there are no keys found for the server.
     fn signing_keys_for(&self, origin: &ServerName) -> Result<Option<SigningKeys>>;
+    /// Stores the keys of a server exactly as the server returned them, for serving them as a
+    /// notary.
+    fn set_origin_server_keys(
+        &self,
+        origin: &ServerName,
+        keys: &Raw<ServerSigningKeys>,
+    ) -> Result<()>;
+    /// Returns the last keys the server returned, with only its own signature.
+    fn origin_server_keys(&self, origin: &ServerName) -> Result<Option<Raw<ServerSigningKeys>>>;
     fn database_version(&self) -> Result<u64>;
     fn bump_database_version(&self, new_version: u64) -> Result<()>;
 }
@@ -1,8 +1,10 @@
 mod data;
 pub use data::{Data, SigningKeys};
 use ruma::{
-    room_version_rules::RoomVersionRules, serde::Base64, MilliSecondsSinceUnixEpoch, OwnedDeviceId,
-    OwnedEventId, OwnedRoomAliasId, OwnedRoomId, OwnedServerName, OwnedUserId, RoomAliasId,
+    room_version_rules::RoomVersionRules,
+    serde::{Base64, Raw},
+    MilliSecondsSinceUnixEpoch, OwnedDeviceId, OwnedEventId, OwnedRoomAliasId, OwnedRoomId,
+    OwnedServerName, OwnedUserId, RoomAliasId,
 };
 
 use crate::api::server_server::DestinationResponse;
@@ -14,6 +16,7 @@ use crate::{
 use futures_util::FutureExt;
 use hickory_resolver::TokioResolver;
 use hyper_util::client::legacy::connect::dns::{GaiResolver, Name as HyperName};
+use ipnet::IpNet;
 use reqwest::dns::{Addrs, Name, Resolve, Resolving};
 use ruma::{
     api::{client::sync::sync_events, federation::discovery::ServerSigningKeys},
@@ -58,6 +61,7 @@ pub struct Service {
     jwt_decoding_key: Option<jsonwebtoken::DecodingKey>,
     federation_client: reqwest::Client,
     default_client: reqwest::Client,
+    url_preview_client: reqwest::Client,
     pub stable_room_versions: Vec<RoomVersionId>,
     pub unstable_room_versions: Vec<RoomVersionId>,
     pub bad_event_ratelimiter: Arc<RwLock<HashMap<OwnedEventId, RateLimitState>>>,
@@ -154,6 +158,49 @@ impl Resolve for Resolver {
     }
 }
 
+/// Resolves hostnames like the default client, but refuses to resolve names pointing to addresses
+/// in the `url_preview_ip_range_denylist`. This makes sure url previews can't be used to reach
+/// internal services, even if the DNS record changes between checking and connecting.
+pub struct UrlPreviewResolver {
+    inner: GaiResolver,
+    denylist: Arc<Vec<IpNet>>,
+}
+
+impl UrlPreviewResolver {
+    pub fn new(denylist: Arc<Vec<IpNet>>) -> Self {
+        UrlPreviewResolver {
+            inner: GaiResolver::new(),
+            denylist,
+        }
+    }
+}
+
+impl Resolve for UrlPreviewResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let this = &mut self.inner.clone();
+        let denylist = Arc::clone(&self.denylist);
+        Box::pin(
+            TowerService::<HyperName>::call(
+                this,
+                HyperName::from_str(name.as_str())
+                    .expect("reqwest Name is just wrapper for hyper-util Name"),
+            )
+            .map(move |result| {
+                let addrs = result
+                    .map_err(|err| -> Box<dyn StdError + Send + Sync> { Box::new(err) })?
+                    .collect::<Vec<_>>();
+
+                if addrs.iter().any(|addr| is_ip_denied(&denylist, addr.ip())) {
+                    return Err("Host resolves to a denied IP address".into());
+                }
+
+                let addrs: Addrs = Box::new(addrs.into_iter());
+                Ok(addrs)
+            }),
+        )
+    }
+}
+
 impl Service {
     pub fn load(db: &'static dyn Data, config: Config) -> Result<Self> {
         let keypair = db.load_keypair();
@@ -175,6 +222,22 @@ impl Service {
             .map(|secret| jsonwebtoken::DecodingKey::from_secret(secret.as_bytes()));
 
         let default_client = reqwest_client_builder(&config)?.build()?;
+        let url_preview_denylist = Arc::new(config.url_preview_ip_range_denylist.clone());
+        let url_preview_client = reqwest_client_builder(&config)?
+            .dns_resolver(Arc::new(UrlPreviewResolver::new(Arc::clone(
+                &url_preview_denylist,
+            ))))
+            .redirect(reqwest::redirect::Policy::custom(move |attempt| {
+                // Hostnames are checked by the resolver, but IP addresses are used as-is
+                if attempt.previous().len() >= 10 {
+                    attempt.error("Too many redirects")
+                } else if is_url_denied(&url_preview_denylist, attempt.url()) {
+                    attempt.error("Redirect to a denied IP address")
+                } else {
+                    attempt.follow()
+                }
+            }))
+            .build()?;
         let federation_client = reqwest_client_builder(&config)?
             .dns_resolver(Arc::new(Resolver::new(tls_name_override.clone())))
             .build()?;
@@ -190,7 +253,13 @@ impl Service {
             RoomVersionId::V12,
         ];
         // Experimental, partially supported room versions
-        let unstable_room_versions = vec![RoomVersionId::V3, RoomVersionId::V4, RoomVersionId::V5];
+        let unstable_room_versions = vec![
+            RoomVersionId::V1,
+            RoomVersionId::V2,
+            RoomVersionId::V3,
+            RoomVersionId::V4,
+            RoomVersionId::V5,
+        ];
 
         let mut s = Self {
             allow_registration: RwLock::new(config.allow_registration),
@@ -214,6 +283,7 @@ impl Service {
             tls_name_override,
             federation_client,
             default_client,
+            url_preview_client,
             jwt_decoding_key,
             stable_room_versions,
             unstable_room_versions,
@@ -257,6 +327,13 @@ impl Service {
         self.default_client.clone()
     }
 
+    /// Returns a client which refuses to connect to addresses in the
+    /// `url_preview_ip_range_denylist`, to be used for fetching url previews and other urls
+    /// chosen by users, like identity servers
+    pub fn url_preview_client(&self) -> reqwest::Client {
+        self.url_preview_client.clone()
+    }
+
     /// Returns a client used for resolving .well-knowns
     pub fn federation_client(&self) -> reqwest::Client {
         // Client is cheap to clone (Arc wrapper) and avoids lifetime issues
@@ -338,6 +415,33 @@ impl Service {
         self.config.allow_unstable_room_versions
     }
 
+    pub fn allow_presence(&self) -> bool {
+        self.config.allow_presence
+    }
+
+    pub fn presence_idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.config.presence_idle_timeout_s)
+    }
+
+    pub fn presence_offline_timeout(&self) -> Duration {
+        Duration::from_secs(self.config.presence_offline_timeout_s)
+    }
+
+    pub fn metrics_enabled(&self) -> bool {
+        self.config.metrics_port.is_some()
+    }
+
+    pub fn allow_url_preview(&self) -> bool {
+        self.config.allow_url_preview
+    }
+
+    /// Checks whether the host of the url is an IP address in the `url_preview_ip_range_denylist`.
+    ///
+    /// Hostnames are only checked when the url is actually fetched using the `url_preview_client`.
+    pub fn is_url_preview_denied(&self, url: &url::Url) -> bool {
+        is_url_denied(&self.config.url_preview_ip_range_denylist, url)
+    }
+
     pub fn default_room_version(&self) -> RoomVersionId {
         self.config.default_room_version.clone()
     }
@@ -420,6 +524,33 @@ impl Service {
         }))
     }
 
+    /// Stores the keys of a server exactly as the server returned them, if they are its own.
+    pub fn set_origin_server_keys(
+        &self,
+        origin: &ServerName,
+        keys: &Raw<ServerSigningKeys>,
+    ) -> Result<()> {
+        if keys
+            .get_field::<OwnedServerName>("server_name")
+            .ok()
+            .flatten()
+            .as_deref()
+            != Some(origin)
+        {
+            return Ok(());
+        }
+
+        self.db.set_origin_server_keys(origin, keys)
+    }
+
+    /// Returns the last keys the server returned, as it signed them.
+    pub fn origin_server_keys(
+        &self,
+        origin: &ServerName,
+    ) -> Result<Option<Raw<ServerSigningKeys>>> {
+        self.db.origin_server_keys(origin)
+    }
+
     /// Filters the key map of multiple servers down to keys that should be accepted given the expiry time,
     /// room version, and timestamp of the parameters, as well as ignoring keys that are listed in `ignored_keys`.
     pub fn filter_keys_server_map(
@@ -542,6 +673,21 @@ impl Service {
     }
 }
 
+fn is_ip_denied(denylist: &[IpNet], ip: IpAddr) -> bool {
+    // IPv4-mapped IPv6 addresses must not be used to get around the IPv4 ranges
+    let ip = ip.to_canonical();
+    denylist.iter().any(|net| net.contains(&ip))
+}
+
+fn is_url_denied(denylist: &[IpNet], url: &url::Url) -> bool {
+    match url.host() {
+        Some(url::Host::Ipv4(ip)) => is_ip_denied(denylist, ip.into()),
+        Some(url::Host::Ipv6(ip)) => is_ip_denied(denylist, ip.into()),
+        Some(url::Host::Domain(_)) => false,
+        None => true,
+    }
+}
+
 fn reqwest_client_builder(config: &Config) -> Result<reqwest::ClientBuilder> {
     let mut reqwest_client_builder = reqwest::Client::builder()
         .pool_max_idle_per_host(0)
@@ -0,0 +1,122 @@
+use std::time::Duration;
+
+use ldap3::{ldap_escape, LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+use tracing::warn;
+
+use crate::{config::LdapConfig, service::email::render, Error, Result};
+
+/// A user whose password was confirmed by the directory
+pub struct LdapUser {
+    pub displayname: Option<String>,
+}
+
+/// Checks passwords against an LDAP directory.
+pub struct Service {
+    config: Option<LdapConfig>,
+}
+
+impl Service {
+    pub fn build(config: Option<&LdapConfig>) -> Self {
+        Self {
+            config: config.cloned(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.is_some()
+    }
+
+    /// Whether the password hashes stored in the database are checked when the directory doesn't
+    /// know the user or is unreachable.
+    pub fn local_fallback(&self) -> bool {
+        self.config
+            .as_ref()
+            .is_none_or(|config| config.local_fallback)
+    }
+
+    /// Searches the user in the directory and binds as them to check the password. Returns
+    /// `None` if the user doesn't exist or the password is wrong.
+    pub async fn authenticate(&self, username: &str, password: &str) -> Result<Option<LdapUser>> {
+        let Some(config) = &self.config else {
+            return Ok(None);
+        };
+
+        // Binding without password succeeds as an anonymous bind
+        if password.is_empty() {
+            return Ok(None);
+        }
+
+        let ldap_error = |e: ldap3::LdapError| {
+            warn!("LDAP request to {} failed: {e}", config.uri);
+            Error::BadServerResponse("Failed to reach the LDAP server.")
+        };
+
+        let timeout = Duration::from_secs(config.timeout_s);
+
+        let (connection, mut ldap) = LdapConnAsync::with_settings(
+            LdapConnSettings::new()
+                .set_starttls(config.starttls)
+                .set_conn_timeout(timeout),
+            config.uri.as_str(),
+        )
+        .await
+        .map_err(ldap_error)?;
+        ldap3::drive!(connection);
+
+        // A server that accepted the connection could still never answer, so every request
+        // has a timeout as well
+        if let Some(bind_dn) = &config.bind_dn {
+            ldap.with_timeout(timeout)
+                .simple_bind(bind_dn, config.bind_password.as_deref().unwrap_or_default())
+                .await
+                .and_then(|result| result.success())
+                .map_err(ldap_error)?;
+        }
+
+        let escaped_username = ldap_escape(username);
+        let filter = render(&config.filter, &[("username", escaped_username.as_ref())]);
+        let (entries, _) = ldap
+            .with_timeout(timeout)
+            .search(
+                &config.base_dn,
+                Scope::Subtree,
+                &filter,
+                vec![config.displayname_attribute.as_str()],
+            )
+            .await
+            .and_then(|result| result.success())
+            .map_err(ldap_error)?;
+
+        let entry = match <[_; 1]>::try_from(entries) {
+            Ok([entry]) => SearchEntry::construct(entry),
+            Err(entries) => {
+                if entries.len() > 1 {
+                    warn!(
+                        "LDAP search for {username} returned {} entries",
+                        entries.len()
+                    );
+                }
+                return Ok(None);
+            }
+        };
+
+        let bind = ldap
+            .with_timeout(timeout)
+            .simple_bind(&entry.dn, password)
+            .await
+            .map_err(ldap_error)?;
+        let _ = ldap.with_timeout(timeout).unbind().await;
+
+        if bind.rc != 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(LdapUser {
+            displayname: entry
+                .attrs
+                .get(&config.displayname_attribute)
+                .and_then(|values| values.first())
+                .cloned(),
+        }))
+    }
+}
@@ -0,0 +1,314 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    sync::Mutex as StdMutex,
+    time::Duration,
+};
+
+use http::{Method, StatusCode};
+
+use crate::{service::sending::OutgoingKind, services, Result};
+
+/// Upper bounds of the histogram buckets, in seconds
+const BUCKETS: [f64; 13] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+
+        for (bucket, le) in self.buckets.iter_mut().zip(BUCKETS) {
+            if seconds <= le {
+                *bucket += 1;
+            }
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+
+        for (bucket, le) in self.buckets.iter().zip(BUCKETS) {
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{{labels}{separator}le=\"{le}\"}} {bucket}"
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{name}_bucket{{{labels}{separator}le=\"+Inf\"}} {}",
+            self.count
+        );
+        if labels.is_empty() {
+            let _ = writeln!(out, "{name}_sum {}", self.sum);
+            let _ = writeln!(out, "{name}_count {}", self.count);
+        } else {
+            let _ = writeln!(out, "{name}_sum{{{labels}}} {}", self.sum);
+            let _ = writeln!(out, "{name}_count{{{labels}}} {}", self.count);
+        }
+    }
+}
+
+#[derive(Default)]
+struct RouteMetrics {
+    responses: BTreeMap<u16, u64>,
+    duration: Histogram,
+}
+
+/// Collects metrics in memory and renders them in the Prometheus text format.
+///
+/// Nothing is recorded unless `metrics_port` is set in the config.
+pub struct Service {
+    requests: StdMutex<BTreeMap<(&'static str, String), RouteMetrics>>,
+    transaction_failures: StdMutex<HashMap<Destination, u64>>,
+    queue_depths: StdMutex<HashMap<Destination, u64>>,
+    event_handler: StdMutex<Histogram>,
+}
+
+impl Service {
+    pub fn build() -> Self {
+        Self {
+            requests: StdMutex::new(BTreeMap::new()),
+            transaction_failures: StdMutex::new(HashMap::new()),
+            queue_depths: StdMutex::new(HashMap::new()),
+            event_handler: StdMutex::new(Histogram::default()),
+        }
+    }
+
+    /// Records a handled request. `route` is the path of the route, not the actual request path.
+    pub fn record_request(
+        &self,
+        method: &Method,
+        route: &'static str,
+        status: StatusCode,
+        duration: Duration,
+    ) {
+        if !services().globals.metrics_enabled() {
+            return;
+        }
+
+        let mut requests = self.requests.lock().unwrap();
+        let metrics = requests
+            .entry((route, method.as_str().to_owned()))
+            .or_default();
+
+        *metrics.responses.entry(status.as_u16()).or_default() += 1;
+        metrics.duration.observe(duration);
+    }
+
+    /// Records that sending a transaction failed and will be retried later.
+    pub fn record_transaction_failure(&self, outgoing_kind: &OutgoingKind) {
+        if !services().globals.metrics_enabled() {
+            return;
+        }
+
+        *self
+            .transaction_failures
+            .lock()
+            .unwrap()
+            .entry(destination(outgoing_kind))
+            .or_default() += 1;
+    }
+
+    /// Counts the requests that were waiting to be sent when the server started. Afterwards, the
+    /// sending service reports every change, so the queue is not read on every scrape.
+    pub fn init_queue_depths(
+        &self,
+        queued: impl Iterator<Item = Result<OutgoingKind>>,
+    ) -> Result<()> {
+        if !services().globals.metrics_enabled() {
+            return Ok(());
+        }
+
+        let mut queue_depths = self.queue_depths.lock().unwrap();
+        for outgoing_kind in queued {
+            *queue_depths
+                .entry(destination(&outgoing_kind?))
+                .or_default() += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Records that requests were added to the sending queue.
+    pub fn record_queued(&self, outgoing_kind: &OutgoingKind, count: usize) {
+        if !services().globals.metrics_enabled() {
+            return;
+        }
+
+        *self
+            .queue_depths
+            .lock()
+            .unwrap()
+            .entry(destination(outgoing_kind))
+            .or_default() += count as u64;
+    }
+
+    /// Records that requests were taken out of the sending queue to be sent.
+    pub fn record_dequeued(&self, outgoing_kind: &OutgoingKind, count: usize) {
+        if !services().globals.metrics_enabled() {
+            return;
+        }
+
+        if let Some(depth) = self
+            .queue_depths
+            .lock()
+            .unwrap()
+            .get_mut(&destination(outgoing_kind))
+        {
+            *depth = depth.saturating_sub(count as u64);
+        }
+    }
+
+    /// Records that all requests to the destination were dropped.
+    pub fn record_queue_cleared(&self, outgoing_kind: &OutgoingKind) {
+        if !services().globals.metrics_enabled() {
+            return;
+        }
+
+        self.queue_depths
+            .lock()
+            .unwrap()
+            .remove(&destination(outgoing_kind));
+    }
+
+    /// Records how long it took to handle an incoming federation event.
+    pub fn record_event_handling(&self, duration: Duration) {
+        if !services().globals.metrics_enabled() {
+            return;
+        }
+
+        self.event_handler.lock().unwrap().observe(duration);
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub async fn render(&self) -> Result<String> {
+        let mut out = String::new();
+
+        {
+            let requests = self.requests.lock().unwrap();
+
+            out.push_str(
+                "# HELP conduit_http_requests_total Number of handled requests per route and status.\n\
+                 # TYPE conduit_http_requests_total counter\n",
+            );
+            for ((route, method), metrics) in requests.iter() {
+                for (status, count) in &metrics.responses {
+                    let _ = writeln!(
+                        out,
+                        "conduit_http_requests_total{{method=\"{method}\",route=\"{}\",status=\"{status}\"}} {count}",
+                        escape(route)
+                    );
+                }
+            }
+
+            out.push_str(
+                "# HELP conduit_http_request_duration_seconds Time it took to handle requests per route.\n\
+                 # TYPE conduit_http_request_duration_seconds histogram\n",
+            );
+            for ((route, method), metrics) in requests.iter() {
+                metrics.duration.render(
+                    &mut out,
+                    "conduit_http_request_duration_seconds",
+                    &format!("method=\"{method}\",route=\"{}\"", escape(route)),
+                );
+            }
+        }
+
+        out.push_str(
+            "# HELP conduit_sending_queue_depth Number of events waiting to be sent.\n\
+             # TYPE conduit_sending_queue_depth gauge\n",
+        );
+        for (destination, depth) in self.queue_depths.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "conduit_sending_queue_depth{{{}}} {depth}",
+                labels(destination)
+            );
+        }
+
+        out.push_str(
+            "# HELP conduit_sending_transaction_failures_total Number of transactions that failed to be sent.\n\
+             # TYPE conduit_sending_transaction_failures_total counter\n",
+        );
+        for (destination, failures) in self.transaction_failures.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "conduit_sending_transaction_failures_total{{{}}} {failures}",
+                labels(destination)
+            );
+        }
+
+        out.push_str(
+            "# HELP conduit_event_handler_duration_seconds Time it took to handle incoming federation events.\n\
+             # TYPE conduit_event_handler_duration_seconds histogram\n",
+        );
+        self.event_handler.lock().unwrap().render(
+            &mut out,
+            "conduit_event_handler_duration_seconds",
+            "",
+        );
+
+        out.push_str(
+            "# HELP conduit_cache_entries Number of entries in the in-memory caches.\n\
+             # TYPE conduit_cache_entries gauge\n",
+        );
+        for (cache, entries) in services()
+            .cache_sizes()
+            .await
+            .into_iter()
+            .chain(services().globals.db.cache_sizes())
+        {
+            let _ = writeln!(out, "conduit_cache_entries{{cache=\"{cache}\"}} {entries}");
+        }
+
+        out.push_str(
+            "# HELP conduit_database_memory_bytes Approximate memory usage of the database engine.\n\
+             # TYPE conduit_database_memory_bytes gauge\n",
+        );
+        for (usage, bytes) in services().globals.db.database_memory_usage() {
+            let _ = writeln!(
+                out,
+                "conduit_database_memory_bytes{{usage=\"{usage}\"}} {bytes}"
+            );
+        }
+
+        Ok(out)
+    }
+}
+
+/// Kind and destination of outgoing requests.
+///
+/// Pushes are counted together, as they are not interesting on their own and would leak user ids.
+type Destination = (&'static str, Option<String>);
+
+fn destination(outgoing_kind: &OutgoingKind) -> Destination {
+    match outgoing_kind {
+        OutgoingKind::Appservice(id) => ("appservice", Some(id.clone())),
+        OutgoingKind::Push(_, _) => ("push", None),
+        OutgoingKind::Normal(server) => ("federation", Some(server.to_string())),
+    }
+}
+
+fn labels((kind, destination): &Destination) -> String {
+    match destination {
+        Some(destination) => format!("kind=\"{kind}\",destination=\"{}\"", escape(destination)),
+        None => format!("kind=\"{kind}\""),
+    }
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -1,10 +1,10 @@
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::{BTreeMap, HashMap, HashSet},
     sync::{Arc, Mutex as StdMutex},
 };
 
 use lru_cache::LruCache;
-use tokio::sync::{broadcast, Mutex};
+use tokio::sync::{broadcast, Mutex, Notify};
 
 use crate::{Config, Result};
 use tokio::sync::RwLock;
@@ -12,30 +12,48 @@ use tokio::sync::RwLock;
 pub mod account_data;
 pub mod admin;
 pub mod appservice;
+pub mod email;
 pub mod globals;
 pub mod key_backups;
+pub mod ldap;
 pub mod media;
+pub mod metrics;
 pub mod pdu;
+pub mod policy;
 pub mod pusher;
 pub mod rooms;
 pub mod sending;
+pub mod server_notices;
+pub mod sso;
+pub mod threepid;
 pub mod transaction_ids;
 pub mod uiaa;
+pub mod url_preview;
+pub mod user_directory;
 pub mod users;
 
 pub struct Services {
     pub appservice: appservice::Service,
+    pub email: email::Service,
     pub pusher: pusher::Service,
     pub rooms: rooms::Service,
+    pub threepid: threepid::Service,
     pub transaction_ids: transaction_ids::Service,
     pub uiaa: uiaa::Service,
     pub users: Arc<users::Service>,
+    pub user_directory: user_directory::Service,
     pub account_data: account_data::Service,
     pub admin: Arc<admin::Service>,
     pub globals: globals::Service,
     pub key_backups: key_backups::Service,
+    pub ldap: ldap::Service,
     pub media: Arc<media::Service>,
+    pub metrics: metrics::Service,
+    pub policy: policy::Service,
+    pub url_preview: url_preview::Service,
     pub sending: Arc<sending::Service>,
+    pub server_notices: server_notices::Service,
+    pub sso: sso::Service,
 }
 
 impl Services {
@@ -43,14 +61,18 @@ impl Services {
         D: appservice::Data
             + pusher::Data
             + rooms::Data
+            + threepid::Data
             + transaction_ids::Data
             + uiaa::Data
             + users::Data
+            + user_directory::Data
             + account_data::Data
             + globals::Data
             + key_backups::Data
             + media::Data
+            + url_preview::Data
             + sending::Data
+            + sso::Data
             + 'static,
     >(
         db: &'static D,
@@ -58,7 +80,11 @@ impl Services {
     ) -> Result<Self> {
         Ok(Self {
             appservice: appservice::Service::build(db)?,
-            pusher: pusher::Service { db },
+            email: email::Service::build(config.email.as_ref())?,
+            pusher: pusher::Service {
+                db,
+                pending_emails: Mutex::new(HashSet::new()),
+            },
             rooms: rooms::Service {
                 alias: rooms::alias::Service { db },
                 auth_chain: rooms::auth_chain::Service { db },
@@ -80,6 +106,10 @@ impl Services {
                 },
                 metadata: rooms::metadata::Service { db },
                 outlier: rooms::outlier::Service { db },
+                partial_state: rooms::partial_state::Service {
+                    db,
+                    resynced: Notify::new(),
+                },
                 pdu_metadata: rooms::pdu_metadata::Service { db },
                 search: rooms::search::Service { db },
                 short: rooms::short::Service { db },
@@ -110,6 +140,7 @@ impl Services {
                 },
                 user: rooms::user::Service { db },
             },
+            threepid: threepid::Service { db },
             transaction_ids: transaction_ids::Service { db },
             uiaa: uiaa::Service { db },
             users: Arc::new(users::Service {
@@ -117,63 +148,84 @@ impl Services {
                 connections: StdMutex::new(BTreeMap::new()),
                 device_last_seen: Mutex::new(BTreeMap::new()),
             }),
+            user_directory: user_directory::Service { db },
             account_data: account_data::Service { db },
             admin: admin::Service::build(),
             key_backups: key_backups::Service { db },
+            ldap: ldap::Service::build(config.ldap.as_ref()),
             media: Arc::new(media::Service { db }),
+            metrics: metrics::Service::build(),
+            policy: policy::Service::build(&config),
+            url_preview: url_preview::Service { db },
             sending: sending::Service::build(db, &config),
+            server_notices: server_notices::Service::build(),
+            sso: sso::Service::build(db, &config)?,
 
             globals: globals::Service::load(db, config)?,
         })
     }
+
+    /// Returns the number of entries in each of the in-memory caches of the services.
+    pub async fn cache_sizes(&self) -> Vec<(&'static str, usize)> {
+        vec![
+            (
+                "lazy_load_waiting",
+                self.rooms.lazy_loading.lazy_load_waiting.lock().await.len(),
+            ),
+            (
+                "server_visibility_cache",
+                self.rooms
+                    .state_accessor
+                    .server_visibility_cache
+                    .lock()
+                    .unwrap()
+                    .len(),
+            ),
+            (
+                "user_visibility_cache",
+                self.rooms
+                    .state_accessor
+                    .user_visibility_cache
+                    .lock()
+                    .unwrap()
+                    .len(),
+            ),
+            (
+                "stateinfo_cache",
+                self.rooms
+                    .state_compressor
+                    .stateinfo_cache
+                    .lock()
+                    .unwrap()
+                    .len(),
+            ),
+            (
+                "lasttimelinecount_cache",
+                self.rooms
+                    .timeline
+                    .lasttimelinecount_cache
+                    .lock()
+                    .await
+                    .len(),
+            ),
+            (
+                "roomid_spacechunk_cache",
+                self.rooms
+                    .spaces
+                    .roomid_spacehierarchy_cache
+                    .lock()
+                    .await
+                    .len(),
+            ),
+        ]
+    }
     async fn memory_usage(&self) -> String {
-        let lazy_load_waiting = self.rooms.lazy_loading.lazy_load_waiting.lock().await.len();
-        let server_visibility_cache = self
-            .rooms
-            .state_accessor
-            .server_visibility_cache
-            .lock()
-            .unwrap()
-            .len();
-        let user_visibility_cache = self
-            .rooms
-            .state_accessor
-            .user_visibility_cache
-            .lock()
-            .unwrap()
-            .len();
-        let stateinfo_cache = self
-            .rooms
-            .state_compressor
-            .stateinfo_cache
-            .lock()
-            .unwrap()
-            .len();
-        let lasttimelinecount_cache = self
-            .rooms
-            .timeline
-            .lasttimelinecount_cache
-            .lock()
+        self.cache_sizes()
             .await
-            .len();
-        let roomid_spacehierarchy_cache = self
-            .rooms
-            .spaces
-            .roomid_spacehierarchy_cache
-            .lock()
-            .await
-            .len();
-
-        format!(
-            "\
-lazy_load_waiting: {lazy_load_waiting}
-server_visibility_cache: {server_visibility_cache}
-user_visibility_cache: {user_visibility_cache}
-stateinfo_cache: {stateinfo_cache}
-lasttimelinecount_cache: {lasttimelinecount_cache}
-roomid_spacechunk_cache: {roomid_spacehierarchy_cache}\
-            "
-        )
+            .into_iter()
+            .map(|(cache, entries)| format!("{cache}: {entries}"))
+            .collect::<Vec<_>>()
+            .join("\n")
     }
     async fn clear_caches(&self, amount: u32) {
         if amount > 0 {
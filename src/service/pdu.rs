@@ -1,4 +1,4 @@
-use crate::Error;
+use crate::{services, Error};
 use ruma::{
     api::client::error::ErrorKind,
     canonical_json::redact_content_in_place,
@@ -9,12 +9,12 @@ use ruma::{
         AnySyncMessageLikeEvent, AnySyncStateEvent, AnySyncTimelineEvent, AnyTimelineEvent,
         StateEvent, TimelineEventType,
     },
-    room_version_rules::{RedactionRules, RoomVersionRules},
+    room_version_rules::{EventIdFormatVersion, RedactionRules, RoomVersionRules},
     serde::{JsonCastable, Raw},
     state_res, CanonicalJsonObject, CanonicalJsonValue, EventId, MilliSecondsSinceUnixEpoch,
     OwnedEventId, OwnedRoomId, OwnedUserId, RoomId, UInt, UserId,
 };
-use serde::{Deserialize, Serialize};
+use serde::{de::IgnoredAny, Deserialize, Deserializer, Serialize};
 use serde_json::{
     json,
     value::{to_raw_value, RawValue as RawJsonValue},
@@ -41,8 +41,10 @@ pub struct PduEvent {
     pub content: Box<RawJsonValue>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub state_key: Option<String>,
+    #[serde(deserialize_with = "deserialize_event_references")]
     pub prev_events: Vec<Arc<EventId>>,
     pub depth: UInt,
+    #[serde(deserialize_with = "deserialize_event_references")]
     pub auth_events: Vec<Arc<EventId>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub redacts: Option<Arc<EventId>>,
@@ -358,7 +360,15 @@ impl PduEvent {
             unsigned.remove("transaction_id");
         }
 
-        pdu_json.remove("event_id");
+        // Only the ids of room versions 1 and 2 contain a server name. They are part of the event.
+        if pdu_json
+            .get("event_id")
+            .and_then(|event_id| event_id.as_str())
+            .and_then(|event_id| <&EventId>::try_from(event_id).ok())
+            .is_none_or(|event_id| event_id.server_name().is_none())
+        {
+            pdu_json.remove("event_id");
+        }
 
         // TODO: another option would be to convert it to a canonical string to validate size
         // and return a Result<Raw<...>>
@@ -465,16 +475,96 @@ pub(crate) fn gen_event_id_canonical_json(
         Error::BadServerResponse("Invalid PDU in server response")
     })?;
 
-    let event_id = format!(
+    let event_id = gen_event_id(&value, room_version_rules)?;
+
+    Ok((event_id, value))
+}
+
+/// Returns the id of the event. Events of room versions 1 and 2 contain the id their origin
+/// server assigned, later versions are identified by their reference hash.
+pub(crate) fn gen_event_id(
+    value: &CanonicalJsonObject,
+    room_version_rules: &RoomVersionRules,
+) -> crate::Result<OwnedEventId> {
+    if uses_origin_event_ids(room_version_rules) {
+        return value
+            .get("event_id")
+            .and_then(|event_id| event_id.as_str())
+            .and_then(|event_id| EventId::parse(event_id).ok())
+            .ok_or(Error::BadRequest(
+                ErrorKind::BadJson,
+                "Event has no valid event id",
+            ));
+    }
+
+    Ok(format!(
         "${}",
         // Anything higher than version3 behaves the same
-        ruma::signatures::reference_hash(&value, room_version_rules)
+        ruma::signatures::reference_hash(value, room_version_rules)
             .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid PDU format"))?
     )
     .try_into()
-    .expect("ruma's reference hashes are valid event ids");
+    .expect("ruma's reference hashes are valid event ids"))
+}
 
-    Ok((event_id, value))
+/// Whether events of the room version have ids assigned by their origin server, which are hashed
+/// and signed as part of the event. This is the case for room versions 1 and 2.
+pub(crate) fn uses_origin_event_ids(room_version_rules: &RoomVersionRules) -> bool {
+    room_version_rules.event_id_format == EventIdFormatVersion::V1
+}
+
+/// Prepares the id of an event we are about to hash and sign: Room versions 1 and 2 get a new id
+/// assigned by this server, later versions must not contain an id.
+pub(crate) fn set_origin_event_id(
+    pdu_json: &mut CanonicalJsonObject,
+    room_version_rules: &RoomVersionRules,
+) {
+    if uses_origin_event_ids(room_version_rules) {
+        pdu_json.insert(
+            "event_id".to_owned(),
+            CanonicalJsonValue::String(EventId::new(services().globals.server_name()).to_string()),
+        );
+    } else {
+        pdu_json.remove("event_id");
+    }
+}
+
+/// A reference to another event in `prev_events` or `auth_events`. Room versions 1 and 2 reference
+/// events by their id and their hashes, later versions only by their id.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum EventReference {
+    Id(Arc<EventId>),
+    IdAndHashes(Arc<EventId>, IgnoredAny),
+}
+
+impl EventReference {
+    fn into_event_id(self) -> Arc<EventId> {
+        match self {
+            Self::Id(event_id) | Self::IdAndHashes(event_id, _) => event_id,
+        }
+    }
+}
+
+fn deserialize_event_references<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<Arc<EventId>>, D::Error> {
+    Ok(Vec::<EventReference>::deserialize(deserializer)?
+        .into_iter()
+        .map(EventReference::into_event_id)
+        .collect())
+}
+
+/// Returns the event ids of the `prev_events` or `auth_events` of a PDU in any room version.
+pub(crate) fn referenced_event_ids(references: &CanonicalJsonValue) -> Option<Vec<Arc<EventId>>> {
+    serde_json::from_value::<Vec<EventReference>>(references.clone().into())
+        .ok()
+        .map(|references| {
+            references
+                .into_iter()
+                .map(EventReference::into_event_id)
+                .collect()
+        })
 }
 
 /// Build the start of a PDU in order to add it to the Database.
@@ -493,3 +583,89 @@ pub struct PduBuilder {
 }
 
 impl JsonCastable<PduBuilder> for AnyInitialStateEvent {}
+
+#[cfg(test)]
+mod tests {
+    use ruma::{room_version_rules::RoomVersionRules, CanonicalJsonValue};
+    use serde_json::{json, value::to_raw_value};
+
+    use super::{gen_event_id_canonical_json, PduEvent};
+
+    fn join_event(event_id: Option<&str>, prev_event: serde_json::Value) -> serde_json::Value {
+        let mut event = json!({
+            "room_id": "!old:example.org",
+            "sender": "@alice:example.com",
+            "origin": "example.com",
+            "origin_server_ts": 1_500_000_000_000_u64,
+            "type": "m.room.member",
+            "state_key": "@alice:example.com",
+            "content": { "membership": "join" },
+            "prev_events": [prev_event.clone()],
+            "auth_events": [prev_event],
+            "depth": 12,
+            "hashes": { "sha256": "aGFzaA" },
+            "signatures": {},
+        });
+        if let Some(event_id) = event_id {
+            event["event_id"] = event_id.into();
+        }
+
+        event
+    }
+
+    #[test]
+    fn join_v1_room() {
+        let event = join_event(
+            Some("$join:example.com"),
+            json!(["$create:example.org", { "sha256": "aGFzaA" }]),
+        );
+
+        let (event_id, value) =
+            gen_event_id_canonical_json(&to_raw_value(&event).unwrap(), &RoomVersionRules::V1)
+                .unwrap();
+        assert_eq!(event_id, "$join:example.com");
+
+        let pdu = PduEvent::from_id_val(&event_id, value.clone()).unwrap();
+        assert_eq!(pdu.prev_events.len(), 1);
+        assert_eq!(&*pdu.prev_events[0], "$create:example.org");
+        assert_eq!(&*pdu.auth_events[0], "$create:example.org");
+
+        // The event id is part of the event in the federation format
+        let outgoing: serde_json::Value =
+            serde_json::from_str(PduEvent::convert_to_outgoing_federation_event(value).get())
+                .unwrap();
+        assert_eq!(outgoing["event_id"], "$join:example.com");
+    }
+
+    #[test]
+    fn join_v1_room_without_event_id() {
+        let event = join_event(None, json!(["$create:example.org", { "sha256": "aGFzaA" }]));
+
+        assert!(
+            gen_event_id_canonical_json(&to_raw_value(&event).unwrap(), &RoomVersionRules::V1)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn reference_hash_event_id_is_not_sent() {
+        let event = join_event(None, json!("$Y3JlYXRl"));
+
+        let (event_id, mut value) =
+            gen_event_id_canonical_json(&to_raw_value(&event).unwrap(), &RoomVersionRules::V11)
+                .unwrap();
+        assert!(event_id.server_name().is_none());
+
+        let pdu = PduEvent::from_id_val(&event_id, value.clone()).unwrap();
+        assert_eq!(&*pdu.prev_events[0], "$Y3JlYXRl");
+
+        value.insert(
+            "event_id".to_owned(),
+            CanonicalJsonValue::String(event_id.to_string()),
+        );
+        let outgoing: serde_json::Value =
+            serde_json::from_str(PduEvent::convert_to_outgoing_federation_event(value).get())
+                .unwrap();
+        assert!(outgoing.get("event_id").is_none());
+    }
+}
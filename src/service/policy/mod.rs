@@ -0,0 +1,338 @@
+//! Enforcement of moderation policy lists, which are rooms with `m.policy.rule.*` state events
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use regex::Regex;
+use ruma::{
+    api::client::error::ErrorKind,
+    events::{room::message::RoomMessageEventContent, StateEventType},
+    OwnedRoomId, OwnedRoomOrAliasId, RoomId, RoomOrAliasId, ServerName, UserId,
+};
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::{services, Config, Error, Result};
+
+/// Recommendations which mean that the entity should be banned
+const BAN_RECOMMENDATIONS: [&str; 2] = ["m.ban", "org.matrix.mjolnir.ban"];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum EntityKind {
+    User,
+    Server,
+    Room,
+}
+
+#[derive(Deserialize)]
+struct PolicyRuleContent {
+    entity: String,
+    recommendation: String,
+    #[serde(default)]
+    reason: String,
+}
+
+struct Rule {
+    kind: EntityKind,
+    entity: String,
+    pattern: Regex,
+    reason: String,
+}
+
+/// A policy rule that matched
+pub struct Ban {
+    pub entity: String,
+    pub reason: String,
+    pub policy_room: OwnedRoomId,
+}
+
+impl fmt::Display for Ban {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is banned by {}", self.entity, self.policy_room)?;
+        if !self.reason.is_empty() {
+            write!(f, ": {}", self.reason)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub struct Service {
+    policy_rooms: Vec<OwnedRoomOrAliasId>,
+    /// The policy rooms the server user joined
+    room_ids: RwLock<Vec<OwnedRoomId>>,
+    /// The compiled rules of each policy room, with the state they were compiled from
+    rules: Mutex<HashMap<OwnedRoomId, (u64, Arc<Vec<Rule>>)>>,
+    /// Senders of incoming events whose matches were already reported
+    reported_senders: Mutex<HashSet<String>>,
+}
+
+impl Service {
+    pub fn build(config: &Config) -> Self {
+        Self {
+            policy_rooms: config.policy_rooms.clone(),
+            room_ids: RwLock::new(Vec::new()),
+            rules: Mutex::new(HashMap::new()),
+            reported_senders: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Makes the server user join the configured policy rooms in the background, retrying until
+    /// it succeeds.
+    pub fn start_subscriptions(&self) {
+        for room in self.policy_rooms.clone() {
+            tokio::spawn(async move {
+                let mut delay = Duration::from_secs(10);
+
+                while let Err(e) = services().policy.subscribe(&room).await {
+                    warn!("Failed to join policy room {room}, retrying in {delay:?}: {e}");
+
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(Duration::from_secs(60 * 60));
+                }
+            });
+        }
+    }
+
+    async fn subscribe(&self, room: &RoomOrAliasId) -> Result<()> {
+        let server_user = services().globals.server_user();
+
+        let (servers, room_id) = services()
+            .rooms
+            .state_cache
+            .get_room_id_and_via_servers(server_user, room.to_owned(), Vec::new())
+            .await?;
+
+        if !services()
+            .rooms
+            .state_cache
+            .is_joined(server_user, &room_id)?
+        {
+            services()
+                .rooms
+                .helpers
+                .join_room_by_id(server_user, &room_id, None, &servers, None)
+                .await?;
+        }
+
+        self.room_ids.write().unwrap().push(room_id);
+
+        Ok(())
+    }
+
+    pub fn is_policy_room(&self, room_id: &RoomId) -> bool {
+        self.room_ids.read().unwrap().iter().any(|id| id == room_id)
+    }
+
+    /// Returns the rules of the policy room, compiling them again if the state changed.
+    async fn room_rules(&self, room_id: &RoomId) -> Result<Arc<Vec<Rule>>> {
+        let Some(shortstatehash) = services().rooms.state.get_room_shortstatehash(room_id)? else {
+            return Ok(Arc::default());
+        };
+
+        if let Some((compiled_from, rules)) = self.rules.lock().unwrap().get(room_id) {
+            if *compiled_from == shortstatehash {
+                return Ok(Arc::clone(rules));
+            }
+        }
+
+        let rules = Arc::new(
+            services()
+                .rooms
+                .state_accessor
+                .state_full(shortstatehash)
+                .await?
+                .into_iter()
+                .filter_map(|((event_type, _), pdu)| {
+                    let kind = match event_type {
+                        StateEventType::PolicyRuleUser => EntityKind::User,
+                        StateEventType::PolicyRuleServer => EntityKind::Server,
+                        StateEventType::PolicyRuleRoom => EntityKind::Room,
+                        _ => return None,
+                    };
+
+                    // Rules are removed by replacing them with an empty content
+                    let content =
+                        serde_json::from_str::<PolicyRuleContent>(pdu.content.get()).ok()?;
+                    if !BAN_RECOMMENDATIONS.contains(&content.recommendation.as_str()) {
+                        return None;
+                    }
+
+                    Some(Rule {
+                        kind,
+                        pattern: glob_to_regex(&content.entity)?,
+                        entity: content.entity,
+                        reason: content.reason,
+                    })
+                })
+                .collect::<Vec<_>>(),
+        );
+
+        self.rules
+            .lock()
+            .unwrap()
+            .insert(room_id.to_owned(), (shortstatehash, Arc::clone(&rules)));
+        self.reported_senders.lock().unwrap().clear();
+
+        Ok(rules)
+    }
+
+    async fn find_ban(&self, kind: EntityKind, entity: &str) -> Result<Option<Ban>> {
+        let room_ids = self.room_ids.read().unwrap().clone();
+
+        for room_id in room_ids {
+            if let Some(rule) = self
+                .room_rules(&room_id)
+                .await?
+                .iter()
+                .find(|rule| rule.kind == kind && rule.pattern.is_match(entity))
+            {
+                return Ok(Some(Ban {
+                    entity: rule.entity.clone(),
+                    reason: rule.reason.clone(),
+                    policy_room: room_id,
+                }));
+            }
+        }
+
+        Ok(None)
+    }
+
+    pub async fn server_ban(&self, server_name: &ServerName) -> Result<Option<Ban>> {
+        self.find_ban(EntityKind::Server, server_name.as_str())
+            .await
+    }
+
+    /// Checks the rules for the user and for their server.
+    pub async fn user_ban(&self, user_id: &UserId) -> Result<Option<Ban>> {
+        match self.find_ban(EntityKind::User, user_id.as_str()).await? {
+            Some(ban) => Ok(Some(ban)),
+            None => self.server_ban(user_id.server_name()).await,
+        }
+    }
+
+    pub async fn room_ban(&self, room_id: &RoomId) -> Result<Option<Ban>> {
+        self.find_ban(EntityKind::Room, room_id.as_str()).await
+    }
+
+    /// Fails if the user or the room is banned by a policy list. `action` describes what the
+    /// user tried to do for the report in the admin room.
+    pub async fn check_membership(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        action: &str,
+    ) -> Result<()> {
+        if self.is_policy_room(room_id) || user_id == services().globals.server_user() {
+            return Ok(());
+        }
+
+        let ban = match self.user_ban(user_id).await? {
+            Some(ban) => Some(ban),
+            None => self.room_ban(room_id).await?,
+        };
+
+        if let Some(ban) = ban {
+            services()
+                .admin
+                .send_message(RoomMessageEventContent::text_plain(format!(
+                    "Prevented {user_id} from {action} {room_id}, because {ban}"
+                )));
+
+            return Err(Error::BadRequest(
+                ErrorKind::forbidden(),
+                "This is not allowed by the moderation policy of this server.",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Fails if the user id that is about to be registered is banned by a policy list.
+    pub async fn check_registration(&self, user_id: &UserId) -> Result<()> {
+        if let Some(ban) = self.find_ban(EntityKind::User, user_id.as_str()).await? {
+            services()
+                .admin
+                .send_message(RoomMessageEventContent::text_plain(format!(
+                    "Prevented the registration of {user_id}, because {ban}"
+                )));
+
+            return Err(Error::BadRequest(
+                ErrorKind::forbidden(),
+                "This user id is not allowed by the moderation policy of this server.",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Whether the sender of an incoming event is banned by a policy list, in which case the
+    /// event is soft failed. Each sender is only reported once in the admin room.
+    pub async fn is_banned_sender(&self, sender: &UserId, room_id: &RoomId) -> Result<bool> {
+        if self.is_policy_room(room_id) {
+            return Ok(false);
+        }
+
+        if let Some(ban) = self.user_ban(sender).await? {
+            if self
+                .reported_senders
+                .lock()
+                .unwrap()
+                .insert(sender.to_string())
+            {
+                services()
+                    .admin
+                    .send_message(RoomMessageEventContent::text_plain(format!(
+                        "Soft failing events from {sender}, because {ban}"
+                    )));
+            }
+
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+}
+
+/// Turns a glob with `*` and `?` wildcards into a regex that matches the whole string.
+fn glob_to_regex(glob: &str) -> Option<Regex> {
+    let mut pattern = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => pattern.push_str(".*"),
+            '?' => pattern.push('.'),
+            c => pattern.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    pattern.push('$');
+
+    Regex::new(&pattern).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::glob_to_regex;
+
+    #[test]
+    fn glob_wildcards() {
+        let glob = glob_to_regex("@spam*:example.?om").unwrap();
+        assert!(glob.is_match("@spam:example.com"));
+        assert!(glob.is_match("@spammer:example.dom"));
+        assert!(!glob.is_match("@ham:example.com"));
+        assert!(!glob.is_match("@spam:example.co"));
+    }
+
+    #[test]
+    fn glob_matches_whole_entity() {
+        let glob = glob_to_regex("evil.org").unwrap();
+        assert!(glob.is_match("evil.org"));
+        assert!(!glob.is_match("evil.org.example.com"));
+        assert!(!glob.is_match("not-evil.org"));
+        // Regex syntax is matched literally
+        assert!(!glob.is_match("evilxorg"));
+    }
+}
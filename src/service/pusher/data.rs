@@ -1,7 +1,7 @@
 use crate::Result;
 use ruma::{
     api::client::push::{set_pusher, Pusher},
-    UserId,
+    EventId, OwnedEventId, OwnedRoomId, OwnedUserId, RoomId, UserId,
 };
 
 pub trait Data: Send + Sync {
@@ -13,4 +13,31 @@ pub trait Data: Send + Sync {
 
     fn get_pushkeys<'a>(&'a self, sender: &UserId)
         -> Box<dyn Iterator<Item = Result<String>> + 'a>;
+
+    /// Adds a notification to the email that will be sent to `address` about unread events in
+    /// the room.
+    fn queue_email_notification(
+        &self,
+        user: &UserId,
+        address: &str,
+        room_id: &RoomId,
+        event_id: &EventId,
+        highlight: bool,
+    ) -> Result<()>;
+
+    /// Removes the notifications of the email and returns their event ids and whether they
+    /// highlight the user.
+    fn take_email_notifications(
+        &self,
+        user: &UserId,
+        address: &str,
+        room_id: &RoomId,
+    ) -> Result<Vec<(OwnedEventId, bool)>>;
+
+    /// Returns the user, address and room of every email with waiting notifications, once per
+    /// notification.
+    #[allow(clippy::type_complexity)]
+    fn queued_emails<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<(OwnedUserId, String, OwnedRoomId)>> + 'a>;
 }
@@ -0,0 +1,58 @@
+//! Contents of notification emails
+
+use ruma::{
+    events::{room::member::MembershipState, TimelineEventType},
+    UserId,
+};
+use serde::Deserialize;
+
+use crate::{config::EmailTemplates, PduEvent};
+
+pub(super) enum EmailKind {
+    Message,
+    Mention,
+    Invite,
+}
+
+impl EmailKind {
+    /// Returns the subject and body templates.
+    pub(super) fn templates(self, templates: &EmailTemplates) -> (&str, &str) {
+        match self {
+            Self::Message => (&templates.message_subject, &templates.message_body),
+            Self::Mention => (&templates.mention_subject, &templates.mention_body),
+            Self::Invite => (&templates.invite_subject, &templates.invite_body),
+        }
+    }
+}
+
+pub(super) fn is_invite(pdu: &PduEvent, user: &UserId) -> bool {
+    #[derive(Deserialize)]
+    struct ExtractMembership {
+        membership: MembershipState,
+    }
+
+    pdu.kind == TimelineEventType::RoomMember
+        && pdu.state_key.as_deref() == Some(user.as_str())
+        && serde_json::from_str::<ExtractMembership>(pdu.content.get())
+            .is_ok_and(|content| content.membership == MembershipState::Invite)
+}
+
+/// A line of text describing the event.
+pub(super) fn summary(pdu: &PduEvent, user: &UserId) -> String {
+    #[derive(Deserialize)]
+    struct ExtractBody {
+        body: String,
+    }
+
+    if is_invite(pdu, user) {
+        return "(invited you)".to_owned();
+    }
+
+    if pdu.kind == TimelineEventType::RoomEncrypted {
+        return "(encrypted message)".to_owned();
+    }
+
+    serde_json::from_str::<ExtractBody>(pdu.content.get())
+        .map(|content| content.body)
+        .unwrap_or_else(|_| format!("({})", pdu.kind))
+}
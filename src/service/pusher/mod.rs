@@ -1,8 +1,9 @@
 mod data;
+mod email;
 pub use data::Data;
 use ruma::{events::AnySyncTimelineEvent, push::PushConditionPowerLevelsCtx};
 
-use crate::{services, Error, PduEvent, Result, SUPPORTED_VERSIONS};
+use crate::{service::email::render, services, Error, PduEvent, Result, SUPPORTED_VERSIONS};
 use bytes::BytesMut;
 use ruma::{
     api::{
@@ -16,14 +17,23 @@ use ruma::{
     events::TimelineEventType,
     push::{Action, PushConditionRoomCtx, PushFormat, Ruleset, Tweak},
     serde::Raw,
-    uint, RoomId, UInt, UserId,
+    uint, OwnedRoomId, OwnedUserId, RoomId, UInt, UserId,
 };
 
-use std::{fmt::Debug, mem};
+use std::{collections::HashSet, fmt::Debug, mem, time::Duration};
+use tokio::sync::Mutex;
 use tracing::{info, warn};
 
+use self::email::EmailKind;
+
+/// An email pusher, identified by its user and email address, and a room
+type EmailBatchKey = (OwnedUserId, String, OwnedRoomId);
+
 pub struct Service {
     pub db: &'static dyn Data,
+    /// Emails that will be sent soon. Their notifications are stored in the database, so they
+    /// survive restarts.
+    pub pending_emails: Mutex<HashSet<EmailBatchKey>>,
 }
 
 impl Service {
@@ -173,7 +183,7 @@ impl Service {
         }
 
         if notify == Some(true) {
-            self.send_notice(unread, pusher, tweaks, pdu).await?;
+            self.send_notice(user, unread, pusher, tweaks, pdu).await?;
         }
         // Else the event triggered no actions
 
@@ -203,15 +213,15 @@ impl Service {
         Ok(ruleset.get_actions(pdu, &ctx).await)
     }
 
-    #[tracing::instrument(skip(self, unread, pusher, tweaks, event))]
+    #[tracing::instrument(skip(self, user, unread, pusher, tweaks, event))]
     async fn send_notice(
         &self,
+        user: &UserId,
         unread: UInt,
         pusher: &Pusher,
         tweaks: Vec<Tweak>,
         event: &PduEvent,
     ) -> Result<()> {
-        // TODO: email
         match &pusher.kind {
             PusherKind::Http(http) => {
                 // TODO:
@@ -270,9 +280,160 @@ impl Service {
 
                 Ok(())
             }
-            // TODO: Handle email
-            PusherKind::Email(_) => Ok(()),
+            PusherKind::Email(_) => {
+                self.queue_email(user, &pusher.ids.pushkey, &tweaks, event)
+                    .await
+            }
             _ => Ok(()),
         }
     }
+
+    /// Collects the notifications of an email pusher for a while, so that several unread events
+    /// of a room are sent in one email.
+    async fn queue_email(
+        &self,
+        user: &UserId,
+        address: &str,
+        tweaks: &[Tweak],
+        event: &PduEvent,
+    ) -> Result<()> {
+        let Some(config) = &services().globals.config.email else {
+            return Ok(());
+        };
+
+        let key = (
+            user.to_owned(),
+            address.to_owned(),
+            event.room_id().into_owned(),
+        );
+
+        let mut pending_emails = self.pending_emails.lock().await;
+        self.db.queue_email_notification(
+            user,
+            address,
+            &key.2,
+            &event.event_id,
+            tweaks
+                .iter()
+                .any(|tweak| matches!(tweak, Tweak::Highlight(true))),
+        )?;
+        let first = pending_emails.insert(key.clone());
+        drop(pending_emails);
+
+        if first {
+            Self::schedule_email(key, config.notification_delay);
+        }
+
+        Ok(())
+    }
+
+    /// Schedules the emails that were still waiting to be sent when the server stopped.
+    pub async fn start_email_tasks(&self) {
+        let Some(config) = &services().globals.config.email else {
+            return;
+        };
+
+        let mut pending_emails = self.pending_emails.lock().await;
+        for key in self.db.queued_emails().filter_map(|r| r.ok()) {
+            if pending_emails.insert(key.clone()) {
+                Self::schedule_email(key, config.notification_delay);
+            }
+        }
+    }
+
+    fn schedule_email(key: EmailBatchKey, delay: Duration) {
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+
+            if let Err(e) = services().pusher.send_email(key).await {
+                warn!("Failed to send notification email: {e}");
+            }
+        });
+    }
+
+    async fn send_email(&self, key: EmailBatchKey) -> Result<()> {
+        let mut pending_emails = self.pending_emails.lock().await;
+        pending_emails.remove(&key);
+        let (user, address, room_id) = key;
+        let notifications = self
+            .db
+            .take_email_notifications(&user, &address, &room_id)?;
+        drop(pending_emails);
+
+        let Some(config) = &services().globals.config.email else {
+            return Ok(());
+        };
+
+        // The user has read the events in the meantime
+        if services().rooms.user.notification_count(&user, &room_id)? == 0 {
+            return Ok(());
+        }
+
+        let pdus = notifications
+            .iter()
+            .filter_map(|(event_id, highlight)| {
+                services()
+                    .rooms
+                    .timeline
+                    .get_pdu(event_id)
+                    .ok()
+                    .flatten()
+                    .map(|pdu| (pdu, *highlight))
+            })
+            .collect::<Vec<_>>();
+
+        let invite = pdus.iter().find(|(pdu, _)| email::is_invite(pdu, &user));
+        let mention = pdus.iter().find(|(_, highlight)| *highlight);
+
+        let ((pdu, _), kind) = match (invite, mention, pdus.first()) {
+            (Some(invite), _, _) => (invite, EmailKind::Invite),
+            (None, Some(mention), _) => (mention, EmailKind::Mention),
+            (None, None, Some(first)) => (first, EmailKind::Message),
+            (None, None, None) => return Ok(()),
+        };
+
+        let display_name = |user_id: &UserId| {
+            services()
+                .users
+                .displayname(user_id)
+                .ok()
+                .flatten()
+                .unwrap_or_else(|| user_id.to_string())
+        };
+
+        let sender = display_name(&pdu.sender);
+        let room_name = services()
+            .rooms
+            .state_accessor
+            .get_name(&room_id)?
+            .unwrap_or_else(|| room_id.to_string());
+        let messages = pdus
+            .iter()
+            .map(|(pdu, _)| {
+                format!(
+                    "{}: {}",
+                    display_name(&pdu.sender),
+                    email::summary(pdu, &user)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let count = pdus.len().to_string();
+
+        let values = [
+            ("server_name", services().globals.server_name().as_str()),
+            ("room_name", room_name.as_str()),
+            ("sender", sender.as_str()),
+            ("count", count.as_str()),
+            ("messages", messages.as_str()),
+        ];
+
+        let (subject, body) = kind.templates(&config.templates);
+
+        services()
+            .email
+            .send(&address, &render(subject, &values), render(body, &values))
+            .await
+    }
 }
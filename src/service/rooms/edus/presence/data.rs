@@ -1,38 +1,44 @@
 use std::collections::HashMap;
 
 use crate::Result;
-use ruma::{events::presence::PresenceEvent, OwnedUserId, RoomId, UserId};
+use ruma::{events::presence::PresenceEvent, OwnedRoomId, OwnedUserId, RoomId, UserId};
 
 pub trait Data: Send + Sync {
-    /// Adds a presence event which will be saved until a new event replaces it.
+    /// Replaces the presence event of the user and announces the change in the given rooms.
+    /// `count` is used to find the updates that happened since a sync or federation transaction.
     ///
-    /// Note: This method takes a RoomId because presence updates are always bound to rooms to
-    /// make sure users outside these rooms can't see them.
+    /// Note: Presence updates are announced per room to make sure users outside these rooms
+    /// can't see them.
     fn update_presence(
         &self,
         user_id: &UserId,
-        room_id: &RoomId,
+        room_ids: &[OwnedRoomId],
+        count: u64,
         presence: PresenceEvent,
     ) -> Result<()>;
 
     /// Resets the presence timeout, so the user will stay in their current presence state.
     fn ping_presence(&self, user_id: &UserId) -> Result<()>;
 
+    /// Sets the time of the last activity of the user, in millis since the unix epoch.
+    fn set_last_presence_update(&self, user_id: &UserId, timestamp: u64) -> Result<()>;
+
     /// Returns the timestamp of the last presence update of this user in millis since the unix epoch.
     fn last_presence_update(&self, user_id: &UserId) -> Result<Option<u64>>;
 
-    /// Returns the presence event with correct last_active_ago.
-    fn get_presence_event(
-        &self,
-        room_id: &RoomId,
-        user_id: &UserId,
-        count: u64,
-    ) -> Result<Option<PresenceEvent>>;
+    /// Returns an iterator over all users and the timestamp of their last presence update.
+    fn last_presence_updates<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<(OwnedUserId, u64)>> + 'a>;
+
+    /// Returns the current presence event of the user, as it was stored.
+    fn get_presence_event(&self, user_id: &UserId) -> Result<Option<PresenceEvent>>;
 
-    /// Returns the most recent presence updates that happened after the event with id `since`.
+    /// Returns the most recent presence updates that happened after the event with id `since`,
+    /// together with their counts.
     fn presence_since(
         &self,
         room_id: &RoomId,
         since: u64,
-    ) -> Result<HashMap<OwnedUserId, PresenceEvent>>;
+    ) -> Result<HashMap<OwnedUserId, (u64, PresenceEvent)>>;
 }
@@ -1,125 +1,413 @@
 mod data;
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Duration};
 
 pub use data::Data;
-use ruma::{events::presence::PresenceEvent, OwnedUserId, RoomId, UserId};
+use ruma::{
+    api::federation::transactions::edu::PresenceUpdate,
+    events::presence::{PresenceEvent, PresenceEventContent},
+    presence::PresenceState,
+    OwnedUserId, RoomId, UInt, UserId,
+};
+use tokio::time::interval;
+use tracing::{debug, error};
 
-use crate::Result;
+use crate::{services, utils, Result};
 
 pub struct Service {
     pub db: &'static dyn Data,
 }
 
 impl Service {
-    /// Adds a presence event which will be saved until a new event replaces it.
+    /// Replaces the presence event of the user and announces it in all rooms they are joined to.
     ///
-    /// Note: This method takes a RoomId because presence updates are always bound to rooms to
-    /// make sure users outside these rooms can't see them.
-    pub fn update_presence(
-        &self,
-        _user_id: &UserId,
-        _room_id: &RoomId,
-        _presence: PresenceEvent,
-    ) -> Result<()> {
-        // self.db.update_presence(user_id, room_id, presence)
-        Ok(())
-    }
+    /// All updates are sent to the appservices in these rooms. Updates of local users are picked
+    /// up by the sending service and sent to the other servers in batches.
+    pub async fn update_presence(&self, user_id: &UserId, presence: PresenceEvent) -> Result<()> {
+        if !services().globals.allow_presence() {
+            return Ok(());
+        }
+
+        let room_ids = services()
+            .rooms
+            .state_cache
+            .rooms_joined(user_id)
+            .filter_map(|r| r.ok())
+            .collect::<Vec<_>>();
+
+        let count = services().globals.next_count()?;
+        self.db
+            .update_presence(user_id, &room_ids, count, presence.clone())?;
+
+        let presence = self.to_client_event(presence)?;
+        services()
+            .sending
+            .send_ephemeral_appservices(
+                &room_ids,
+                serde_json::to_value(&presence).expect("presence event can be serialized"),
+            )
+            .await?;
 
-    /// Resets the presence timeout, so the user will stay in their current presence state.
-    pub fn ping_presence(&self, _user_id: &UserId) -> Result<()> {
-        // self.db.ping_presence(user_id)
         Ok(())
     }
 
-    pub fn get_last_presence_event(
-        &self,
-        _user_id: &UserId,
-        _room_id: &RoomId,
-    ) -> Result<Option<PresenceEvent>> {
-        // let last_update = match self.db.last_presence_update(user_id)? {
-        //     Some(last) => last,
-        //     None => return Ok(None),
-        // };
+    /// Stores a presence update of a remote user that was received over federation.
+    pub async fn update_remote_presence(&self, update: PresenceUpdate) -> Result<()> {
+        if !services().globals.allow_presence() {
+            return Ok(());
+        }
 
-        // self.db.get_presence_event(room_id, user_id, last_update)
-        Ok(None)
+        // Remember the last activity, so the user times out like local users if their server
+        // doesn't tell us when they go offline
+        let last_active =
+            utils::millis_since_unix_epoch().saturating_sub(update.last_active_ago.into());
+        self.db
+            .set_last_presence_update(&update.user_id, last_active)?;
+
+        self.update_presence(
+            &update.user_id,
+            PresenceEvent {
+                content: PresenceEventContent {
+                    avatar_url: None,
+                    currently_active: Some(update.currently_active),
+                    displayname: None,
+                    last_active_ago: None,
+                    presence: update.presence,
+                    status_msg: update.status_msg,
+                },
+                sender: update.user_id.clone(),
+            },
+        )
+        .await
     }
 
-    /* TODO
-    /// Sets all users to offline who have been quiet for too long.
-    fn _presence_maintain(
+    /// Sets the presence of a local user, using their current profile information.
+    ///
+    /// This also counts as activity of the user.
+    pub async fn set_presence(
         &self,
-        rooms: &super::Rooms,
-        globals: &super::super::globals::Globals,
+        user_id: &UserId,
+        presence: PresenceState,
+        status_msg: Option<String>,
     ) -> Result<()> {
-        let current_timestamp = utils::millis_since_unix_epoch();
+        if !services().globals.allow_presence() {
+            return Ok(());
+        }
 
-        for (user_id_bytes, last_timestamp) in self
-            .userid_lastpresenceupdate
-            .iter()
-            .filter_map(|(k, bytes)| {
-                Some((
-                    k,
-                    utils::u64_from_bytes(&bytes)
-                        .map_err(|_| {
-                            Error::bad_database("Invalid timestamp in userid_lastpresenceupdate.")
-                        })
-                        .ok()?,
-                ))
-            })
-            .take_while(|(_, timestamp)| current_timestamp.saturating_sub(*timestamp) > 5 * 60_000)
-        // 5 Minutes
-        {
-            // Send new presence events to set the user offline
-            let count = globals.next_count()?.to_be_bytes();
-            let user_id: Box<_> = utils::string_from_bytes(&user_id_bytes)
-                .map_err(|_| {
-                    Error::bad_database("Invalid UserId bytes in userid_lastpresenceupdate.")
-                })?
-                .try_into()
-                .map_err(|_| Error::bad_database("Invalid UserId in userid_lastpresenceupdate."))?;
-            for room_id in rooms.rooms_joined(&user_id).filter_map(|r| r.ok()) {
-                let mut presence_id = room_id.as_bytes().to_vec();
-                presence_id.push(0xff);
-                presence_id.extend_from_slice(&count);
-                presence_id.push(0xff);
-                presence_id.extend_from_slice(&user_id_bytes);
-
-                self.presenceid_presence.insert(
-                    &presence_id,
-                    &serde_json::to_vec(&PresenceEvent {
-                        content: PresenceEventContent {
-                            avatar_url: None,
-                            currently_active: None,
-                            displayname: None,
-                            last_active_ago: Some(
-                                last_timestamp.try_into().expect("time is valid"),
-                            ),
-                            presence: PresenceState::Offline,
-                            status_msg: None,
-                        },
-                        sender: user_id.to_owned(),
-                    })
-                    .expect("PresenceEvent can be serialized"),
-                )?;
+        self.db.ping_presence(user_id)?;
+
+        self.update_presence(
+            user_id,
+            PresenceEvent {
+                content: PresenceEventContent {
+                    avatar_url: services().users.avatar_url(user_id)?,
+                    currently_active: None,
+                    displayname: services().users.displayname(user_id)?,
+                    last_active_ago: Some(
+                        utils::millis_since_unix_epoch()
+                            .try_into()
+                            .expect("time is valid"),
+                    ),
+                    presence,
+                    status_msg,
+                },
+                sender: user_id.to_owned(),
+            },
+        )
+        .await
+    }
+
+    /// Announces the current presence of a local user again, e.g. after their profile changed.
+    pub async fn resend_presence(&self, user_id: &UserId) -> Result<()> {
+        let (presence, status_msg) = match self.db.get_presence_event(user_id)? {
+            Some(event) => (event.content.presence, event.content.status_msg),
+            None => (PresenceState::Online, None),
+        };
+
+        self.set_presence(user_id, presence, status_msg).await
+    }
+
+    /// Resets the presence timeout, so the user will stay in their current presence state.
+    ///
+    /// `requested` is the presence the client asked for while syncing. Users who are offline
+    /// or only unavailable because they were idle are switched to it.
+    pub async fn ping_presence(&self, user_id: &UserId, requested: &PresenceState) -> Result<()> {
+        if !services().globals.allow_presence() || *requested == PresenceState::Offline {
+            return Ok(());
+        }
+
+        let was_idle = is_idle(
+            self.db.last_presence_update(user_id)?,
+            utils::millis_since_unix_epoch(),
+            services().globals.presence_idle_timeout().as_millis() as u64,
+        );
+
+        self.db.ping_presence(user_id)?;
+
+        let event = self.db.get_presence_event(user_id)?;
+        match presence_after_ping(
+            event.as_ref().map(|event| &event.content.presence),
+            requested,
+            was_idle,
+        ) {
+            Some(presence) => {
+                self.set_presence(
+                    user_id,
+                    presence,
+                    event.and_then(|event| event.content.status_msg),
+                )
+                .await
             }
+            None => Ok(()),
+        }
+    }
 
-            self.userid_lastpresenceupdate.insert(
-                user_id.as_bytes(),
-                &utils::millis_since_unix_epoch().to_be_bytes(),
-            )?;
+    /// Returns the current presence event of the user.
+    pub fn get_last_presence_event(&self, user_id: &UserId) -> Result<Option<PresenceEvent>> {
+        if !services().globals.allow_presence() {
+            return Ok(None);
         }
 
-        Ok(())
-    }*/
+        self.db
+            .get_presence_event(user_id)?
+            .map(|presence| self.to_client_event(presence))
+            .transpose()
+    }
 
     /// Returns the most recent presence updates that happened after the event with id `since`.
     pub fn presence_since(
         &self,
-        _room_id: &RoomId,
-        _since: u64,
+        room_id: &RoomId,
+        since: u64,
     ) -> Result<HashMap<OwnedUserId, PresenceEvent>> {
-        // self.db.presence_since(room_id, since)
-        Ok(HashMap::new())
+        Ok(self
+            .presence_updates_since(room_id, since)?
+            .into_iter()
+            .map(|(user_id, (_, presence))| (user_id, presence))
+            .collect())
+    }
+
+    /// Like [`Self::presence_since`], but also returns the count of every update.
+    pub fn presence_updates_since(
+        &self,
+        room_id: &RoomId,
+        since: u64,
+    ) -> Result<HashMap<OwnedUserId, (u64, PresenceEvent)>> {
+        if !services().globals.allow_presence() {
+            return Ok(HashMap::new());
+        }
+
+        self.db
+            .presence_since(room_id, since)?
+            .into_iter()
+            .map(|(user_id, (count, presence))| {
+                Ok((user_id, (count, self.to_client_event(presence)?)))
+            })
+            .collect()
+    }
+
+    /// Replaces the stored timestamp of the last activity with the time since then, and works
+    /// out whether local users are currently active.
+    fn to_client_event(&self, mut presence: PresenceEvent) -> Result<PresenceEvent> {
+        let last_active = match self.db.last_presence_update(&presence.sender)? {
+            Some(last_active) => Some(last_active),
+            None => presence.content.last_active_ago.map(u64::from),
+        };
+        let now = utils::millis_since_unix_epoch();
+        let idle_timeout = services().globals.presence_idle_timeout().as_millis() as u64;
+
+        presence.content.last_active_ago = last_active_ago(last_active, now);
+
+        if presence.sender.server_name() == services().globals.server_name() {
+            presence.content.currently_active = (presence.content.presence
+                == PresenceState::Online)
+                .then(|| !is_idle(last_active, now, idle_timeout));
+        }
+
+        Ok(presence)
+    }
+
+    pub fn start_maintain_task(&self) {
+        tokio::spawn(async move {
+            let mut i = interval(Duration::from_secs(60));
+            loop {
+                i.tick().await;
+                debug!("presence_maintain: Timer ticked");
+                if let Err(e) = services().rooms.edus.presence.presence_maintain().await {
+                    error!("presence_maintain: Errored: {}", e);
+                }
+            }
+        });
+    }
+
+    /// Sets users who have been quiet for a while to unavailable, and later to offline.
+    async fn presence_maintain(&self) -> Result<()> {
+        let current_timestamp = utils::millis_since_unix_epoch();
+        let idle_timeout = services().globals.presence_idle_timeout().as_millis() as u64;
+        let offline_timeout = services().globals.presence_offline_timeout().as_millis() as u64;
+
+        for r in self.db.last_presence_updates() {
+            let (user_id, last_active) = r?;
+            let inactive_for = current_timestamp.saturating_sub(last_active);
+
+            let Some(mut presence) = self.db.get_presence_event(&user_id)? else {
+                continue;
+            };
+
+            let Some(timed_out) = timed_out_presence(
+                &presence.content.presence,
+                inactive_for,
+                idle_timeout,
+                offline_timeout,
+            ) else {
+                continue;
+            };
+            presence.content.presence = timed_out;
+            presence.content.currently_active = None;
+
+            self.update_presence(&user_id, presence).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether a user whose last activity was at `last_active` has been quiet for longer than the
+/// idle timeout. Users without any recorded activity count as idle.
+fn is_idle(last_active: Option<u64>, now: u64, idle_timeout: u64) -> bool {
+    last_active.is_none_or(|last_active| now.saturating_sub(last_active) >= idle_timeout)
+}
+
+/// How many millis ago the user was last active.
+fn last_active_ago(last_active: Option<u64>, now: u64) -> Option<UInt> {
+    last_active.map(|last_active| {
+        now.saturating_sub(last_active)
+            .try_into()
+            .expect("time is valid")
+    })
+}
+
+/// The presence a user who has been inactive for `inactive_for` millis falls back to, if it
+/// changes at all. Online users become unavailable after the idle timeout, and both become
+/// offline after the offline timeout.
+fn timed_out_presence(
+    presence: &PresenceState,
+    inactive_for: u64,
+    idle_timeout: u64,
+    offline_timeout: u64,
+) -> Option<PresenceState> {
+    match presence {
+        PresenceState::Online | PresenceState::Unavailable if inactive_for >= offline_timeout => {
+            Some(PresenceState::Offline)
+        }
+        PresenceState::Online if inactive_for >= idle_timeout => Some(PresenceState::Unavailable),
+        _ => None,
+    }
+}
+
+/// The presence a user is switched to when their client syncs with the `requested` presence, if
+/// it changes at all. Users who explicitly set themselves as unavailable stay unavailable, unless
+/// they were idle anyway.
+fn presence_after_ping(
+    current: Option<&PresenceState>,
+    requested: &PresenceState,
+    was_idle: bool,
+) -> Option<PresenceState> {
+    match current {
+        Some(current) if current == requested => None,
+        Some(PresenceState::Unavailable) if *requested == PresenceState::Online && !was_idle => {
+            None
+        }
+        _ => Some(requested.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma::{presence::PresenceState, uint};
+
+    use super::{is_idle, last_active_ago, presence_after_ping, timed_out_presence};
+
+    const IDLE_TIMEOUT: u64 = 5 * 60 * 1000;
+    const OFFLINE_TIMEOUT: u64 = 30 * 60 * 1000;
+
+    fn timed_out(presence: PresenceState, inactive_for: u64) -> Option<PresenceState> {
+        timed_out_presence(&presence, inactive_for, IDLE_TIMEOUT, OFFLINE_TIMEOUT)
+    }
+
+    #[test]
+    fn online_becomes_unavailable_when_idle() {
+        assert_eq!(timed_out(PresenceState::Online, IDLE_TIMEOUT - 1), None);
+        assert_eq!(
+            timed_out(PresenceState::Online, IDLE_TIMEOUT),
+            Some(PresenceState::Unavailable)
+        );
+        assert_eq!(timed_out(PresenceState::Unavailable, IDLE_TIMEOUT), None);
+    }
+
+    #[test]
+    fn becomes_offline_after_offline_timeout() {
+        assert_eq!(
+            timed_out(PresenceState::Online, OFFLINE_TIMEOUT),
+            Some(PresenceState::Offline)
+        );
+        assert_eq!(
+            timed_out(PresenceState::Unavailable, OFFLINE_TIMEOUT - 1),
+            None
+        );
+        assert_eq!(
+            timed_out(PresenceState::Unavailable, OFFLINE_TIMEOUT),
+            Some(PresenceState::Offline)
+        );
+        assert_eq!(timed_out(PresenceState::Offline, OFFLINE_TIMEOUT), None);
+    }
+
+    #[test]
+    fn ping_resets_idle_timer() {
+        let now = 1_700_000_000_000;
+        let last_active = now - IDLE_TIMEOUT;
+        assert!(is_idle(Some(last_active), now, IDLE_TIMEOUT));
+        assert!(is_idle(None, now, IDLE_TIMEOUT));
+        assert_eq!(
+            last_active_ago(Some(last_active), now),
+            Some(uint!(300_000))
+        );
+
+        // The idle user is brought back online by the ping, which stores the current time as
+        // the last activity
+        assert_eq!(
+            presence_after_ping(
+                Some(&PresenceState::Unavailable),
+                &PresenceState::Online,
+                true
+            ),
+            Some(PresenceState::Online)
+        );
+        assert!(!is_idle(Some(now), now, IDLE_TIMEOUT));
+        assert_eq!(last_active_ago(Some(now), now), Some(uint!(0)));
+        assert_eq!(timed_out(PresenceState::Online, 0), None);
+    }
+
+    #[test]
+    fn ping_keeps_explicit_presence() {
+        assert_eq!(
+            presence_after_ping(Some(&PresenceState::Online), &PresenceState::Online, false),
+            None
+        );
+        assert_eq!(
+            presence_after_ping(
+                Some(&PresenceState::Unavailable),
+                &PresenceState::Online,
+                false
+            ),
+            None
+        );
+        assert_eq!(
+            presence_after_ping(Some(&PresenceState::Offline), &PresenceState::Online, false),
+            Some(PresenceState::Online)
+        );
+        assert_eq!(
+            presence_after_ping(None, &PresenceState::Unavailable, true),
+            Some(PresenceState::Unavailable)
+        );
     }
 }
@@ -2,7 +2,7 @@ mod data;
 
 pub use data::Data;
 
-use crate::Result;
+use crate::{services, Result};
 use ruma::{events::receipt::ReceiptEvent, serde::Raw, OwnedUserId, RoomId, UserId};
 
 pub struct Service {
@@ -10,14 +10,21 @@ pub struct Service {
 }
 
 impl Service {
-    /// Replaces the previous read receipt.
-    pub fn readreceipt_update(
+    /// Replaces the previous read receipt and passes it on to the appservices in the room.
+    pub async fn readreceipt_update(
         &self,
         user_id: &UserId,
         room_id: &RoomId,
         event: ReceiptEvent,
     ) -> Result<()> {
-        self.db.readreceipt_update(user_id, room_id, event)
+        let json = serde_json::to_value(&event).expect("receipt event can be serialized");
+
+        self.db.readreceipt_update(user_id, room_id, event)?;
+
+        services()
+            .sending
+            .send_ephemeral_appservices(&[room_id.to_owned()], json)
+            .await
     }
 
     /// Returns an iterator over the most recent read_receipts in a room that happened after the event with id `since`.
@@ -1,4 +1,5 @@
 use ruma::{events::SyncEphemeralRoomEvent, OwnedRoomId, OwnedUserId, RoomId, UserId};
+use serde_json::json;
 use std::collections::BTreeMap;
 use tokio::sync::{broadcast, RwLock};
 
@@ -25,7 +26,7 @@ impl Service {
             .await
             .insert(room_id.to_owned(), services().globals.next_count()?);
         let _ = self.typing_update_sender.send(room_id.to_owned());
-        Ok(())
+        self.send_appservices(room_id).await
     }
 
     /// Removes a user from typing before the timeout is reached.
@@ -41,7 +42,7 @@ impl Service {
             .await
             .insert(room_id.to_owned(), services().globals.next_count()?);
         let _ = self.typing_update_sender.send(room_id.to_owned());
-        Ok(())
+        self.send_appservices(room_id).await
     }
 
     pub async fn wait_for_update(&self, room_id: &RoomId) -> Result<()> {
@@ -82,10 +83,28 @@ impl Service {
                 .await
                 .insert(room_id.to_owned(), services().globals.next_count()?);
             let _ = self.typing_update_sender.send(room_id.to_owned());
+            self.send_appservices(room_id).await?;
         }
         Ok(())
     }
 
+    /// Tells the appservices in the room who is typing now.
+    async fn send_appservices(&self, room_id: &RoomId) -> Result<()> {
+        let typings = self.typings_all(room_id).await?;
+
+        services()
+            .sending
+            .send_ephemeral_appservices(
+                &[room_id.to_owned()],
+                json!({
+                    "type": "m.typing",
+                    "room_id": room_id,
+                    "content": typings.content,
+                }),
+            )
+            .await
+    }
+
     /// Returns the count of the last typing update in this room.
     pub async fn last_typing_update(&self, room_id: &RoomId) -> Result<u64> {
         self.typings_maintain(room_id).await?;
@@ -1,3 +1,5 @@
+mod state_res_v1;
+
 /// An async function that can recursively call itself.
 type AsyncRecursiveType<'a, T> = Pin<Box<dyn Future<Output = T> + 'a + Send>>;
 
@@ -31,7 +33,7 @@ use ruma::{
         StateEventType, TimelineEventType,
     },
     int,
-    room_version_rules::{AuthorizationRules, RoomVersionRules, StateResolutionV2Rules},
+    room_version_rules::RoomVersionRules,
     serde::Base64,
     state_res::{self, StateMap},
     uint, CanonicalJsonObject, CanonicalJsonValue, EventId, MilliSecondsSinceUnixEpoch,
@@ -558,7 +560,12 @@ impl Service {
         debug!("Requesting state at event");
         let mut state_at_incoming_event = None;
 
-        if incoming_pdu.prev_events.len() == 1 {
+        // Our state is missing members, which the auth checks need
+        let partial_state = services().rooms.partial_state.is_partial_state(room_id)?;
+
+        if partial_state {
+            debug!("Room has partial state, asking the origin for the state at the event");
+        } else if incoming_pdu.prev_events.len() == 1 {
             let prev_event = &*incoming_pdu.prev_events[0];
             let prev_event_sstatehash = services()
                 .rooms
@@ -676,32 +683,8 @@ impl Service {
                     fork_states.push(state);
                 }
 
-                let lock = services().globals.stateres_mutex.lock();
-
-                let result = state_res::resolve(
-                    &room_version_rules.authorization,
-                    room_version_rules
-                        .state_res
-                        .v2_rules()
-                        .expect("We only support room versions using state resolution v2"),
-                    &fork_states,
-                    auth_chain_sets,
-                    |id| {
-                        let res = services().rooms.timeline.get_pdu(id);
-                        if let Err(e) = &res {
-                            error!("LOOK AT ME Failed to fetch event: {}", e);
-                        }
-                        res.ok().flatten()
-                    },
-                    |css| {
-                        services()
-                            .rooms
-                            .auth_chain
-                            .get_conflicted_state_subgraph(room_id, css)
-                            .ok()
-                    },
-                );
-                drop(lock);
+                let result =
+                    resolve_state_sets(room_id, &room_version_rules, &fork_states, auth_chain_sets);
 
                 state_at_incoming_event = match result {
                     Ok(new_state) => Some(
@@ -874,6 +857,14 @@ impl Service {
                     false
                 };
 
+        // The current state is not complete enough to soft fail events while it has partial state
+        let soft_fail = soft_fail && !partial_state
+            // Events of senders that are banned by a policy list are not shown to local users
+            || services()
+                .policy
+                .is_banned_sender(&incoming_pdu.sender, room_id)
+                .await?;
+
         // 14. Use state resolution to find new room state
 
         // We start looking at current room state now, so lets lock the room
@@ -939,15 +930,7 @@ impl Service {
             }
 
             let new_room_state = self
-                .resolve_state(
-                    room_id,
-                    &room_version_rules.authorization,
-                    room_version_rules
-                        .state_res
-                        .v2_rules()
-                        .expect("We only support room versions using state resolution v2"),
-                    state_after,
-                )
+                .resolve_state(room_id, &room_version_rules, state_after)
                 .await?;
 
             // Set the new room state to the resolved state
@@ -1024,8 +1007,7 @@ impl Service {
     async fn resolve_state(
         &self,
         room_id: &RoomId,
-        auth_rules: &AuthorizationRules,
-        state_res_rules: &StateResolutionV2Rules,
+        room_version_rules: &RoomVersionRules,
         incoming_state: HashMap<u64, Arc<EventId>>,
     ) -> Result<Arc<HashSet<CompressedStateEvent>>> {
         debug!("Loading current room state ids");
@@ -1075,36 +1057,7 @@ impl Service {
 
         debug!("Resolving state");
 
-        let fetch_event = |id: &_| {
-            let res = services().rooms.timeline.get_pdu(id);
-            if let Err(e) = &res {
-                error!("LOOK AT ME Failed to fetch event: {}", e);
-            }
-            res.ok().flatten()
-        };
-
-        let lock = services().globals.stateres_mutex.lock();
-        let state = match state_res::resolve(
-            auth_rules,
-            state_res_rules,
-            &fork_states,
-            auth_chain_sets,
-            fetch_event,
-            |css| {
-                services()
-                    .rooms
-                    .auth_chain
-                    .get_conflicted_state_subgraph(room_id, css)
-                    .ok()
-            },
-        ) {
-            Ok(new_state) => new_state,
-            Err(_) => {
-                return Err(Error::bad_database("State resolution failed, either an event could not be found or deserialization"));
-            }
-        };
-
-        drop(lock);
+        let state = resolve_state_sets(room_id, room_version_rules, &fork_states, auth_chain_sets)?;
 
         debug!("State resolution done. Compressing state");
 
@@ -1246,18 +1199,9 @@ impl Service {
                             }
 
                             if let Some(auth_events) =
-                                value.get("auth_events").and_then(|c| c.as_array())
+                                value.get("auth_events").and_then(pdu::referenced_event_ids)
                             {
-                                for auth_event in auth_events {
-                                    if let Ok(auth_event) =
-                                        serde_json::from_value(auth_event.clone().into())
-                                    {
-                                        let a: Arc<EventId> = auth_event;
-                                        todo_auth_events.push(a);
-                                    } else {
-                                        warn!("Auth event id is not valid");
-                                    }
-                                }
+                                todo_auth_events.extend(auth_events);
                             } else {
                                 warn!("Auth event list invalid");
                             }
@@ -1531,13 +1475,8 @@ impl Service {
             Error::BadServerResponse("Invalid PDU in server response")
         })?;
 
-        let event_id = format!(
-            "${}",
-            ruma::signatures::reference_hash(&value, room_version_rules)
-                .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid PDU format"))?
-        );
-        let event_id = <&EventId>::try_from(event_id.as_str())
-            .expect("ruma's reference hashes are valid event ids");
+        let event_id = pdu::gen_event_id(&value, room_version_rules)?;
+        let event_id = &*event_id;
 
         if let Some((time, tries)) = services()
             .globals
@@ -1641,6 +1580,27 @@ impl Service {
         event: &create_join_event::v2::Response,
         room_version_rules: &RoomVersionRules,
         pub_key_map: &RwLock<BTreeMap<String, SigningKeys>>,
+    ) -> Result<()> {
+        self.fetch_state_signing_keys(
+            event
+                .room_state
+                .state
+                .iter()
+                .chain(&event.room_state.auth_chain)
+                .map(|pdu| &**pdu),
+            room_version_rules,
+            pub_key_map,
+        )
+        .await
+    }
+
+    /// Fetches the keys needed to verify the signatures of a batch of PDUs, like the state of a
+    /// room, in as few requests as possible.
+    pub(crate) async fn fetch_state_signing_keys<'a>(
+        &self,
+        pdus: impl Iterator<Item = &'a RawJsonValue>,
+        room_version_rules: &RoomVersionRules,
+        pub_key_map: &RwLock<BTreeMap<String, SigningKeys>>,
     ) -> Result<()> {
         let mut servers: BTreeMap<
             OwnedServerName,
@@ -1652,12 +1612,7 @@ impl Service {
 
             // Try to fetch keys, failure is okay
             // Servers we couldn't find in the cache will be added to `servers`
-            for pdu in &event.room_state.state {
-                let _ = self
-                    .get_server_keys_from_cache(pdu, &mut servers, room_version_rules, &mut pkm)
-                    .await;
-            }
-            for pdu in &event.room_state.auth_chain {
+            for pdu in pdus {
                 let _ = self
                     .get_server_keys_from_cache(pdu, &mut servers, room_version_rules, &mut pkm)
                     .await;
@@ -1733,6 +1688,9 @@ impl Service {
             info!("Received new result");
             if let (Ok(get_keys_response), origin) = result {
                 info!("Result is from {origin}");
+                services()
+                    .globals
+                    .set_origin_server_keys(&origin, &get_keys_response.server_key)?;
                 if let Ok(key) = get_keys_response.server_key.deserialize() {
                     let result = services()
                         .globals
@@ -1914,7 +1872,14 @@ impl Service {
             .send_federation_request(origin, get_server_keys::v2::Request::new())
             .await
             .ok()
-            .and_then(|resp| resp.server_key.deserialize().ok())
+            .map(|resp| {
+                services()
+                    .globals
+                    .set_origin_server_keys(origin, &resp.server_key)?;
+                Ok::<_, Error>(resp.server_key.deserialize().ok())
+            })
+            .transpose()?
+            .flatten()
         {
             // Keys should only be valid for a maximum of seven days
             server_key.valid_until_ts = server_key.valid_until_ts.min(
@@ -2057,3 +2022,49 @@ impl Service {
         Ok(())
     }
 }
+
+/// Resolves the state of the forks with the state resolution algorithm of the room version.
+fn resolve_state_sets(
+    room_id: &RoomId,
+    room_version_rules: &RoomVersionRules,
+    fork_states: &[StateMap<Arc<EventId>>],
+    auth_chain_sets: Vec<HashSet<Arc<EventId>>>,
+) -> Result<StateMap<Arc<EventId>>> {
+    let fetch_event = |id: &EventId| {
+        let res = services().rooms.timeline.get_pdu(id);
+        if let Err(e) = &res {
+            error!("LOOK AT ME Failed to fetch event: {}", e);
+        }
+        res.ok().flatten()
+    };
+
+    let _lock = services().globals.stateres_mutex.lock();
+
+    match room_version_rules.state_res.v2_rules() {
+        Some(state_res_rules) => state_res::resolve(
+            &room_version_rules.authorization,
+            state_res_rules,
+            fork_states,
+            auth_chain_sets,
+            fetch_event,
+            |css| {
+                services()
+                    .rooms
+                    .auth_chain
+                    .get_conflicted_state_subgraph(room_id, css)
+                    .ok()
+            },
+        )
+        .map_err(|e| {
+            warn!("State resolution failed: {}", e);
+            Error::bad_database(
+                "State resolution failed, either an event could not be found or deserialization",
+            )
+        }),
+        // Only room version 1 uses state resolution v1
+        None => state_res_v1::resolve(&room_version_rules.authorization, fork_states, fetch_event)
+            .ok_or(Error::bad_database(
+                "State resolution failed, an event could not be found",
+            )),
+    }
+}
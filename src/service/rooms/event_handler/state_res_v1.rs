@@ -0,0 +1,384 @@
+//! State resolution v1, which is used by room version 1. Later room versions use state resolution
+//! v2 of `ruma::state_res`.
+
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use ruma::{
+    events::StateEventType,
+    room_version_rules::AuthorizationRules,
+    state_res::{self, StateMap},
+    EventId,
+};
+use sha1::{Digest, Sha1};
+
+use crate::PduEvent;
+
+/// Resolves the state of the state sets. Returns `None` if one of the conflicting events can't
+/// be found.
+pub(super) fn resolve(
+    auth_rules: &AuthorizationRules,
+    state_sets: &[StateMap<Arc<EventId>>],
+    fetch_event: impl Fn(&EventId) -> Option<Arc<PduEvent>>,
+) -> Option<StateMap<Arc<EventId>>> {
+    let (mut state, conflicted) = separate(state_sets);
+
+    let mut conflicted = conflicted
+        .into_iter()
+        .map(|(key, event_ids)| {
+            event_ids
+                .iter()
+                .map(|event_id| fetch_event(event_id))
+                .collect::<Option<Vec<_>>>()
+                .map(|events| (key, events))
+        })
+        .collect::<Option<HashMap<_, _>>>()?;
+
+    // The power levels and join rules are resolved first, as they authorize the other events
+    for key in [
+        (StateEventType::RoomPowerLevels, String::new()),
+        (StateEventType::RoomJoinRules, String::new()),
+    ] {
+        if let Some(events) = conflicted.remove(&key) {
+            let event = resolve_auth_events(auth_rules, events, &state, &fetch_event);
+            state.insert(key, event.event_id.clone());
+        }
+    }
+
+    // Each membership is resolved against the same state, and other events against the state
+    // with the resolved memberships
+    let (members, others): (HashMap<_, _>, HashMap<_, _>) = conflicted
+        .into_iter()
+        .partition(|((kind, _), _)| *kind == StateEventType::RoomMember);
+
+    let members: Vec<_> = members
+        .into_iter()
+        .map(|(key, events)| {
+            let event = resolve_auth_events(auth_rules, events, &state, &fetch_event);
+            (key, event.event_id.clone())
+        })
+        .collect();
+    state.extend(members);
+
+    let others: Vec<_> = others
+        .into_iter()
+        .map(|(key, events)| {
+            let event = resolve_normal_events(auth_rules, events, &state, &fetch_event);
+            (key, event.event_id.clone())
+        })
+        .collect();
+    state.extend(others);
+
+    Some(state)
+}
+
+/// Splits the state sets into the state all sets agree on and the event ids of the conflicts. A
+/// key that is missing from some sets is conflicted as well.
+fn separate(
+    state_sets: &[StateMap<Arc<EventId>>],
+) -> (StateMap<Arc<EventId>>, StateMap<HashSet<Arc<EventId>>>) {
+    let mut unconflicted = StateMap::new();
+    let mut conflicted = StateMap::new();
+
+    let keys: HashSet<_> = state_sets.iter().flat_map(|state| state.keys()).collect();
+    for key in keys {
+        let event_ids: HashSet<_> = state_sets.iter().map(|state| state.get(key)).collect();
+
+        if event_ids.len() == 1 {
+            if let Some(Some(event_id)) = event_ids.into_iter().next() {
+                unconflicted.insert(key.clone(), event_id.clone());
+            }
+        } else {
+            conflicted.insert(
+                key.clone(),
+                event_ids.into_iter().flatten().cloned().collect(),
+            );
+        }
+    }
+
+    (unconflicted, conflicted)
+}
+
+/// Sorts the events by descending depth, and by the SHA-1 hash of their event id if the depth is
+/// the same.
+fn ordered(mut events: Vec<Arc<PduEvent>>) -> Vec<Arc<PduEvent>> {
+    events.sort_by_cached_key(|event| {
+        (
+            Reverse(event.depth),
+            Sha1::digest(event.event_id.as_bytes()),
+        )
+    });
+    events
+}
+
+/// Checks the event against the state, in which `replaced` takes the place of the event with the
+/// same type and state key.
+fn is_allowed(
+    auth_rules: &AuthorizationRules,
+    event: &PduEvent,
+    state: &StateMap<Arc<EventId>>,
+    replaced: Option<&Arc<PduEvent>>,
+    fetch_event: &impl Fn(&EventId) -> Option<Arc<PduEvent>>,
+) -> bool {
+    state_res::check_state_dependent_auth_rules(auth_rules, event, |kind, state_key| match replaced
+    {
+        Some(replaced)
+            if replaced.kind.to_string() == kind.to_string()
+                && replaced.state_key.as_deref() == Some(state_key) =>
+        {
+            Some(replaced.clone())
+        }
+        _ => state
+            .get(&(kind.clone(), state_key.to_owned()))
+            .and_then(|event_id| fetch_event(event_id)),
+    })
+    .is_ok()
+}
+
+/// Resolves a conflict of events that authorize other events. Starting with the shallowest event,
+/// each event replaces the previous one as long as it is allowed by it.
+fn resolve_auth_events(
+    auth_rules: &AuthorizationRules,
+    events: Vec<Arc<PduEvent>>,
+    state: &StateMap<Arc<EventId>>,
+    fetch_event: &impl Fn(&EventId) -> Option<Arc<PduEvent>>,
+) -> Arc<PduEvent> {
+    let mut events = ordered(events).into_iter().rev();
+    let mut resolved = events
+        .next()
+        .expect("conflicts consist of at least one event");
+
+    for event in events {
+        if !is_allowed(auth_rules, &event, state, Some(&resolved), fetch_event) {
+            break;
+        }
+        resolved = event;
+    }
+
+    resolved
+}
+
+/// Resolves a conflict of other events by taking the deepest event that is allowed by the state.
+fn resolve_normal_events(
+    auth_rules: &AuthorizationRules,
+    events: Vec<Arc<PduEvent>>,
+    state: &StateMap<Arc<EventId>>,
+    fetch_event: &impl Fn(&EventId) -> Option<Arc<PduEvent>>,
+) -> Arc<PduEvent> {
+    let events = ordered(events);
+
+    events
+        .iter()
+        .find(|event| is_allowed(auth_rules, event, state, None, fetch_event))
+        .or(events.last())
+        .expect("conflicts consist of at least one event")
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use ruma::{
+        event_id, events::StateEventType, room_version_rules::AuthorizationRules,
+        state_res::StateMap, EventId,
+    };
+    use serde_json::json;
+
+    use super::{ordered, resolve, separate};
+    use crate::PduEvent;
+
+    fn pdu(
+        event_id: &str,
+        kind: &str,
+        state_key: &str,
+        sender: &str,
+        content: serde_json::Value,
+        depth: u64,
+    ) -> Arc<PduEvent> {
+        Arc::new(
+            serde_json::from_value(json!({
+                "event_id": event_id,
+                "room_id": "!room:example.com",
+                "sender": sender,
+                "origin_server_ts": 0,
+                "type": kind,
+                "state_key": state_key,
+                "content": content,
+                "prev_events": [],
+                "depth": depth,
+                "auth_events": [],
+                "hashes": { "sha256": "" },
+            }))
+            .unwrap(),
+        )
+    }
+
+    fn state(entries: &[(StateEventType, &str, &EventId)]) -> StateMap<Arc<EventId>> {
+        entries
+            .iter()
+            .map(|(kind, state_key, event_id)| {
+                ((kind.clone(), state_key.to_string()), Arc::from(*event_id))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn separate_conflicts() {
+        let a = state(&[
+            (
+                StateEventType::RoomCreate,
+                "",
+                event_id!("$create:example.com"),
+            ),
+            (
+                StateEventType::RoomName,
+                "",
+                event_id!("$name1:example.com"),
+            ),
+            (
+                StateEventType::RoomTopic,
+                "",
+                event_id!("$topic:example.com"),
+            ),
+        ]);
+        let b = state(&[
+            (
+                StateEventType::RoomCreate,
+                "",
+                event_id!("$create:example.com"),
+            ),
+            (
+                StateEventType::RoomName,
+                "",
+                event_id!("$name2:example.com"),
+            ),
+        ]);
+
+        let (unconflicted, conflicted) = separate(&[a, b]);
+
+        assert_eq!(unconflicted.len(), 1);
+        assert!(unconflicted.contains_key(&(StateEventType::RoomCreate, String::new())));
+        assert_eq!(
+            conflicted[&(StateEventType::RoomName, String::new())].len(),
+            2
+        );
+        assert_eq!(
+            conflicted[&(StateEventType::RoomTopic, String::new())].len(),
+            1
+        );
+    }
+
+    #[test]
+    fn order_by_depth() {
+        let shallow = pdu(
+            "$a:example.com",
+            "m.room.name",
+            "",
+            "@a:example.com",
+            json!({}),
+            1,
+        );
+        let deep = pdu(
+            "$b:example.com",
+            "m.room.name",
+            "",
+            "@a:example.com",
+            json!({}),
+            2,
+        );
+
+        let events = ordered(vec![shallow, deep]);
+
+        assert_eq!(&*events[0].event_id, "$b:example.com");
+    }
+
+    #[test]
+    fn resolve_power_levels() {
+        let alice = "@alice:example.com";
+        let events: HashMap<Arc<EventId>, Arc<PduEvent>> = [
+            pdu(
+                "$create:example.com",
+                "m.room.create",
+                "",
+                alice,
+                json!({ "creator": alice }),
+                1,
+            ),
+            pdu(
+                "$join:example.com",
+                "m.room.member",
+                alice,
+                alice,
+                json!({ "membership": "join" }),
+                2,
+            ),
+            pdu(
+                "$pl1:example.com",
+                "m.room.power_levels",
+                "",
+                alice,
+                json!({ "users": { alice: 100 } }),
+                3,
+            ),
+            pdu(
+                "$pl2:example.com",
+                "m.room.power_levels",
+                "",
+                alice,
+                json!({ "users": { alice: 100 }, "state_default": 100 }),
+                4,
+            ),
+            // Bob is not in the room, so his change is not allowed despite being the deepest
+            pdu(
+                "$pl3:example.com",
+                "m.room.power_levels",
+                "",
+                "@bob:example.com",
+                json!({ "users": { "@bob:example.com": 100 } }),
+                5,
+            ),
+        ]
+        .into_iter()
+        .map(|event| (event.event_id.clone(), event))
+        .collect();
+
+        let common = [
+            (
+                StateEventType::RoomCreate,
+                "",
+                event_id!("$create:example.com"),
+            ),
+            (
+                StateEventType::RoomMember,
+                alice,
+                event_id!("$join:example.com"),
+            ),
+        ];
+        let state_sets = ["$pl1:example.com", "$pl2:example.com", "$pl3:example.com"].map(|id| {
+            let mut state = state(&common);
+            state.insert(
+                (StateEventType::RoomPowerLevels, String::new()),
+                Arc::from(<&EventId>::try_from(id).unwrap()),
+            );
+            state
+        });
+
+        let resolved = resolve(&AuthorizationRules::V1, &state_sets, |event_id| {
+            events.get(event_id).cloned()
+        })
+        .unwrap();
+
+        assert_eq!(
+            &*resolved[&(StateEventType::RoomPowerLevels, String::new())],
+            "$pl2:example.com"
+        );
+        assert_eq!(
+            &*resolved[&(StateEventType::RoomMember, alice.to_owned())],
+            "$join:example.com"
+        );
+    }
+}
@@ -20,8 +20,9 @@ use ruma::{
         TimelineEventType,
     },
     room_version_rules::RoomVersionRules,
+    serde::Base64,
     state_res, CanonicalJsonObject, CanonicalJsonValue, EventId, MilliSecondsSinceUnixEpoch,
-    OwnedEventId, OwnedServerName, RoomId, RoomVersionId, UserId,
+    OwnedEventId, OwnedServerName, RoomId, RoomVersionId, ServerName, UserId,
 };
 use serde_json::value::{to_raw_value, RawValue as RawJsonValue};
 use tokio::sync::RwLock;
@@ -30,7 +31,7 @@ use tracing::{debug, error, info, warn};
 use crate::{
     service::{
         globals::SigningKeys,
-        pdu::{gen_event_id_canonical_json, PduBuilder},
+        pdu::{gen_event_id, gen_event_id_canonical_json, set_origin_event_id, PduBuilder},
     },
     services, utils, Error, PduEvent, Result,
 };
@@ -50,6 +51,18 @@ impl Service {
         servers: &[OwnedServerName],
         _third_party_signed: Option<&ThirdPartySigned>,
     ) -> Result<join_room_by_id::v3::Response, Error> {
+        if services().rooms.metadata.is_banned(room_id)? {
+            return Err(Error::BadRequest(
+                ErrorKind::forbidden(),
+                "This room has been shut down on this server.",
+            ));
+        }
+
+        services()
+            .policy
+            .check_membership(sender_user, room_id, "joining")
+            .await?;
+
         if let Ok(true) = services().rooms.state_cache.is_joined(sender_user, room_id) {
             return Ok(join_room_by_id::v3::Response {
                 room_id: room_id.into(),
@@ -112,7 +125,8 @@ impl Service {
                         room_id: room_id.to_owned(),
                         event_id: event_id.to_owned(),
                         pdu: PduEvent::convert_to_outgoing_federation_event(join_event.clone()),
-                        omit_members: false,
+                        // The members are fetched in the background after joining
+                        omit_members: true,
                     },
                 )
                 .await?;
@@ -284,6 +298,35 @@ impl Service {
                 ));
             };
 
+            if send_join_response.room_state.members_omitted {
+                info!("Members were omitted from send_join, the room has partial state");
+
+                let servers_in_room = send_join_response
+                    .room_state
+                    .servers_in_room
+                    .iter()
+                    .flatten()
+                    .filter_map(|server| ServerName::parse(server).ok())
+                    .collect::<Vec<_>>();
+
+                // The server that helped us join is the most likely to answer
+                let mut servers = vec![&*remote_server];
+                servers.extend(
+                    servers_in_room
+                        .iter()
+                        .map(|server| &**server)
+                        .filter(|server| {
+                            *server != &*remote_server
+                                && *server != services().globals.server_name()
+                        }),
+                );
+
+                services()
+                    .rooms
+                    .partial_state
+                    .mark_partial_state(room_id, &event_id, &servers)?;
+            }
+
             info!("Saving state from send_join");
             let (statehash_before_join, new, removed) =
                 services().rooms.state_compressor.save_state(
@@ -333,6 +376,13 @@ impl Service {
                 .rooms
                 .state
                 .set_room_state(room_id, statehash_after_join, &state_lock)?;
+
+            if send_join_response.room_state.members_omitted {
+                services()
+                    .rooms
+                    .partial_state
+                    .start_resync(room_id.to_owned());
+            }
         } else {
             info!("We can join locally");
 
@@ -575,7 +625,7 @@ impl Service {
         member_event_stub.insert("sender".to_owned(), sender_user.to_string().into());
         member_event_stub.insert("state_key".to_owned(), sender_user.to_string().into());
 
-        member_event_stub.remove("event_id");
+        set_origin_event_id(&mut member_event_stub, room_version_rules);
 
         ruma::signatures::hash_and_sign_event(
             services().globals.server_name().as_str(),
@@ -585,14 +635,8 @@ impl Service {
         )
         .expect("event is valid, we just created it");
 
-        let event_id = format!(
-            "${}",
-            ruma::signatures::reference_hash(&member_event_stub, room_version_rules)
-                .expect("Event format validated when event was hashed")
-        );
-
-        let event_id = <OwnedEventId>::try_from(event_id)
-            .expect("ruma's reference hashes are valid event ids");
+        let event_id = gen_event_id(&member_event_stub, room_version_rules)
+            .expect("Event format validated when event was hashed");
 
         member_event_stub.insert(
             "event_id".to_owned(),
@@ -641,26 +685,23 @@ async fn make_join_request(
 
     make_join_response_and_server
 }
-async fn validate_and_add_event_id(
+/// Parses a PDU of a server response and checks its format and signatures. The keys need to be
+/// fetched before.
+pub(crate) async fn validate_and_add_event_id(
     pdu: &RawJsonValue,
     room_version: &RoomVersionId,
     pub_key_map: &RwLock<BTreeMap<String, SigningKeys>>,
 ) -> Result<(OwnedEventId, CanonicalJsonObject)> {
-    let mut value: CanonicalJsonObject = serde_json::from_str(pdu.get()).map_err(|e| {
+    let value: CanonicalJsonObject = serde_json::from_str(pdu.get()).map_err(|e| {
         error!("Invalid PDU in server response: {:?}: {:?}", pdu, e);
         Error::BadServerResponse("Invalid PDU in server response")
     })?;
-    let event_id = EventId::parse(format!(
-        "${}",
-        ruma::signatures::reference_hash(
-            &value,
-            &room_version
-                .rules()
-                .expect("Supported room version has rules")
-        )
-        .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid PDU format"))?
-    ))
-    .expect("ruma's reference hashes are valid event ids");
+    let event_id = gen_event_id(
+        &value,
+        &room_version
+            .rules()
+            .expect("Supported room version has rules"),
+    )?;
 
     let back_off = |id| async {
         match services()
@@ -732,16 +773,91 @@ async fn validate_and_add_event_id(
         .globals
         .filter_keys_server_map(unfiltered_keys, origin_server_ts, rules);
 
-    if let Err(e) = ruma::signatures::verify_event(&keys, &value, rules) {
-        warn!("Event {} failed verification {:?} {}", event_id, pdu, e);
-        back_off(event_id).await;
-        return Err(Error::BadServerResponse("Event failed verification."));
+    match verify_and_add_event_id(&event_id, value, &keys, rules) {
+        Ok(value) => Ok((event_id, value)),
+        Err(e) => {
+            warn!("Event {} failed verification {:?} {}", event_id, pdu, e);
+            back_off(event_id).await;
+            Err(Error::BadServerResponse("Event failed verification."))
+        }
     }
+}
+
+/// Verifies the signatures of a PDU with the given public keys and adds its event id, which is
+/// not part of the PDU in room versions that derive it from the reference hash.
+fn verify_and_add_event_id(
+    event_id: &EventId,
+    mut value: CanonicalJsonObject,
+    keys: &BTreeMap<String, BTreeMap<String, Base64>>,
+    rules: &RoomVersionRules,
+) -> std::result::Result<CanonicalJsonObject, ruma::signatures::Error> {
+    ruma::signatures::verify_event(keys, &value, rules)?;
 
     value.insert(
         "event_id".to_owned(),
         CanonicalJsonValue::String(event_id.as_str().to_owned()),
     );
 
-    Ok((event_id, value))
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use ruma::{
+        room_version_rules::RoomVersionRules,
+        serde::Base64,
+        signatures::{hash_and_sign_event, Ed25519KeyPair},
+        CanonicalJsonObject, CanonicalJsonValue,
+    };
+    use serde_json::json;
+
+    use super::verify_and_add_event_id;
+    use crate::{service::pdu::gen_event_id, PduEvent};
+
+    #[test]
+    fn send_join_response_of_v1_room() {
+        let rules = RoomVersionRules::V1;
+        let key_pair =
+            Ed25519KeyPair::from_der(&Ed25519KeyPair::generate().unwrap(), "1".to_owned()).unwrap();
+        let keys = BTreeMap::from([(
+            "example.org".to_owned(),
+            BTreeMap::from([(
+                "ed25519:1".to_owned(),
+                Base64::new(key_pair.public_key().to_vec()),
+            )]),
+        )]);
+
+        // Events of room version 1 carry their id and reference earlier events with their hashes
+        let mut event: CanonicalJsonObject = serde_json::from_value(json!({
+            "event_id": "$join:example.org",
+            "room_id": "!old:example.org",
+            "sender": "@alice:example.org",
+            "origin": "example.org",
+            "origin_server_ts": 1_500_000_000_000_u64,
+            "type": "m.room.member",
+            "state_key": "@alice:example.org",
+            "content": { "membership": "join" },
+            "prev_events": [["$create:example.org", { "sha256": "aGFzaA" }]],
+            "auth_events": [["$create:example.org", { "sha256": "aGFzaA" }]],
+            "depth": 2,
+        }))
+        .unwrap();
+        hash_and_sign_event("example.org", &key_pair, &mut event, &rules.redaction).unwrap();
+
+        let event_id = gen_event_id(&event, &rules).unwrap();
+        assert_eq!(event_id, "$join:example.org");
+
+        let value = verify_and_add_event_id(&event_id, event.clone(), &keys, &rules).unwrap();
+        let pdu = PduEvent::from_id_val(&event_id, value).unwrap();
+        assert_eq!(&*pdu.prev_events[0], "$create:example.org");
+        assert_eq!(&*pdu.auth_events[0], "$create:example.org");
+
+        event.insert(
+            "depth".to_owned(),
+            CanonicalJsonValue::Integer(3_u32.into()),
+        );
+        assert!(verify_and_add_event_id(&event_id, event, &keys, &rules).is_err());
+    }
 }
@@ -6,4 +6,9 @@ pub trait Data: Send + Sync {
     fn iter_ids<'a>(&'a self) -> Box<dyn Iterator<Item = Result<OwnedRoomId>> + 'a>;
     fn is_disabled(&self, room_id: &RoomId) -> Result<bool>;
     fn disable_room(&self, room_id: &RoomId, disabled: bool) -> Result<()>;
+    fn is_banned(&self, room_id: &RoomId) -> Result<bool>;
+    fn ban_room(&self, room_id: &RoomId, banned: bool) -> Result<()>;
+    /// Removes the timeline, state, search index, short ids, aliases, memberships and all other
+    /// data of the room.
+    fn purge_room(&self, room_id: &RoomId) -> Result<()>;
 }
@@ -3,7 +3,7 @@ mod data;
 pub use data::Data;
 use ruma::{OwnedRoomId, RoomId};
 
-use crate::Result;
+use crate::{services, Result};
 
 pub struct Service {
     pub db: &'static dyn Data,
@@ -27,4 +27,25 @@ impl Service {
     pub fn disable_room(&self, room_id: &RoomId, disabled: bool) -> Result<()> {
         self.db.disable_room(room_id, disabled)
     }
+
+    /// Checks if the room was shut down, in which case nobody can join it through this server.
+    pub fn is_banned(&self, room_id: &RoomId) -> Result<bool> {
+        self.db.is_banned(room_id)
+    }
+
+    pub fn ban_room(&self, room_id: &RoomId, banned: bool) -> Result<()> {
+        self.db.ban_room(room_id, banned)
+    }
+
+    /// Deletes all data of the room from the database.
+    ///
+    /// Whether the room is disabled or banned is kept, so that it does not come back.
+    #[tracing::instrument(skip(self))]
+    pub fn purge_room(&self, room_id: &RoomId) -> Result<()> {
+        if let Some(shortroomid) = services().rooms.short.get_shortroomid(room_id)? {
+            services().sending.remove_room(shortroomid)?;
+        }
+
+        self.db.purge_room(room_id)
+    }
 }
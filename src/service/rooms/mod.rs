@@ -7,6 +7,7 @@ pub mod helpers;
 pub mod lazy_loading;
 pub mod metadata;
 pub mod outlier;
+pub mod partial_state;
 pub mod pdu_metadata;
 pub mod search;
 pub mod short;
@@ -27,6 +28,7 @@ pub trait Data:
     + lazy_loading::Data
     + metadata::Data
     + outlier::Data
+    + partial_state::Data
     + pdu_metadata::Data
     + search::Data
     + short::Data
@@ -50,6 +52,7 @@ pub struct Service {
     pub lazy_loading: lazy_loading::Service,
     pub metadata: metadata::Service,
     pub outlier: outlier::Service,
+    pub partial_state: partial_state::Service,
     pub pdu_metadata: pdu_metadata::Service,
     pub search: search::Service,
     pub short: short::Service,
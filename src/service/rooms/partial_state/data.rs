@@ -0,0 +1,27 @@
+use crate::Result;
+use ruma::{EventId, OwnedEventId, OwnedRoomId, OwnedServerName, RoomId, ServerName};
+
+pub trait Data: Send + Sync {
+    /// Marks the room as having partial state, after joining it with `event_id` without receiving
+    /// the members. `servers` were in the room at the time.
+    fn mark_partial_state(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+        servers: &[&ServerName],
+    ) -> Result<()>;
+
+    /// Marks the room as having its full state again, since the event with id `count`.
+    fn unmark_partial_state(&self, room_id: &RoomId, count: u64) -> Result<()>;
+
+    /// Returns the count since which the room has its full state, if it ever had partial state.
+    fn full_state_count(&self, room_id: &RoomId) -> Result<Option<u64>>;
+
+    /// Returns the join event and the servers in the room, if the room has partial state.
+    fn partial_state(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Option<(OwnedEventId, Vec<OwnedServerName>)>>;
+
+    fn partial_state_rooms<'a>(&'a self) -> Box<dyn Iterator<Item = Result<OwnedRoomId>> + 'a>;
+}
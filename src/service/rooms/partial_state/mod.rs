@@ -0,0 +1,344 @@
+mod data;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::Duration,
+};
+
+pub use data::Data;
+use ruma::{
+    api::federation::event::get_room_state, EventId, OwnedRoomId, OwnedServerName, RoomId,
+    ServerName,
+};
+use tokio::sync::{Notify, RwLock};
+use tracing::{debug, info, warn};
+
+use crate::{
+    service::rooms::helpers::validate_and_add_event_id, services, Error, PduEvent, Result,
+};
+
+/// How `/sync` shows a room that might have partial state.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SyncBehavior {
+    Normal,
+    /// The room is left out of the response
+    Hidden,
+    /// The room is sent as if the user had just joined it
+    Rejoined,
+}
+
+/// Rooms can be joined without receiving the member events, which can be a large part of the
+/// state of big rooms. The rest of the state is fetched in the background afterwards.
+pub struct Service {
+    pub db: &'static dyn Data,
+    /// Woken up whenever a room has received its full state
+    pub resynced: Notify,
+}
+
+impl Service {
+    pub fn is_partial_state(&self, room_id: &RoomId) -> Result<bool> {
+        Ok(self.db.partial_state(room_id)?.is_some())
+    }
+
+    /// Decides how `/sync` shows the room to a client that last synced at `since`.
+    pub fn sync_behavior(
+        &self,
+        room_id: &RoomId,
+        since: u64,
+        lazy_load_members: bool,
+    ) -> Result<SyncBehavior> {
+        Ok(sync_behavior(
+            self.is_partial_state(room_id)?,
+            self.db.full_state_count(room_id)?,
+            since,
+            lazy_load_members,
+        ))
+    }
+
+    /// Returns the servers that were in the room when we joined it, if the room has partial state.
+    /// Most of them are not known from the member events yet.
+    pub fn servers_in_room(&self, room_id: &RoomId) -> Result<Vec<OwnedServerName>> {
+        Ok(self
+            .db
+            .partial_state(room_id)?
+            .map(|(_, servers)| servers)
+            .unwrap_or_default())
+    }
+
+    pub fn mark_partial_state(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+        servers: &[&ServerName],
+    ) -> Result<()> {
+        self.db.mark_partial_state(room_id, event_id, servers)
+    }
+
+    /// Waits until the room has its full state.
+    pub async fn wait_for_full_state(&self, room_id: &RoomId) -> Result<()> {
+        loop {
+            // Created before checking, so no wake up can be missed
+            let resynced = self.resynced.notified();
+
+            if !self.is_partial_state(room_id)? {
+                return Ok(());
+            }
+
+            debug!("Waiting for the full state of {room_id}");
+            resynced.await;
+        }
+    }
+
+    /// Continues fetching the state of rooms that still have partial state, e.g. after a restart.
+    pub fn start_resync_tasks(&self) {
+        for room_id in self.db.partial_state_rooms().filter_map(|r| r.ok()) {
+            self.start_resync(room_id);
+        }
+    }
+
+    /// Fetches the full state of the room in the background, retrying until it succeeds.
+    pub fn start_resync(&self, room_id: OwnedRoomId) {
+        tokio::spawn(async move {
+            let mut delay = Duration::from_secs(10);
+
+            while let Err(e) = services().rooms.partial_state.resync(&room_id).await {
+                warn!("Failed to fetch the full state of {room_id}, retrying in {delay:?}: {e}");
+
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(Duration::from_secs(60 * 60));
+            }
+        });
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn resync(&self, room_id: &RoomId) -> Result<()> {
+        let Some((event_id, servers)) = self.db.partial_state(room_id)? else {
+            return Ok(());
+        };
+
+        let room_version_id = services().rooms.state.get_room_version(room_id)?;
+        let room_version_rules = room_version_id
+            .rules()
+            .expect("Supported room version has rules");
+        let pub_key_map = RwLock::new(BTreeMap::new());
+
+        let mut last_error =
+            Error::BadServerResponse("No server available to fetch the room state from.");
+
+        for server in servers
+            .iter()
+            .filter(|server| *server != services().globals.server_name())
+        {
+            debug!("Asking {server} for the state at our join event");
+            // The whole state and its auth chain come in one response, instead of fetching
+            // every event on its own
+            let response = match services()
+                .sending
+                .send_federation_request(
+                    server,
+                    get_room_state::v1::Request {
+                        room_id: room_id.to_owned(),
+                        event_id: event_id.clone(),
+                    },
+                )
+                .await
+            {
+                Ok(response) => response,
+                Err(e) => {
+                    debug!("Fetching the state from {server} failed: {e}");
+                    last_error = e;
+                    continue;
+                }
+            };
+
+            services()
+                .rooms
+                .event_handler
+                .fetch_state_signing_keys(
+                    response
+                        .pdus
+                        .iter()
+                        .chain(&response.auth_chain)
+                        .map(|pdu| &**pdu),
+                    &room_version_rules,
+                    &pub_key_map,
+                )
+                .await?;
+
+            for pdu in &response.auth_chain {
+                let Ok((event_id, value)) =
+                    validate_and_add_event_id(pdu, &room_version_id, &pub_key_map).await
+                else {
+                    continue;
+                };
+
+                services()
+                    .rooms
+                    .outlier
+                    .add_pdu_outlier(&event_id, &value)?;
+            }
+
+            let mut state = HashMap::new();
+            for pdu in &response.pdus {
+                let Ok((event_id, value)) =
+                    validate_and_add_event_id(pdu, &room_version_id, &pub_key_map).await
+                else {
+                    continue;
+                };
+
+                let pdu = PduEvent::from_id_val(&event_id, value.clone()).map_err(|e| {
+                    warn!("Invalid PDU in state response: {e} {value:?}");
+                    Error::BadServerResponse("Invalid PDU in state response.")
+                })?;
+
+                services()
+                    .rooms
+                    .outlier
+                    .add_pdu_outlier(&event_id, &value)?;
+
+                if let Some(state_key) = &pdu.state_key {
+                    let shortstatekey = services()
+                        .rooms
+                        .short
+                        .get_or_create_shortstatekey(&pdu.kind.to_string().into(), state_key)?;
+                    state.insert(shortstatekey, Arc::from(&*event_id));
+                }
+            }
+
+            if state.len() < response.pdus.len() {
+                warn!(
+                    "Only {} of {} state events of {room_id} from {server} were valid",
+                    state.len(),
+                    response.pdus.len()
+                );
+            }
+
+            self.complete_state(room_id, state).await?;
+
+            info!("Fetched the full state of {room_id} from {server}");
+            return Ok(());
+        }
+
+        Err(last_error)
+    }
+
+    /// Adds the state events we were missing to the current state of the room.
+    async fn complete_state(
+        &self,
+        room_id: &RoomId,
+        state_at_join: HashMap<u64, Arc<EventId>>,
+    ) -> Result<()> {
+        let mutex_state = Arc::clone(
+            services()
+                .globals
+                .roomid_mutex_state
+                .write()
+                .await
+                .entry(room_id.to_owned())
+                .or_default(),
+        );
+        let state_lock = mutex_state.lock().await;
+
+        let mut state = match services().rooms.state.get_room_shortstatehash(room_id)? {
+            Some(shortstatehash) => {
+                services()
+                    .rooms
+                    .state_accessor
+                    .state_full_ids(shortstatehash)
+                    .await?
+            }
+            None => HashMap::new(),
+        };
+
+        // Events we received since joining are newer than the state at the join
+        for (shortstatekey, event_id) in state_at_join {
+            state.entry(shortstatekey).or_insert(event_id);
+        }
+
+        let state = state
+            .iter()
+            .map(|(shortstatekey, event_id)| {
+                services()
+                    .rooms
+                    .state_compressor
+                    .compress_state_event(*shortstatekey, event_id)
+            })
+            .collect::<Result<_>>()?;
+
+        let (shortstatehash, new, removed) = services()
+            .rooms
+            .state_compressor
+            .save_state(room_id, Arc::new(state))?;
+
+        services()
+            .rooms
+            .state
+            .force_state(room_id, shortstatehash, new, removed, &state_lock)
+            .await?;
+
+        self.db
+            .unmark_partial_state(room_id, services().globals.next_count()?)?;
+        drop(state_lock);
+
+        self.resynced.notify_waiters();
+
+        Ok(())
+    }
+}
+
+/// Clients that don't lazy load members expect the member list to be complete. They don't see
+/// rooms with partial state, and receive them like a newly joined room once the state is complete.
+fn sync_behavior(
+    partial_state: bool,
+    full_state_count: Option<u64>,
+    since: u64,
+    lazy_load_members: bool,
+) -> SyncBehavior {
+    if lazy_load_members {
+        SyncBehavior::Normal
+    } else if partial_state {
+        SyncBehavior::Hidden
+    } else if full_state_count.is_some_and(|count| count > since) {
+        SyncBehavior::Rejoined
+    } else {
+        SyncBehavior::Normal
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sync_behavior, SyncBehavior};
+
+    #[test]
+    fn lazy_loading_clients_see_partial_state() {
+        assert_eq!(sync_behavior(true, None, 10, true), SyncBehavior::Normal);
+        assert_eq!(
+            sync_behavior(false, Some(20), 10, true),
+            SyncBehavior::Normal
+        );
+    }
+
+    #[test]
+    fn other_clients_wait_for_full_state() {
+        assert_eq!(sync_behavior(true, None, 10, false), SyncBehavior::Hidden);
+        // Joined again with partial state after an earlier resync
+        assert_eq!(
+            sync_behavior(true, Some(5), 10, false),
+            SyncBehavior::Hidden
+        );
+    }
+
+    #[test]
+    fn resynced_rooms_are_sent_once_as_new() {
+        assert_eq!(
+            sync_behavior(false, Some(20), 10, false),
+            SyncBehavior::Rejoined
+        );
+        assert_eq!(
+            sync_behavior(false, Some(20), 20, false),
+            SyncBehavior::Normal
+        );
+        assert_eq!(sync_behavior(false, None, 10, false), SyncBehavior::Normal);
+    }
+}
@@ -1,15 +1,27 @@
+use std::collections::{HashMap, HashSet};
+
 use crate::Result;
-use ruma::RoomId;
+
+use super::Posting;
 
 pub trait Data: Send + Sync {
-    fn index_pdu(&self, shortroomid: u64, pdu_id: &[u8], message_body: &str) -> Result<()>;
+    /// Adds a pdu to the index. `tokens` maps every token of the message to how often it occurs,
+    /// and `length` is the total number of tokens in the message.
+    fn index_pdu(
+        &self,
+        shortroomid: u64,
+        pdu_id: &[u8],
+        tokens: &HashMap<String, u32>,
+        length: u32,
+    ) -> Result<()>;
 
-    fn deindex_pdu(&self, shortroomid: u64, pdu_id: &[u8], message_body: &str) -> Result<()>;
+    fn deindex_pdu(&self, shortroomid: u64, pdu_id: &[u8], tokens: &HashSet<String>) -> Result<()>;
 
-    #[allow(clippy::type_complexity)]
-    fn search_pdus<'a>(
+    /// Returns all pdus in the room containing a token that starts with `prefix`. The pdus
+    /// containing exactly the token come first, newest first.
+    fn search_prefix<'a>(
         &'a self,
-        room_id: &RoomId,
-        search_string: &str,
-    ) -> Result<Option<(Box<dyn Iterator<Item = Vec<u8>> + 'a>, Vec<String>)>>;
+        shortroomid: u64,
+        prefix: &str,
+    ) -> Box<dyn Iterator<Item = Result<Posting>> + 'a>;
 }
@@ -1,9 +1,93 @@
 mod data;
 
+use std::collections::HashMap;
+
 pub use data::Data;
 
-use crate::Result;
+use crate::{services, Result};
 use ruma::RoomId;
+use serde::Deserialize;
+
+/// Longer words are not indexed
+const MAX_WORD_LENGTH: usize = 50;
+
+/// Parameters of the BM25 ranking function
+const K1: f64 = 1.2;
+const B: f64 = 0.75;
+
+/// Words that only start with a search token count less than exact matches
+const PREFIX_MATCH_WEIGHT: f64 = 0.5;
+
+/// Only this many pdus are considered for each token, so searches for common words don't read
+/// the whole index of large rooms
+const MAX_POSTINGS_PER_TOKEN: usize = 10_000;
+
+/// An entry of the index: a pdu containing a token.
+pub struct Posting {
+    pub pdu_id: Vec<u8>,
+    /// Whether the token is exactly the searched one, and not just starting with it
+    pub exact: bool,
+    /// How often the token occurs in the message
+    pub frequency: u32,
+    /// The total number of tokens in the message
+    pub length: u32,
+}
+
+/// A parsed search term.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SearchQuery {
+    /// Every token has to be the start of a word in the message
+    tokens: Vec<String>,
+    /// Every phrase has to occur in the message as it is
+    phrases: Vec<String>,
+}
+
+impl SearchQuery {
+    /// Parses a search term. Text in double quotes is searched for as a phrase.
+    pub fn parse(search_term: &str) -> Self {
+        let mut tokens = Vec::new();
+        let mut phrases = Vec::new();
+
+        for (i, part) in search_term.split('"').enumerate() {
+            // Everything between two quotes is a phrase
+            if i % 2 == 1 {
+                let phrase = normalize(part);
+                if !phrase.is_empty() {
+                    phrases.push(phrase);
+                }
+            }
+
+            for (word, cjk) in words(part) {
+                if !cjk {
+                    tokens.push(word.to_lowercase());
+                    continue;
+                }
+
+                let chars = word.chars().collect::<Vec<_>>();
+                if chars.len() == 1 {
+                    tokens.push(word.to_owned());
+                } else {
+                    tokens.extend(chars.windows(2).map(|pair| pair.iter().collect()));
+                }
+
+                // The pairs of characters could be in any order
+                if chars.len() > 2 {
+                    phrases.push(word.to_owned());
+                }
+            }
+        }
+
+        tokens.sort_unstable();
+        tokens.dedup();
+
+        Self { tokens, phrases }
+    }
+
+    /// Words clients should highlight in the results.
+    pub fn highlights(&self) -> Vec<String> {
+        self.tokens.clone()
+    }
+}
 
 pub struct Service {
     pub db: &'static dyn Data,
@@ -12,20 +96,244 @@ pub struct Service {
 impl Service {
     #[tracing::instrument(skip(self))]
     pub fn index_pdu(&self, shortroomid: u64, pdu_id: &[u8], message_body: &str) -> Result<()> {
-        self.db.index_pdu(shortroomid, pdu_id, message_body)
+        let mut tokens = HashMap::new();
+        let mut length = 0;
+
+        for token in tokenize(message_body) {
+            *tokens.entry(token).or_default() += 1;
+            length += 1;
+        }
+
+        self.db.index_pdu(shortroomid, pdu_id, &tokens, length)
     }
 
     #[tracing::instrument(skip(self))]
     pub fn deindex_pdu(&self, shortroomid: u64, pdu_id: &[u8], message_body: &str) -> Result<()> {
-        self.db.deindex_pdu(shortroomid, pdu_id, message_body)
+        self.db
+            .deindex_pdu(shortroomid, pdu_id, &tokenize(message_body).collect())
     }
 
+    /// Returns the ids of the pdus in the room matching the query, together with their relevance.
+    /// Only the newest `MAX_POSTINGS_PER_TOKEN` exact matches of each token are considered.
+    ///
+    /// The relevance is calculated using BM25, where the documents matching any of the tokens
+    /// make up the collection.
     #[tracing::instrument(skip(self))]
-    pub fn search_pdus<'a>(
-        &'a self,
+    pub fn search_pdus(
+        &self,
         room_id: &RoomId,
-        search_string: &str,
-    ) -> Result<Option<(impl Iterator<Item = Vec<u8>> + 'a, Vec<String>)>> {
-        self.db.search_pdus(room_id, search_string)
+        query: &SearchQuery,
+    ) -> Result<Vec<(Vec<u8>, f64)>> {
+        let Some(shortroomid) = services().rooms.short.get_shortroomid(room_id)? else {
+            return Ok(Vec::new());
+        };
+
+        if query.tokens.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // For every token: pdu id -> weighted frequency
+        let mut matches = Vec::new();
+        let mut lengths = HashMap::new();
+
+        for token in &query.tokens {
+            let mut token_matches = HashMap::new();
+
+            for posting in self
+                .db
+                .search_prefix(shortroomid, token)
+                .take(MAX_POSTINGS_PER_TOKEN)
+            {
+                let posting = posting?;
+
+                let weight = if posting.exact {
+                    1.0
+                } else {
+                    PREFIX_MATCH_WEIGHT
+                };
+                *token_matches.entry(posting.pdu_id.clone()).or_insert(0.0) +=
+                    weight * f64::from(posting.frequency);
+                lengths.insert(posting.pdu_id, f64::from(posting.length));
+            }
+
+            if token_matches.is_empty() {
+                return Ok(Vec::new());
+            }
+
+            matches.push(token_matches);
+        }
+
+        let collection_size = lengths.len() as f64;
+        let average_length = lengths.values().sum::<f64>() / collection_size;
+
+        let (first, rest) = matches.split_first().expect("there is at least one token");
+
+        let mut results = Vec::new();
+        for pdu_id in first.keys() {
+            if !rest
+                .iter()
+                .all(|token_matches| token_matches.contains_key(pdu_id))
+            {
+                continue;
+            }
+
+            if !query.phrases.is_empty() && !self.contains_phrases(pdu_id, &query.phrases)? {
+                continue;
+            }
+
+            let length = lengths[pdu_id];
+            let rank = matches
+                .iter()
+                .map(|token_matches| {
+                    let frequency = token_matches[pdu_id];
+                    let idf = (1.0
+                        + (collection_size - token_matches.len() as f64 + 0.5)
+                            / (token_matches.len() as f64 + 0.5))
+                        .ln();
+
+                    idf * frequency * (K1 + 1.0)
+                        / (frequency + K1 * (1.0 - B + B * length / average_length))
+                })
+                .sum();
+
+            results.push((pdu_id.clone(), rank));
+        }
+
+        Ok(results)
+    }
+
+    fn contains_phrases(&self, pdu_id: &[u8], phrases: &[String]) -> Result<bool> {
+        #[derive(Deserialize)]
+        struct ExtractBody {
+            body: String,
+        }
+
+        let Some(pdu) = services().rooms.timeline.get_pdu_from_id(pdu_id)? else {
+            return Ok(false);
+        };
+
+        let Ok(content) = serde_json::from_str::<ExtractBody>(pdu.content.get()) else {
+            return Ok(false);
+        };
+
+        let body = normalize(&content.body);
+
+        Ok(phrases.iter().all(|phrase| body.contains(phrase.as_str())))
+    }
+}
+
+/// Splits text into the tokens used as keys in the search index.
+///
+/// Chinese and Japanese are written without spaces, so their characters are indexed on their own
+/// and as pairs of adjacent characters.
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    words(text).flat_map(|(word, cjk)| {
+        if cjk {
+            let chars = word.chars().collect::<Vec<_>>();
+            chars
+                .iter()
+                .map(char::to_string)
+                .chain(chars.windows(2).map(|pair| pair.iter().collect()))
+                .collect::<Vec<_>>()
+        } else {
+            vec![word.to_lowercase()]
+        }
+    })
+}
+
+/// Splits text into words and runs of CJK characters. Also returns whether it is a CJK run.
+fn words(text: &str) -> impl Iterator<Item = (&str, bool)> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .flat_map(|word| {
+            let mut runs = Vec::new();
+            let mut start = 0;
+            let mut previous = None;
+
+            for (i, c) in word.char_indices() {
+                if previous.is_some_and(|previous| is_cjk(previous) != is_cjk(c)) {
+                    runs.push(&word[start..i]);
+                    start = i;
+                }
+                previous = Some(c);
+            }
+            runs.push(&word[start..]);
+
+            runs
+        })
+        .filter(|word| !word.is_empty())
+        .map(|word| (word, word.chars().next().is_some_and(is_cjk)))
+        // CJK runs can be whole sentences, but they are split up further anyway
+        .filter(|(word, cjk)| *cjk || word.len() <= MAX_WORD_LENGTH)
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30ff}' // Hiragana and Katakana
+        | '\u{3400}'..='\u{4dbf}' // CJK Unified Ideographs Extension A
+        | '\u{4e00}'..='\u{9fff}' // CJK Unified Ideographs
+        | '\u{f900}'..='\u{faff}' // CJK Compatibility Ideographs
+        | '\u{20000}'..='\u{2ffff}' // Supplementary Ideographic Planes
+    )
+}
+
+/// Lowercases text and collapses whitespace, so phrases can be compared.
+fn normalize(text: &str) -> String {
+    text.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{tokenize, words, SearchQuery, MAX_WORD_LENGTH};
+
+    #[test]
+    fn parse_words_and_phrases() {
+        assert_eq!(
+            SearchQuery::parse("Hello \"big  World\" cats"),
+            SearchQuery {
+                tokens: vec!["big".into(), "cats".into(), "hello".into(), "world".into()],
+                phrases: vec!["big world".into()],
+            }
+        );
+    }
+
+    #[test]
+    fn parse_cjk() {
+        let mut tokens: Vec<String> =
+            vec!["東京".into(), "京タ".into(), "タワ".into(), "ワー".into()];
+        tokens.sort_unstable();
+
+        assert_eq!(
+            SearchQuery::parse("東京タワー"),
+            SearchQuery {
+                tokens,
+                phrases: vec!["東京タワー".into()],
+            }
+        );
+        assert_eq!(SearchQuery::parse("京").tokens, vec!["京".to_owned()]);
+    }
+
+    #[test]
+    fn split_cjk_runs() {
+        assert_eq!(
+            words("hello世界world, ok").collect::<Vec<_>>(),
+            vec![
+                ("hello", false),
+                ("世界", true),
+                ("world", false),
+                ("ok", false)
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenize_text() {
+        assert_eq!(
+            tokenize("Hello 世界").collect::<Vec<_>>(),
+            vec!["hello", "世", "界", "世界"]
+        );
+        assert_eq!(tokenize(&"a".repeat(MAX_WORD_LENGTH + 1)).count(), 0);
     }
 }
@@ -198,7 +198,7 @@ impl Service {
             self.update_joined_count(room_id)?;
         }
 
-        Ok(())
+        services().user_directory.update_user(user_id)
     }
 
     #[tracing::instrument(skip(self, room_id))]
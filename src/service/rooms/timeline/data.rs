@@ -12,6 +12,9 @@ pub trait Data: Send + Sync {
     /// Returns the `count` of this pdu's id.
     fn get_pdu_count(&self, event_id: &EventId) -> Result<Option<PduCount>>;
 
+    /// Returns the `count` contained in a pdu id.
+    fn pdu_id_count(&self, pdu_id: &[u8]) -> Result<PduCount>;
+
     /// Returns the json of a pdu.
     fn get_pdu_json(&self, event_id: &EventId) -> Result<Option<CanonicalJsonObject>>;
 
@@ -84,9 +87,11 @@ pub trait Data: Send + Sync {
         from: PduCount,
     ) -> Result<Box<dyn Iterator<Item = Result<(PduCount, PduEvent)>> + 'a>>;
 
+    /// Increments the counts of the room, and those of the thread if the event is in one.
     fn increment_notification_counts(
         &self,
         room_id: &RoomId,
+        thread_root: Option<&EventId>,
         notifies: Vec<OwnedUserId>,
         highlights: Vec<OwnedUserId>,
     ) -> Result<()>;
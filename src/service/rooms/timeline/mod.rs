@@ -10,7 +10,7 @@ use std::{
 pub use data::Data;
 
 use ruma::{
-    api::{client::error::ErrorKind, federation},
+    api::{client::error::ErrorKind, federation, Direction},
     canonical_json::to_canonical_value,
     events::{
         push_rules::PushRulesEvent,
@@ -29,13 +29,13 @@ use ruma::{
 use serde::Deserialize;
 use serde_json::value::{to_raw_value, RawValue as RawJsonValue};
 use tokio::sync::{Mutex, MutexGuard, RwLock};
-use tracing::{error, info, warn};
+use tracing::{debug, error, info, warn};
 
 use crate::{
     api::server_server,
     service::{
         globals::SigningKeys,
-        pdu::{EventHash, PduBuilder},
+        pdu::{gen_event_id, set_origin_event_id, uses_origin_event_ids, EventHash, PduBuilder},
     },
     services, utils, Error, PduEvent, Result,
 };
@@ -115,6 +115,11 @@ impl Service {
         self.db.get_pdu_count(event_id)
     }
 
+    /// Returns the `count` contained in a pdu id.
+    pub fn pdu_id_count(&self, pdu_id: &[u8]) -> Result<PduCount> {
+        self.db.pdu_id_count(pdu_id)
+    }
+
     // TODO Is this the same as the function above?
     /*
     #[tracing::instrument(skip(self))]
@@ -305,6 +310,20 @@ impl Service {
             {
                 let sync_pdu = pdu.to_sync_room_event();
 
+                #[derive(Deserialize)]
+                struct ExtractThreadRoot {
+                    #[serde(rename = "m.relates_to")]
+                    relates_to: Relation,
+                }
+
+                // Events in threads also count towards the unread counts of the thread
+                let thread_root = serde_json::from_str::<ExtractThreadRoot>(pdu.content.get())
+                    .ok()
+                    .and_then(|content| match content.relates_to {
+                        Relation::Thread(thread) => Some(thread.event_id),
+                        _ => None,
+                    });
+
                 let mut notifies = Vec::new();
                 let mut highlights = Vec::new();
 
@@ -383,8 +402,12 @@ impl Service {
                     }
                 }
 
-                self.db
-                    .increment_notification_counts(&pdu.room_id(), notifies, highlights)?;
+                self.db.increment_notification_counts(
+                    &pdu.room_id(),
+                    thread_root.as_deref(),
+                    notifies,
+                    highlights,
+                )?;
             }
         }
 
@@ -832,7 +855,33 @@ impl Service {
             Error::BadRequest(ErrorKind::InvalidParam, "Event content provided is invalid")
         })?;
 
-        pdu_json.remove("event_id");
+        set_origin_event_id(&mut pdu_json, &room_version_rules);
+
+        // Room versions 1 and 2 reference events together with their hashes
+        if uses_origin_event_ids(&room_version_rules) {
+            for (field, event_ids) in [
+                ("prev_events", &pdu.prev_events),
+                ("auth_events", &pdu.auth_events),
+            ] {
+                let references = event_ids
+                    .iter()
+                    .map(|event_id| {
+                        let hashes = self
+                            .get_pdu(event_id)?
+                            .ok_or_else(|| Error::bad_database("Referenced event is missing."))?
+                            .hashes
+                            .clone();
+
+                        Ok(CanonicalJsonValue::Array(vec![
+                            CanonicalJsonValue::String(event_id.to_string()),
+                            to_canonical_value(hashes).expect("EventHash is valid json"),
+                        ]))
+                    })
+                    .collect::<Result<_>>()?;
+
+                pdu_json.insert(field.to_owned(), CanonicalJsonValue::Array(references));
+            }
+        }
 
         // Add origin because synapse likes that (and it's required in the spec)
         pdu_json.insert(
@@ -871,12 +920,9 @@ impl Service {
         }
 
         // Generate event id
-        pdu.event_id = EventId::parse_arc(format!(
-            "${}",
-            ruma::signatures::reference_hash(&pdu_json, &room_version_rules)
-                .expect("Event format validated when event was hashed")
-        ))
-        .expect("ruma's reference hashes are valid event ids");
+        pdu.event_id = gen_event_id(&pdu_json, &room_version_rules)
+            .expect("Event format validated when event was hashed")
+            .into();
 
         pdu_json.insert(
             "event_id".to_owned(),
@@ -1141,6 +1187,9 @@ impl Service {
             .filter_map(|r| r.ok())
             .collect();
 
+        // Most servers are not known from the member events while the room has partial state
+        servers.extend(services().rooms.partial_state.servers_in_room(room_id)?);
+
         // In case we are kicking or banning a user, we need to inform their server of the change
         if pdu.kind == TimelineEventType::RoomMember {
             if let Some(state_key_uid) = &pdu
@@ -1337,6 +1386,221 @@ impl Service {
         Ok(())
     }
 
+    /// Returns the id and timestamp of the event closest to `ts` in the given direction.
+    ///
+    /// Other servers in the room are asked as well if there is a gap in our timeline where the
+    /// closest event could be.
+    pub async fn closest_event_by_timestamp(
+        &self,
+        room_id: &RoomId,
+        ts: MilliSecondsSinceUnixEpoch,
+        dir: Direction,
+    ) -> Result<Option<(OwnedEventId, MilliSecondsSinceUnixEpoch)>> {
+        let (local, gap) = self.closest_local_pdu_by_timestamp(room_id, ts, dir)?;
+        let local = local.map(|pdu| {
+            (
+                pdu.event_id.as_ref().to_owned(),
+                MilliSecondsSinceUnixEpoch(pdu.origin_server_ts),
+            )
+        });
+
+        if !gap || !services().globals.allow_federation() {
+            return Ok(local);
+        }
+
+        let servers = services()
+            .rooms
+            .state_cache
+            .room_servers(room_id)
+            .filter_map(|r| r.ok())
+            .filter(|server| &**server != services().globals.server_name());
+
+        for server in servers {
+            debug!("Asking {server} for the closest event to {ts:?}");
+            let response = services()
+                .sending
+                .send_federation_request(
+                    &server,
+                    federation::event::get_event_by_timestamp::v1::Request {
+                        room_id: room_id.to_owned(),
+                        ts,
+                        dir,
+                    },
+                )
+                .await;
+
+            match response {
+                Ok(response) => {
+                    let remote = (response.event_id, response.origin_server_ts);
+
+                    let is_valid = match dir {
+                        Direction::Forward => remote.1 >= ts,
+                        Direction::Backward => remote.1 <= ts,
+                    };
+                    if !is_valid {
+                        warn!("{server} returned an event in the wrong direction");
+                        continue;
+                    }
+
+                    // Our own event is closer
+                    if let Some(local) = local.as_ref().filter(|local| match dir {
+                        Direction::Forward => local.1 <= remote.1,
+                        Direction::Backward => local.1 >= remote.1,
+                    }) {
+                        return Ok(Some(local.clone()));
+                    }
+
+                    // Clients will ask for the event, so it has to be in our timeline
+                    if self.get_pdu_count(&remote.0)?.is_none() {
+                        if let Err(e) = self.backfill_event(&server, &remote.0).await {
+                            warn!("Failed to backfill {} from {server}: {e}", remote.0);
+                            continue;
+                        }
+                    }
+
+                    return Ok(Some(remote));
+                }
+                Err(e) => {
+                    debug!("{server} could not find the closest event: {e}");
+                }
+            }
+        }
+
+        Ok(local)
+    }
+
+    /// Bisects the timeline of the room to skip the events that are far from `ts`. Returns the
+    /// count of an event before `ts` and the event after `ts`, between which the closest event
+    /// in the direction is.
+    ///
+    /// Events are mostly, but not strictly, ordered by their timestamps, so the result is where
+    /// walking the timeline starts, and might miss events that were sent out of order.
+    fn seek_timestamp(
+        &self,
+        room_id: &RoomId,
+        ts: MilliSecondsSinceUnixEpoch,
+        dir: Direction,
+    ) -> Result<(PduCount, Option<(PduCount, PduEvent)>)> {
+        let user_id = user_id!("@doesntmatter:conduit.rs");
+
+        // Backfilled events come before the normal ones, so they are mapped to negative numbers
+        let to_number = |count: PduCount| match count {
+            PduCount::Backfilled(count) => -i128::from(count),
+            PduCount::Normal(count) => i128::from(count),
+        };
+        let to_count = |number: i128| {
+            if number < 0 {
+                PduCount::Backfilled(number.unsigned_abs() as u64)
+            } else {
+                PduCount::Normal(number as u64)
+            }
+        };
+        let is_before = |pdu: &PduEvent| match dir {
+            Direction::Forward => pdu.origin_server_ts < ts.0,
+            Direction::Backward => pdu.origin_server_ts <= ts.0,
+        };
+
+        let mut before = to_number(PduCount::min());
+        let mut after = to_number(PduCount::max());
+        let mut after_pdu = None;
+
+        while after - before > 1 {
+            let middle = before + (after - before) / 2;
+
+            match self
+                .pdus_after(user_id, room_id, to_count(middle))?
+                .next()
+                .transpose()?
+            {
+                Some((count, pdu)) if to_number(count) < after => {
+                    if is_before(&pdu) {
+                        before = to_number(count);
+                    } else {
+                        after = to_number(count);
+                        after_pdu = Some((count, pdu));
+                    }
+                }
+                // There are no events between the middle and the event after `ts`
+                _ => after = middle,
+            }
+        }
+
+        Ok((to_count(before), after_pdu))
+    }
+
+    /// Fetches an event from the server and adds it to our timeline.
+    async fn backfill_event(&self, server: &ServerName, event_id: &EventId) -> Result<()> {
+        let response = services()
+            .sending
+            .send_federation_request(
+                server,
+                federation::event::get_event::v1::Request {
+                    event_id: event_id.to_owned(),
+                },
+            )
+            .await?;
+
+        let pub_key_map = RwLock::new(BTreeMap::new());
+        self.backfill_pdu(server, response.pdu, &pub_key_map).await
+    }
+
+    /// Returns the PDU closest to `ts` in the given direction, only looking at our own timeline.
+    ///
+    /// Also returns whether events might be missing between the PDU and `ts`, because our
+    /// timeline has a gap there.
+    pub fn closest_local_pdu_by_timestamp(
+        &self,
+        room_id: &RoomId,
+        ts: MilliSecondsSinceUnixEpoch,
+        dir: Direction,
+    ) -> Result<(Option<PduEvent>, bool)> {
+        let user_id = user_id!("@doesntmatter:conduit.rs");
+        let is_missing = |pdu: &PduEvent| {
+            pdu.prev_events
+                .iter()
+                .any(|event_id| !matches!(self.get_pdu_count(event_id), Ok(Some(_))))
+        };
+
+        let (before, after) = self.seek_timestamp(room_id, ts, dir)?;
+
+        match dir {
+            Direction::Forward => {
+                for pdu in self.pdus_after(user_id, room_id, before)? {
+                    let (_, pdu) = pdu?;
+
+                    if pdu.origin_server_ts >= ts.0 {
+                        let gap = is_missing(&pdu);
+                        return Ok((Some(pdu), gap));
+                    }
+                }
+
+                // We would have received newer events
+                Ok((None, false))
+            }
+            Direction::Backward => {
+                // The event after the current one in our timeline
+                let (until, mut later) = match after {
+                    Some((count, pdu)) => (count, Some(pdu)),
+                    None => (PduCount::max(), None),
+                };
+
+                for pdu in self.pdus_until(user_id, room_id, until)? {
+                    let (_, pdu) = pdu?;
+
+                    if pdu.origin_server_ts <= ts.0 {
+                        let gap = later.as_ref().is_some_and(is_missing);
+                        return Ok((Some(pdu), gap));
+                    }
+
+                    later = Some(pdu);
+                }
+
+                // Older events are missing unless we have the whole room history
+                Ok((None, later.as_ref().is_some_and(is_missing)))
+            }
+        }
+    }
+
     #[tracing::instrument(skip(self, pdu))]
     pub async fn backfill_pdu(
         &self,
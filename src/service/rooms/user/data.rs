@@ -1,13 +1,31 @@
+use std::collections::BTreeMap;
+
 use crate::Result;
-use ruma::{OwnedRoomId, OwnedUserId, RoomId, UserId};
+use ruma::{EventId, OwnedEventId, OwnedRoomId, OwnedUserId, RoomId, UserId};
 
 pub trait Data: Send + Sync {
+    /// Resets the counts of the whole room, including all threads.
     fn reset_notification_counts(&self, user_id: &UserId, room_id: &RoomId) -> Result<()>;
 
+    /// Resets the counts of a thread, or of the main timeline if `thread_root` is `None`.
+    fn reset_thread_notification_counts(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        thread_root: Option<&EventId>,
+    ) -> Result<()>;
+
     fn notification_count(&self, user_id: &UserId, room_id: &RoomId) -> Result<u64>;
 
     fn highlight_count(&self, user_id: &UserId, room_id: &RoomId) -> Result<u64>;
 
+    /// Returns the notification and highlight counts of every thread with unread events.
+    fn thread_notification_counts(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+    ) -> Result<BTreeMap<OwnedEventId, (u64, u64)>>;
+
     // Returns the count at which the last reset_notification_counts was called
     fn last_notification_read(&self, user_id: &UserId, room_id: &RoomId) -> Result<u64>;
 
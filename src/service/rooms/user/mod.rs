@@ -1,17 +1,42 @@
 mod data;
 
+use std::collections::BTreeMap;
+
 pub use data::Data;
-use ruma::{OwnedRoomId, OwnedUserId, RoomId, UserId};
+use ruma::{
+    api::client::error::ErrorKind, events::receipt::ReceiptThread, OwnedEventId, OwnedRoomId,
+    OwnedUserId, RoomId, UserId,
+};
 
-use crate::Result;
+use crate::{Error, Result};
 
 pub struct Service {
     pub db: &'static dyn Data,
 }
 
 impl Service {
-    pub fn reset_notification_counts(&self, user_id: &UserId, room_id: &RoomId) -> Result<()> {
-        self.db.reset_notification_counts(user_id, room_id)
+    /// Resets the counts of the part of the room a read receipt is for: the whole room for
+    /// unthreaded receipts, otherwise the main timeline or a single thread.
+    pub fn reset_notification_counts(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        thread: &ReceiptThread,
+    ) -> Result<()> {
+        match thread {
+            ReceiptThread::Unthreaded => self.db.reset_notification_counts(user_id, room_id),
+            ReceiptThread::Main => self
+                .db
+                .reset_thread_notification_counts(user_id, room_id, None),
+            ReceiptThread::Thread(thread_root) => {
+                self.db
+                    .reset_thread_notification_counts(user_id, room_id, Some(thread_root))
+            }
+            _ => Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Unknown thread id in receipt.",
+            )),
+        }
     }
 
     pub fn notification_count(&self, user_id: &UserId, room_id: &RoomId) -> Result<u64> {
@@ -22,6 +47,15 @@ impl Service {
         self.db.highlight_count(user_id, room_id)
     }
 
+    /// Returns the notification and highlight counts of every thread with unread events.
+    pub fn thread_notification_counts(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+    ) -> Result<BTreeMap<OwnedEventId, (u64, u64)>> {
+        self.db.thread_notification_counts(user_id, room_id)
+    }
+
     pub fn last_notification_read(&self, user_id: &UserId, room_id: &RoomId) -> Result<u64> {
         self.db.last_notification_read(user_id, room_id)
     }
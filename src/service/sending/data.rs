@@ -24,7 +24,15 @@ pub trait Data: Send + Sync {
         &'a self,
         outgoing_kind: &OutgoingKind,
     ) -> Box<dyn Iterator<Item = Result<(SendingEventType, Vec<u8>)>> + 'a>;
+    /// Returns the destination of every request that is waiting to be sent.
+    fn all_queued_requests<'a>(&'a self) -> Box<dyn Iterator<Item = Result<OutgoingKind>> + 'a>;
     fn mark_as_active(&self, events: &[(SendingEventType, Vec<u8>)]) -> Result<()>;
+    /// Removes the queued pdus of the room. Returns the destination of every removed request.
+    fn delete_queued_pdus(&self, shortroomid: u64) -> Result<Vec<OutgoingKind>>;
     fn set_latest_educount(&self, server_name: &ServerName, educount: u64) -> Result<()>;
     fn get_latest_educount(&self, server_name: &ServerName) -> Result<u64>;
+    /// Sets the count up to which device list changes and one-time key counts were sent to the
+    /// appservice.
+    fn set_latest_appservice_educount(&self, appservice_id: &str, educount: u64) -> Result<()>;
+    fn get_latest_appservice_educount(&self, appservice_id: &str) -> Result<u64>;
 }
@@ -3,7 +3,7 @@ mod data;
 pub use data::Data;
 
 use std::{
-    collections::{BTreeMap, HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     fmt::Debug,
     sync::Arc,
     time::{Duration, Instant},
@@ -19,24 +19,30 @@ use federation::transactions::send_transaction_message;
 use futures_util::{stream::FuturesUnordered, StreamExt};
 
 use base64::{engine::general_purpose, Engine as _};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, value::to_raw_value};
 
 use ruma::{
     api::{
-        appservice::{self, Registration},
+        appservice::{self, event::push_events::v1::DeviceLists, Registration},
         federation::{
             self,
             transactions::edu::{
-                DeviceListUpdateContent, Edu, ReceiptContent, ReceiptData, ReceiptMap,
+                DeviceListUpdateContent, Edu, PresenceContent, PresenceUpdate, ReceiptContent,
+                ReceiptData, ReceiptMap,
             },
         },
         OutgoingRequest,
     },
     device_id,
     events::{
-        push_rules::PushRulesEvent, receipt::ReceiptType, AnySyncEphemeralRoomEvent,
-        GlobalAccountDataEventType,
+        presence::PresenceEvent, push_rules::PushRulesEvent, receipt::ReceiptType,
+        AnySyncEphemeralRoomEvent, GlobalAccountDataEventType,
     },
-    push, uint, MilliSecondsSinceUnixEpoch, OwnedServerName, OwnedUserId, ServerName, UInt, UserId,
+    push,
+    serde::Raw,
+    uint, DeviceId, MilliSecondsSinceUnixEpoch, OneTimeKeyAlgorithm, OwnedDeviceId, OwnedRoomId,
+    OwnedServerName, OwnedUserId, ServerName, UInt, UserId,
 };
 use tokio::{
     select,
@@ -44,6 +50,11 @@ use tokio::{
 };
 use tracing::{debug, error, warn};
 
+type OneTimeKeyCounts =
+    BTreeMap<OwnedUserId, BTreeMap<OwnedDeviceId, BTreeMap<OneTimeKeyAlgorithm, UInt>>>;
+type UnusedFallbackKeyTypes =
+    BTreeMap<OwnedUserId, BTreeMap<OwnedDeviceId, Vec<OneTimeKeyAlgorithm>>>;
+
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum OutgoingKind {
     Appservice(String),
@@ -85,6 +96,14 @@ pub enum SendingEventType {
     Edu(Vec<u8>), // pdu json
 }
 
+/// Ephemeral data for appservices, stored as the json of [`SendingEventType::Edu`]
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", content = "event", rename_all = "snake_case")]
+enum AppserviceEdu {
+    Ephemeral(serde_json::Value),
+    ToDevice(serde_json::Value),
+}
+
 pub struct Service {
     db: &'static dyn Data,
 
@@ -125,6 +144,10 @@ impl Service {
 
         let mut current_transaction_status = HashMap::<OutgoingKind, TransactionStatus>::new();
 
+        services()
+            .metrics
+            .init_queue_depths(self.db.all_queued_requests())?;
+
         // Retry requests we could not finish yet
         let mut initial_transactions = HashMap::<OutgoingKind, Vec<SendingEventType>>::new();
 
@@ -162,7 +185,7 @@ impl Service {
 
                             if !new_events.is_empty() {
                                 // Insert pdus we found
-                                self.db.mark_as_active(&new_events)?;
+                                self.mark_as_active(&outgoing_kind, &new_events)?;
 
                                 futures.push(
                                     Self::handle_events(
@@ -175,6 +198,7 @@ impl Service {
                             }
                         }
                         Err((outgoing_kind, _)) => {
+                            services().metrics.record_transaction_failure(&outgoing_kind);
                             current_transaction_status.entry(outgoing_kind).and_modify(|e| *e = match e {
                                 TransactionStatus::Running => TransactionStatus::Failed(1, Instant::now()),
                                 TransactionStatus::Retrying(n) => TransactionStatus::Failed(*n+1, Instant::now()),
@@ -249,7 +273,7 @@ impl Service {
                 events.push(e);
             }
         } else {
-            self.db.mark_as_active(&new_events)?;
+            self.mark_as_active(&outgoing_kind, &new_events)?;
             for (e, _) in new_events {
                 events.push(e);
             }
@@ -273,6 +297,7 @@ impl Service {
         let mut events = Vec::new();
         let mut max_edu_count = since;
         let mut device_list_changes = HashSet::new();
+        let mut presence_updates = HashMap::new();
 
         'outer: for room_id in services().rooms.state_cache.server_rooms(server_name) {
             let room_id = room_id?;
@@ -285,6 +310,17 @@ impl Service {
                     .filter(|user_id| user_id.server_name() == services().globals.server_name()),
             );
 
+            // Look for presence updates in this room
+            max_edu_count = max_edu_count.max(batch_presence_updates(
+                &mut presence_updates,
+                services()
+                    .rooms
+                    .edus
+                    .presence
+                    .presence_updates_since(&room_id, since)?,
+                services().globals.server_name(),
+            ));
+
             // Look for read receipts in this room
             for r in services()
                 .rooms
@@ -350,6 +386,15 @@ impl Service {
             }
         }
 
+        if !presence_updates.is_empty() {
+            // All updates fit into one edu
+            let edu = Edu::Presence(PresenceContent {
+                push: presence_updates.into_values().collect(),
+            });
+
+            events.push(serde_json::to_vec(&edu).expect("json can be serialized"));
+        }
+
         for user_id in device_list_changes {
             // Empty prev id forces synapse to resync: https://github.com/matrix-org/synapse/blob/98aec1cc9da2bd6b8e34ffb282c85abf9b8b42ca/synapse/handlers/device.py#L767
             // Because synapse resyncs, we can just insert dummy data
@@ -373,7 +418,7 @@ impl Service {
     pub fn send_push_pdu(&self, pdu_id: &[u8], user: &UserId, pushkey: String) -> Result<()> {
         let outgoing_kind = OutgoingKind::Push(user.to_owned(), pushkey);
         let event = SendingEventType::Pdu(pdu_id.to_owned());
-        let keys = self.db.queue_requests(&[(&outgoing_kind, event.clone())])?;
+        let keys = self.queue_requests(&[(&outgoing_kind, event.clone())])?;
         self.sender
             .send((outgoing_kind, event, keys.into_iter().next().unwrap()))
             .unwrap();
@@ -396,7 +441,7 @@ impl Service {
                 )
             })
             .collect::<Vec<_>>();
-        let keys = self.db.queue_requests(
+        let keys = self.queue_requests(
             &requests
                 .iter()
                 .map(|(o, e)| (o, e.clone()))
@@ -420,7 +465,7 @@ impl Service {
     ) -> Result<()> {
         let outgoing_kind = OutgoingKind::Normal(server.to_owned());
         let event = SendingEventType::Edu(serialized);
-        let keys = self.db.queue_requests(&[(&outgoing_kind, event.clone())])?;
+        let keys = self.queue_requests(&[(&outgoing_kind, event.clone())])?;
         self.sender
             .send((outgoing_kind, event, keys.into_iter().next().unwrap()))
             .unwrap();
@@ -432,7 +477,7 @@ impl Service {
     pub fn send_pdu_appservice(&self, appservice_id: String, pdu_id: Vec<u8>) -> Result<()> {
         let outgoing_kind = OutgoingKind::Appservice(appservice_id);
         let event = SendingEventType::Pdu(pdu_id);
-        let keys = self.db.queue_requests(&[(&outgoing_kind, event.clone())])?;
+        let keys = self.queue_requests(&[(&outgoing_kind, event.clone())])?;
         self.sender
             .send((outgoing_kind, event, keys.into_iter().next().unwrap()))
             .unwrap();
@@ -440,13 +485,228 @@ impl Service {
         Ok(())
     }
 
+    /// Queues an ephemeral event (typing, receipt or presence) for all appservices that receive
+    /// ephemeral events and are in one of the rooms.
+    #[tracing::instrument(skip(self, room_ids, event))]
+    pub async fn send_ephemeral_appservices(
+        &self,
+        room_ids: &[OwnedRoomId],
+        event: serde_json::Value,
+    ) -> Result<()> {
+        let appservice_ids = services()
+            .appservice
+            .read()
+            .await
+            .iter()
+            .filter(|(_, info)| info.registration.receive_ephemeral)
+            .filter(|(_, info)| {
+                room_ids.iter().any(|room_id| {
+                    services()
+                        .rooms
+                        .state_cache
+                        .appservice_in_room(room_id, info)
+                        .unwrap_or(false)
+                })
+            })
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+
+        self.send_appservice_edus(appservice_ids, AppserviceEdu::Ephemeral(event))
+    }
+
+    /// Hands a to-device event for a user of an appservice to the appservice, if it receives
+    /// ephemeral events. Returns false if the event has to be stored for the device instead.
+    #[tracing::instrument(skip(self, content))]
+    pub async fn send_to_device_appservice(
+        &self,
+        sender: &UserId,
+        target_user_id: &UserId,
+        target_device_id: &DeviceId,
+        event_type: &str,
+        content: &serde_json::Value,
+    ) -> Result<bool> {
+        if target_user_id.server_name() != services().globals.server_name() {
+            return Ok(false);
+        }
+
+        let Some(appservice_id) = services()
+            .appservice
+            .read()
+            .await
+            .iter()
+            .find(|(_, info)| {
+                info.registration.receive_ephemeral && info.is_exclusive_user_match(target_user_id)
+            })
+            .map(|(id, _)| id.clone())
+        else {
+            return Ok(false);
+        };
+
+        self.send_appservice_edus(
+            vec![appservice_id],
+            AppserviceEdu::ToDevice(json!({
+                "type": event_type,
+                "sender": sender,
+                "content": content,
+                "to_user_id": target_user_id,
+                "to_device_id": target_device_id,
+            })),
+        )?;
+
+        Ok(true)
+    }
+
+    fn send_appservice_edus(&self, appservice_ids: Vec<String>, edu: AppserviceEdu) -> Result<()> {
+        let edu = serde_json::to_vec(&edu).expect("json can be serialized");
+        let requests = appservice_ids
+            .into_iter()
+            .map(|id| {
+                (
+                    OutgoingKind::Appservice(id),
+                    SendingEventType::Edu(edu.clone()),
+                )
+            })
+            .collect::<Vec<_>>();
+        let keys = self.queue_requests(
+            &requests
+                .iter()
+                .map(|(o, e)| (o, e.clone()))
+                .collect::<Vec<_>>(),
+        )?;
+        for ((outgoing_kind, event), key) in requests.into_iter().zip(keys) {
+            self.sender.send((outgoing_kind, event, key)).unwrap();
+        }
+
+        Ok(())
+    }
+
+    /// Collects the device list changes, one-time key counts and unused fallback key types of the
+    /// devices of the users of the appservice since `since` (MSC3202).
+    async fn appservice_device_changes(
+        appservice_id: &str,
+        since: u64,
+        until: u64,
+    ) -> Result<(DeviceLists, OneTimeKeyCounts, UnusedFallbackKeyTypes)> {
+        let mut device_lists = DeviceLists::new();
+        let mut one_time_keys_count = BTreeMap::new();
+        let mut unused_fallback_key_types = BTreeMap::new();
+
+        let Some(info) = services()
+            .appservice
+            .read()
+            .await
+            .get(appservice_id)
+            .cloned()
+        else {
+            return Ok((device_lists, one_time_keys_count, unused_fallback_key_types));
+        };
+
+        let mut changed = BTreeSet::new();
+
+        for user_id in services()
+            .appservice
+            .iter_users(appservice_id)
+            .filter_map(|r| r.ok())
+            .filter(|user_id| info.is_user_match(user_id))
+        {
+            let device_ids = services()
+                .users
+                .all_device_ids(&user_id)
+                .filter_map(|r| r.ok())
+                .collect::<Vec<_>>();
+
+            // Users without devices don't take part in end-to-end encryption
+            if device_ids.is_empty() {
+                continue;
+            }
+
+            changed.extend(
+                services()
+                    .users
+                    .keys_changed(user_id.as_str(), since, Some(until))
+                    .filter_map(|r| r.ok()),
+            );
+            for room_id in services()
+                .rooms
+                .state_cache
+                .rooms_joined(&user_id)
+                .filter_map(|r| r.ok())
+            {
+                changed.extend(
+                    services()
+                        .users
+                        .keys_changed(room_id.as_str(), since, Some(until))
+                        .filter_map(|r| r.ok()),
+                );
+            }
+
+            if services().users.last_one_time_keys_update(&user_id)? > since {
+                let mut counts = BTreeMap::new();
+                let mut fallback_key_types = BTreeMap::new();
+                for device_id in device_ids {
+                    let count = services().users.count_one_time_keys(&user_id, &device_id)?;
+                    let types = services()
+                        .users
+                        .unused_fallback_key_types(&user_id, &device_id)?;
+                    counts.insert(device_id.clone(), count);
+                    fallback_key_types.insert(device_id, types);
+                }
+                one_time_keys_count.insert(user_id.clone(), counts);
+                unused_fallback_key_types.insert(user_id, fallback_key_types);
+            }
+        }
+
+        device_lists.changed = changed.into_iter().collect();
+
+        Ok((device_lists, one_time_keys_count, unused_fallback_key_types))
+    }
+
     /// Cleanup event data
     /// Used for instance after we remove an appservice registration
     ///
     #[tracing::instrument(skip(self))]
     pub fn cleanup_events(&self, appservice_id: String) -> Result<()> {
-        self.db
-            .delete_all_requests_for(&OutgoingKind::Appservice(appservice_id))?;
+        let outgoing_kind = OutgoingKind::Appservice(appservice_id);
+        self.db.delete_all_requests_for(&outgoing_kind)?;
+        services().metrics.record_queue_cleared(&outgoing_kind);
+
+        Ok(())
+    }
+
+    /// Drops the queued pdus of a room that is being purged, so they are not sent afterwards.
+    #[tracing::instrument(skip(self))]
+    pub fn remove_room(&self, shortroomid: u64) -> Result<()> {
+        for outgoing_kind in self.db.delete_queued_pdus(shortroomid)? {
+            services().metrics.record_dequeued(&outgoing_kind, 1);
+        }
+
+        Ok(())
+    }
+
+    /// Stores the requests until they can be sent, and keeps the queue depth metrics up to date.
+    fn queue_requests(
+        &self,
+        requests: &[(&OutgoingKind, SendingEventType)],
+    ) -> Result<Vec<Vec<u8>>> {
+        let keys = self.db.queue_requests(requests)?;
+
+        for (outgoing_kind, _) in requests {
+            services().metrics.record_queued(outgoing_kind, 1);
+        }
+
+        Ok(keys)
+    }
+
+    /// Marks queued requests as being sent right now.
+    fn mark_as_active(
+        &self,
+        outgoing_kind: &OutgoingKind,
+        events: &[(SendingEventType, Vec<u8>)],
+    ) -> Result<()> {
+        self.db.mark_as_active(events)?;
+        services()
+            .metrics
+            .record_dequeued(outgoing_kind, events.len());
 
         Ok(())
     }
@@ -459,6 +719,8 @@ impl Service {
         match &kind {
             OutgoingKind::Appservice(id) => {
                 let mut pdu_jsons = Vec::new();
+                let mut ephemeral = Vec::new();
+                let mut to_device = Vec::new();
 
                 for event in &events {
                     match event {
@@ -476,31 +738,68 @@ impl Service {
                                 })?
                                 .to_room_event())
                         }
-                        SendingEventType::Edu(_) => {
-                            // Appservices don't need EDUs (?)
-                        }
+                        SendingEventType::Edu(edu) => match serde_json::from_slice(edu) {
+                            Ok(AppserviceEdu::Ephemeral(event)) => ephemeral.push(Raw::from_json(
+                                to_raw_value(&event).expect("json can be serialized"),
+                            )),
+                            Ok(AppserviceEdu::ToDevice(event)) => to_device.push(Raw::from_json(
+                                to_raw_value(&event).expect("json can be serialized"),
+                            )),
+                            Err(_) => warn!("[Appservice] Invalid EDU in servernameevent_data"),
+                        },
                     }
                 }
 
+                let registration = services()
+                    .appservice
+                    .get_registration(id)
+                    .await
+                    .ok_or_else(|| {
+                        (
+                            kind.clone(),
+                            Error::bad_database(
+                                "[Appservice] Could not load registration from db.",
+                            ),
+                        )
+                    })?;
+
+                let mut device_lists = DeviceLists::new();
+                let mut device_one_time_keys_count = BTreeMap::new();
+                let mut device_unused_fallback_key_types = BTreeMap::new();
+                let mut educount = None;
+
+                if registration.receive_ephemeral {
+                    let since = services()
+                        .sending
+                        .db
+                        .get_latest_appservice_educount(id)
+                        .map_err(|e| (kind.clone(), e))?;
+                    let until = services()
+                        .globals
+                        .current_count()
+                        .map_err(|e| (kind.clone(), e))?;
+
+                    (
+                        device_lists,
+                        device_one_time_keys_count,
+                        device_unused_fallback_key_types,
+                    ) = Self::appservice_device_changes(id, since, until)
+                        .await
+                        .map_err(|e| (kind.clone(), e))?;
+                    educount = Some(until);
+                }
+
                 let permit = services().sending.maximum_requests.acquire().await;
 
                 let response = match appservice_server::send_request(
-                    services()
-                        .appservice
-                        .get_registration(id)
-                        .await
-                        .ok_or_else(|| {
-                            (
-                                kind.clone(),
-                                Error::bad_database(
-                                    "[Appservice] Could not load registration from db.",
-                                ),
-                            )
-                        })?,
+                    registration,
                     appservice::event::push_events::v1::Request {
-                        //TODO: ephemeral pushing
-                        ephemeral: Vec::new(),
                         events: pdu_jsons,
+                        ephemeral,
+                        to_device,
+                        device_lists,
+                        device_one_time_keys_count,
+                        device_unused_fallback_key_types,
                         txn_id: (&*general_purpose::URL_SAFE_NO_PAD.encode(calculate_hash(
                             &events
                                 .iter()
@@ -520,6 +819,14 @@ impl Service {
 
                 drop(permit);
 
+                if let (Ok(_), Some(educount)) = (&response, educount) {
+                    services()
+                        .sending
+                        .db
+                        .set_latest_appservice_educount(id, educount)
+                        .map_err(|e| (kind.clone(), e))?;
+                }
+
                 response
             }
             OutgoingKind::Push(userid, pushkey) => {
@@ -721,3 +1028,92 @@ impl Service {
         response
     }
 }
+
+/// Adds the presence updates of local users in a room the destination is in to the batch for
+/// this destination. Returns the highest count of all updates in the room.
+fn batch_presence_updates(
+    batch: &mut HashMap<OwnedUserId, PresenceUpdate>,
+    updates: HashMap<OwnedUserId, (u64, PresenceEvent)>,
+    server_name: &ServerName,
+) -> u64 {
+    let mut max_count = 0;
+
+    for (user_id, (count, presence)) in updates {
+        max_count = max_count.max(count);
+
+        if user_id.server_name() != server_name {
+            continue;
+        }
+
+        batch.insert(
+            user_id.clone(),
+            PresenceUpdate {
+                user_id,
+                presence: presence.content.presence,
+                status_msg: presence.content.status_msg,
+                last_active_ago: presence.content.last_active_ago.unwrap_or_default(),
+                currently_active: presence.content.currently_active.unwrap_or(false),
+            },
+        );
+    }
+
+    max_count
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use ruma::{
+        events::presence::{PresenceEvent, PresenceEventContent},
+        owned_room_id, owned_user_id,
+        presence::PresenceState,
+        server_name, OwnedUserId,
+    };
+
+    use super::batch_presence_updates;
+
+    fn updates(users: &[(&OwnedUserId, u64)]) -> HashMap<OwnedUserId, (u64, PresenceEvent)> {
+        users
+            .iter()
+            .map(|&(user_id, count)| {
+                let presence = PresenceEvent {
+                    content: PresenceEventContent::new(PresenceState::Online),
+                    sender: user_id.clone(),
+                };
+                (user_id.clone(), (count, presence))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn presence_edu_only_contains_users_in_shared_rooms() {
+        let alice = owned_user_id!("@alice:example.com");
+        let bob = owned_user_id!("@bob:example.com");
+        let carol = owned_user_id!("@carol:remote.org");
+        let shared = owned_room_id!("!shared:example.com");
+        let other = owned_room_id!("!other:example.com");
+
+        // The updates are announced per room, bob is only in a room the destination is not in
+        let room_updates = HashMap::from([
+            (shared.clone(), updates(&[(&alice, 3), (&carol, 5)])),
+            (other, updates(&[(&bob, 4)])),
+        ]);
+        let destination_rooms = [shared];
+
+        let mut batch = HashMap::new();
+        let mut max_count = 0;
+        for room_id in &destination_rooms {
+            max_count = max_count.max(batch_presence_updates(
+                &mut batch,
+                room_updates[room_id].clone(),
+                server_name!("example.com"),
+            ));
+        }
+
+        // Remote users are announced by their own server
+        assert_eq!(batch.keys().collect::<Vec<_>>(), [&alice]);
+        assert_eq!(batch[&alice].presence, PresenceState::Online);
+        assert_eq!(max_count, 5);
+    }
+}
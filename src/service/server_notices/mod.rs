@@ -0,0 +1,326 @@
+//! Server notices, which are messages from the server to a single user, sent in a room that only
+//! the server user can post to
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use ruma::{
+    api::client::error::ErrorKind,
+    events::{
+        room::{
+            create::RoomCreateEventContent,
+            guest_access::{GuestAccess, RoomGuestAccessEventContent},
+            history_visibility::{HistoryVisibility, RoomHistoryVisibilityEventContent},
+            join_rules::{JoinRule, RoomJoinRulesEventContent},
+            member::{MembershipState, RoomMemberEventContent},
+            message::{
+                LimitType, MessageType, RoomMessageEventContent, ServerNoticeMessageEventContent,
+                ServerNoticeType,
+            },
+            name::RoomNameEventContent,
+            power_levels::RoomPowerLevelsEventContent,
+        },
+        tag::{TagEvent, TagEventContent, TagInfo, TagName},
+        RoomAccountDataEventType, TimelineEventType,
+    },
+    OwnedRoomId, UserId,
+};
+use serde_json::value::{to_raw_value, RawValue as RawJsonValue};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::{service::pdu::PduBuilder, services, Error, Result};
+
+pub struct Service {
+    /// Makes sure that only one notices room is created for each user
+    room_creation: Mutex<()>,
+}
+
+impl Service {
+    pub fn build() -> Self {
+        Self {
+            room_creation: Mutex::new(()),
+        }
+    }
+
+    /// Sends the notice to a local user, creating their server notices room if they don't have
+    /// one or left it.
+    pub async fn send(&self, user_id: &UserId, content: &RoomMessageEventContent) -> Result<()> {
+        if user_id.server_name() != services().globals.server_name()
+            || user_id == services().globals.server_user()
+        {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Server notices can only be sent to local users.",
+            ));
+        }
+        if !services().users.exists(user_id)? || services().users.is_deactivated(user_id)? {
+            return Err(Error::BadRequest(
+                ErrorKind::NotFound,
+                "User does not exist.",
+            ));
+        }
+
+        let room_id = self.notices_room(user_id).await?;
+
+        let mutex_state = Arc::clone(
+            services()
+                .globals
+                .roomid_mutex_state
+                .write()
+                .await
+                .entry(room_id.clone())
+                .or_default(),
+        );
+        let state_lock = mutex_state.lock().await;
+
+        services()
+            .rooms
+            .timeline
+            .build_and_append_pdu(
+                PduBuilder {
+                    event_type: TimelineEventType::RoomMessage,
+                    content: to_raw_value(content).expect("event is valid, we just created it"),
+                    unsigned: None,
+                    state_key: None,
+                    redacts: None,
+                    timestamp: None,
+                },
+                services().globals.server_user(),
+                &room_id,
+                &state_lock,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Sends the notice to every local user who is not deactivated. Returns the number of users
+    /// that were notified and the number of users the notice could not be sent to.
+    pub async fn send_to_all(&self, content: &RoomMessageEventContent) -> (usize, usize) {
+        let user_ids = services()
+            .users
+            .iter()
+            .filter_map(Result::ok)
+            .filter(|user_id| {
+                user_id.server_name() == services().globals.server_name()
+                    && user_id != services().globals.server_user()
+                    && !services().users.is_deactivated(user_id).unwrap_or(true)
+            })
+            .collect::<Vec<_>>();
+
+        let mut failed_count = 0;
+        for user_id in &user_ids {
+            if let Err(e) = self.send(user_id, content).await {
+                warn!("Failed to send server notice to {user_id}: {e}");
+                failed_count += 1;
+            }
+        }
+
+        (user_ids.len() - failed_count, failed_count)
+    }
+
+    /// Returns the server notices room of the user. A new room is created if the user neither is
+    /// in their previous room nor was invited to it.
+    async fn notices_room(&self, user_id: &UserId) -> Result<OwnedRoomId> {
+        let _guard = self.room_creation.lock().await;
+
+        if let Some(room_id) = services().users.server_notices_room(user_id)? {
+            if services().rooms.state_cache.is_joined(user_id, &room_id)?
+                || services().rooms.state_cache.is_invited(user_id, &room_id)?
+            {
+                return Ok(room_id);
+            }
+        }
+
+        let room_id = self.create_notices_room(user_id).await?;
+        services()
+            .users
+            .set_server_notices_room(user_id, &room_id)?;
+        info!("Created server notices room {room_id} for {user_id}");
+
+        Ok(room_id)
+    }
+
+    /// Creates a room where only the server user can send messages, invites the user and tags the
+    /// room with `m.server_notice` for them.
+    async fn create_notices_room(&self, user_id: &UserId) -> Result<OwnedRoomId> {
+        // Notices are only ever sent to local users
+        let (room_id, _) = self
+            .create_room(
+                false,
+                vec![
+                    (
+                        TimelineEventType::RoomName,
+                        to_raw_value(&RoomNameEventContent::new("Server Notices".to_owned()))
+                            .expect("event is valid, we just created it"),
+                        Some("".to_owned()),
+                    ),
+                    (
+                        TimelineEventType::RoomMember,
+                        to_raw_value(&RoomMemberEventContent::new(MembershipState::Invite))
+                            .expect("event is valid, we just created it"),
+                        Some(user_id.to_string()),
+                    ),
+                ],
+            )
+            .await?;
+
+        let mut tags = BTreeMap::new();
+        tags.insert(TagName::ServerNotice, TagInfo::new());
+        services().account_data.update(
+            Some(&room_id),
+            user_id,
+            RoomAccountDataEventType::Tag,
+            &serde_json::to_value(TagEvent {
+                content: TagEventContent { tags },
+            })
+            .expect("to json value always works"),
+        )?;
+
+        Ok(room_id)
+    }
+
+    /// Creates an invite-only room where only the server user can send messages. The `events`
+    /// are sent by the server user after the initial state. Returns the room id and the mutex of
+    /// the room state.
+    pub async fn create_room(
+        &self,
+        federate: bool,
+        events: Vec<(TimelineEventType, Box<RawJsonValue>, Option<String>)>,
+    ) -> Result<(OwnedRoomId, Arc<Mutex<()>>)> {
+        let conduit_user = services().globals.server_user();
+
+        let room_version = services().globals.default_room_version();
+        let rules = room_version
+            .rules()
+            .expect("Supported room version must have rules.")
+            .authorization;
+        let mut content = if rules.use_room_create_sender {
+            RoomCreateEventContent::new_v11()
+        } else {
+            RoomCreateEventContent::new_v1(conduit_user.to_owned())
+        };
+        content.federate = federate;
+        content.predecessor = None;
+        content.room_version = room_version;
+
+        let (room_id, mutex_state) = services()
+            .rooms
+            .timeline
+            .send_create_room(
+                to_raw_value(&content).expect("event is valid, we just created it"),
+                conduit_user,
+                &rules,
+            )
+            .await?;
+        let state_lock = mutex_state.lock().await;
+
+        let mut users = BTreeMap::new();
+        if !rules.explicitly_privilege_room_creators {
+            users.insert(conduit_user.to_owned(), 100.into());
+        }
+
+        let initial_state = [
+            (
+                TimelineEventType::RoomMember,
+                to_raw_value(&RoomMemberEventContent::new(MembershipState::Join))
+                    .expect("event is valid, we just created it"),
+                Some(conduit_user.to_string()),
+            ),
+            (
+                TimelineEventType::RoomPowerLevels,
+                to_raw_value(&RoomPowerLevelsEventContent {
+                    users,
+                    events_default: 100.into(),
+                    ..RoomPowerLevelsEventContent::new(&rules)
+                })
+                .expect("event is valid, we just created it"),
+                Some("".to_owned()),
+            ),
+            (
+                TimelineEventType::RoomJoinRules,
+                to_raw_value(&RoomJoinRulesEventContent::new(JoinRule::Invite))
+                    .expect("event is valid, we just created it"),
+                Some("".to_owned()),
+            ),
+            (
+                TimelineEventType::RoomHistoryVisibility,
+                to_raw_value(&RoomHistoryVisibilityEventContent::new(
+                    HistoryVisibility::Shared,
+                ))
+                .expect("event is valid, we just created it"),
+                Some("".to_owned()),
+            ),
+            (
+                TimelineEventType::RoomGuestAccess,
+                to_raw_value(&RoomGuestAccessEventContent::new(GuestAccess::Forbidden))
+                    .expect("event is valid, we just created it"),
+                Some("".to_owned()),
+            ),
+        ];
+
+        for (event_type, content, state_key) in initial_state.into_iter().chain(events) {
+            services()
+                .rooms
+                .timeline
+                .build_and_append_pdu(
+                    PduBuilder {
+                        event_type,
+                        content,
+                        unsigned: None,
+                        state_key,
+                        redacts: None,
+                        timestamp: None,
+                    },
+                    conduit_user,
+                    &room_id,
+                    &state_lock,
+                )
+                .await?;
+        }
+
+        drop(state_lock);
+
+        Ok((room_id, mutex_state))
+    }
+}
+
+/// A notice that the user hit a resource limit of the server, like the number of monthly active
+/// users. Clients may show it prominently and link to the admin contact.
+pub fn usage_limit_reached_notice(
+    body: String,
+    limit_type: &str,
+    admin_contact: Option<String>,
+) -> RoomMessageEventContent {
+    let mut content =
+        ServerNoticeMessageEventContent::new(body, ServerNoticeType::UsageLimitReached);
+    content.limit_type = Some(LimitType::from(limit_type));
+    content.admin_contact = admin_contact;
+
+    RoomMessageEventContent::new(MessageType::ServerNotice(content))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::usage_limit_reached_notice;
+
+    #[test]
+    fn usage_limit_reached_notice_format() {
+        let content = usage_limit_reached_notice(
+            "The server reached its user limit.".to_owned(),
+            "monthly_active_user",
+            Some("mailto:admin@example.com".to_owned()),
+        );
+
+        assert_eq!(
+            serde_json::to_value(content).unwrap(),
+            serde_json::json!({
+                "msgtype": "m.server_notice",
+                "body": "The server reached its user limit.",
+                "server_notice_type": "m.server_notice.usage_limit_reached",
+                "admin_contact": "mailto:admin@example.com",
+                "limit_type": "monthly_active_user",
+            })
+        );
+    }
+}
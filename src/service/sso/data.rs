@@ -0,0 +1,10 @@
+use ruma::{OwnedUserId, UserId};
+
+use crate::Result;
+
+pub trait Data: Send + Sync {
+    /// Remembers which user the subject of an identity provider logs in as.
+    fn set_sso_user(&self, idp_id: &str, subject: &str, user_id: &UserId) -> Result<()>;
+
+    fn sso_user(&self, idp_id: &str, subject: &str) -> Result<Option<OwnedUserId>>;
+}
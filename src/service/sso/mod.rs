@@ -0,0 +1,393 @@
+mod data;
+mod oidc;
+
+pub use data::Data;
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use ruma::{
+    api::client::{error::ErrorKind, session::get_login_types::v3::IdentityProvider},
+    OwnedUserId, ServerName, UserId,
+};
+use serde_json::Value;
+use tracing::warn;
+use url::Url;
+
+use crate::{
+    api::client_server::TOKEN_LENGTH,
+    services,
+    utils::{self, HtmlEscape},
+    Config, Error, Result,
+};
+
+/// How long users can take to log in at the identity provider
+const SESSION_LIFETIME: Duration = Duration::from_secs(10 * 60);
+/// How long clients can take to exchange the login token for an access token
+const LOGIN_TOKEN_LIFETIME: Duration = Duration::from_secs(2 * 60);
+const CODE_VERIFIER_LENGTH: usize = 64;
+
+/// A login that is waiting for the redirect from the identity provider
+struct Session {
+    provider: usize,
+    redirect_url: Url,
+    nonce: String,
+    code_verifier: String,
+    started: Instant,
+}
+
+pub struct Service {
+    pub db: &'static dyn Data,
+    providers: Vec<oidc::Provider>,
+    sessions: Mutex<HashMap<String, Session>>,
+    login_tokens: Mutex<HashMap<String, (OwnedUserId, Instant)>>,
+}
+
+impl Service {
+    pub fn build(db: &'static dyn Data, config: &Config) -> Result<Self> {
+        let mut ids = HashSet::new();
+        for provider in &config.oidc_providers {
+            if provider.id.is_empty()
+                || provider.id.len() > 255
+                || !provider
+                    .id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "._~-".contains(c))
+            {
+                return Err(Error::bad_config(
+                    "The id of an OpenID Connect provider may only contain letters, digits and ._~-",
+                ));
+            }
+
+            if !ids.insert(&provider.id) {
+                return Err(Error::bad_config(
+                    "The ids of OpenID Connect providers must be unique.",
+                ));
+            }
+        }
+
+        Ok(Self {
+            db,
+            providers: config
+                .oidc_providers
+                .iter()
+                .cloned()
+                .map(oidc::Provider::new)
+                .collect(),
+            sessions: Mutex::new(HashMap::new()),
+            login_tokens: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.providers.is_empty()
+    }
+
+    pub fn identity_providers(&self) -> Vec<IdentityProvider> {
+        self.providers
+            .iter()
+            .map(|provider| {
+                let mut identity_provider = IdentityProvider::new(
+                    provider.config.id.clone(),
+                    provider
+                        .config
+                        .name
+                        .clone()
+                        .unwrap_or_else(|| provider.config.id.clone()),
+                );
+                identity_provider.icon = provider.config.icon.clone();
+                identity_provider
+            })
+            .collect()
+    }
+
+    /// The URL the identity providers redirect the user back to.
+    fn callback_url() -> String {
+        format!(
+            "{}/_conduit/client/oidc/callback",
+            services().globals.well_known_client().trim_end_matches('/')
+        )
+    }
+
+    /// Starts a login at the identity provider, or at the first one if none is given. Returns
+    /// the URL the user has to be redirected to.
+    pub async fn start_login(&self, idp_id: Option<&str>, redirect_url: &str) -> Result<String> {
+        let (index, provider) = match idp_id {
+            Some(idp_id) => self
+                .providers
+                .iter()
+                .enumerate()
+                .find(|(_, provider)| provider.config.id == idp_id)
+                .ok_or(Error::BadRequest(
+                    ErrorKind::NotFound,
+                    "Unknown identity provider.",
+                ))?,
+            None => self
+                .providers
+                .iter()
+                .enumerate()
+                .next()
+                .ok_or(Error::BadRequest(
+                    ErrorKind::Unrecognized,
+                    "Single sign-on is not configured on this server.",
+                ))?,
+        };
+
+        let redirect_url = redirect_url_of(redirect_url)?;
+
+        let state = utils::random_string(TOKEN_LENGTH);
+        let nonce = utils::random_string(TOKEN_LENGTH);
+        let code_verifier = utils::random_string(CODE_VERIFIER_LENGTH);
+
+        let location = provider
+            .authorization_url(
+                &services().globals.default_client(),
+                &Self::callback_url(),
+                &state,
+                &nonce,
+                &code_verifier,
+            )
+            .await?;
+
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, session| session.started.elapsed() < SESSION_LIFETIME);
+        sessions.insert(
+            state,
+            Session {
+                provider: index,
+                redirect_url,
+                nonce,
+                code_verifier,
+                started: Instant::now(),
+            },
+        );
+
+        Ok(location.into())
+    }
+
+    /// Finishes the login when the identity provider redirects the user back to us. Returns the
+    /// URL of the client with a login token the client can log in with.
+    ///
+    /// The user must confirm the redirect on the page of `confirmation_page`, as anyone can start
+    /// a login that redirects to their own URL.
+    pub async fn complete_login(&self, state: &str, code: &str) -> Result<Url> {
+        let session = self
+            .sessions
+            .lock()
+            .unwrap()
+            .remove(state)
+            .filter(|session| session.started.elapsed() < SESSION_LIFETIME)
+            .ok_or(Error::BadRequest(
+                ErrorKind::forbidden(),
+                "Unknown or expired login session.",
+            ))?;
+        let provider = &self.providers[session.provider];
+
+        let claims = provider
+            .claims(
+                &services().globals.default_client(),
+                code,
+                &Self::callback_url(),
+                &session.nonce,
+                &session.code_verifier,
+            )
+            .await?;
+
+        let subject = claims
+            .get("sub")
+            .and_then(Value::as_str)
+            .ok_or(Error::BadServerResponse(
+                "Identity provider did not identify the user.",
+            ))?;
+
+        let user_id = match self.db.sso_user(&provider.config.id, subject)? {
+            Some(user_id) => user_id,
+            None => self.map_user(provider, subject, &claims).await?,
+        };
+
+        if services().users.is_deactivated(&user_id)? {
+            return Err(Error::BadRequest(
+                ErrorKind::UserDeactivated,
+                "The user has been deactivated",
+            ));
+        }
+
+        let token = utils::random_string(TOKEN_LENGTH);
+
+        let mut login_tokens = self.login_tokens.lock().unwrap();
+        login_tokens.retain(|_, (_, issued)| issued.elapsed() < LOGIN_TOKEN_LIFETIME);
+        login_tokens.insert(token.clone(), (user_id, Instant::now()));
+
+        let mut redirect_url = session.redirect_url;
+        redirect_url
+            .query_pairs_mut()
+            .append_pair("loginToken", &token);
+
+        Ok(redirect_url)
+    }
+
+    /// Returns the user a login token was issued for. Each token can only be used once.
+    pub fn consume_login_token(&self, token: &str) -> Option<OwnedUserId> {
+        self.login_tokens
+            .lock()
+            .unwrap()
+            .remove(token)
+            .filter(|(_, issued)| issued.elapsed() < LOGIN_TOKEN_LIFETIME)
+            .map(|(user_id, _)| user_id)
+    }
+
+    /// Finds or creates the account of a user who logs in through the provider for the first
+    /// time.
+    async fn map_user(
+        &self,
+        provider: &oidc::Provider,
+        subject: &str,
+        claims: &oidc::Claims,
+    ) -> Result<OwnedUserId> {
+        let localpart = claims
+            .get(&provider.config.localpart_claim)
+            .and_then(Value::as_str)
+            .and_then(localpart)
+            .ok_or(Error::BadRequest(
+                ErrorKind::InvalidUsername,
+                "The identity provider did not provide a valid username.",
+            ))?;
+
+        let user_id = UserId::parse_with_server_name(localpart, services().globals.server_name())
+            .map_err(|_| {
+            Error::BadRequest(ErrorKind::InvalidUsername, "Username is invalid.")
+        })?;
+
+        if services().appservice.is_exclusive_user_id(&user_id).await {
+            return Err(Error::BadRequest(
+                ErrorKind::Exclusive,
+                "User id reserved by appservice.",
+            ));
+        }
+
+        if services().users.exists(&user_id)? {
+            if !provider.config.allow_existing_users {
+                warn!(
+                    "{subject} of {} can't log in as {user_id}, as the user already exists",
+                    provider.config.id
+                );
+                return Err(Error::BadRequest(
+                    ErrorKind::UserInUse,
+                    "An account with your username already exists.",
+                ));
+            }
+        } else if provider.config.auto_provision {
+            let displayname = claims
+                .get(&provider.config.displayname_claim)
+                .and_then(Value::as_str)
+                .map(ToOwned::to_owned);
+
+            services()
+                .users
+                .provision(&user_id, displayname, "single sign-on")
+                .await?;
+        } else {
+            return Err(Error::BadRequest(
+                ErrorKind::forbidden(),
+                "You don't have an account on this server.",
+            ));
+        }
+
+        self.db
+            .set_sso_user(&provider.config.id, subject, &user_id)?;
+
+        Ok(user_id)
+    }
+}
+
+/// Parses the URL of the client. Custom schemes of mobile apps are allowed, but not the ones
+/// that would run code on our origin when the link on the confirmation page is followed.
+fn redirect_url_of(redirect_url: &str) -> Result<Url> {
+    Url::parse(redirect_url)
+        .ok()
+        .filter(|url| !matches!(url.scheme(), "javascript" | "data" | "vbscript"))
+        .ok_or(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Invalid redirect URL.",
+        ))
+}
+
+/// The page that asks the user to confirm that they want to log in to the client at the URL.
+pub fn confirmation_page(server_name: &ServerName, redirect_url: &Url) -> String {
+    let host = redirect_url.host_str().unwrap_or(redirect_url.scheme());
+
+    format!(
+        "<!DOCTYPE html>\n\
+        <html><head><meta charset=\"utf-8\"><title>Continue to {host}</title></head><body>\n\
+        <p>You are about to log in to {server_name} with the client at <strong>{host}</strong>.</p>\n\
+        <p>Only continue if you started this login yourself.</p>\n\
+        <p><a href=\"{url}\">Continue to {host}</a></p>\n\
+        </body></html>\n",
+        host = HtmlEscape(host),
+        server_name = HtmlEscape(server_name.as_str()),
+        url = HtmlEscape(redirect_url.as_str()),
+    )
+}
+
+/// Turns the username claim into a localpart. Email addresses are cut off at the `@`, other
+/// claims must only contain characters that are allowed in user ids.
+fn localpart(claim: &str) -> Option<String> {
+    let localpart = claim
+        .split_once('@')
+        .map_or(claim, |(localpart, _)| localpart)
+        .to_lowercase();
+
+    (!localpart.is_empty()
+        && localpart
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "._=-/".contains(c)))
+    .then_some(localpart)
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma::server_name;
+    use url::Url;
+
+    use super::{confirmation_page, localpart, redirect_url_of};
+
+    #[test]
+    fn confirmation_page_names_client() {
+        let url = Url::parse("https://evil.example.org/login?a=1&loginToken=\"<x>").unwrap();
+        let page = confirmation_page(server_name!("example.com"), &url);
+
+        assert!(page.contains("<strong>evil.example.org</strong>"));
+        assert!(page.contains("href=\"https://evil.example.org/login?a=1&amp;loginToken="));
+        assert!(!page.contains("<x>"));
+    }
+
+    #[test]
+    fn localpart_from_claim() {
+        assert_eq!(localpart("Alice").as_deref(), Some("alice"));
+        assert_eq!(localpart("alice@example.com").as_deref(), Some("alice"));
+        assert_eq!(localpart("alice.smith-2").as_deref(), Some("alice.smith-2"));
+    }
+
+    #[test]
+    fn invalid_localpart() {
+        assert_eq!(localpart(""), None);
+        assert_eq!(localpart("@example.com"), None);
+        assert_eq!(localpart("alice smith"), None);
+        assert_eq!(localpart("älice"), None);
+    }
+
+    #[test]
+    fn redirect_url_schemes() {
+        assert!(redirect_url_of("https://app.example.org/login").is_ok());
+        assert!(redirect_url_of("im.example.app://login").is_ok());
+
+        assert!(redirect_url_of("javascript:alert(document.cookie)").is_err());
+        assert!(redirect_url_of("JavaScript:alert(1)").is_err());
+        assert!(redirect_url_of("data:text/html,<script>alert(1)</script>").is_err());
+        assert!(redirect_url_of("vbscript:msgbox(1)").is_err());
+        assert!(redirect_url_of("/relative").is_err());
+    }
+}
@@ -0,0 +1,425 @@
+//! OpenID Connect authorization code flow
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tokio::sync::OnceCell;
+use tracing::warn;
+use url::Url;
+
+use crate::{config::OidcProviderConfig, Error, Result};
+
+pub type Claims = serde_json::Map<String, Value>;
+
+/// The parts of the discovery document we need
+#[derive(Deserialize)]
+struct Metadata {
+    issuer: String,
+    authorization_endpoint: Url,
+    token_endpoint: Url,
+    userinfo_endpoint: Option<Url>,
+    jwks_uri: Option<Url>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    id_token: String,
+}
+
+pub struct Provider {
+    pub config: OidcProviderConfig,
+    metadata: OnceCell<Metadata>,
+}
+
+impl Provider {
+    pub fn new(config: OidcProviderConfig) -> Self {
+        Self {
+            config,
+            metadata: OnceCell::new(),
+        }
+    }
+
+    /// Fetches the discovery document of the provider once.
+    async fn metadata(&self, client: &reqwest::Client) -> Result<&Metadata> {
+        self.metadata
+            .get_or_try_init(|| async {
+                let url = format!(
+                    "{}/.well-known/openid-configuration",
+                    self.config.issuer.as_str().trim_end_matches('/')
+                );
+                let metadata: Metadata = get_json(client.get(url)).await?;
+
+                if metadata.issuer.trim_end_matches('/')
+                    != self.config.issuer.as_str().trim_end_matches('/')
+                {
+                    warn!(
+                        "OpenID Connect provider {} claims to be {}",
+                        self.config.id, metadata.issuer
+                    );
+                    return Err(Error::BadServerResponse(
+                        "Issuer of the identity provider does not match.",
+                    ));
+                }
+
+                Ok(metadata)
+            })
+            .await
+    }
+
+    /// Returns the URL the user has to visit to log in at the provider.
+    pub async fn authorization_url(
+        &self,
+        client: &reqwest::Client,
+        redirect_uri: &str,
+        state: &str,
+        nonce: &str,
+        code_verifier: &str,
+    ) -> Result<Url> {
+        let mut url = self.metadata(client).await?.authorization_endpoint.clone();
+
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.config.client_id)
+            .append_pair("redirect_uri", redirect_uri)
+            .append_pair("scope", &self.config.scopes.join(" "))
+            .append_pair("state", state)
+            .append_pair("nonce", nonce)
+            .append_pair("code_challenge", &code_challenge(code_verifier))
+            .append_pair("code_challenge_method", "S256");
+
+        Ok(url)
+    }
+
+    /// Exchanges the code from the redirect of the provider for the claims about the user. The
+    /// claims of the userinfo endpoint are added to the ones of the ID token.
+    pub async fn claims(
+        &self,
+        client: &reqwest::Client,
+        code: &str,
+        redirect_uri: &str,
+        nonce: &str,
+        code_verifier: &str,
+    ) -> Result<Claims> {
+        let metadata = self.metadata(client).await?;
+
+        let tokens: TokenResponse = get_json(
+            client
+                .post(metadata.token_endpoint.clone())
+                .basic_auth(&self.config.client_id, Some(&self.config.client_secret))
+                .form(&[
+                    ("grant_type", "authorization_code"),
+                    ("code", code),
+                    ("redirect_uri", redirect_uri),
+                    ("client_id", self.config.client_id.as_str()),
+                    ("code_verifier", code_verifier),
+                ]),
+        )
+        .await?;
+
+        let mut claims = self
+            .verify_id_token(client, metadata, &tokens.id_token)
+            .await?;
+
+        if claims.get("nonce").and_then(Value::as_str) != Some(nonce) {
+            return Err(Error::BadServerResponse(
+                "ID token of the identity provider has the wrong nonce.",
+            ));
+        }
+
+        if let Some(userinfo_endpoint) = &metadata.userinfo_endpoint {
+            let userinfo: Claims = get_json(
+                client
+                    .get(userinfo_endpoint.clone())
+                    .bearer_auth(&tokens.access_token),
+            )
+            .await?;
+
+            if userinfo.get("sub") != claims.get("sub") {
+                return Err(Error::BadServerResponse(
+                    "Userinfo of the identity provider is about a different user.",
+                ));
+            }
+
+            for (name, value) in userinfo {
+                claims.entry(name).or_insert(value);
+            }
+        }
+
+        Ok(claims)
+    }
+
+    async fn verify_id_token(
+        &self,
+        client: &reqwest::Client,
+        metadata: &Metadata,
+        id_token: &str,
+    ) -> Result<Claims> {
+        let header = jsonwebtoken::decode_header(id_token)
+            .map_err(|_| Error::BadServerResponse("Invalid ID token from identity provider."))?;
+
+        if !self.config.id_token_signing_algs.contains(&header.alg) {
+            warn!(
+                "{} signed an ID token with {:?}, which is not allowed",
+                self.config.id, header.alg
+            );
+            return Err(Error::BadServerResponse(
+                "Identity provider signed ID token with a forbidden algorithm.",
+            ));
+        }
+
+        let key = match header.alg {
+            // Symmetric algorithms use the client secret as key
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                DecodingKey::from_secret(self.config.client_secret.as_bytes())
+            }
+            _ => {
+                let jwks_uri = metadata.jwks_uri.clone().ok_or(Error::BadServerResponse(
+                    "Identity provider has no keys to verify ID tokens.",
+                ))?;
+                let jwks: JwkSet = get_json(client.get(jwks_uri)).await?;
+
+                let jwk = match &header.kid {
+                    Some(kid) => jwks.find(kid),
+                    None => jwks.keys.first(),
+                }
+                .ok_or(Error::BadServerResponse(
+                    "Identity provider signed ID token with unknown key.",
+                ))?;
+
+                DecodingKey::from_jwk(jwk).map_err(|_| {
+                    Error::BadServerResponse("Identity provider published an invalid key.")
+                })?
+            }
+        };
+
+        let mut validation = Validation::new(header.alg);
+        validation.algorithms = self.config.id_token_signing_algs.clone();
+        validation.set_audience(&[&self.config.client_id]);
+        validation.set_issuer(&[&metadata.issuer]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+
+        jsonwebtoken::decode::<Claims>(id_token, &key, &validation)
+            .map(|token| token.claims)
+            .map_err(|e| {
+                warn!("Invalid ID token from {}: {e}", self.config.id);
+                Error::BadServerResponse("Invalid ID token from identity provider.")
+            })
+    }
+}
+
+/// PKCE code challenge using the S256 method
+fn code_challenge(code_verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+async fn get_json<T: for<'de> Deserialize<'de>>(request: reqwest::RequestBuilder) -> Result<T> {
+    let response = request.send().await.map_err(|e| {
+        warn!("Failed to reach identity provider: {e}");
+        Error::BadServerResponse("Failed to reach the identity provider.")
+    })?;
+
+    if !response.status().is_success() {
+        warn!(
+            "Identity provider returned {} for {}",
+            response.status(),
+            response.url()
+        );
+        return Err(Error::BadServerResponse(
+            "Identity provider returned an error.",
+        ));
+    }
+
+    let body = response.bytes().await.map_err(|e| {
+        warn!("Failed to read response of identity provider: {e}");
+        Error::BadServerResponse("Failed to reach the identity provider.")
+    })?;
+
+    serde_json::from_slice(&body)
+        .map_err(|_| Error::BadServerResponse("Invalid response from identity provider."))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+
+    use axum::{routing::get, routing::post, Form, Json, Router};
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+
+    use super::*;
+    use crate::utils;
+
+    const CLIENT_SECRET: &str = "client secret";
+    const CODE_VERIFIER: &str = "code verifier";
+
+    /// Starts an identity provider that accepts the code `code` and signs ID tokens for `alice`
+    /// with the given nonce.
+    fn mock_idp(nonce: &'static str) -> Provider {
+        let listener = TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+
+        let discovery = json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{issuer}/authorize"),
+            "token_endpoint": format!("{issuer}/token"),
+            "userinfo_endpoint": format!("{issuer}/userinfo"),
+        });
+        let id_token = jsonwebtoken::encode(
+            &Header::default(),
+            &json!({
+                "iss": issuer,
+                "aud": "conduit",
+                "sub": "alice-id",
+                "exp": utils::secs_since_unix_epoch() + 60,
+                "nonce": nonce,
+                "preferred_username": "alice",
+            }),
+            &EncodingKey::from_secret(CLIENT_SECRET.as_bytes()),
+        )
+        .unwrap();
+
+        let app = Router::new()
+            .route(
+                "/.well-known/openid-configuration",
+                get(move || {
+                    let discovery = discovery.clone();
+                    async move { Json(discovery) }
+                }),
+            )
+            .route(
+                "/token",
+                post(move |Form(form): Form<Vec<(String, String)>>| {
+                    let id_token = id_token.clone();
+                    async move {
+                        let has = |name: &str, value: &str| {
+                            form.iter().any(|(n, v)| n == name && v == value)
+                        };
+
+                        if has("code", "code") && has("code_verifier", CODE_VERIFIER) {
+                            Ok(Json(json!({
+                                "access_token": "access token",
+                                "token_type": "Bearer",
+                                "id_token": id_token,
+                            })))
+                        } else {
+                            Err(http::StatusCode::BAD_REQUEST)
+                        }
+                    }
+                }),
+            )
+            .route(
+                "/userinfo",
+                get(|| async {
+                    Json(json!({
+                        "sub": "alice-id",
+                        "name": "Alice",
+                        "preferred_username": "not alice",
+                    }))
+                }),
+            );
+
+        tokio::spawn(axum_server::from_tcp(listener).serve(app.into_make_service()));
+
+        Provider::new(OidcProviderConfig {
+            id: "mock".to_owned(),
+            name: None,
+            icon: None,
+            issuer: issuer.parse().unwrap(),
+            client_id: "conduit".to_owned(),
+            client_secret: CLIENT_SECRET.to_owned(),
+            scopes: vec!["openid".to_owned()],
+            localpart_claim: "preferred_username".to_owned(),
+            displayname_claim: "name".to_owned(),
+            auto_provision: true,
+            allow_existing_users: false,
+        })
+    }
+
+    #[tokio::test]
+    async fn authorization_url() {
+        let provider = mock_idp("nonce");
+        let client = reqwest::Client::new();
+
+        let url = provider
+            .authorization_url(
+                &client,
+                "https://conduit.rs/callback",
+                "state",
+                "nonce",
+                CODE_VERIFIER,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(url.path(), "/authorize");
+        let pairs = url.query_pairs().into_owned().collect::<Vec<_>>();
+        for (name, value) in [
+            ("client_id", "conduit"),
+            ("redirect_uri", "https://conduit.rs/callback"),
+            ("state", "state"),
+            ("code_challenge", &code_challenge(CODE_VERIFIER)),
+        ] {
+            assert!(pairs.contains(&(name.to_owned(), value.to_owned())));
+        }
+    }
+
+    #[tokio::test]
+    async fn claims_from_code() {
+        let provider = mock_idp("nonce");
+        let client = reqwest::Client::new();
+
+        let claims = provider
+            .claims(
+                &client,
+                "code",
+                "https://conduit.rs/callback",
+                "nonce",
+                CODE_VERIFIER,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(claims["sub"], "alice-id");
+        // Claims of the ID token take precedence
+        assert_eq!(claims["preferred_username"], "alice");
+        assert_eq!(claims["name"], "Alice");
+    }
+
+    #[tokio::test]
+    async fn wrong_nonce() {
+        let provider = mock_idp("other nonce");
+        let client = reqwest::Client::new();
+
+        assert!(provider
+            .claims(
+                &client,
+                "code",
+                "https://conduit.rs/callback",
+                "nonce",
+                CODE_VERIFIER
+            )
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn wrong_code() {
+        let provider = mock_idp("nonce");
+        let client = reqwest::Client::new();
+
+        assert!(provider
+            .claims(
+                &client,
+                "bad code",
+                "https://conduit.rs/callback",
+                "nonce",
+                CODE_VERIFIER
+            )
+            .await
+            .is_err());
+    }
+}
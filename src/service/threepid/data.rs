@@ -0,0 +1,36 @@
+use ruma::{
+    thirdparty::{Medium, ThirdPartyIdentifier},
+    ClientSecret, OwnedSessionId, OwnedUserId, SessionId, UserId,
+};
+
+use super::Validation;
+use crate::Result;
+
+pub trait Data: Send + Sync {
+    /// Creates or updates a validation session.
+    fn save_validation(&self, session_id: &SessionId, validation: &Validation) -> Result<()>;
+
+    fn validation(&self, session_id: &SessionId) -> Result<Option<Validation>>;
+
+    /// Finds the session that was started with this client secret for this address.
+    fn validation_session(
+        &self,
+        client_secret: &ClientSecret,
+        medium: &Medium,
+        address: &str,
+    ) -> Result<Option<OwnedSessionId>>;
+
+    fn remove_validation(&self, session_id: &SessionId) -> Result<()>;
+
+    fn add_threepid(&self, user_id: &UserId, threepid: &ThirdPartyIdentifier) -> Result<()>;
+
+    fn remove_threepid(&self, user_id: &UserId, medium: &Medium, address: &str) -> Result<()>;
+
+    /// Returns the user this third party identifier belongs to.
+    fn threepid_owner(&self, medium: &Medium, address: &str) -> Result<Option<OwnedUserId>>;
+
+    fn threepids<'a>(
+        &'a self,
+        user_id: &UserId,
+    ) -> Box<dyn Iterator<Item = Result<ThirdPartyIdentifier>> + 'a>;
+}
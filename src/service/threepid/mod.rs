@@ -0,0 +1,404 @@
+mod data;
+
+pub use data::Data;
+
+use std::time::{Duration, SystemTime};
+
+use ruma::{
+    api::client::error::ErrorKind,
+    thirdparty::{Medium, ThirdPartyIdentifier, ThirdPartyIdentifierInit},
+    ClientSecret, MilliSecondsSinceUnixEpoch, OwnedClientSecret, OwnedSessionId, OwnedUserId,
+    SessionId, UInt, UserId,
+};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::{
+    api::client_server::SESSION_ID_LENGTH, service::email::render, services, utils, Error, Result,
+};
+
+/// How long the link in a validation email can be used
+const VALIDATION_LIFETIME: Duration = Duration::from_secs(60 * 60);
+const VALIDATION_TOKEN_LENGTH: usize = 32;
+
+/// An attempt to prove the ownership of a third party identifier
+#[derive(Serialize, Deserialize)]
+pub struct Validation {
+    pub client_secret: OwnedClientSecret,
+    pub medium: Medium,
+    pub address: String,
+    pub token: String,
+    pub send_attempt: UInt,
+    pub next_link: Option<Url>,
+    pub expires_at: MilliSecondsSinceUnixEpoch,
+    pub validated_at: Option<MilliSecondsSinceUnixEpoch>,
+    /// Sessions stored before this was recorded can only be used to add a threepid
+    #[serde(default)]
+    pub purpose: Purpose,
+}
+
+impl Validation {
+    /// Whether the token is the one from the email.
+    fn token_matches(&self, token: &str) -> bool {
+        utils::constant_time_eq(self.token.as_bytes(), token.as_bytes())
+    }
+
+    /// Makes sure the user followed the link in the email, and that the session was requested
+    /// for the same purpose it is used for now.
+    fn check_validated(&self, purpose: Purpose) -> Result<()> {
+        if self.validated_at.is_none() {
+            return Err(Error::BadRequest(
+                ErrorKind::ThreepidAuthFailed,
+                "The email address has not been validated yet.",
+            ));
+        }
+
+        if self.purpose != purpose {
+            return Err(Error::BadRequest(
+                ErrorKind::ThreepidAuthFailed,
+                "The validation session was requested for a different purpose.",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Only http(s) links are accepted, so that the submit_token page can't be used to redirect to
+/// other schemes.
+fn parse_next_link(next_link: &str) -> Result<Url> {
+    Url::parse(next_link)
+        .ok()
+        .filter(|url| matches!(url.scheme(), "http" | "https"))
+        .ok_or(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "next_link must be an http or https URL.",
+        ))
+}
+
+/// What a validated email address is going to be used for
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Purpose {
+    #[default]
+    AddThreepid,
+    PasswordReset,
+}
+
+pub struct Service {
+    pub db: &'static dyn Data,
+}
+
+impl Service {
+    /// Sends an email with a link that validates the session. If the client retries with the
+    /// same `send_attempt` and purpose, the existing session is returned and no email is sent.
+    pub async fn request_email_validation(
+        &self,
+        client_secret: &ClientSecret,
+        email: &str,
+        send_attempt: UInt,
+        next_link: Option<String>,
+        purpose: Purpose,
+    ) -> Result<OwnedSessionId> {
+        let Some(config) = &services().globals.config.email else {
+            return Err(Error::BadRequest(
+                ErrorKind::ThreepidDenied,
+                "Sending emails is not configured on this server.",
+            ));
+        };
+
+        let next_link = next_link.as_deref().map(parse_next_link).transpose()?;
+
+        let address = email.trim().to_lowercase();
+        if address.parse::<lettre::Address>().is_err() {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Invalid email address.",
+            ));
+        }
+
+        match (purpose, self.db.threepid_owner(&Medium::Email, &address)?) {
+            (Purpose::AddThreepid, Some(_)) => {
+                return Err(Error::BadRequest(
+                    ErrorKind::ThreepidInUse,
+                    "This email address is already in use.",
+                ))
+            }
+            (Purpose::PasswordReset, None) => {
+                return Err(Error::BadRequest(
+                    ErrorKind::ThreepidNotFound,
+                    "This email address is not linked to any account.",
+                ))
+            }
+            _ => {}
+        }
+
+        let session_id = self
+            .db
+            .validation_session(client_secret, &Medium::Email, &address)?;
+
+        if let Some(session_id) = &session_id {
+            if self.db.validation(session_id)?.is_some_and(|validation| {
+                validation.purpose == purpose && send_attempt <= validation.send_attempt
+            }) {
+                return Ok(session_id.clone());
+            }
+        }
+
+        let session_id = session_id.unwrap_or_else(|| {
+            SessionId::parse(utils::random_string(SESSION_ID_LENGTH))
+                .expect("random string is a valid session id")
+        });
+        let token = utils::random_string(VALIDATION_TOKEN_LENGTH);
+
+        let link = format!(
+            "{}/_conduit/client/3pid/email/submit_token?sid={session_id}&client_secret={client_secret}&token={token}",
+            services().globals.well_known_client().trim_end_matches('/')
+        );
+
+        self.db.save_validation(
+            &session_id,
+            &Validation {
+                client_secret: client_secret.to_owned(),
+                medium: Medium::Email,
+                address: address.clone(),
+                token,
+                send_attempt,
+                next_link,
+                expires_at: MilliSecondsSinceUnixEpoch::from_system_time(
+                    SystemTime::now() + VALIDATION_LIFETIME,
+                )
+                .expect("time is valid"),
+                validated_at: None,
+                purpose,
+            },
+        )?;
+
+        let (subject, body) = match purpose {
+            Purpose::AddThreepid => (
+                &config.templates.validation_subject,
+                &config.templates.validation_body,
+            ),
+            Purpose::PasswordReset => (
+                &config.templates.password_reset_subject,
+                &config.templates.password_reset_body,
+            ),
+        };
+
+        let values = [
+            ("server_name", services().globals.server_name().as_str()),
+            ("link", link.as_str()),
+        ];
+
+        services()
+            .email
+            .send(&address, &render(subject, &values), render(body, &values))
+            .await?;
+
+        Ok(session_id)
+    }
+
+    /// Marks the session as validated if the token is the one from the email. Returns the link
+    /// the user wants to be redirected to afterwards.
+    pub fn submit_token(
+        &self,
+        session_id: &SessionId,
+        client_secret: &ClientSecret,
+        token: &str,
+    ) -> Result<Option<Url>> {
+        let mut validation = self.session(session_id, client_secret)?;
+
+        if !validation.token_matches(token) {
+            return Err(Error::BadRequest(
+                ErrorKind::ThreepidAuthFailed,
+                "Invalid validation token.",
+            ));
+        }
+
+        validation.validated_at = Some(MilliSecondsSinceUnixEpoch::now());
+        self.db.save_validation(session_id, &validation)?;
+
+        Ok(validation.next_link)
+    }
+
+    /// Returns the session if the user followed the link in the email and it was requested for
+    /// `purpose`.
+    pub fn validated(
+        &self,
+        session_id: &SessionId,
+        client_secret: &ClientSecret,
+        purpose: Purpose,
+    ) -> Result<Validation> {
+        let validation = self.session(session_id, client_secret)?;
+        validation.check_validated(purpose)?;
+
+        Ok(validation)
+    }
+
+    /// Makes sure a validated session can't be used a second time.
+    pub fn end_validation(&self, session_id: &SessionId) -> Result<()> {
+        self.db.remove_validation(session_id)
+    }
+
+    fn session(&self, session_id: &SessionId, client_secret: &ClientSecret) -> Result<Validation> {
+        self.db
+            .validation(session_id)?
+            .filter(|validation| {
+                utils::constant_time_eq(
+                    validation.client_secret.as_bytes(),
+                    client_secret.as_bytes(),
+                ) && validation.expires_at > MilliSecondsSinceUnixEpoch::now()
+            })
+            .ok_or(Error::BadRequest(
+                ErrorKind::ThreepidAuthFailed,
+                "Unknown or expired validation session.",
+            ))
+    }
+
+    /// Links the third party identifier that was validated in the session to the user.
+    pub fn add(
+        &self,
+        user_id: &UserId,
+        session_id: &SessionId,
+        client_secret: &ClientSecret,
+    ) -> Result<()> {
+        let validation = self.validated(session_id, client_secret, Purpose::AddThreepid)?;
+
+        if self
+            .db
+            .threepid_owner(&validation.medium, &validation.address)?
+            .is_some_and(|owner| &*owner != user_id)
+        {
+            return Err(Error::BadRequest(
+                ErrorKind::ThreepidInUse,
+                "This third party identifier is already in use.",
+            ));
+        }
+
+        self.db.add_threepid(
+            user_id,
+            &ThirdPartyIdentifierInit {
+                address: validation.address,
+                medium: validation.medium,
+                validated_at: validation
+                    .validated_at
+                    .expect("validated sessions have a timestamp"),
+                added_at: MilliSecondsSinceUnixEpoch::now(),
+            }
+            .into(),
+        )?;
+
+        self.end_validation(session_id)
+    }
+
+    pub fn remove(&self, user_id: &UserId, medium: &Medium, address: &str) -> Result<()> {
+        let address = address.trim().to_lowercase();
+
+        if self.db.threepid_owner(medium, &address)?.as_deref() != Some(user_id) {
+            return Err(Error::BadRequest(
+                ErrorKind::ThreepidNotFound,
+                "This third party identifier is not linked to your account.",
+            ));
+        }
+
+        self.db.remove_threepid(user_id, medium, &address)
+    }
+
+    /// Unlinks all third party identifiers, e.g. when the account is deactivated.
+    pub fn remove_all(&self, user_id: &UserId) -> Result<()> {
+        let threepids = self.threepids(user_id).collect::<Result<Vec<_>>>()?;
+
+        for threepid in threepids {
+            self.db
+                .remove_threepid(user_id, &threepid.medium, &threepid.address)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the user this third party identifier belongs to.
+    pub fn owner(&self, medium: &Medium, address: &str) -> Result<Option<OwnedUserId>> {
+        self.db
+            .threepid_owner(medium, &address.trim().to_lowercase())
+    }
+
+    pub fn threepids<'a>(
+        &'a self,
+        user_id: &UserId,
+    ) -> impl Iterator<Item = Result<ThirdPartyIdentifier>> + 'a {
+        self.db.threepids(user_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma::{thirdparty::Medium, uint, ClientSecret, MilliSecondsSinceUnixEpoch};
+
+    use super::{parse_next_link, Purpose, Validation};
+
+    fn validation(purpose: Purpose, validated: bool) -> Validation {
+        Validation {
+            client_secret: ClientSecret::parse("secret").unwrap(),
+            medium: Medium::Email,
+            address: "alice@example.com".to_owned(),
+            token: "token".to_owned(),
+            send_attempt: uint!(1),
+            next_link: None,
+            expires_at: MilliSecondsSinceUnixEpoch::now(),
+            validated_at: validated.then(MilliSecondsSinceUnixEpoch::now),
+            purpose,
+        }
+    }
+
+    #[test]
+    fn token_must_match() {
+        let validation = validation(Purpose::AddThreepid, false);
+
+        assert!(validation.token_matches("token"));
+        assert!(!validation.token_matches("tokeN"));
+        assert!(!validation.token_matches("token2"));
+        assert!(!validation.token_matches(""));
+    }
+
+    #[test]
+    fn session_must_be_validated() {
+        assert!(validation(Purpose::PasswordReset, false)
+            .check_validated(Purpose::PasswordReset)
+            .is_err());
+        assert!(validation(Purpose::PasswordReset, true)
+            .check_validated(Purpose::PasswordReset)
+            .is_ok());
+    }
+
+    #[test]
+    fn purpose_must_match() {
+        assert!(validation(Purpose::AddThreepid, true)
+            .check_validated(Purpose::PasswordReset)
+            .is_err());
+        assert!(validation(Purpose::PasswordReset, true)
+            .check_validated(Purpose::AddThreepid)
+            .is_err());
+        assert!(validation(Purpose::AddThreepid, true)
+            .check_validated(Purpose::AddThreepid)
+            .is_ok());
+    }
+
+    #[test]
+    fn old_sessions_can_only_add_threepids() {
+        let mut json = serde_json::to_value(validation(Purpose::PasswordReset, true)).unwrap();
+        json.as_object_mut().unwrap().remove("purpose");
+
+        let validation: Validation = serde_json::from_value(json).unwrap();
+        assert_eq!(validation.purpose, Purpose::AddThreepid);
+    }
+
+    #[test]
+    fn next_link_must_be_http() {
+        assert!(parse_next_link("https://client.example.org/validated?a=1").is_ok());
+        assert!(parse_next_link("http://localhost:8080/").is_ok());
+
+        assert!(parse_next_link("javascript:alert(1)").is_err());
+        assert!(parse_next_link("data:text/html,<script>alert(1)</script>").is_err());
+        assert!(parse_next_link("/relative/path").is_err());
+        assert!(parse_next_link("not a url").is_err());
+    }
+}
@@ -5,13 +5,16 @@ pub use data::Data;
 use ruma::{
     api::client::{
         error::ErrorKind,
-        uiaa::{AuthData, AuthType, Password, UiaaInfo, UserIdentifier},
+        uiaa::{AuthData, AuthType, EmailIdentity, Password, UiaaInfo, UserIdentifier},
     },
     CanonicalJsonValue, DeviceId, UserId,
 };
 use tracing::error;
 
-use crate::{api::client_server::SESSION_ID_LENGTH, services, utils, Error, Result};
+use crate::{
+    api::client_server::SESSION_ID_LENGTH, service::threepid::Purpose, services, utils, Error,
+    Result,
+};
 
 pub struct Service {
     pub db: &'static dyn Data,
@@ -40,7 +43,7 @@ impl Service {
         )
     }
 
-    pub fn try_auth(
+    pub async fn try_auth(
         &self,
         user_id: &UserId,
         device_id: &DeviceId,
@@ -80,17 +83,12 @@ impl Service {
                 .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "User ID is invalid."))?;
 
                 // Check if password is correct
-                if let Some(hash) = services().users.password_hash(&user_id)? {
-                    let hash_matches =
-                        argon2::verify_encoded(&hash, password.as_bytes()).unwrap_or(false);
-
-                    if !hash_matches {
-                        uiaainfo.auth_error = Some(ruma::api::client::error::StandardErrorBody {
-                            kind: ErrorKind::forbidden(),
-                            message: "Invalid username or password.".to_owned(),
-                        });
-                        return Ok((false, uiaainfo));
-                    }
+                if !services().users.check_password(&user_id, password).await? {
+                    uiaainfo.auth_error = Some(ruma::api::client::error::StandardErrorBody {
+                        kind: ErrorKind::forbidden(),
+                        message: "Invalid username or password.".to_owned(),
+                    });
+                    return Ok((false, uiaainfo));
                 }
 
                 // Password was correct! Let's add it to `completed`
@@ -107,6 +105,36 @@ impl Service {
                     return Ok((false, uiaainfo));
                 }
             }
+            AuthData::EmailIdentity(EmailIdentity {
+                thirdparty_id_creds,
+                ..
+            }) => {
+                let owner = services()
+                    .threepid
+                    .validated(
+                        &thirdparty_id_creds.sid,
+                        &thirdparty_id_creds.client_secret,
+                        Purpose::PasswordReset,
+                    )
+                    .and_then(|validation| {
+                        services()
+                            .threepid
+                            .owner(&validation.medium, &validation.address)
+                    });
+
+                if !matches!(owner, Ok(Some(owner)) if &*owner == user_id) {
+                    uiaainfo.auth_error = Some(ruma::api::client::error::StandardErrorBody {
+                        kind: ErrorKind::ThreepidAuthFailed,
+                        message: "Your email address could not be verified.".to_owned(),
+                    });
+                    return Ok((false, uiaainfo));
+                }
+
+                services()
+                    .threepid
+                    .end_validation(&thirdparty_id_creds.sid)?;
+                uiaainfo.completed.push(AuthType::EmailIdentity);
+            }
             AuthData::Dummy(_) => {
                 uiaainfo.completed.push(AuthType::Dummy);
             }
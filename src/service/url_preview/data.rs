@@ -0,0 +1,18 @@
+use crate::Result;
+
+use super::UrlPreviewData;
+
+pub trait Data: Send + Sync {
+    /// Caches the preview of the url, together with the time it was fetched in millis since the
+    /// unix epoch.
+    fn set_url_preview(&self, url: &str, timestamp: u64, preview: &UrlPreviewData) -> Result<()>;
+
+    /// Returns the cached preview of the url and the time it was fetched.
+    fn url_preview(&self, url: &str) -> Result<Option<(u64, UrlPreviewData)>>;
+
+    /// Remembers the media id a preview image with the given sha256 digest was stored as.
+    fn set_preview_image(&self, sha256_digest: &[u8], media_id: &str) -> Result<()>;
+
+    /// Returns the media id a preview image with the given sha256 digest was stored as.
+    fn preview_image(&self, sha256_digest: &[u8]) -> Result<Option<String>>;
+}
@@ -0,0 +1,393 @@
+mod data;
+use std::{collections::HashMap, io::Cursor, sync::LazyLock, time::Duration};
+
+pub use data::Data;
+use http::header::CONTENT_TYPE;
+use regex::Regex;
+use ruma::api::client::error::ErrorKind;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::debug;
+use url::Url;
+
+use crate::{services, utils, Error, Result};
+
+const MXC_LENGTH: usize = 32;
+
+/// How long a preview is served from the cache before the page is fetched again
+const CACHE_DURATION: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// Only the start of a page is read, which is where the metadata is
+const MAX_HTML_SIZE: usize = 1024 * 1024;
+
+static META_TAG: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?is)<meta\s[^>]*>").expect("Regex compilation should not fail"));
+static ATTRIBUTE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(?is)([a-z][a-z0-9:_-]*)\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'>]+))"#)
+        .expect("Regex compilation should not fail")
+});
+static TITLE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?is)<title[^>]*>(.*?)</title>").expect("Regex compilation should not fail")
+});
+
+/// OpenGraph data of a page, as returned by the preview_url endpoints.
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Eq)]
+pub struct UrlPreviewData {
+    #[serde(rename = "og:title", skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(rename = "og:description", skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(rename = "og:image", skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    #[serde(rename = "og:image:type", skip_serializing_if = "Option::is_none")]
+    pub image_type: Option<String>,
+    #[serde(rename = "matrix:image:size", skip_serializing_if = "Option::is_none")]
+    pub image_size: Option<u64>,
+    #[serde(rename = "og:image:width", skip_serializing_if = "Option::is_none")]
+    pub image_width: Option<u32>,
+    #[serde(rename = "og:image:height", skip_serializing_if = "Option::is_none")]
+    pub image_height: Option<u32>,
+}
+
+pub struct Service {
+    pub db: &'static dyn Data,
+}
+
+impl Service {
+    /// Returns the preview of the url, fetching the page if there is no recent preview cached.
+    pub async fn get(&self, url: &str) -> Result<UrlPreviewData> {
+        let url = Url::parse(url)
+            .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid URL."))?;
+
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Only http and https URLs can be previewed.",
+            ));
+        }
+
+        if services().globals.is_url_preview_denied(&url) {
+            return Err(Error::BadRequest(
+                ErrorKind::forbidden(),
+                "Previewing this URL is not allowed.",
+            ));
+        }
+
+        let now = utils::millis_since_unix_epoch();
+
+        if let Some((fetched_at, preview)) = self.db.url_preview(url.as_str())? {
+            if now.saturating_sub(fetched_at) < CACHE_DURATION.as_millis() as u64 {
+                return Ok(preview);
+            }
+        }
+
+        let preview = self.fetch(&url).await?;
+        self.db.set_url_preview(url.as_str(), now, &preview)?;
+
+        Ok(preview)
+    }
+
+    async fn fetch(&self, url: &Url) -> Result<UrlPreviewData> {
+        let response = services()
+            .globals
+            .url_preview_client()
+            .get(url.clone())
+            .send()
+            .await
+            .map_err(|e| {
+                debug!("Failed to fetch url preview of {url}: {e}");
+                Error::BadRequest(ErrorKind::Unknown, "Failed to fetch URL.")
+            })?;
+
+        if !response.status().is_success() {
+            return Err(Error::BadRequest(
+                ErrorKind::Unknown,
+                "Server of the URL returned an error.",
+            ));
+        }
+
+        let content_type = content_type(&response);
+        // Relative image urls need to be resolved against the page we ended up at
+        let final_url = response.url().clone();
+
+        match content_type.as_deref() {
+            Some(content_type) if content_type.starts_with("image/") => {
+                let (image, complete) =
+                    read_body(response, services().globals.max_request_size() as usize).await?;
+
+                if !complete {
+                    return Err(Error::BadRequest(
+                        ErrorKind::TooLarge,
+                        "Image is too large.",
+                    ));
+                }
+
+                self.store_image(image, Some(content_type)).await
+            }
+            Some(content_type) if content_type.starts_with("text/html") => {
+                let (html, _) = read_body(response, MAX_HTML_SIZE).await?;
+                let page = parse_html(&String::from_utf8_lossy(&html));
+
+                let mut preview = UrlPreviewData {
+                    title: page.title,
+                    description: page.description,
+                    ..Default::default()
+                };
+
+                if let Some(image_url) = page.image.and_then(|image| final_url.join(&image).ok()) {
+                    match self.fetch_image(&image_url).await {
+                        Ok(image) => {
+                            preview.image = image.image;
+                            preview.image_type = image.image_type;
+                            preview.image_size = image.image_size;
+                            preview.image_width = image.image_width;
+                            preview.image_height = image.image_height;
+                        }
+                        Err(e) => debug!("Failed to fetch preview image {image_url}: {e}"),
+                    }
+                }
+
+                Ok(preview)
+            }
+            // There is nothing we can show for other files
+            _ => Ok(UrlPreviewData::default()),
+        }
+    }
+
+    async fn fetch_image(&self, url: &Url) -> Result<UrlPreviewData> {
+        if !matches!(url.scheme(), "http" | "https")
+            || services().globals.is_url_preview_denied(url)
+        {
+            return Err(Error::BadRequest(
+                ErrorKind::forbidden(),
+                "Previewing this URL is not allowed.",
+            ));
+        }
+
+        let response = services()
+            .globals
+            .url_preview_client()
+            .get(url.clone())
+            .send()
+            .await?;
+
+        let content_type = content_type(&response)
+            .filter(|content_type| content_type.starts_with("image/"))
+            .ok_or(Error::BadServerResponse("Preview image is not an image."))?;
+
+        let (image, complete) =
+            read_body(response, services().globals.max_request_size() as usize).await?;
+
+        if !complete {
+            return Err(Error::BadServerResponse("Preview image is too large."));
+        }
+
+        self.store_image(image, Some(content_type.as_str())).await
+    }
+
+    /// Stores the image as local media, so clients can download it from us. Images that were
+    /// stored before are reused.
+    async fn store_image(
+        &self,
+        image: Vec<u8>,
+        content_type: Option<&str>,
+    ) -> Result<UrlPreviewData> {
+        let (image_width, image_height) = image::ImageReader::new(Cursor::new(&image))
+            .with_guessed_format()
+            .ok()
+            .and_then(|reader| reader.into_dimensions().ok())
+            .unzip();
+
+        let sha256_digest = Sha256::digest(&image);
+
+        let stored = self.db.preview_image(&sha256_digest)?.filter(|media_id| {
+            // The media might have been purged or blocked in the meantime
+            services()
+                .media
+                .query(services().globals.server_name(), media_id)
+                .is_ok_and(|query| {
+                    !query.is_blocked
+                        && query.source_file.is_some_and(|file| {
+                            !file.is_blocked_via_filehash && file.file_info.is_some()
+                        })
+                })
+        });
+
+        let media_id = match stored {
+            Some(media_id) => media_id,
+            None => {
+                let media_id = utils::random_string(MXC_LENGTH);
+
+                services()
+                    .media
+                    .create(
+                        services().globals.server_name(),
+                        &media_id,
+                        None,
+                        content_type,
+                        &image,
+                        None,
+                    )
+                    .await?;
+
+                self.db.set_preview_image(&sha256_digest, &media_id)?;
+
+                media_id
+            }
+        };
+
+        Ok(UrlPreviewData {
+            image: Some(format!(
+                "mxc://{}/{}",
+                services().globals.server_name(),
+                media_id
+            )),
+            image_type: content_type.map(ToOwned::to_owned),
+            image_size: Some(image.len() as u64),
+            image_width,
+            image_height,
+            ..Default::default()
+        })
+    }
+}
+
+fn content_type(response: &reqwest::Response) -> Option<String> {
+    response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .map(|content_type| content_type.trim().to_ascii_lowercase())
+}
+
+/// Reads at most `limit` bytes of the response body. Also returns whether the whole body was read.
+async fn read_body(mut response: reqwest::Response, limit: usize) -> Result<(Vec<u8>, bool)> {
+    let mut body = Vec::new();
+
+    while let Some(chunk) = response.chunk().await? {
+        if body.len() + chunk.len() > limit {
+            body.extend_from_slice(&chunk[..limit - body.len()]);
+            return Ok((body, false));
+        }
+
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok((body, true))
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct HtmlPreview {
+    title: Option<String>,
+    description: Option<String>,
+    image: Option<String>,
+}
+
+/// Extracts the title, description and image of a page from its OpenGraph tags, falling back to
+/// the other common meta tags and the `<title>` element.
+fn parse_html(html: &str) -> HtmlPreview {
+    let mut meta = HashMap::new();
+
+    for tag in META_TAG.find_iter(html) {
+        let mut key = None;
+        let mut content = None;
+
+        for attribute in ATTRIBUTE.captures_iter(tag.as_str()) {
+            let value = attribute
+                .get(2)
+                .or_else(|| attribute.get(3))
+                .or_else(|| attribute.get(4))
+                .map(|value| value.as_str());
+
+            match attribute[1].to_ascii_lowercase().as_str() {
+                "property" | "name" => key = value.map(str::to_ascii_lowercase),
+                "content" => content = value,
+                _ => {}
+            }
+        }
+
+        if let (Some(key), Some(content)) = (key, content) {
+            // The first occurrence of a tag wins
+            meta.entry(key).or_insert_with(|| decode_entities(content));
+        }
+    }
+
+    let mut find = |keys: &[&str]| {
+        keys.iter()
+            .find_map(|key| meta.remove(*key))
+            .filter(|value| !value.is_empty())
+    };
+
+    HtmlPreview {
+        title: find(&["og:title", "twitter:title"]).or_else(|| {
+            TITLE
+                .captures(html)
+                .map(|title| decode_entities(&title[1]))
+                .filter(|title| !title.is_empty())
+        }),
+        description: find(&["og:description", "twitter:description", "description"]),
+        image: find(&["og:image", "og:image:url", "twitter:image"]),
+    }
+}
+
+fn decode_entities(text: &str) -> String {
+    text.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .replace("&quot;", "\"")
+        .replace("&#34;", "\"")
+        .replace("&apos;", "'")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opengraph_tags_are_preferred() {
+        let html = r#"
+            <html><head>
+                <title>Fallback title</title>
+                <meta name="description" content="Fallback description">
+                <meta property="og:title" content="Conduit &amp; friends" />
+                <meta content='A Matrix homeserver' property='og:description'>
+                <meta property="og:image" content="/logo.png">
+            </head></html>
+        "#;
+
+        assert_eq!(
+            parse_html(html),
+            HtmlPreview {
+                title: Some("Conduit & friends".to_owned()),
+                description: Some("A Matrix homeserver".to_owned()),
+                image: Some("/logo.png".to_owned()),
+            }
+        );
+    }
+
+    #[test]
+    fn falls_back_to_title_and_description() {
+        let html = r#"
+            <HEAD>
+                <TITLE>
+                    Some   page
+                </TITLE>
+                <META NAME="Description" CONTENT="Describes the page">
+            </HEAD>
+        "#;
+
+        assert_eq!(
+            parse_html(html),
+            HtmlPreview {
+                title: Some("Some page".to_owned()),
+                description: Some("Describes the page".to_owned()),
+                image: None,
+            }
+        );
+    }
+}
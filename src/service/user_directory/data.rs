@@ -0,0 +1,24 @@
+use std::collections::BTreeSet;
+
+use ruma::{OwnedUserId, UserId};
+
+use crate::Result;
+
+pub trait Data: Send + Sync {
+    /// Replaces the words the user can be found by.
+    fn set_user_tokens(&self, user_id: &UserId, tokens: &BTreeSet<String>) -> Result<()>;
+
+    /// Removes the user from the directory.
+    fn remove_user(&self, user_id: &UserId) -> Result<()>;
+
+    /// Returns the words the user can be found by.
+    fn user_tokens(&self, user_id: &UserId) -> Result<Vec<String>>;
+
+    /// Returns all users that have a word starting with `prefix`, ordered by that word.
+    ///
+    /// A user can be returned more than once if multiple of their words match.
+    fn search_prefix<'a>(
+        &'a self,
+        prefix: &str,
+    ) -> Box<dyn Iterator<Item = Result<OwnedUserId>> + 'a>;
+}
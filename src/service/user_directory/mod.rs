@@ -0,0 +1,114 @@
+mod data;
+use std::collections::{BTreeSet, HashSet};
+
+pub use data::Data;
+use ruma::{
+    events::{
+        room::join_rules::{JoinRule, RoomJoinRulesEventContent},
+        StateEventType,
+    },
+    OwnedUserId, UserId,
+};
+
+use crate::{services, Result};
+
+pub struct Service {
+    pub db: &'static dyn Data,
+}
+
+impl Service {
+    /// Updates the entry of the user after their displayname or rooms changed.
+    ///
+    /// Only users that are joined to at least one room are in the directory, as nobody else
+    /// could see them anyway.
+    pub fn update_user(&self, user_id: &UserId) -> Result<()> {
+        if services()
+            .rooms
+            .state_cache
+            .rooms_joined(user_id)
+            .next()
+            .is_none()
+        {
+            return self.db.remove_user(user_id);
+        }
+
+        let mut tokens = tokenize(user_id.as_str()).collect::<BTreeSet<_>>();
+        if let Some(displayname) = services().users.displayname(user_id)? {
+            tokens.extend(tokenize(&displayname));
+        }
+
+        self.db.set_user_tokens(user_id, &tokens)
+    }
+
+    /// Returns the users the sender can see where every word of the search term is the start of
+    /// a word in their user id or displayname. Also returns whether there were more results.
+    pub fn search(
+        &self,
+        sender_user: &UserId,
+        search_term: &str,
+        limit: usize,
+    ) -> Result<(Vec<OwnedUserId>, bool)> {
+        let search_tokens = tokenize(search_term).collect::<Vec<_>>();
+
+        // The longest word usually matches the fewest users
+        let Some(longest) = search_tokens.iter().max_by_key(|token| token.len()) else {
+            return Ok((Vec::new(), false));
+        };
+
+        let mut seen = HashSet::new();
+        let mut users = self
+            .db
+            .search_prefix(longest)
+            .filter_map(|r| r.ok())
+            .filter(|user_id| seen.insert(user_id.clone()))
+            .filter(|user_id| {
+                self.db.user_tokens(user_id).is_ok_and(|tokens| {
+                    search_tokens
+                        .iter()
+                        .all(|search| tokens.iter().any(|token| token.starts_with(search)))
+                })
+            })
+            .filter(|user_id| self.is_visible(sender_user, user_id));
+
+        let results = users.by_ref().take(limit).collect();
+        let limited = users.next().is_some();
+
+        Ok((results, limited))
+    }
+
+    /// Users can be seen if they are in a public room or share a room with the sender.
+    fn is_visible(&self, sender_user: &UserId, user_id: &UserId) -> bool {
+        let user_is_in_public_rooms = services()
+            .rooms
+            .state_cache
+            .rooms_joined(user_id)
+            .filter_map(|r| r.ok())
+            .any(|room| {
+                services()
+                    .rooms
+                    .state_accessor
+                    .room_state_get(&room, &StateEventType::RoomJoinRules, "")
+                    .is_ok_and(|event| {
+                        event.is_some_and(|event| {
+                            serde_json::from_str(event.content.get()).is_ok_and(
+                                |r: RoomJoinRulesEventContent| r.join_rule == JoinRule::Public,
+                            )
+                        })
+                    })
+            });
+
+        user_is_in_public_rooms
+            || services()
+                .rooms
+                .user
+                .get_shared_rooms(vec![sender_user.to_owned(), user_id.to_owned()])
+                .is_ok_and(|mut rooms| rooms.next().is_some())
+    }
+}
+
+/// Splits text into lowercase words.
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+}
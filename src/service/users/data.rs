@@ -1,11 +1,13 @@
 use crate::Result;
 use ruma::{
-    api::client::{device::Device, filter::FilterDefinition},
+    api::client::{
+        dehydrated_device::DehydratedDeviceData, device::Device, filter::FilterDefinition,
+    },
     encryption::{CrossSigningKey, DeviceKeys, OneTimeKey},
     events::AnyToDeviceEvent,
     serde::Raw,
     DeviceId, MilliSecondsSinceUnixEpoch, OneTimeKeyAlgorithm, OwnedDeviceId, OwnedMxcUri,
-    OwnedOneTimeKeyId, OwnedUserId, UInt, UserId,
+    OwnedOneTimeKeyId, OwnedRoomId, OwnedUserId, RoomId, UInt, UserId,
 };
 use std::collections::BTreeMap;
 
@@ -73,8 +75,29 @@ pub trait Data: Send + Sync {
     ) -> Box<dyn Iterator<Item = Result<OwnedDeviceId>> + 'a>;
 
     /// Replaces the access token of one device.
+    ///
+    /// The new access token never expires and any refresh token of the device is invalidated.
     fn set_token(&self, user_id: &UserId, device_id: &DeviceId, token: &str) -> Result<()>;
 
+    /// Creates a refresh token for one device and makes its current access token expire after
+    /// `expires_in` seconds. Returns the refresh token.
+    fn create_refresh_token(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        expires_in: u64,
+    ) -> Result<String>;
+
+    /// Find out which device a refresh token belongs to.
+    fn find_from_refresh_token(
+        &self,
+        refresh_token: &str,
+    ) -> Result<Option<(OwnedUserId, OwnedDeviceId)>>;
+
+    /// Returns when the access token of the device expires in millis since the unix epoch, if it
+    /// does at all.
+    fn token_expires_at(&self, user_id: &UserId, device_id: &DeviceId) -> Result<Option<u64>>;
+
     fn add_one_time_key(
         &self,
         user_id: &UserId,
@@ -98,6 +121,31 @@ pub trait Data: Send + Sync {
         device_id: &DeviceId,
     ) -> Result<BTreeMap<OneTimeKeyAlgorithm, UInt>>;
 
+    /// Replaces the fallback key of the device for the algorithm of the key and marks it as
+    /// unused.
+    fn add_fallback_key(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        fallback_key_key: &OwnedOneTimeKeyId,
+        fallback_key_value: &Raw<OneTimeKey>,
+    ) -> Result<()>;
+
+    /// Returns the fallback key of the device for the algorithm and marks it as used. The key
+    /// is kept, so it can be handed out again until the device uploads a new one.
+    fn take_fallback_key(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        key_algorithm: &OneTimeKeyAlgorithm,
+    ) -> Result<Option<(OwnedOneTimeKeyId, Raw<OneTimeKey>)>>;
+
+    fn unused_fallback_key_types(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Vec<OneTimeKeyAlgorithm>>;
+
     fn add_device_keys(
         &self,
         user_id: &UserId,
@@ -212,6 +260,28 @@ pub trait Data: Send + Sync {
         devices: &'a BTreeMap<(OwnedUserId, OwnedDeviceId), MilliSecondsSinceUnixEpoch>,
     ) -> Box<dyn Iterator<Item = Result<()>> + 'a>;
 
+    /// Replaces the dehydrated device of the user.
+    fn set_dehydrated_device(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        device_data: &Raw<DehydratedDeviceData>,
+    ) -> Result<()>;
+
+    /// Returns the id and the data of the dehydrated device of the user.
+    fn get_dehydrated_device(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Option<(OwnedDeviceId, Raw<DehydratedDeviceData>)>>;
+
+    fn remove_dehydrated_device(&self, user_id: &UserId) -> Result<()>;
+
+    /// Remembers the room in which the user receives server notices.
+    fn set_server_notices_room(&self, user_id: &UserId, room_id: &RoomId) -> Result<()>;
+
+    /// Returns the room in which the user receives server notices, if there is one.
+    fn server_notices_room(&self, user_id: &UserId) -> Result<Option<OwnedRoomId>>;
+
     /// Creates a new sync filter. Returns the filter id.
     fn create_filter(&self, user_id: &UserId, filter: &FilterDefinition) -> Result<String>;
 
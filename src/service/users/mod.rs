@@ -9,20 +9,30 @@ use std::{
 pub use data::Data;
 use ruma::{
     api::client::{
+        dehydrated_device::DehydratedDeviceData,
         device::Device,
         filter::FilterDefinition,
         sync::sync_events::{self},
     },
     encryption::{CrossSigningKey, DeviceKeys, OneTimeKey},
-    events::AnyToDeviceEvent,
+    events::{
+        room::{member::RoomMemberEventContent, message::RoomMessageEventContent},
+        AnyToDeviceEvent, GlobalAccountDataEventType, StateEventType, TimelineEventType,
+    },
+    push,
     serde::Raw,
     DeviceId, MilliSecondsSinceUnixEpoch, OneTimeKeyAlgorithm, OwnedDeviceId, OwnedMxcUri,
-    OwnedOneTimeKeyId, OwnedRoomId, OwnedUserId, UInt, UserId,
+    OwnedOneTimeKeyId, OwnedRoomId, OwnedUserId, RoomId, UInt, UserId,
 };
+use serde_json::value::to_raw_value;
 use tokio::{sync::Mutex, time::interval};
-use tracing::{debug, warn};
+use tracing::{debug, info, warn};
 
-use crate::{services, Error, Result};
+use crate::{
+    api::client_server::{AUTO_GEN_PASSWORD_LENGTH, TOKEN_LENGTH},
+    service::pdu::PduBuilder,
+    services, utils, Error, Result,
+};
 
 pub struct SlidingSyncCache {
     lists: BTreeMap<String, sync_events::v5::request::List>,
@@ -291,6 +301,114 @@ impl Service {
         Ok(())
     }
 
+    /// Creates an account for a user who was authenticated by an external service, like an
+    /// identity provider or LDAP. The user doesn't need to know their password.
+    pub async fn provision(
+        &self,
+        user_id: &UserId,
+        displayname: Option<String>,
+        source: &str,
+    ) -> Result<()> {
+        services().policy.check_registration(user_id).await?;
+
+        self.create(
+            user_id,
+            Some(&utils::random_string(AUTO_GEN_PASSWORD_LENGTH)),
+        )?;
+
+        // Default to pretty displayname
+        let displayname = displayname.unwrap_or_else(|| {
+            let mut displayname = user_id.localpart().to_owned();
+
+            // If enabled append lightning bolt to display name (default true)
+            if services().globals.enable_lightning_bolt() {
+                displayname.push_str(" ⚡️");
+            }
+
+            displayname
+        });
+
+        self.set_displayname(user_id, Some(displayname.clone()))?;
+
+        // Initial account data
+        services().account_data.update(
+            None,
+            user_id,
+            GlobalAccountDataEventType::PushRules.to_string().into(),
+            &serde_json::to_value(ruma::events::push_rules::PushRulesEvent {
+                content: ruma::events::push_rules::PushRulesEventContent {
+                    global: push::Ruleset::server_default(user_id),
+                },
+            })
+            .expect("to json always works"),
+        )?;
+
+        info!(
+            "New user {} registered on this server through {}.",
+            user_id, source
+        );
+        services()
+            .admin
+            .send_message(RoomMessageEventContent::notice_plain(format!(
+                "New user {user_id} registered on this server through {source}."
+            )));
+
+        // If this is the first real user, grant them admin privileges
+        // Note: the server user, @conduit:servername, is generated first
+        if let Some(admin_room) = services().admin.get_admin_room()? {
+            if services()
+                .rooms
+                .state_cache
+                .room_joined_count(&admin_room)?
+                == Some(1)
+            {
+                services()
+                    .admin
+                    .make_user_admin(user_id, displayname)
+                    .await?;
+
+                warn!("Granting {} admin privileges as the first user", user_id);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks the password of a local user. If LDAP is configured, the directory is asked first
+    /// and users who only exist there get an account on their first login.
+    pub async fn check_password(&self, user_id: &UserId, password: &str) -> Result<bool> {
+        if services().ldap.is_enabled() && user_id.server_name() == services().globals.server_name()
+        {
+            match services()
+                .ldap
+                .authenticate(user_id.localpart(), password)
+                .await
+            {
+                Ok(Some(ldap_user)) => {
+                    if !self.exists(user_id)? {
+                        self.provision(user_id, ldap_user.displayname, "LDAP")
+                            .await?;
+                    } else if ldap_user.displayname.is_some()
+                        && ldap_user.displayname != self.displayname(user_id)?
+                    {
+                        self.update_displayname(user_id, ldap_user.displayname)
+                            .await?;
+                    }
+
+                    return Ok(true);
+                }
+                Ok(None) if !services().ldap.local_fallback() => return Ok(false),
+                Err(e) if !services().ldap.local_fallback() => return Err(e),
+                // Fall back to the password stored in the database
+                Ok(None) | Err(_) => {}
+            }
+        }
+
+        Ok(self.password_hash(user_id)?.is_some_and(|hash| {
+            argon2::verify_encoded(&hash, password.as_bytes()).unwrap_or(false)
+        }))
+    }
+
     /// Returns the number of users registered on this server.
     pub fn count(&self) -> Result<usize> {
         self.db.count()
@@ -330,7 +448,92 @@ impl Service {
 
     /// Sets a new displayname or removes it if displayname is None. You still need to nofify all rooms of this change.
     pub fn set_displayname(&self, user_id: &UserId, displayname: Option<String>) -> Result<()> {
-        self.db.set_displayname(user_id, displayname)
+        self.db.set_displayname(user_id, displayname)?;
+        services().user_directory.update_user(user_id)
+    }
+
+    /// Sets the displayname of a local user and sends the new membership event and presence update
+    /// into all joined rooms.
+    pub async fn update_displayname(
+        &self,
+        sender_user: &UserId,
+        displayname: Option<String>,
+    ) -> Result<()> {
+        self.set_displayname(sender_user, displayname.clone())?;
+
+        // Send a new membership event and presence update into all joined rooms
+        let all_rooms_joined: Vec<_> = services()
+            .rooms
+            .state_cache
+            .rooms_joined(sender_user)
+            .filter_map(|r| r.ok())
+            .map(|room_id| {
+                Ok::<_, Error>((
+                    PduBuilder {
+                        event_type: TimelineEventType::RoomMember,
+                        content: to_raw_value(&RoomMemberEventContent {
+                            displayname: displayname.clone(),
+                            join_authorized_via_users_server: None,
+                            ..serde_json::from_str(
+                                services()
+                                    .rooms
+                                    .state_accessor
+                                    .room_state_get(
+                                        &room_id,
+                                        &StateEventType::RoomMember,
+                                        sender_user.as_str(),
+                                    )?
+                                    .ok_or_else(|| {
+                                        Error::bad_database(
+                                            "Tried to send displayname update for user not in the \
+                                         room.",
+                                        )
+                                    })?
+                                    .content
+                                    .get(),
+                            )
+                            .map_err(|_| Error::bad_database("Database contains invalid PDU."))?
+                        })
+                        .expect("event is valid, we just created it"),
+                        unsigned: None,
+                        state_key: Some(sender_user.to_string()),
+                        redacts: None,
+                        timestamp: None,
+                    },
+                    room_id,
+                ))
+            })
+            .filter_map(|r| r.ok())
+            .collect();
+
+        for (pdu_builder, room_id) in all_rooms_joined {
+            let mutex_state = Arc::clone(
+                services()
+                    .globals
+                    .roomid_mutex_state
+                    .write()
+                    .await
+                    .entry(room_id.clone())
+                    .or_default(),
+            );
+            let state_lock = mutex_state.lock().await;
+
+            let _ = services()
+                .rooms
+                .timeline
+                .build_and_append_pdu(pdu_builder, sender_user, &room_id, &state_lock)
+                .await;
+        }
+
+        // Presence update
+        services()
+            .rooms
+            .edus
+            .presence
+            .resend_presence(sender_user)
+            .await?;
+
+        Ok(())
     }
 
     /// Get the avatar_url of a user.
@@ -379,10 +582,48 @@ impl Service {
     }
 
     /// Replaces the access token of one device.
+    ///
+    /// The new access token never expires and any refresh token of the device is invalidated.
     pub fn set_token(&self, user_id: &UserId, device_id: &DeviceId, token: &str) -> Result<()> {
         self.db.set_token(user_id, device_id, token)
     }
 
+    /// Makes the current access token of the device expire after the configured `access_token_ttl`
+    /// and returns a refresh token for it, together with the time until the access token expires.
+    ///
+    /// Returns `None` if access tokens are configured to never expire.
+    pub fn create_refresh_token(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Option<(String, Duration)>> {
+        let Some(expires_in) = services().globals.config.access_token_ttl else {
+            return Ok(None);
+        };
+
+        let refresh_token = self
+            .db
+            .create_refresh_token(user_id, device_id, expires_in)?;
+
+        Ok(Some((refresh_token, Duration::from_secs(expires_in))))
+    }
+
+    /// Find out which device a refresh token belongs to.
+    pub fn find_from_refresh_token(
+        &self,
+        refresh_token: &str,
+    ) -> Result<Option<(OwnedUserId, OwnedDeviceId)>> {
+        self.db.find_from_refresh_token(refresh_token)
+    }
+
+    /// Checks whether the access token of the device has expired and needs to be refreshed.
+    pub fn is_token_expired(&self, user_id: &UserId, device_id: &DeviceId) -> Result<bool> {
+        Ok(self
+            .db
+            .token_expires_at(user_id, device_id)?
+            .is_some_and(|expires_at| expires_at <= utils::millis_since_unix_epoch()))
+    }
+
     pub fn add_one_time_key(
         &self,
         user_id: &UserId,
@@ -404,7 +645,14 @@ impl Service {
         device_id: &DeviceId,
         key_algorithm: &OneTimeKeyAlgorithm,
     ) -> Result<Option<(OwnedOneTimeKeyId, Raw<OneTimeKey>)>> {
-        self.db.take_one_time_key(user_id, device_id, key_algorithm)
+        match self
+            .db
+            .take_one_time_key(user_id, device_id, key_algorithm)?
+        {
+            Some(one_time_key) => Ok(Some(one_time_key)),
+            // Devices that ran out of one-time keys can still be reached with their fallback key
+            None => self.db.take_fallback_key(user_id, device_id, key_algorithm),
+        }
     }
 
     pub fn count_one_time_keys(
@@ -415,6 +663,26 @@ impl Service {
         self.db.count_one_time_keys(user_id, device_id)
     }
 
+    pub fn add_fallback_key(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        fallback_key_key: &OwnedOneTimeKeyId,
+        fallback_key_value: &Raw<OneTimeKey>,
+    ) -> Result<()> {
+        self.db
+            .add_fallback_key(user_id, device_id, fallback_key_key, fallback_key_value)
+    }
+
+    /// Returns the algorithms of the fallback keys of the device that were not handed out yet.
+    pub fn unused_fallback_key_types(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Vec<OneTimeKeyAlgorithm>> {
+        self.db.unused_fallback_key_types(user_id, device_id)
+    }
+
     pub fn add_device_keys(
         &self,
         user_id: &UserId,
@@ -515,7 +783,8 @@ impl Service {
         self.db.get_user_signing_key(user_id)
     }
 
-    pub fn add_to_device_event(
+    /// Stores a to-device event for the device, or hands it to the appservice of the user.
+    pub async fn add_to_device_event(
         &self,
         sender: &UserId,
         target_user_id: &UserId,
@@ -523,6 +792,20 @@ impl Service {
         event_type: &str,
         content: serde_json::Value,
     ) -> Result<()> {
+        if services()
+            .sending
+            .send_to_device_appservice(
+                sender,
+                target_user_id,
+                target_device_id,
+                event_type,
+                &content,
+            )
+            .await?
+        {
+            return Ok(());
+        }
+
         self.db.add_to_device_event(
             sender,
             target_user_id,
@@ -608,6 +891,57 @@ impl Service {
         Ok(())
     }
 
+    /// Replaces the dehydrated device of the user with a new device that has the given keys.
+    pub fn set_dehydrated_device(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        initial_device_display_name: Option<String>,
+        device_data: &Raw<DehydratedDeviceData>,
+        device_keys: &Raw<DeviceKeys>,
+        one_time_keys: &BTreeMap<OwnedOneTimeKeyId, Raw<OneTimeKey>>,
+        fallback_keys: &BTreeMap<OwnedOneTimeKeyId, Raw<OneTimeKey>>,
+    ) -> Result<()> {
+        if let Some((old_device_id, _)) = self.db.get_dehydrated_device(user_id)? {
+            self.remove_device(user_id, &old_device_id)?;
+        }
+
+        // Nobody ever gets the access token of a dehydrated device
+        self.create_device(
+            user_id,
+            device_id,
+            &utils::random_string(TOKEN_LENGTH),
+            initial_device_display_name,
+        )?;
+        self.add_device_keys(user_id, device_id, device_keys)?;
+        for (one_time_key_key, one_time_key_value) in one_time_keys {
+            self.add_one_time_key(user_id, device_id, one_time_key_key, one_time_key_value)?;
+        }
+        for (fallback_key_key, fallback_key_value) in fallback_keys {
+            self.add_fallback_key(user_id, device_id, fallback_key_key, fallback_key_value)?;
+        }
+
+        self.db
+            .set_dehydrated_device(user_id, device_id, device_data)
+    }
+
+    /// Returns the id and the data of the dehydrated device of the user.
+    pub fn get_dehydrated_device(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Option<(OwnedDeviceId, Raw<DehydratedDeviceData>)>> {
+        self.db.get_dehydrated_device(user_id)
+    }
+
+    pub fn set_server_notices_room(&self, user_id: &UserId, room_id: &RoomId) -> Result<()> {
+        self.db.set_server_notices_room(user_id, room_id)
+    }
+
+    /// Returns the room in which the user receives server notices, if there is one.
+    pub fn server_notices_room(&self, user_id: &UserId) -> Result<Option<OwnedRoomId>> {
+        self.db.server_notices_room(user_id)
+    }
+
     /// Creates a new sync filter. Returns the filter id.
     pub fn create_filter(&self, user_id: &UserId, filter: &FilterDefinition) -> Result<String> {
         self.db.create_filter(user_id, filter)
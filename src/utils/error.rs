@@ -29,6 +29,12 @@ pub enum Error {
         #[from]
         source: rocksdb::Error,
     },
+    #[cfg(feature = "postgres")]
+    #[error("There was a problem with the connection to the postgres database: {source}")]
+    PostgresError {
+        #[from]
+        source: postgres::Error,
+    },
     #[error("Could not generate an image.")]
     ImageError {
         #[from]
@@ -155,6 +161,8 @@ impl Error {
             Self::SqliteError { .. } => db_error,
             #[cfg(feature = "rocksdb")]
             Self::RocksDbError { .. } => db_error,
+            #[cfg(feature = "postgres")]
+            Self::PostgresError { .. } => db_error,
             Self::IoError { .. } => db_error,
             Self::BadConfig { .. } => db_error,
             Self::BadDatabase { .. } => db_error,